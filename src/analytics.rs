@@ -0,0 +1,188 @@
+//! Snapshot analytics
+//!
+//! Figures derived from the metadata of snapshots and files, for retention decisions, capacity
+//! forecasts and maintenance scheduling.
+
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::ioctl;
+use crate::ioctl::SearchKey;
+use crate::subvolume::Subvolume;
+use crate::subvolume::SubvolumeId;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::Duration;
+
+/// Largest extent btrfs writes for uncompressed data.
+const MAX_EXTENT_SIZE: u64 = 128 * 1024 * 1024;
+/// Largest extent btrfs writes for compressed data.
+const MAX_COMPRESSED_EXTENT_SIZE: u64 = 128 * 1024;
+
+/// Change between two consecutive snapshots.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChurnPoint {
+    /// Id of the older snapshot.
+    pub from: SubvolumeId,
+    /// Id of the newer snapshot.
+    pub to: SubvolumeId,
+    /// Time between the creation of the two snapshots.
+    pub interval: Duration,
+    /// Number of file extents written in between.
+    pub extents_changed: u64,
+    /// Number of bytes written in between.
+    pub bytes_changed: u64,
+}
+
+impl ChurnPoint {
+    /// Get the rate of change in bytes per second, or None if both snapshots were created at the
+    /// same time.
+    pub fn rate(&self) -> Option<f64> {
+        let secs = self.interval.as_secs_f64();
+        if secs == 0.0 {
+            return None;
+        }
+        Some(self.bytes_changed as f64 / secs)
+    }
+}
+
+/// Compute how much changed between each pair of consecutive snapshots of a series.
+///
+/// The snapshots must be of the same subvolume, ordered from oldest to newest. Changes are the
+/// file extents of the newer snapshot written after the older one was last changed, so data that
+/// was deleted or overwritten more than once in between is not counted.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn churn(snapshot_series: &[Subvolume]) -> Result<Vec<ChurnPoint>> {
+    let paths: Vec<&Path> = snapshot_series.iter().map(Subvolume::path).collect();
+    churn_impl(snapshot_series).context("analytics::churn", &paths)
+}
+
+fn churn_impl(snapshot_series: &[Subvolume]) -> Result<Vec<ChurnPoint>> {
+    let infos = snapshot_series
+        .iter()
+        .map(|snapshot| snapshot.info())
+        .collect::<Result<Vec<_>>>()?;
+
+    snapshot_series
+        .windows(2)
+        .zip(infos.windows(2))
+        .map(|(snapshots, infos)| {
+            let (older, newer) = (&infos[0], &infos[1]);
+            let (extents_changed, bytes_changed) = written_since(&snapshots[1], older.ctransid)?;
+            Ok(ChurnPoint {
+                from: older.id,
+                to: newer.id,
+                interval: newer.otime.duration_since(older.otime).unwrap_or_default(),
+                extents_changed,
+                bytes_changed,
+            })
+        })
+        .collect()
+}
+
+/// How fragmented a file is, as computed by [fragmentation_score].
+///
+/// [fragmentation_score]: fn.fragmentation_score.html
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Fragmentation {
+    /// Number of extents of the file, inline extents excluded.
+    pub extents: u64,
+    /// Least number of extents btrfs could store the same data in.
+    pub ideal_extents: u64,
+    /// Number of consecutive extents that are not physically contiguous.
+    pub discontiguities: u64,
+    /// Number of bytes the extents cover.
+    pub bytes: u64,
+}
+
+impl Fragmentation {
+    /// Get the number of discontiguities per ideal extent: zero for a file laid out as well as it
+    /// can be, growing with the seeks needed to read it sequentially.
+    pub fn score(&self) -> f64 {
+        if self.ideal_extents == 0 {
+            return 0.0;
+        }
+        self.discontiguities as f64 / self.ideal_extents as f64
+    }
+}
+
+/// Compute how fragmented a file is from its extent map.
+///
+/// Compressed extents are compared against the smaller extents btrfs writes for compressed
+/// data, so compression alone does not make a file look fragmented.
+pub fn fragmentation_score<P>(path: P) -> Result<Fragmentation>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    fragmentation_score_impl(path).context("analytics::fragmentation_score", &[path])
+}
+
+pub(crate) fn fragmentation_score_impl(path: &Path) -> Result<Fragmentation> {
+    let file =
+        File::open(path).map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?;
+
+    let mut frag = Fragmentation::default();
+    let mut ideal_bytes: (u64, u64) = (0, 0);
+    let mut next_physical: Option<u64> = None;
+    for extent in ioctl::fiemap(file.as_raw_fd())? {
+        if extent.flags & ioctl::FIEMAP_EXTENT_DATA_INLINE != 0 {
+            continue;
+        }
+        if next_physical.is_some_and(|next| next != extent.physical) {
+            frag.discontiguities += 1;
+        }
+        next_physical = Some(extent.physical + extent.length);
+
+        frag.extents += 1;
+        frag.bytes += extent.length;
+        if extent.flags & ioctl::FIEMAP_EXTENT_ENCODED != 0 {
+            ideal_bytes.1 += extent.length;
+        } else {
+            ideal_bytes.0 += extent.length;
+        }
+    }
+    frag.ideal_extents = ideal_bytes.0.div_ceil(MAX_EXTENT_SIZE)
+        + ideal_bytes.1.div_ceil(MAX_COMPRESSED_EXTENT_SIZE);
+
+    Ok(frag)
+}
+
+/// Count the file extents of a subvolume written after a transaction, and their bytes.
+fn written_since(subvol: &Subvolume, transid: u64) -> Result<(u64, u64)> {
+    let file = ioctl::open(subvol.path())?;
+
+    let mut key = SearchKey::new(
+        subvol.id().get(),
+        (0, ioctl::BTRFS_EXTENT_DATA_KEY, 0),
+        (u64::MAX, ioctl::BTRFS_EXTENT_DATA_KEY, u64::MAX),
+    );
+    // leaves untouched since the transaction cannot hold newer extents
+    key.min_transid = transid + 1;
+
+    let mut extents: u64 = 0;
+    let mut bytes: u64 = 0;
+    ioctl::tree_search(file.as_raw_fd(), key, |header, item| {
+        if header.item_type != ioctl::BTRFS_EXTENT_DATA_KEY || item.len() < 21 {
+            return true;
+        }
+        // the generation the extent was written in leads the item
+        if ioctl::le_u64(item, 0) <= transid {
+            return true;
+        }
+        let num_bytes = match item[20] {
+            ioctl::BTRFS_FILE_EXTENT_INLINE => ioctl::le_u64(item, 8),
+            _ if item.len() >= 53 => ioctl::le_u64(item, 45),
+            _ => return true,
+        };
+        extents += 1;
+        bytes += num_bytes;
+        true
+    })?;
+
+    Ok((extents, bytes))
+}
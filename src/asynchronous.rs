@@ -0,0 +1,218 @@
+//! Async wrappers
+//!
+//! The operations of this library block on ioctls, some of them for as long as a transaction
+//! commit takes. The functions in this module run them on the blocking thread pool of the tokio
+//! runtime and return the same types as their blocking counterparts.
+//!
+//! A panic in a blocking operation is resumed in the task awaiting it. Operations that had not
+//! started yet when the runtime shuts down fail with [GlueError::Cancelled].
+//!
+//! Subvolumes can be enumerated with a [SubvolumeStream].
+//!
+//! [GlueError::Cancelled]: ../error/enum.GlueError.html#variant.Cancelled
+//! [SubvolumeStream]: struct.SubvolumeStream.html
+
+use crate::error::GlueError;
+use crate::qgroup::QgroupInherit;
+use crate::subvolume::AdminSubvolume;
+use crate::subvolume::DeleteFlags;
+use crate::subvolume::SnapshotFlags;
+use crate::subvolume::Subvolume;
+use crate::subvolume::SubvolumeInfo;
+use crate::subvolume::SubvolumeIterator;
+use crate::subvolume::SubvolumeIteratorFlags;
+use crate::sync;
+use crate::sync::TransactionId;
+pub use crate::Result;
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use futures_core::Stream;
+use tokio::sync::mpsc;
+use tokio::task;
+
+/// Number of subvolumes a [SubvolumeStream] reads ahead by default.
+///
+/// [SubvolumeStream]: struct.SubvolumeStream.html
+pub const SUBVOLUME_STREAM_BUFFER: usize = 64;
+
+/// Run a blocking operation on the blocking thread pool.
+async fn blocking<T, F>(op: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    match task::spawn_blocking(op).await {
+        Ok(result) => result,
+        Err(err) if err.is_panic() => std::panic::resume_unwind(err.into_panic()),
+        // blocking tasks that have not started yet are cancelled when the runtime shuts down
+        Err(_) => Err(GlueError::Cancelled.into()),
+    }
+}
+
+/// Build a qgroup inheritance specifier from qgroup ids.
+fn qgroup_inherit(qgroup_ids: Option<Vec<u64>>) -> Result<Option<QgroupInherit>> {
+    let ids = match qgroup_ids {
+        Some(ids) => ids,
+        None => return Ok(None),
+    };
+    let mut qgroup = QgroupInherit::create()?;
+    for id in ids {
+        qgroup.add(id)?;
+    }
+    Ok(Some(qgroup))
+}
+
+/// Same as [Subvolume::create].
+///
+/// The qgroup inheritance specifier is given by the ids of the qgroups to inherit from, since
+/// [QgroupInherit] cannot be sent to another thread.
+///
+/// [Subvolume::create]: ../subvolume/struct.Subvolume.html#method.create
+/// [QgroupInherit]: ../qgroup/struct.QgroupInherit.html
+pub async fn create<P>(path: P, qgroup_ids: Option<Vec<u64>>) -> Result<Subvolume>
+where
+    P: AsRef<Path>,
+{
+    let path: PathBuf = path.as_ref().to_path_buf();
+    blocking(move || Subvolume::create(&path, qgroup_inherit(qgroup_ids)?)).await
+}
+
+/// Same as [Subvolume::snapshot], with the qgroup inheritance specifier given as in [create].
+///
+/// [Subvolume::snapshot]: ../subvolume/struct.Subvolume.html#method.snapshot
+/// [create]: fn.create.html
+pub async fn snapshot<P, F>(
+    subvol: &Subvolume,
+    path: P,
+    flags: F,
+    qgroup_ids: Option<Vec<u64>>,
+) -> Result<Subvolume>
+where
+    P: AsRef<Path>,
+    F: Into<Option<SnapshotFlags>>,
+{
+    let subvol = subvol.clone();
+    let path: PathBuf = path.as_ref().to_path_buf();
+    let flags = flags.into();
+    blocking(move || subvol.snapshot(&path, flags, qgroup_inherit(qgroup_ids)?)).await
+}
+
+/// Same as [AdminSubvolume::delete].
+///
+/// [AdminSubvolume::delete]: ../subvolume/struct.AdminSubvolume.html#method.delete
+pub async fn delete<D>(subvol: AdminSubvolume, flags: D) -> Result<()>
+where
+    D: Into<Option<DeleteFlags>>,
+{
+    let flags = flags.into();
+    blocking(move || subvol.delete(flags)).await
+}
+
+/// Same as [AdminSubvolume::deleted].
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+///
+/// [AdminSubvolume::deleted]: ../subvolume/struct.AdminSubvolume.html#method.deleted
+pub async fn deleted(subvol: AdminSubvolume) -> Result<Vec<Subvolume>> {
+    blocking(move || subvol.deleted()).await
+}
+
+/// Same as [Subvolume::info].
+///
+/// [Subvolume::info]: ../subvolume/struct.Subvolume.html#method.info
+pub async fn info(subvol: &Subvolume) -> Result<SubvolumeInfo> {
+    let subvol = subvol.clone();
+    blocking(move || subvol.info()).await
+}
+
+/// Same as [sync::sync].
+///
+/// [sync::sync]: ../sync/fn.sync.html
+pub async fn sync<P>(path: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let path: PathBuf = path.as_ref().to_path_buf();
+    blocking(move || sync::sync(&path)).await
+}
+
+/// Same as [TransactionId::wait].
+///
+/// [TransactionId::wait]: ../sync/struct.TransactionId.html#method.wait
+pub async fn wait(transid: &TransactionId) -> Result<()> {
+    let transid = transid.clone();
+    blocking(move || transid.wait()).await
+}
+
+/// A stream of subvolumes, the async counterpart of [SubvolumeIterator].
+///
+/// The iterator is driven on the blocking thread pool and reads ahead by at most the size of its
+/// buffer, so a slow consumer holds the iteration back instead of piling up subvolumes in memory.
+/// Dropping the stream stops the iteration.
+///
+/// Failing to create the iterator is yielded as the only item of the stream.
+///
+/// [SubvolumeIterator]: ../subvolume/struct.SubvolumeIterator.html
+#[derive(Debug)]
+pub struct SubvolumeStream(mpsc::Receiver<Result<Subvolume>>);
+
+impl SubvolumeStream {
+    /// Create a new subvolume stream, reading ahead by [SUBVOLUME_STREAM_BUFFER] subvolumes.
+    ///
+    /// Must be called within a tokio runtime.
+    ///
+    /// [SUBVOLUME_STREAM_BUFFER]: constant.SUBVOLUME_STREAM_BUFFER.html
+    pub fn new<P, F>(path: P, flags: F) -> Self
+    where
+        P: AsRef<Path>,
+        F: Into<Option<SubvolumeIteratorFlags>>,
+    {
+        Self::with_buffer(path, flags, SUBVOLUME_STREAM_BUFFER)
+    }
+
+    /// Create a new subvolume stream reading ahead by at most `buffer` subvolumes, which must be
+    /// greater than zero.
+    ///
+    /// Must be called within a tokio runtime.
+    pub fn with_buffer<P, F>(path: P, flags: F, buffer: usize) -> Self
+    where
+        P: AsRef<Path>,
+        F: Into<Option<SubvolumeIteratorFlags>>,
+    {
+        let path: PathBuf = path.as_ref().to_path_buf();
+        let flags = flags.into();
+        let (tx, rx) = mpsc::channel(buffer);
+
+        task::spawn_blocking(move || {
+            // created on the blocking thread, since the iterator cannot be sent across threads
+            let iter = match SubvolumeIterator::new(&path, flags) {
+                Ok(iter) => iter,
+                Err(err) => {
+                    let _ = tx.blocking_send(Err(err));
+                    return;
+                }
+            };
+            for subvol in iter {
+                // the stream has been dropped
+                if tx.blocking_send(subvol).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Self(rx)
+    }
+}
+
+impl Stream for SubvolumeStream {
+    type Item = Result<Subvolume>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx)
+    }
+}
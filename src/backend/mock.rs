@@ -0,0 +1,378 @@
+use crate::backend::Backend;
+use crate::backend::KernelVersion;
+use crate::error::LibError;
+use crate::ioctl;
+use crate::subvolume::DeleteFlags;
+use crate::subvolume::SnapshotFlags;
+use crate::subvolume::Subvolume;
+use crate::subvolume::SubvolumeId;
+use crate::subvolume::SubvolumeInfo;
+use crate::BtrfsUtilError;
+use crate::RawUuid;
+use crate::Result;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::UNIX_EPOCH;
+
+/// An in-memory [Backend] simulating a single btrfs filesystem, for unit tests.
+///
+/// The filesystem is mounted at the root path it is created with and starts out with only its
+/// top-level subvolume. Subvolumes get ids in creation order from [SubvolumeId::FIRST_FREE],
+/// UUIDs derived from their ids, the nil UUID as fsid and times derived from the transaction
+/// creating them, so the same operations always give the same results. No operation requires
+/// privileges.
+///
+/// Directories are not simulated: a subvolume can be created at any path below another one.
+///
+/// It reports running on [MockBackend::KERNEL] unless told otherwise with
+/// [MockBackend::with_kernel], to test how code degrades on older kernels.
+///
+/// [Backend]: trait.Backend.html
+/// [SubvolumeId::FIRST_FREE]: ../subvolume/struct.SubvolumeId.html#associatedconstant.FIRST_FREE
+/// [MockBackend::KERNEL]: #associatedconstant.KERNEL
+/// [MockBackend::with_kernel]: #method.with_kernel
+#[derive(Debug)]
+pub struct MockBackend {
+    root: PathBuf,
+    kernel: KernelVersion,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    subvolumes: BTreeMap<SubvolumeId, SubvolumeInfo>,
+    next_id: u64,
+    generation: u64,
+    default: SubvolumeId,
+}
+
+impl MockBackend {
+    /// The kernel version reported by default.
+    pub const KERNEL: KernelVersion = KernelVersion::new(6, 1, 0);
+
+    /// Create a filesystem with only its top-level subvolume, mounted at `root`.
+    pub fn new<P>(root: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        let root = root.into();
+        let mut state = State {
+            subvolumes: BTreeMap::new(),
+            next_id: SubvolumeId::FIRST_FREE.get(),
+            generation: 0,
+            default: SubvolumeId::FS_TREE,
+        };
+        state.insert(SubvolumeId::FS_TREE, root.clone(), None, None, false);
+
+        Self {
+            root,
+            kernel: Self::KERNEL,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Report running on another kernel version.
+    pub fn with_kernel(mut self, kernel: KernelVersion) -> Self {
+        self.kernel = kernel;
+        self
+    }
+
+    /// Get the path the filesystem is mounted at.
+    #[inline]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // the state is left consistent by every operation, even a panicking test
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Check that a path is on the filesystem.
+    fn check_path(&self, path: &Path) -> Result<()> {
+        if path.starts_with(&self.root) {
+            Ok(())
+        } else {
+            Err(LibError::NotBtrfs.into())
+        }
+    }
+}
+
+impl State {
+    fn insert(
+        &mut self,
+        id: SubvolumeId,
+        path: PathBuf,
+        parent_id: Option<SubvolumeId>,
+        parent_uuid: Option<RawUuid>,
+        ro: bool,
+    ) -> Subvolume {
+        self.generation += 1;
+        let time = UNIX_EPOCH + Duration::from_secs(self.generation);
+        let info = SubvolumeInfo {
+            id,
+            path: path.clone(),
+            parent_id,
+            dir_id: parent_id.map(|_| ioctl::BTRFS_FIRST_FREE_OBJECTID),
+            flags: if ro {
+                ioctl::BTRFS_ROOT_SUBVOL_RDONLY
+            } else {
+                0
+            },
+            uuid: RawUuid::from_bytes(u128::from(id.get()).to_be_bytes()),
+            parent_uuid,
+            received_uuid: None,
+            generation: self.generation,
+            ctransid: self.generation,
+            otransid: self.generation,
+            stransid: None,
+            rtransid: None,
+            ctime: time,
+            otime: time,
+            stime: None,
+            rtime: None,
+        };
+        self.subvolumes.insert(id, info);
+        Subvolume::new(id, RawUuid::NIL, path)
+    }
+
+    /// Add a subvolume at a path, failing with `err` if it cannot be created there.
+    fn add(
+        &mut self,
+        path: &Path,
+        parent_uuid: Option<RawUuid>,
+        ro: bool,
+        err: LibError,
+    ) -> Result<Subvolume> {
+        if self.find(path).is_some() {
+            return Err(BtrfsUtilError::os(err, libc::EEXIST));
+        }
+        let parent = self.containing(path).ok_or(LibError::NotBtrfs)?;
+        if is_ro(parent) {
+            return Err(BtrfsUtilError::os(err, libc::EROFS));
+        }
+
+        let parent_id = parent.id;
+        let id = SubvolumeId::from_raw(self.next_id);
+        self.next_id += 1;
+        Ok(self.insert(id, path.to_path_buf(), Some(parent_id), parent_uuid, ro))
+    }
+
+    fn find(&self, path: &Path) -> Option<&SubvolumeInfo> {
+        self.subvolumes.values().find(|info| info.path == path)
+    }
+
+    /// Get the subvolume a path which is not the root of a subvolume lies in.
+    fn containing(&self, path: &Path) -> Option<&SubvolumeInfo> {
+        self.subvolumes
+            .values()
+            .filter(|info| path.starts_with(&info.path) && path != info.path)
+            .max_by_key(|info| info.path.components().count())
+    }
+
+    /// Get the subvolumes below a subvolume, in pre-order.
+    fn below(&self, info: &SubvolumeInfo) -> Vec<&SubvolumeInfo> {
+        let mut below: Vec<&SubvolumeInfo> = self
+            .subvolumes
+            .values()
+            .filter(|other| other.path.starts_with(&info.path) && other.id != info.id)
+            .collect();
+        below.sort_by(|a, b| a.path.cmp(&b.path));
+        below
+    }
+
+    fn get(&self, subvolume: &Subvolume) -> Result<&SubvolumeInfo> {
+        self.subvolumes
+            .get(&subvolume.id())
+            .filter(|info| info.path == subvolume.path())
+            .ok_or_else(|| LibError::SubvolumeNotFound.into())
+    }
+
+    fn get_mut(&mut self, subvolume: &Subvolume) -> Result<&mut SubvolumeInfo> {
+        self.subvolumes
+            .get_mut(&subvolume.id())
+            .filter(|info| info.path == subvolume.path())
+            .ok_or_else(|| LibError::SubvolumeNotFound.into())
+    }
+}
+
+fn is_ro(info: &SubvolumeInfo) -> bool {
+    info.flags & ioctl::BTRFS_ROOT_SUBVOL_RDONLY != 0
+}
+
+impl Backend for MockBackend {
+    fn subvolume(&self, path: &Path) -> Result<Subvolume> {
+        self.check_path(path)?;
+        match self.lock().find(path) {
+            Some(info) => Ok(Subvolume::new(info.id, RawUuid::NIL, info.path.clone())),
+            None => Err(LibError::NotSubvolume.into()),
+        }
+    }
+
+    fn info(&self, subvolume: &Subvolume) -> Result<SubvolumeInfo> {
+        self.lock().get(subvolume).cloned()
+    }
+
+    fn create(&self, path: &Path) -> Result<Subvolume> {
+        self.check_path(path)?;
+        self.lock()
+            .add(path, None, false, LibError::SubvolCreateFailed)
+    }
+
+    fn snapshot(
+        &self,
+        source: &Subvolume,
+        path: &Path,
+        flags: Option<SnapshotFlags>,
+    ) -> Result<Subvolume> {
+        self.check_path(path)?;
+        let flags = flags.unwrap_or_else(SnapshotFlags::empty);
+        let mut state = self.lock();
+
+        let source_info = state.get(source)?;
+        let nested: Vec<(PathBuf, RawUuid)> = if flags.contains(SnapshotFlags::RECURSIVE) {
+            state
+                .below(source_info)
+                .into_iter()
+                .filter_map(|info| {
+                    let rel = info.path.strip_prefix(source.path()).ok()?;
+                    Some((path.join(rel), info.uuid))
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let uuid = source_info.uuid;
+
+        let snapshot = state.add(path, Some(uuid), false, LibError::SnapCreateFailed)?;
+        // only the snapshot itself is made read-only, once the nested ones are in it
+        for (path, uuid) in nested {
+            state.add(&path, Some(uuid), false, LibError::SnapCreateFailed)?;
+        }
+        if flags.contains(SnapshotFlags::READ_ONLY) {
+            state.get_mut(&snapshot)?.flags |= ioctl::BTRFS_ROOT_SUBVOL_RDONLY;
+        }
+        Ok(snapshot)
+    }
+
+    fn delete(&self, subvolume: Subvolume, flags: Option<DeleteFlags>) -> Result<()> {
+        let recursive = flags.is_some_and(|flags| flags.contains(DeleteFlags::RECURSIVE));
+        let mut state = self.lock();
+
+        let info = state.get(&subvolume)?;
+        let below: Vec<SubvolumeId> = state.below(info).iter().map(|info| info.id).collect();
+        if !below.is_empty() && !recursive {
+            return Err(BtrfsUtilError::os(
+                LibError::SnapDestroyFailed,
+                libc::ENOTEMPTY,
+            ));
+        }
+
+        let ids: Vec<SubvolumeId> = below.into_iter().chain(Some(info.id)).collect();
+        if ids.iter().any(|id| id.is_fs_tree() || *id == state.default) {
+            return Err(BtrfsUtilError::os(LibError::SnapDestroyFailed, libc::EPERM));
+        }
+
+        state.generation += 1;
+        for id in ids {
+            state.subvolumes.remove(&id);
+        }
+        Ok(())
+    }
+
+    fn list(&self, subvolume: &Subvolume) -> Result<Vec<Subvolume>> {
+        let state = self.lock();
+        let info = state.get(subvolume)?;
+        Ok(state
+            .below(info)
+            .into_iter()
+            .map(|info| Subvolume::new(info.id, RawUuid::NIL, info.path.clone()))
+            .collect())
+    }
+
+    fn is_ro(&self, subvolume: &Subvolume) -> Result<bool> {
+        self.lock().get(subvolume).map(is_ro)
+    }
+
+    fn set_ro(&self, subvolume: &Subvolume, ro: bool) -> Result<()> {
+        let mut state = self.lock();
+        let info = state.get_mut(subvolume)?;
+        if ro {
+            info.flags |= ioctl::BTRFS_ROOT_SUBVOL_RDONLY;
+        } else {
+            info.flags &= !ioctl::BTRFS_ROOT_SUBVOL_RDONLY;
+        }
+        Ok(())
+    }
+
+    fn get_default(&self, path: &Path) -> Result<Subvolume> {
+        self.check_path(path)?;
+        let state = self.lock();
+        let info = &state.subvolumes[&state.default];
+        Ok(Subvolume::new(info.id, RawUuid::NIL, info.path.clone()))
+    }
+
+    fn set_default(&self, subvolume: &Subvolume) -> Result<()> {
+        let mut state = self.lock();
+        state.default = state.get(subvolume)?.id;
+        Ok(())
+    }
+
+    fn version(&self) -> Result<KernelVersion> {
+        Ok(self.kernel)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::KernelFeature;
+
+    #[test]
+    fn mock_subvolumes() {
+        let backend = MockBackend::new("/mnt");
+        let top = backend.subvolume(Path::new("/mnt")).unwrap();
+        assert!(top.id().is_fs_tree());
+
+        let root = backend.create(Path::new("/mnt/@")).unwrap();
+        let home = backend.create(Path::new("/mnt/@/home")).unwrap();
+        assert_eq!(root.id(), SubvolumeId::FIRST_FREE);
+        assert_eq!(backend.info(&home).unwrap().parent_id, Some(root.id()));
+        assert_eq!(backend.list(&top).unwrap(), vec![root.clone(), home]);
+
+        let snapshot = backend
+            .snapshot(
+                &root,
+                Path::new("/mnt/snap"),
+                Some(SnapshotFlags::READ_ONLY | SnapshotFlags::RECURSIVE),
+            )
+            .unwrap();
+        let info = backend.info(&snapshot).unwrap();
+        assert_eq!(info.parent_uuid, Some(backend.info(&root).unwrap().uuid));
+        assert!(backend.is_ro(&snapshot).unwrap());
+        assert_eq!(backend.list(&snapshot).unwrap().len(), 1);
+        assert!(backend.create(Path::new("/mnt/snap/x")).is_err());
+
+        backend.set_default(&root).unwrap();
+        assert_eq!(backend.get_default(Path::new("/mnt")).unwrap(), root);
+        assert!(backend
+            .delete(root.clone(), Some(DeleteFlags::RECURSIVE))
+            .is_err());
+        assert!(backend.delete(snapshot.clone(), None).is_err());
+        backend
+            .delete(snapshot, Some(DeleteFlags::RECURSIVE))
+            .unwrap();
+        assert_eq!(backend.list(&top).unwrap().len(), 2);
+        assert!(backend.subvolume(Path::new("/mnt/snap")).is_err());
+        assert!(backend.subvolume(Path::new("/elsewhere")).is_err());
+
+        let old = MockBackend::new("/mnt").with_kernel(KernelVersion::new(4, 14, 0));
+        assert!(backend.supports(KernelFeature::DeleteById).unwrap());
+        assert!(!old.supports(KernelFeature::UnprivilegedSubvolumes).unwrap());
+    }
+}
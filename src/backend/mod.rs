@@ -0,0 +1,183 @@
+//! Backends
+//!
+//! A [Backend] performs the core subvolume operations. Code written against it rather than
+//! against [Subvolume] directly runs on real filesystems with the [LibBtrfsutil] of the
+//! `libbtrfsutil` feature, or with the [IoctlBackend] of the `pure-rust` feature which issues the
+//! btrfs ioctls itself, and can be unit tested without a btrfs filesystem or root with the
+//! in-memory [MockBackend] of the `mock` feature.
+//!
+//! Backends report the [KernelVersion] they run on, so that features missing from older kernels
+//! can be detected up front with [Backend::supports] instead of by failing operations.
+//!
+//! [Backend]: trait.Backend.html
+//! [Subvolume]: ../subvolume/struct.Subvolume.html
+//! [LibBtrfsutil]: struct.LibBtrfsutil.html
+//! [IoctlBackend]: struct.IoctlBackend.html
+//! [MockBackend]: struct.MockBackend.html
+//! [KernelVersion]: struct.KernelVersion.html
+//! [Backend::supports]: trait.Backend.html#method.supports
+
+#[cfg(feature = "mock")]
+mod mock;
+#[cfg(feature = "pure-rust")]
+mod pure;
+mod version;
+
+use crate::subvolume::DeleteFlags;
+use crate::subvolume::SnapshotFlags;
+use crate::subvolume::Subvolume;
+use crate::subvolume::SubvolumeInfo;
+#[cfg(feature = "libbtrfsutil")]
+use crate::subvolume::SubvolumeIterator;
+pub use crate::Result;
+
+use std::path::Path;
+
+#[cfg(feature = "mock")]
+#[cfg_attr(docsrs, doc(cfg(feature = "mock")))]
+pub use mock::MockBackend;
+#[cfg(feature = "pure-rust")]
+#[cfg_attr(docsrs, doc(cfg(feature = "pure-rust")))]
+pub use pure::IoctlBackend;
+pub use version::KernelFeature;
+pub use version::KernelVersion;
+
+/// The core subvolume operations.
+///
+/// Paths are absolute paths to the roots of subvolumes, or to where subvolumes are created.
+pub trait Backend {
+    /// Get the subvolume whose root is at a path, see [Subvolume::get].
+    ///
+    /// [Subvolume::get]: ../subvolume/struct.Subvolume.html#method.get
+    fn subvolume(&self, path: &Path) -> Result<Subvolume>;
+
+    /// Get information about a subvolume, see [Subvolume::info].
+    ///
+    /// [Subvolume::info]: ../subvolume/struct.Subvolume.html#method.info
+    fn info(&self, subvolume: &Subvolume) -> Result<SubvolumeInfo>;
+
+    /// Create a subvolume, see [Subvolume::create].
+    ///
+    /// [Subvolume::create]: ../subvolume/struct.Subvolume.html#method.create
+    fn create(&self, path: &Path) -> Result<Subvolume>;
+
+    /// Snapshot a subvolume, see [Subvolume::snapshot].
+    ///
+    /// [Subvolume::snapshot]: ../subvolume/struct.Subvolume.html#method.snapshot
+    fn snapshot(
+        &self,
+        source: &Subvolume,
+        path: &Path,
+        flags: Option<SnapshotFlags>,
+    ) -> Result<Subvolume>;
+
+    /// Delete a subvolume, see [AdminSubvolume::delete].
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    ///
+    /// [AdminSubvolume::delete]: ../subvolume/struct.AdminSubvolume.html#method.delete
+    fn delete(&self, subvolume: Subvolume, flags: Option<DeleteFlags>) -> Result<()>;
+
+    /// List the subvolumes below a subvolume, in pre-order, see [SubvolumeIterator].
+    ///
+    /// [SubvolumeIterator]: ../subvolume/struct.SubvolumeIterator.html
+    fn list(&self, subvolume: &Subvolume) -> Result<Vec<Subvolume>>;
+
+    /// Check whether a subvolume is read-only, see [Subvolume::is_ro].
+    ///
+    /// [Subvolume::is_ro]: ../subvolume/struct.Subvolume.html#method.is_ro
+    fn is_ro(&self, subvolume: &Subvolume) -> Result<bool>;
+
+    /// Set whether a subvolume is read-only, see [AdminSubvolume::set_ro].
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    ///
+    /// [AdminSubvolume::set_ro]: ../subvolume/struct.AdminSubvolume.html#method.set_ro
+    fn set_ro(&self, subvolume: &Subvolume, ro: bool) -> Result<()>;
+
+    /// Get the default subvolume of the filesystem a path belongs to, see
+    /// [Subvolume::get_default].
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    ///
+    /// [Subvolume::get_default]: ../subvolume/struct.Subvolume.html#method.get_default
+    fn get_default(&self, path: &Path) -> Result<Subvolume>;
+
+    /// Make a subvolume the default subvolume, see [AdminSubvolume::set_default].
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    ///
+    /// [AdminSubvolume::set_default]: ../subvolume/struct.AdminSubvolume.html#method.set_default
+    fn set_default(&self, subvolume: &Subvolume) -> Result<()>;
+
+    /// Get the version of the kernel the operations run on, the running one by default.
+    fn version(&self) -> Result<KernelVersion> {
+        KernelVersion::running()
+    }
+
+    /// Check whether the kernel the operations run on has a feature.
+    fn supports(&self, feature: KernelFeature) -> Result<bool> {
+        Ok(self.version()? >= feature.since())
+    }
+}
+
+/// The backend performing operations on real filesystems through libbtrfsutil.
+#[cfg(feature = "libbtrfsutil")]
+#[cfg_attr(docsrs, doc(cfg(feature = "libbtrfsutil")))]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LibBtrfsutil;
+
+#[cfg(feature = "libbtrfsutil")]
+impl Backend for LibBtrfsutil {
+    fn subvolume(&self, path: &Path) -> Result<Subvolume> {
+        Subvolume::get(path)
+    }
+
+    fn info(&self, subvolume: &Subvolume) -> Result<SubvolumeInfo> {
+        subvolume.info()
+    }
+
+    fn create(&self, path: &Path) -> Result<Subvolume> {
+        Subvolume::create(path, None)
+    }
+
+    fn snapshot(
+        &self,
+        source: &Subvolume,
+        path: &Path,
+        flags: Option<SnapshotFlags>,
+    ) -> Result<Subvolume> {
+        source.snapshot(path, flags, None)
+    }
+
+    fn delete(&self, subvolume: Subvolume, flags: Option<DeleteFlags>) -> Result<()> {
+        subvolume.elevate()?.delete(flags)
+    }
+
+    fn list(&self, subvolume: &Subvolume) -> Result<Vec<Subvolume>> {
+        SubvolumeIterator::new(subvolume.path(), None)?
+            .entries()
+            .map(|entry| {
+                entry.map(|(id, path)| {
+                    Subvolume::new(id, subvolume.fsid(), subvolume.path().join(path))
+                })
+            })
+            .collect()
+    }
+
+    fn is_ro(&self, subvolume: &Subvolume) -> Result<bool> {
+        subvolume.is_ro()
+    }
+
+    fn set_ro(&self, subvolume: &Subvolume, ro: bool) -> Result<()> {
+        subvolume.clone().elevate()?.set_ro(ro)
+    }
+
+    fn get_default(&self, path: &Path) -> Result<Subvolume> {
+        Subvolume::get_default(path)
+    }
+
+    fn set_default(&self, subvolume: &Subvolume) -> Result<()> {
+        subvolume.clone().elevate()?.set_default()
+    }
+}
@@ -0,0 +1,107 @@
+use crate::backend::Backend;
+use crate::ioctl;
+use crate::subvolume::DeleteFlags;
+use crate::subvolume::SnapshotFlags;
+use crate::subvolume::Subvolume;
+use crate::subvolume::SubvolumeId;
+use crate::subvolume::SubvolumeInfo;
+use crate::sys::pure;
+use crate::RawUuid;
+use crate::Result;
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// A [Backend] issuing the btrfs ioctls directly instead of calling into libbtrfsutil.
+///
+/// The operations are the ones the library performs without libbtrfsutil, with its semantics:
+/// looking up subvolume information and listing subvolumes use the unprivileged ioctls of
+/// Linux 4.18 when not running as root, and getting the default subvolume searches the root
+/// tree, which requires **CAP_SYS_ADMIN**.
+///
+/// [Backend]: trait.Backend.html
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct IoctlBackend;
+
+impl Backend for IoctlBackend {
+    fn subvolume(&self, path: &Path) -> Result<Subvolume> {
+        pure::is_subvolume(path)?;
+
+        let file = ioctl::open(path)?;
+        Ok(Subvolume::new(
+            SubvolumeId::from_raw(ioctl::subvolume_id(file.as_raw_fd())?),
+            RawUuid(ioctl::fs_info(file.as_raw_fd())?.fsid),
+            path.to_path_buf(),
+        ))
+    }
+
+    fn info(&self, subvolume: &Subvolume) -> Result<SubvolumeInfo> {
+        let info = pure::subvolume_info(subvolume.path(), 0)?;
+        SubvolumeInfo::from_raw(&info, subvolume.path().to_path_buf())
+    }
+
+    fn create(&self, path: &Path) -> Result<Subvolume> {
+        let transid = pure::create_subvolume(path, None)?;
+        pure::wait_sync(path, transid)?;
+
+        self.subvolume(path)
+    }
+
+    fn snapshot(
+        &self,
+        source: &Subvolume,
+        path: &Path,
+        flags: Option<SnapshotFlags>,
+    ) -> Result<Subvolume> {
+        let flags = flags.unwrap_or_else(SnapshotFlags::empty);
+        let transid = pure::create_snapshot(
+            source.path(),
+            path,
+            flags.contains(SnapshotFlags::RECURSIVE),
+            flags.contains(SnapshotFlags::READ_ONLY),
+            None,
+        )?;
+        pure::wait_sync(path, transid)?;
+
+        self.subvolume(path)
+    }
+
+    fn delete(&self, subvolume: Subvolume, flags: Option<DeleteFlags>) -> Result<()> {
+        let recursive = flags.is_some_and(|flags| flags.contains(DeleteFlags::RECURSIVE));
+        pure::delete_subvolume(subvolume.path(), recursive)
+    }
+
+    fn list(&self, subvolume: &Subvolume) -> Result<Vec<Subvolume>> {
+        let mut iter = pure::SubvolumeIter::new(subvolume.path(), 0, false)?;
+        let mut rel = Vec::new();
+        let mut subvolumes = Vec::new();
+        while let Some(id) = iter.next(&mut rel) {
+            subvolumes.push(Subvolume::new(
+                SubvolumeId::from_raw(id?),
+                subvolume.fsid(),
+                subvolume.path().join(OsStr::from_bytes(&rel)),
+            ));
+        }
+        Ok(subvolumes)
+    }
+
+    fn is_ro(&self, subvolume: &Subvolume) -> Result<bool> {
+        pure::subvolume_read_only(subvolume.path())
+    }
+
+    fn set_ro(&self, subvolume: &Subvolume, ro: bool) -> Result<()> {
+        pure::set_subvolume_read_only(subvolume.path(), ro)
+    }
+
+    fn get_default(&self, path: &Path) -> Result<Subvolume> {
+        let id = pure::default_subvolume(path)?;
+
+        self.subvolume(path)?.resolve(SubvolumeId::from_raw(id))
+    }
+
+    fn set_default(&self, subvolume: &Subvolume) -> Result<()> {
+        pure::set_default_subvolume(subvolume.path(), subvolume.id().get())
+    }
+}
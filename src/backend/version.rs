@@ -0,0 +1,122 @@
+use crate::error::LibError;
+use crate::BtrfsUtilError;
+use crate::Result;
+
+use std::ffi::CStr;
+use std::fmt;
+use std::mem::MaybeUninit;
+
+/// A Linux kernel version.
+///
+/// Ordered like versions, so a feature is available when the running version is at least the one
+/// of [KernelFeature::since].
+///
+/// [KernelFeature::since]: enum.KernelFeature.html#method.since
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct KernelVersion {
+    /// The major version.
+    pub major: u32,
+    /// The minor version.
+    pub minor: u32,
+    /// The patch level.
+    pub patch: u32,
+}
+
+impl KernelVersion {
+    /// Create a kernel version.
+    #[inline]
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Get the version of the running kernel, from its release as `uname -r` prints it.
+    pub fn running() -> Result<Self> {
+        let mut uts = MaybeUninit::<libc::utsname>::uninit();
+        if unsafe { libc::uname(uts.as_mut_ptr()) } < 0 {
+            return Err(BtrfsUtilError::last_os_error(LibError::UnameFailed));
+        }
+        let uts = unsafe { uts.assume_init() };
+        let release = unsafe { CStr::from_ptr(uts.release.as_ptr()) };
+
+        release
+            .to_str()
+            .ok()
+            .and_then(Self::parse)
+            .ok_or_else(|| LibError::UnameFailed.into())
+    }
+
+    /// Parse a kernel release like `6.1.0-13-amd64`, ignoring everything after the version.
+    ///
+    /// The patch level defaults to 0 when missing, as in `6.1-rc1`.
+    pub fn parse(release: &str) -> Option<Self> {
+        let end = release
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(release.len());
+        let mut numbers = release[..end].split('.').map(str::parse::<u32>);
+
+        let major = numbers.next()?.ok()?;
+        let minor = numbers.next()?.ok()?;
+        let patch = numbers.next().unwrap_or(Ok(0)).ok()?;
+        Some(Self::new(major, minor, patch))
+    }
+}
+
+impl fmt::Display for KernelVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// A kernel feature that backends use when the running kernel has it and work around otherwise.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum KernelFeature {
+    /// Looking up and listing subvolumes without **CAP_SYS_ADMIN**, with
+    /// `BTRFS_IOC_GET_SUBVOL_INFO`, `BTRFS_IOC_GET_SUBVOL_ROOTREF` and
+    /// `BTRFS_IOC_INO_LOOKUP_USER`.
+    UnprivilegedSubvolumes,
+    /// Deleting subvolumes by id with `BTRFS_IOC_SNAP_DESTROY_V2`.
+    DeleteById,
+}
+
+impl KernelFeature {
+    /// Get the first kernel version with the feature.
+    pub fn since(self) -> KernelVersion {
+        match self {
+            KernelFeature::UnprivilegedSubvolumes => KernelVersion::new(4, 18, 0),
+            KernelFeature::DeleteById => KernelVersion::new(5, 7, 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn kernel_version_parse() {
+        assert_eq!(
+            KernelVersion::parse("6.1.0-13-amd64"),
+            Some(KernelVersion::new(6, 1, 0))
+        );
+        assert_eq!(
+            KernelVersion::parse("4.18.20"),
+            Some(KernelVersion::new(4, 18, 20))
+        );
+        assert_eq!(
+            KernelVersion::parse("6.8-rc1"),
+            Some(KernelVersion::new(6, 8, 0))
+        );
+        assert_eq!(KernelVersion::parse("linux"), None);
+        assert_eq!(KernelVersion::parse("6"), None);
+
+        assert!(KernelVersion::new(4, 17, 19) < KernelFeature::UnprivilegedSubvolumes.since());
+        assert!(KernelVersion::new(5, 10, 0) >= KernelFeature::DeleteById.since());
+        assert_eq!(KernelVersion::new(5, 15, 2).to_string(), "5.15.2");
+    }
+}
@@ -0,0 +1,371 @@
+//! Backups by send and receive
+//!
+//! A [BackupPipeline] sends read-only snapshots to a [BackupTarget], incrementally whenever the
+//! target already holds a related snapshot: it picks the newest such snapshot as the parent,
+//! streams the difference, verifies the target got all of it and records the snapshot as held
+//! by the target, so the next run can build on it.
+//!
+//! Streams written to a [BackupTarget::Writer] are received elsewhere, so which snapshots the
+//! target holds has to be remembered between runs, from [BackupPipeline::received_uuids]. A
+//! [BackupTarget::Local] directory is looked at directly.
+//!
+//! [BackupPipeline]: struct.BackupPipeline.html
+//! [BackupPipeline::received_uuids]: struct.BackupPipeline.html#method.received_uuids
+//! [BackupTarget]: enum.BackupTarget.html
+//! [BackupTarget::Writer]: enum.BackupTarget.html#variant.Writer
+//! [BackupTarget::Local]: enum.BackupTarget.html#variant.Local
+
+use crate::common;
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::idempotency;
+use crate::idempotency::IdempotencyKey;
+use crate::ioctl;
+use crate::ioctl::SendArgs;
+use crate::subvolume::Subvolume;
+use crate::subvolume::SubvolumeInfo;
+use crate::BtrfsUtilError;
+use crate::RawUuid;
+pub use crate::Result;
+
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+use std::thread;
+
+/// Where a backup is sent.
+pub enum BackupTarget<'a> {
+    /// Write the send stream, for example into a file or into `btrfs receive` on another host.
+    Writer(&'a mut dyn Write),
+    /// Receive the snapshot into a directory of a local Btrfs filesystem, with `btrfs receive`,
+    /// which has to be installed.
+    Local(&'a Path),
+}
+
+/// A snapshot sent by a [BackupPipeline].
+///
+/// [BackupPipeline]: struct.BackupPipeline.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackupOutcome {
+    /// The snapshot sent.
+    pub snapshot: Subvolume,
+    /// The parent the snapshot was sent relative to, if the send was incremental.
+    pub parent: Option<Subvolume>,
+    /// The UUID the copy of the snapshot carries as its received UUID.
+    pub received_uuid: RawUuid,
+    /// Number of bytes of the send stream, for writer targets.
+    pub bytes: Option<u64>,
+    /// The copy of the snapshot, for local targets.
+    pub received: Option<Subvolume>,
+}
+
+/// Sends snapshots to a target, incrementally whenever possible.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BackupPipeline {
+    parents: Vec<Subvolume>,
+    received: HashSet<RawUuid>,
+}
+
+impl BackupPipeline {
+    /// Create a pipeline considering snapshots of the source side as incremental parents, such
+    /// as the earlier snapshots of the subvolume being backed up.
+    pub fn new<I>(parents: I) -> Self
+    where
+        I: IntoIterator<Item = Subvolume>,
+    {
+        Self {
+            parents: parents.into_iter().collect(),
+            received: HashSet::new(),
+        }
+    }
+
+    /// Record that the target holds the snapshots with these UUIDs, as recorded by earlier runs.
+    pub fn received<I>(mut self, uuids: I) -> Self
+    where
+        I: IntoIterator<Item = RawUuid>,
+    {
+        self.received.extend(uuids);
+        self
+    }
+
+    /// Get the UUIDs of the snapshots recorded as held by the target, to be kept for later runs.
+    pub fn received_uuids(&self) -> impl Iterator<Item = &RawUuid> {
+        self.received.iter()
+    }
+
+    /// Pick the parent to send a snapshot relative to.
+    ///
+    /// Candidates are read-only snapshots of the same filesystem held by the target and not
+    /// newer than the snapshot; snapshots of the same subvolume as the snapshot are preferred,
+    /// then the newest. Returns None if the snapshot has to be sent in full.
+    pub fn parent_for(
+        &self,
+        snapshot: &Subvolume,
+        target: &BackupTarget<'_>,
+    ) -> Result<Option<Subvolume>> {
+        let info = snapshot.info()?;
+        let held = self.held_by(target)?;
+
+        let mut best: Option<((bool, u64), &Subvolume)> = None;
+        for parent in &self.parents {
+            if parent.id() == snapshot.id() || !parent.same_filesystem(snapshot)? {
+                continue;
+            }
+            let parent_info = match parent.info() {
+                Ok(parent_info) => parent_info,
+                // deleted since the pipeline was created
+                Err(_) => continue,
+            };
+            if !held.contains(&parent_info.uuid)
+                || parent_info.ctransid > info.ctransid
+                || !parent.is_ro()?
+            {
+                continue;
+            }
+            let rank = (
+                info.parent_uuid.is_some() && parent_info.parent_uuid == info.parent_uuid,
+                parent_info.ctransid,
+            );
+            if best.is_none_or(|(best_rank, _)| rank > best_rank) {
+                best = Some((rank, parent));
+            }
+        }
+
+        Ok(best.map(|(_, parent)| parent.clone()))
+    }
+
+    /// Send a read-only snapshot to a target and record it as held by the target.
+    ///
+    /// For a local target, the copy is verified to be read-only and to carry the UUID and
+    /// transaction id of the snapshot. For a writer target, the stream is complete once the
+    /// kernel finished producing it and all of it was written and flushed.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn run(&mut self, snapshot: &Subvolume, target: BackupTarget<'_>) -> Result<BackupOutcome> {
+        self.run_impl(snapshot, target)
+            .context("BackupPipeline::run", &[snapshot.path()])
+    }
+
+    /// Send a read-only snapshot to a local target, unless it was already received there with
+    /// the same idempotency key, see [run].
+    ///
+    /// The key is recorded for the copy once it is verified. When the copy is found with the key
+    /// on a retry, it is verified again and returned without sending anything, with no parent.
+    /// Streams written to a [BackupTarget::Writer] are out of reach of the key, so writer targets
+    /// fail with [LibError::InvalidArgument]; keep track of them with [received_uuids] instead.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    ///
+    /// [run]: #method.run
+    /// [BackupTarget::Writer]: enum.BackupTarget.html#variant.Writer
+    /// [LibError::InvalidArgument]: ../error/enum.LibError.html#variant.InvalidArgument
+    /// [received_uuids]: #method.received_uuids
+    pub fn run_idempotent(
+        &mut self,
+        snapshot: &Subvolume,
+        target: BackupTarget<'_>,
+        key: &IdempotencyKey,
+    ) -> Result<BackupOutcome> {
+        self.run_idempotent_impl(snapshot, target, key)
+            .context("BackupPipeline::run_idempotent", &[snapshot.path()])
+    }
+
+    fn run_idempotent_impl(
+        &mut self,
+        snapshot: &Subvolume,
+        target: BackupTarget<'_>,
+        key: &IdempotencyKey,
+    ) -> Result<BackupOutcome> {
+        let dir = match target {
+            BackupTarget::Local(dir) => dir,
+            BackupTarget::Writer(_) => return Err(LibError::InvalidArgument.into()),
+        };
+        let name = snapshot
+            .path()
+            .file_name()
+            .ok_or(LibError::InvalidArgument)?;
+        let copy = dir.join(name);
+
+        if common::path_exists(&copy)? && idempotency::get_impl(&copy)?.as_ref() == Some(key) {
+            let info = snapshot.info()?;
+            let received = verify_received(snapshot, &info, dir)?;
+            self.record(snapshot, &info);
+            return Ok(BackupOutcome {
+                snapshot: snapshot.clone(),
+                parent: None,
+                received_uuid: info.uuid,
+                bytes: None,
+                received: Some(received),
+            });
+        }
+
+        let outcome = self.run_impl(snapshot, target)?;
+        idempotency::set_impl(&copy, key)?;
+        Ok(outcome)
+    }
+
+    /// Record a snapshot as held by the target and as a parent for the next ones.
+    fn record(&mut self, snapshot: &Subvolume, info: &SubvolumeInfo) {
+        self.received.insert(info.uuid);
+        if !self.parents.iter().any(|p| p.id() == snapshot.id()) {
+            self.parents.push(snapshot.clone());
+        }
+    }
+
+    fn run_impl(
+        &mut self,
+        snapshot: &Subvolume,
+        target: BackupTarget<'_>,
+    ) -> Result<BackupOutcome> {
+        // the kernel only sends read-only subvolumes
+        if !snapshot.is_ro()? {
+            return Err(LibError::InvalidArgument.into());
+        }
+        let info = snapshot.info()?;
+        let parent = self.parent_for(snapshot, &target)?;
+
+        let (bytes, received) = match target {
+            BackupTarget::Writer(writer) => (
+                Some(send_to_writer(snapshot, parent.as_ref(), writer)?),
+                None,
+            ),
+            BackupTarget::Local(dir) => {
+                receive_locally(snapshot, parent.as_ref(), dir)?;
+                (None, Some(verify_received(snapshot, &info, dir)?))
+            }
+        };
+
+        self.record(snapshot, &info);
+
+        Ok(BackupOutcome {
+            snapshot: snapshot.clone(),
+            parent,
+            received_uuid: info.uuid,
+            bytes,
+            received,
+        })
+    }
+
+    /// Get the UUIDs of the snapshots a target holds.
+    fn held_by(&self, target: &BackupTarget<'_>) -> Result<HashSet<RawUuid>> {
+        let mut held = self.received.clone();
+        if let BackupTarget::Local(dir) = target {
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(held),
+                Err(err) => return Err(BtrfsUtilError::from_io(LibError::OpenFailed, &err)),
+            };
+            for entry in entries {
+                let entry =
+                    entry.map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?;
+                let received_uuid = Subvolume::get(entry.path())
+                    .and_then(|subvol| subvol.info())
+                    .ok()
+                    .and_then(|info| info.received_uuid);
+                held.extend(received_uuid);
+            }
+        }
+        Ok(held)
+    }
+}
+
+/// Send a snapshot into a file descriptor.
+fn send(snapshot: &Subvolume, parent: Option<&Subvolume>, fd: RawFd) -> Result<()> {
+    let file = ioctl::open(snapshot.path())?;
+    // the parent is also where data may be cloned from
+    let parent_id = parent.map(|parent| parent.id().get());
+    let mut clone_sources: Vec<u64> = parent_id.into_iter().collect();
+    let mut args = SendArgs::new(fd, parent_id.unwrap_or(0), &mut clone_sources);
+
+    unsafe {
+        ioctl::ioctl(
+            file.as_raw_fd(),
+            ioctl::BTRFS_IOC_SEND,
+            &mut args,
+            LibError::SendFailed,
+        )
+    }
+}
+
+/// Send a snapshot into a writer through a pipe, returning the length of the stream.
+fn send_to_writer(
+    snapshot: &Subvolume,
+    parent: Option<&Subvolume>,
+    writer: &mut dyn Write,
+) -> Result<u64> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(BtrfsUtilError::last_os_error(LibError::SendFailed));
+    }
+    let (mut reader, pipe) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    thread::scope(|scope| {
+        let sender = scope.spawn(move || {
+            let result = send(snapshot, parent, pipe.as_raw_fd());
+            // closing the pipe ends the stream for the reader
+            drop(pipe);
+            result
+        });
+
+        let copied = io::copy(&mut reader, writer).and_then(|bytes| {
+            writer.flush()?;
+            Ok(bytes)
+        });
+        // a failed write leaves the sender on a pipe nobody reads
+        drop(reader);
+
+        let sent = sender.join().unwrap_or(Err(LibError::SendFailed.into()));
+        let bytes = copied.map_err(|err| BtrfsUtilError::from_io(LibError::SendFailed, &err))?;
+        sent.map(|_| bytes)
+    })
+}
+
+/// Send a snapshot into `btrfs receive` running on a local directory.
+fn receive_locally(snapshot: &Subvolume, parent: Option<&Subvolume>, dir: &Path) -> Result<()> {
+    let mut child = Command::new("btrfs")
+        .arg("receive")
+        .arg(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|err| BtrfsUtilError::from_io(LibError::ReceiveFailed, &err))?;
+
+    let stdin = child.stdin.take().ok_or(LibError::ReceiveFailed)?;
+    let sent = send(snapshot, parent, stdin.as_raw_fd());
+    drop(stdin);
+
+    let status = child
+        .wait()
+        .map_err(|err| BtrfsUtilError::from_io(LibError::ReceiveFailed, &err))?;
+    sent?;
+    if !status.success() {
+        return Err(LibError::ReceiveFailed.into());
+    }
+    Ok(())
+}
+
+/// Check the copy of a snapshot received into a local directory.
+fn verify_received(snapshot: &Subvolume, info: &SubvolumeInfo, dir: &Path) -> Result<Subvolume> {
+    let name = snapshot
+        .path()
+        .file_name()
+        .ok_or(LibError::InvalidArgument)?;
+    let received = Subvolume::get(dir.join(name))?;
+    let received_info = received.info()?;
+
+    if received_info.received_uuid != Some(info.uuid)
+        || received_info.stransid != Some(info.ctransid)
+        || !received.is_ro()?
+    {
+        return Err(LibError::BackupVerifyFailed.into());
+    }
+    Ok(received)
+}
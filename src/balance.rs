@@ -0,0 +1,390 @@
+//! Btrfs balance
+//!
+//! A balance rewrites block groups, spreading them over the devices of the filesystem and
+//! optionally converting them to another profile. Filters restrict which block groups are
+//! rewritten.
+
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::fs::Filesystem;
+use crate::ioctl;
+use crate::ioctl::BalanceArgs;
+use crate::ioctl::IoctlBalanceArgs;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::ops::Range;
+use std::ops::RangeInclusive;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+bitflags! {
+    /// Block group profiles.
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct Profiles: u64 {
+        /// RAID0.
+        const RAID0 = 1 << 3;
+        /// RAID1.
+        const RAID1 = 1 << 4;
+        /// DUP.
+        const DUP = 1 << 5;
+        /// RAID10.
+        const RAID10 = 1 << 6;
+        /// RAID5.
+        const RAID5 = 1 << 7;
+        /// RAID6.
+        const RAID6 = 1 << 8;
+        /// RAID1 with three copies.
+        const RAID1C3 = 1 << 9;
+        /// RAID1 with four copies.
+        const RAID1C4 = 1 << 10;
+        /// Single.
+        const SINGLE = 1 << 48;
+    }
+}
+
+bitflags! {
+    /// Balance state.
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct BalanceState: u64 {
+        /// A balance is running.
+        const RUNNING = 1 << 0;
+        /// A pause was requested.
+        const PAUSE_REQ = 1 << 1;
+        /// A cancel was requested.
+        const CANCEL_REQ = 1 << 2;
+    }
+}
+
+/// Filters selecting which block groups of one type are balanced.
+///
+/// Unset filters match every block group.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BalanceFilter {
+    /// Block groups with any of these profiles.
+    pub profiles: Option<Profiles>,
+    /// Block groups whose usage, in percent, is in this range.
+    pub usage: Option<RangeInclusive<u32>>,
+    /// Block groups with a stripe on this device.
+    pub devid: Option<u64>,
+    /// Block groups overlapping this physical byte range of [devid](#structfield.devid).
+    pub drange: Option<Range<u64>>,
+    /// Block groups overlapping this logical byte range.
+    pub vrange: Option<Range<u64>>,
+    /// Balance a number of block groups in this range, at most.
+    pub limit: Option<RangeInclusive<u32>>,
+    /// Block groups spanning a number of stripes in this range.
+    pub stripes: Option<RangeInclusive<u32>>,
+    /// Convert the block groups to this profile.
+    pub convert: Option<Profiles>,
+    /// When converting, skip block groups already having the target profile.
+    pub soft: bool,
+}
+
+impl BalanceFilter {
+    fn to_args(&self) -> BalanceArgs {
+        let mut args = BalanceArgs::default();
+
+        if let Some(profiles) = self.profiles {
+            args.flags |= ioctl::BTRFS_BALANCE_ARGS_PROFILES;
+            args.profiles = profiles.bits();
+        }
+        if let Some(usage) = &self.usage {
+            args.flags |= ioctl::BTRFS_BALANCE_ARGS_USAGE_RANGE;
+            args.usage_min = *usage.start();
+            args.usage_max = *usage.end();
+        }
+        if let Some(devid) = self.devid {
+            args.flags |= ioctl::BTRFS_BALANCE_ARGS_DEVID;
+            args.devid = devid;
+        }
+        if let Some(drange) = &self.drange {
+            args.flags |= ioctl::BTRFS_BALANCE_ARGS_DRANGE;
+            args.pstart = drange.start;
+            args.pend = drange.end;
+        }
+        if let Some(vrange) = &self.vrange {
+            args.flags |= ioctl::BTRFS_BALANCE_ARGS_VRANGE;
+            args.vstart = vrange.start;
+            args.vend = vrange.end;
+        }
+        if let Some(limit) = &self.limit {
+            args.flags |= ioctl::BTRFS_BALANCE_ARGS_LIMIT_RANGE;
+            args.limit_min = *limit.start();
+            args.limit_max = *limit.end();
+        }
+        if let Some(stripes) = &self.stripes {
+            args.flags |= ioctl::BTRFS_BALANCE_ARGS_STRIPES_RANGE;
+            args.stripes_min = *stripes.start();
+            args.stripes_max = *stripes.end();
+        }
+        if let Some(convert) = self.convert {
+            args.flags |= ioctl::BTRFS_BALANCE_ARGS_CONVERT;
+            args.target = convert.bits();
+            if self.soft {
+                args.flags |= ioctl::BTRFS_BALANCE_ARGS_SOFT;
+            }
+        }
+
+        args
+    }
+}
+
+/// What to balance.
+///
+/// If no block group type is given, all of them are balanced without filters. If only
+/// [metadata](#structfield.metadata) is given, system block groups are balanced with the same
+/// filters, like `btrfs balance start` does.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BalanceOptions {
+    /// Filters for data block groups.
+    pub data: Option<BalanceFilter>,
+    /// Filters for metadata block groups.
+    pub metadata: Option<BalanceFilter>,
+    /// Filters for system block groups.
+    pub system: Option<BalanceFilter>,
+    /// Allow reducing the redundancy of metadata.
+    pub force: bool,
+}
+
+impl BalanceOptions {
+    fn to_args(&self) -> IoctlBalanceArgs {
+        let mut args = IoctlBalanceArgs::new(0);
+
+        if let Some(data) = &self.data {
+            args.flags |= ioctl::BTRFS_BALANCE_DATA;
+            args.data = data.to_args();
+        }
+        if let Some(metadata) = &self.metadata {
+            args.flags |= ioctl::BTRFS_BALANCE_METADATA;
+            args.meta = metadata.to_args();
+        }
+        match (&self.system, &self.metadata) {
+            (Some(system), _) => {
+                args.flags |= ioctl::BTRFS_BALANCE_SYSTEM;
+                args.sys = system.to_args();
+            }
+            (None, Some(_)) => {
+                args.flags |= ioctl::BTRFS_BALANCE_SYSTEM;
+                args.sys = args.meta;
+            }
+            (None, None) => (),
+        }
+        if args.flags == 0 {
+            args.flags = ioctl::BTRFS_BALANCE_DATA
+                | ioctl::BTRFS_BALANCE_METADATA
+                | ioctl::BTRFS_BALANCE_SYSTEM;
+        }
+        if self.force {
+            args.flags |= ioctl::BTRFS_BALANCE_FORCE;
+        }
+
+        args
+    }
+}
+
+/// Progress of a balance, in block groups.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BalanceProgress {
+    /// Estimated number of block groups to relocate.
+    pub expected: u64,
+    /// Number of block groups looked at.
+    pub considered: u64,
+    /// Number of block groups relocated.
+    pub completed: u64,
+}
+
+/// State and progress of a balance.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BalanceStatus {
+    /// State of the balance.
+    pub state: BalanceState,
+    /// Progress of the balance.
+    pub progress: BalanceProgress,
+}
+
+impl From<&IoctlBalanceArgs> for BalanceStatus {
+    fn from(args: &IoctlBalanceArgs) -> Self {
+        Self {
+            state: BalanceState::from_bits_truncate(args.state),
+            progress: BalanceProgress {
+                expected: args.stat.expected,
+                considered: args.stat.considered,
+                completed: args.stat.completed,
+            },
+        }
+    }
+}
+
+/// Balance the filesystem mounted at a path.
+///
+/// This blocks until the balance finishes, is paused or is canceled; the returned status tells
+/// these apart. Use [progress] from another thread to follow it.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+///
+/// [progress]: fn.progress.html
+pub fn start<P>(path: P, options: &BalanceOptions) -> Result<BalanceStatus>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    run(path, options.to_args()).context("balance::start", &[path])
+}
+
+/// Resume a paused balance of the filesystem mounted at a path.
+///
+/// This blocks like [start] does.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+///
+/// [start]: fn.start.html
+pub fn resume<P>(path: P) -> Result<BalanceStatus>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    run(path, IoctlBalanceArgs::new(ioctl::BTRFS_BALANCE_RESUME))
+        .context("balance::resume", &[path])
+}
+
+fn run(path: &Path, mut args: IoctlBalanceArgs) -> Result<BalanceStatus> {
+    let file = ioctl::open(path)?;
+
+    match unsafe { ioctl::ioctl_errno(file.as_raw_fd(), ioctl::BTRFS_IOC_BALANCE_V2, &mut args) } {
+        // paused or canceled, the state tells which
+        Ok(()) | Err(libc::ECANCELED) => Ok(BalanceStatus::from(&args)),
+        Err(errno) => Err(BtrfsUtilError::os(LibError::BalanceFailed, errno)),
+    }
+}
+
+/// Pause the running balance of the filesystem mounted at a path.
+///
+/// Returns false if no balance was running.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn pause<P>(path: P) -> Result<bool>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    control(path, ioctl::BTRFS_BALANCE_CTL_PAUSE).context("balance::pause", &[path])
+}
+
+/// Cancel the running or paused balance of the filesystem mounted at a path.
+///
+/// Returns false if there was no balance to cancel.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn cancel<P>(path: P) -> Result<bool>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    control(path, ioctl::BTRFS_BALANCE_CTL_CANCEL).context("balance::cancel", &[path])
+}
+
+fn control(path: &Path, cmd: libc::c_int) -> Result<bool> {
+    let file = ioctl::open(path)?;
+
+    // the command is passed by value, not through a pointer
+    if unsafe { libc::ioctl(file.as_raw_fd(), ioctl::BTRFS_IOC_BALANCE_CTL as _, cmd) } < 0 {
+        return match std::io::Error::last_os_error().raw_os_error() {
+            Some(libc::ENOTCONN) => Ok(false),
+            _ => Err(BtrfsUtilError::last_os_error(LibError::BalanceCtlFailed)),
+        };
+    }
+
+    Ok(true)
+}
+
+/// Get the state and progress of the balance of the filesystem mounted at a path.
+///
+/// Returns None if no balance is running or paused.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn progress<P>(path: P) -> Result<Option<BalanceStatus>>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    progress_impl(path).context("balance::progress", &[path])
+}
+
+fn progress_impl(path: &Path) -> Result<Option<BalanceStatus>> {
+    let file = ioctl::open(path)?;
+    let mut args = IoctlBalanceArgs::new(0);
+
+    match unsafe {
+        ioctl::ioctl_errno(
+            file.as_raw_fd(),
+            ioctl::BTRFS_IOC_BALANCE_PROGRESS,
+            &mut args,
+        )
+    } {
+        Ok(()) => Ok(Some(BalanceStatus::from(&args))),
+        Err(libc::ENOTCONN) => Ok(None),
+        Err(errno) => Err(BtrfsUtilError::os(LibError::BalanceProgressFailed, errno)),
+    }
+}
+
+impl Filesystem {
+    /// Same as [start], counted as a heavy operation.
+    ///
+    /// [start]: ../balance/fn.start.html
+    pub fn balance(&self, options: &BalanceOptions) -> Result<BalanceStatus> {
+        self.heavy(|| run(self.path(), options.to_args()))
+            .context("Filesystem::balance", &[self.path()])
+    }
+
+    /// Same as [resume], counted as a heavy operation.
+    ///
+    /// [resume]: ../balance/fn.resume.html
+    pub fn resume_balance(&self) -> Result<BalanceStatus> {
+        self.heavy(|| {
+            run(
+                self.path(),
+                IoctlBalanceArgs::new(ioctl::BTRFS_BALANCE_RESUME),
+            )
+        })
+        .context("Filesystem::resume_balance", &[self.path()])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn balance_options_args() {
+        let options = BalanceOptions {
+            metadata: Some(BalanceFilter {
+                usage: Some(0..=50),
+                convert: Some(Profiles::DUP),
+                soft: true,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let args = options.to_args();
+
+        assert_eq!(
+            args.flags,
+            ioctl::BTRFS_BALANCE_METADATA | ioctl::BTRFS_BALANCE_SYSTEM
+        );
+        assert_eq!(
+            args.meta.flags,
+            ioctl::BTRFS_BALANCE_ARGS_USAGE_RANGE
+                | ioctl::BTRFS_BALANCE_ARGS_CONVERT
+                | ioctl::BTRFS_BALANCE_ARGS_SOFT
+        );
+        assert_eq!((args.meta.usage_min, args.meta.usage_max), (0, 50));
+        assert_eq!(args.sys.target, Profiles::DUP.bits());
+
+        let full = BalanceOptions::default().to_args();
+        assert_eq!(
+            full.flags,
+            ioctl::BTRFS_BALANCE_DATA | ioctl::BTRFS_BALANCE_METADATA | ioctl::BTRFS_BALANCE_SYSTEM
+        );
+    }
+}
@@ -0,0 +1,107 @@
+//! A/B updates
+//!
+//! The btrfs side of an A/B update agent: a freshly deployed root subvolume is made the default
+//! subvolume and booted into; every boot counts an attempt on it, and once the system comes up
+//! healthy it is marked good. A root that keeps failing to be marked good is rolled back from by
+//! switching the default subvolume to the root most recently marked good.
+//!
+//! Counters and marks are stored as extended attributes on the root subvolumes themselves, so
+//! the roots must be writable.
+
+use crate::common;
+use crate::error::LibError;
+use crate::subvolume::Subvolume;
+use crate::xattr;
+pub use crate::Result;
+
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Name of the extended attribute counting boot attempts.
+pub const ATTEMPTS_XATTR_NAME: &str = "user.btrfsutil.boot_attempts";
+/// Name of the extended attribute holding the time a root was marked good.
+pub const GOOD_XATTR_NAME: &str = "user.btrfsutil.boot_good";
+
+/// Forget the attempts and good mark of a root, e.g. after deploying a new image to it.
+pub fn reset(root: &Subvolume) -> Result<()> {
+    xattr::remove(root.path(), ATTEMPTS_XATTR_NAME)?;
+    xattr::remove(root.path(), GOOD_XATTR_NAME)
+}
+
+/// Count a boot attempt on a root and get the number of attempts so far.
+///
+/// Meant to be called early during boot, before the system is known to be healthy.
+pub fn mark_boot_attempt(root: &Subvolume) -> Result<u32> {
+    let attempts = boot_attempts(root)?.saturating_add(1);
+    xattr::set(
+        root.path(),
+        ATTEMPTS_XATTR_NAME,
+        attempts.to_string().as_bytes(),
+    )?;
+    Ok(attempts)
+}
+
+/// Get the number of boot attempts counted on a root since it was last marked good or reset.
+pub fn boot_attempts(root: &Subvolume) -> Result<u32> {
+    match xattr::get_string(root.path(), ATTEMPTS_XATTR_NAME)? {
+        Some(val) => val
+            .trim()
+            .parse()
+            .map_err(|_| LibError::GetXattrFailed.into()),
+        None => Ok(0),
+    }
+}
+
+/// Mark a root as good and clear its boot attempts.
+pub fn mark_good(root: &Subvolume) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    xattr::set(root.path(), GOOD_XATTR_NAME, now.to_string().as_bytes())?;
+    xattr::remove(root.path(), ATTEMPTS_XATTR_NAME)
+}
+
+/// Get the time a root was last marked good, or None if it never was since being reset.
+pub fn good_since(root: &Subvolume) -> Result<Option<SystemTime>> {
+    let val = match xattr::get_string(root.path(), GOOD_XATTR_NAME)? {
+        Some(val) => val,
+        None => return Ok(None),
+    };
+    let secs: i64 = val.trim().parse().map_err(|_| LibError::GetXattrFailed)?;
+    common::timespec_to_system_time(secs, 0)
+        .map(Some)
+        .ok_or(LibError::GetXattrFailed.into())
+}
+
+/// Check whether a root should be rolled back from: it was never marked good and has been
+/// attempted at least `max_attempts` times.
+pub fn should_rollback(root: &Subvolume, max_attempts: u32) -> Result<bool> {
+    if good_since(root)?.is_some() {
+        return Ok(false);
+    }
+    Ok(boot_attempts(root)? >= max_attempts)
+}
+
+/// Roll back to the candidate most recently marked good by making it the default subvolume, and
+/// get it.
+///
+/// Fails with [LibError::NoGoodSubvolume] if no candidate was ever marked good.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+///
+/// [LibError::NoGoodSubvolume]: ../../error/enum.LibError.html#variant.NoGoodSubvolume
+pub fn perform_rollback(candidates: &[Subvolume]) -> Result<Subvolume> {
+    let mut last_good: Option<(SystemTime, &Subvolume)> = None;
+    for candidate in candidates {
+        if let Some(since) = good_since(candidate)? {
+            if last_good.is_none_or(|(last, _)| since > last) {
+                last_good = Some((since, candidate));
+            }
+        }
+    }
+
+    let (_, root) = last_good.ok_or(LibError::NoGoodSubvolume)?;
+    let root = root.clone().elevate()?;
+    root.set_default()?;
+    Ok(root.into_inner())
+}
@@ -0,0 +1,8 @@
+//! Boot environments
+//!
+//! Helpers for systems booting from a btrfs subvolume, such as the default subvolume being
+//! switched between root filesystems by an update agent.
+
+pub mod ab;
+
+pub use crate::Result;
@@ -1,10 +1,22 @@
+use crate::error::LibError;
+use crate::subvolume::SubvolumeId;
+use crate::BtrfsUtilError;
+use crate::Result;
+
+use std::convert::TryFrom;
 use std::ffi::CStr;
 use std::ffi::CString;
 use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::raw::c_char;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::ffi::OsStringExt;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 /// Convert a Path into a CString safely.
 #[inline]
@@ -13,25 +25,207 @@ pub(crate) fn path_to_cstr(path: &Path) -> CString {
     CString::new(path.as_os_str().as_bytes()).unwrap()
 }
 
-/// Convert a Path into a CString safely.
-#[inline]
-pub(crate) fn cstr_to_path(path: &CStr) -> PathBuf {
-    PathBuf::from(OsString::from_vec(path.to_bytes().into()))
-}
-
-/// Macro for preparing for an unsafe function execution and reacting to its
-/// error code
-macro_rules! unsafe_wrapper {
-    ($unsafe_block: block) => {{
-        let errcode: crate::error::LibErrorCode = unsafe { $unsafe_block };
-        match errcode {
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_OK => Result::Ok(()),
-            err => {
-                #[allow(unused_imports)]
-                use std::convert::TryFrom;
-                let err = crate::error::LibError::try_from(err).unwrap();
-                Result::Err(err.into())
+/// Convert seconds and nanoseconds since the epoch, as held by a timespec, into a SystemTime.
+///
+/// Returns None if the nanoseconds are out of range or the time cannot be represented.
+pub(crate) fn timespec_to_system_time(sec: i64, nsec: i64) -> Option<SystemTime> {
+    let nsec = u32::try_from(nsec)
+        .ok()
+        .filter(|nsec| *nsec < 1_000_000_000)?;
+    let since_epoch = Duration::new(sec.unsigned_abs(), 0);
+    let secs = if sec >= 0 {
+        UNIX_EPOCH.checked_add(since_epoch)?
+    } else {
+        UNIX_EPOCH.checked_sub(since_epoch)?
+    };
+    secs.checked_add(Duration::from_nanos(u64::from(nsec)))
+}
+
+/// Format a point in time in the local time zone, with the conversions of strftime.
+///
+/// Returns an empty string if the time cannot be broken down or the result is too long.
+pub(crate) fn format_local_time(time: SystemTime, format: &CStr) -> String {
+    // rounded down, also before the epoch
+    let secs = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as libc::time_t,
+        Err(err) => {
+            let before = err.duration();
+            -(before.as_secs() as libc::time_t) - libc::time_t::from(before.subsec_nanos() > 0)
+        }
+    };
+
+    let mut buf = [0u8; 64];
+    let len = unsafe {
+        // plain integers and a pointer, all zeroes is a valid value
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&secs, &mut tm).is_null() {
+            return String::new();
+        }
+        libc::strftime(
+            buf.as_mut_ptr() as *mut c_char,
+            buf.len(),
+            format.as_ptr(),
+            &tm,
+        )
+    };
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// Format a point in time in the local time zone as RFC 3339, to the second.
+pub(crate) fn format_rfc3339(time: SystemTime) -> String {
+    let mut formatted = format_local_time(time, c"%Y-%m-%dT%H:%M:%S%z");
+    // strftime writes the offset as +hhmm
+    if formatted.len() > 2 {
+        formatted.insert(formatted.len() - 2, ':');
+    }
+    formatted
+}
+
+/// Check whether something exists at a path, without following symbolic links.
+///
+/// Only a missing path is reported as not existing; failing to look, such as for a lack of
+/// permissions, is an error.
+pub(crate) fn path_exists(path: &Path) -> Result<bool> {
+    match path.symlink_metadata() {
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(BtrfsUtilError::os(
+            LibError::StatFailed,
+            err.raw_os_error().unwrap_or(0),
+        )),
+    }
+}
+
+/// Undo the octal escapes of spaces, tabs, newlines and backslashes in `/proc/self/mountinfo`.
+pub(crate) fn unescape_mountinfo(field: &str) -> PathBuf {
+    let bytes = field.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let code = bytes.get(i + 1..i + 4).and_then(|digits| {
+            let digits = std::str::from_utf8(digits).ok()?;
+            u8::from_str_radix(digits, 8).ok()
+        });
+        match code {
+            Some(code) if bytes[i] == b'\\' => {
+                out.push(code);
+                i += 4;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
             }
         }
-    }};
+    }
+    PathBuf::from(OsString::from_vec(out))
+}
+
+/// A btrfs mount, as listed in `/proc/self/mountinfo`, with the escapes of its fields undone.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Mount {
+    /// Path of the mounted subvolume or directory relative to the top-level subvolume, starting
+    /// with `/`.
+    pub(crate) root: PathBuf,
+    /// Where it is mounted.
+    pub(crate) mount_point: PathBuf,
+    /// The options of this mount.
+    pub(crate) options: String,
+    /// The device the filesystem was mounted from.
+    pub(crate) source: PathBuf,
+    /// The options of the filesystem, as they appear in the file.
+    pub(crate) super_options: String,
+    /// Id of the mounted subvolume, from the `subvolid` option.
+    pub(crate) subvol_id: Option<SubvolumeId>,
+    /// Path of the mounted subvolume relative to the top-level subvolume, from the `subvol`
+    /// option. Unlike the root, it is the subvolume for bind mounts of directories.
+    pub(crate) subvol: Option<PathBuf>,
+}
+
+/// Get the btrfs mounts in `/proc/self/mountinfo`, in the order they were mounted in.
+pub(crate) fn btrfs_mounts() -> Result<Vec<Mount>> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")
+        .map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?;
+    Ok(parse_mountinfo(&mountinfo))
+}
+
+/// Get the btrfs mounts in the contents of a mountinfo file, leaving out malformed lines.
+pub(crate) fn parse_mountinfo(mountinfo: &str) -> Vec<Mount> {
+    // fields are separated from the filesystem type and beyond by a lone dash
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (mount, fs) = line.split_once(" - ")?;
+            let mount: Vec<&str> = mount.split(' ').collect();
+            let fs: Vec<&str> = fs.split(' ').collect();
+            if mount.len() < 6 || fs.len() < 3 || fs[0] != "btrfs" {
+                return None;
+            }
+
+            let mut subvol_id = None;
+            let mut subvol = None;
+            for option in fs[2].split(',') {
+                if let Some(id) = option.strip_prefix("subvolid=") {
+                    subvol_id = id.parse().ok().map(SubvolumeId::from_raw);
+                } else if let Some(path) = option.strip_prefix("subvol=") {
+                    subvol = Some(unescape_mountinfo(path));
+                }
+            }
+
+            Some(Mount {
+                root: unescape_mountinfo(mount[3]),
+                mount_point: unescape_mountinfo(mount[4]),
+                options: mount[5].to_owned(),
+                source: unescape_mountinfo(fs[1]),
+                super_options: fs[2].to_owned(),
+                subvol_id,
+                subvol,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mountinfo_unescape() {
+        assert_eq!(unescape_mountinfo("/mnt/a\\040b"), Path::new("/mnt/a b"));
+        assert_eq!(unescape_mountinfo("/x\\134y\\"), Path::new("/x\\y\\"));
+        assert_eq!(unescape_mountinfo("/plain"), Path::new("/plain"));
+    }
+
+    #[test]
+    fn mountinfo_parse() {
+        let mounts = parse_mountinfo(
+            "22 1 0:21 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+             36 22 0:32 /@home/user /home\\040dir rw,noatime shared:2 - btrfs /dev/sda2 \
+             rw,space_cache=v2,subvolid=257,subvol=/@home\n\
+             37 22 0:32 / /mnt rw - btrfs /dev/sda2 rw\n\
+             malformed - btrfs\n",
+        );
+        assert_eq!(
+            mounts,
+            vec![
+                Mount {
+                    root: PathBuf::from("/@home/user"),
+                    mount_point: PathBuf::from("/home dir"),
+                    options: "rw,noatime".to_owned(),
+                    source: PathBuf::from("/dev/sda2"),
+                    super_options: "rw,space_cache=v2,subvolid=257,subvol=/@home".to_owned(),
+                    subvol_id: Some(SubvolumeId::from_raw(257)),
+                    subvol: Some(PathBuf::from("/@home")),
+                },
+                Mount {
+                    root: PathBuf::from("/"),
+                    mount_point: PathBuf::from("/mnt"),
+                    options: "rw".to_owned(),
+                    source: PathBuf::from("/dev/sda2"),
+                    super_options: "rw".to_owned(),
+                    subvol_id: None,
+                    subvol: None,
+                },
+            ]
+        );
+    }
 }
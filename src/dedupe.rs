@@ -0,0 +1,280 @@
+//! Deduplication
+//!
+//! Makes ranges of files with identical contents share their extents, through
+//! `FIDEDUPERANGE`. Unlike a reflink copy, the kernel compares the contents first under lock, so
+//! nothing is shared unless it is really the same data. Finding duplicates is left to the caller.
+
+use crate::error::BatchError;
+use crate::error::BatchResult;
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::fs::Filesystem;
+use crate::ioctl;
+use crate::ioctl::FileDedupeRange;
+use crate::ioctl::FileDedupeRangeInfo;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Largest length btrfs deduplicates in one call; longer ranges are split.
+const DEDUPE_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+/// Number of targets passed per call, keeping the arguments within a page.
+const DEDUPE_MAX_TARGETS: usize = 120;
+
+/// A file to deduplicate against the source ranges, laid out from `offset` on as they are from
+/// the start of the first one.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DedupeTarget<'a> {
+    /// Path of the file.
+    pub path: &'a Path,
+    /// Offset within the file matching the start of the first source range.
+    pub offset: u64,
+}
+
+impl<'a> DedupeTarget<'a> {
+    /// Create a target.
+    pub fn new(path: &'a Path, offset: u64) -> Self {
+        Self { path, offset }
+    }
+}
+
+/// Outcome of deduplicating one target.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DedupeOutcome {
+    /// The contents matched and this many bytes now share the source extents.
+    Deduped(u64),
+    /// The contents differ. Chunks before the first difference may have been deduplicated.
+    ContentDiffers,
+}
+
+/// Progress of one target while its range is being deduplicated.
+struct Pending {
+    file: File,
+    offset: u64,
+    deduped: u64,
+    result: Option<Result<DedupeOutcome>>,
+}
+
+/// Deduplicate ranges of `src` against the same ranges in each target.
+///
+/// The ranges must be non-empty and in ascending order without overlapping. Each target holds
+/// them at the same distance from its `offset` as they are from the start of the first range, so
+/// a single range is deduplicated against the one of the same length at `offset`. Offsets and
+/// lengths must be multiples of the filesystem block size, except for a range ending at the end
+/// of `src`. Targets are opened for writing, or read-only if that is not allowed, which recent
+/// kernels accept for files owned by the caller.
+///
+/// The result maps each target path to its outcome over all ranges, which stops at the first
+/// range whose contents differ. Invalid ranges or a source that cannot be opened fail every
+/// target with [LibError::InvalidArgument] or [LibError::OpenFailed].
+///
+/// [LibError::InvalidArgument]: ../error/enum.LibError.html#variant.InvalidArgument
+/// [LibError::OpenFailed]: ../error/enum.LibError.html#variant.OpenFailed
+pub fn dedupe_ranges<P>(
+    src: P,
+    ranges: &[Range<u64>],
+    targets: &[DedupeTarget],
+) -> BatchResult<PathBuf, DedupeOutcome>
+where
+    P: AsRef<Path>,
+{
+    dedupe_ranges_impl(src.as_ref(), ranges, targets)
+}
+
+fn dedupe_ranges_impl(
+    src: &Path,
+    ranges: &[Range<u64>],
+    targets: &[DedupeTarget],
+) -> BatchResult<PathBuf, DedupeOutcome> {
+    let fail_all = |err: BtrfsUtilError| {
+        targets
+            .iter()
+            .map(|target| {
+                let result = Err(err.clone()).context("dedupe::dedupe_ranges", &[src, target.path]);
+                (target.path.to_owned(), result)
+            })
+            .collect::<BatchError<PathBuf, DedupeOutcome>>()
+            .into_result()
+    };
+
+    let ordered = ranges.windows(2).all(|pair| pair[0].end <= pair[1].start);
+    if !ordered || ranges.iter().any(|range| range.start >= range.end) {
+        return fail_all(LibError::InvalidArgument.into());
+    }
+
+    let src_file = match File::open(src) {
+        Ok(file) => file,
+        Err(err) => return fail_all(BtrfsUtilError::from_io(LibError::OpenFailed, &err)),
+    };
+
+    let mut pending: Vec<(PathBuf, Result<Pending>)> = targets
+        .iter()
+        .map(|target| {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(target.path)
+                .or_else(|_| File::open(target.path))
+                .map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err));
+            let pending = file.map(|file| Pending {
+                file,
+                offset: target.offset,
+                deduped: 0,
+                result: None,
+            });
+            (target.path.to_owned(), pending)
+        })
+        .collect();
+
+    let mut batch = BatchError::new();
+    let base = ranges.first().map_or(0, |range| range.start);
+    'ranges: for range in ranges {
+        let mut chunk_start = range.start;
+        while chunk_start < range.end {
+            let len = (range.end - chunk_start).min(DEDUPE_CHUNK_SIZE);
+            let mut active: Vec<&mut Pending> = pending
+                .iter_mut()
+                .filter_map(|(_, pending)| pending.as_mut().ok())
+                .filter(|pending| pending.result.is_none())
+                .collect();
+            if active.is_empty() {
+                break 'ranges;
+            }
+
+            for group in active.chunks_mut(DEDUPE_MAX_TARGETS) {
+                dedupe_chunk(&src_file, chunk_start, base, len, group);
+            }
+            chunk_start += len;
+        }
+    }
+
+    for (path, pending) in pending {
+        let result = pending
+            .and_then(|pending| {
+                pending
+                    .result
+                    .unwrap_or(Ok(DedupeOutcome::Deduped(pending.deduped)))
+            })
+            .context("dedupe::dedupe_ranges", &[src, &path]);
+        batch.push(path, result);
+    }
+
+    batch.into_result()
+}
+
+impl Filesystem {
+    /// Same as [dedupe_ranges], counted as a heavy operation.
+    ///
+    /// If the attached limiter refuses to run it, every target fails with the limiter's error.
+    ///
+    /// [dedupe_ranges]: ../dedupe/fn.dedupe_ranges.html
+    pub fn dedupe_ranges<P>(
+        &self,
+        src: P,
+        ranges: &[Range<u64>],
+        targets: &[DedupeTarget],
+    ) -> BatchResult<PathBuf, DedupeOutcome>
+    where
+        P: AsRef<Path>,
+    {
+        let src = src.as_ref();
+        let result = self
+            .heavy(|| Ok(dedupe_ranges_impl(src, ranges, targets)))
+            .context("Filesystem::dedupe_ranges", &[self.path(), src]);
+
+        result.unwrap_or_else(|err| {
+            let mut batch = BatchError::new();
+            for target in targets {
+                batch.push(target.path.to_owned(), Err(err.clone()));
+            }
+            batch.into_result()
+        })
+    }
+}
+
+/// Deduplicate one chunk of a source range against a group of targets, `base` being the start of
+/// the first range.
+fn dedupe_chunk(src: &File, chunk_start: u64, base: u64, len: u64, group: &mut [&mut Pending]) {
+    let header_size = std::mem::size_of::<FileDedupeRange>();
+    let info_size = std::mem::size_of::<FileDedupeRangeInfo>();
+    // u64 words keep the buffer aligned for the header and the infos
+    let mut buf: Vec<u64> = vec![0; (header_size + group.len() * info_size) / 8];
+
+    let header = FileDedupeRange::new(chunk_start, len, group.len() as u16);
+    unsafe {
+        std::ptr::write(buf.as_mut_ptr() as *mut FileDedupeRange, header);
+        let infos = (buf.as_mut_ptr() as *mut u8).add(header_size) as *mut FileDedupeRangeInfo;
+        for (i, pending) in group.iter().enumerate() {
+            let info = FileDedupeRangeInfo::new(
+                pending.file.as_raw_fd(),
+                pending.offset + (chunk_start - base),
+            );
+            std::ptr::write(infos.add(i), info);
+        }
+    }
+
+    let ret = unsafe {
+        ioctl::ioctl(
+            src.as_raw_fd(),
+            ioctl::FIDEDUPERANGE,
+            buf.as_mut_ptr(),
+            LibError::DedupeFailed,
+        )
+    };
+    if let Err(err) = ret {
+        for pending in group.iter_mut() {
+            pending.result = Some(Err(err.clone()));
+        }
+        return;
+    }
+
+    let infos: &[FileDedupeRangeInfo] = unsafe {
+        std::slice::from_raw_parts(
+            (buf.as_ptr() as *const u8).add(header_size) as *const FileDedupeRangeInfo,
+            group.len(),
+        )
+    };
+    for (pending, info) in group.iter_mut().zip(infos) {
+        match info.status {
+            ioctl::FILE_DEDUPE_RANGE_SAME => pending.deduped += info.bytes_deduped,
+            ioctl::FILE_DEDUPE_RANGE_DIFFERS => {
+                pending.result = Some(Ok(DedupeOutcome::ContentDiffers))
+            }
+            // failures are reported as a negative errno
+            status => {
+                pending.result = Some(Err(BtrfsUtilError::os(LibError::DedupeFailed, -status)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn invalid_ranges_fail_every_target() {
+        let targets = [
+            DedupeTarget::new(Path::new("/nonexistent/a"), 0),
+            DedupeTarget::new(Path::new("/nonexistent/b"), 4096),
+        ];
+        for ranges in [
+            &[0..4096, 8192..8192][..],
+            &[8192..12288, 0..4096][..],
+            &[0..8192, 4096..12288][..],
+        ] {
+            let err = dedupe_ranges("/nonexistent/src", ranges, &targets).unwrap_err();
+            assert!(err.succeeded().is_empty());
+            assert_eq!(err.failed().len(), 2);
+            assert!(err
+                .into_iter()
+                .all(|(_, err)| err == LibError::InvalidArgument));
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Differences between snapshots
+//!
+//! Lists the paths added, removed or modified between two read-only snapshots of the same
+//! subvolume by comparing the inodes of their trees, without reading any file data.
+
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::ioctl;
+use crate::ioctl::SearchKey;
+use crate::resolve;
+use crate::subvolume::Subvolume;
+pub use crate::Result;
+
+use std::collections::HashMap;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+/// Kind of change to a path.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Change {
+    /// The path exists only in the newer snapshot.
+    Added,
+    /// The path exists only in the older snapshot.
+    Removed,
+    /// The inode behind the path changed in between, in its data or its metadata.
+    Modified,
+}
+
+/// A changed path, as found by [snapshots].
+///
+/// [snapshots]: fn.snapshots.html
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PathChange {
+    /// Kind of change.
+    pub change: Change,
+    /// Path relative to the root of the snapshots.
+    pub path: PathBuf,
+    /// Inode number behind the path, in the newer snapshot unless it was removed.
+    pub inode: u64,
+}
+
+/// Inode generation and last transaction, as found in an inode item.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct InodeGen {
+    generation: u64,
+    transid: u64,
+}
+
+/// List the paths that changed between two read-only snapshots of the same subvolume, sorted by
+/// path.
+///
+/// Either snapshot may be of the other, or both may be of a common subvolume. Directories are
+/// reported as modified when entries are added to or removed from them. Only the first path of
+/// inodes with several hard links is reported.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn snapshots(older: &Subvolume, newer: &Subvolume) -> Result<Vec<PathChange>> {
+    snapshots_impl(older, newer).context("diff::snapshots", &[older.path(), newer.path()])
+}
+
+fn snapshots_impl(older: &Subvolume, newer: &Subvolume) -> Result<Vec<PathChange>> {
+    let (older_info, newer_info) = (older.info()?, newer.info()?);
+    let related = newer_info.parent_uuid == Some(older_info.uuid)
+        || older_info.parent_uuid == Some(newer_info.uuid)
+        || (older_info.parent_uuid.is_some() && older_info.parent_uuid == newer_info.parent_uuid);
+    if !related || !older.is_ro()? || !newer.is_ro()? || !older.same_filesystem(newer)? {
+        return Err(LibError::SnapshotsUnrelated.into());
+    }
+
+    let older_inodes = inodes(older)?;
+    let newer_inodes = inodes(newer)?;
+
+    let mut changes: Vec<PathChange> = Vec::new();
+    for (inode, gen) in &newer_inodes {
+        let change = match older_inodes.get(inode) {
+            // a reused inode number is a different file
+            Some(old) if old.generation != gen.generation => {
+                push_change(&mut changes, older, Change::Removed, *inode)?;
+                Change::Added
+            }
+            Some(old) if old.transid != gen.transid => Change::Modified,
+            Some(_) => continue,
+            None => Change::Added,
+        };
+        push_change(&mut changes, newer, change, *inode)?;
+    }
+    for inode in older_inodes.keys() {
+        if !newer_inodes.contains_key(inode) {
+            push_change(&mut changes, older, Change::Removed, *inode)?;
+        }
+    }
+
+    changes.sort_by(|a, b| a.path.cmp(&b.path).then(a.inode.cmp(&b.inode)));
+    Ok(changes)
+}
+
+/// Resolve the path of an inode in a snapshot and record the change.
+fn push_change(
+    changes: &mut Vec<PathChange>,
+    snapshot: &Subvolume,
+    change: Change,
+    inode: u64,
+) -> Result<()> {
+    // inodes without a path, such as orphans awaiting cleanup, are not visible anyway
+    if let Some(path) = resolve::inode_to_paths(snapshot.path(), inode)?
+        .into_iter()
+        .next()
+    {
+        changes.push(PathChange {
+            change,
+            path,
+            inode,
+        });
+    }
+    Ok(())
+}
+
+/// Get the inodes of a subvolume, except its root directory.
+fn inodes(subvol: &Subvolume) -> Result<HashMap<u64, InodeGen>> {
+    let file = ioctl::open(subvol.path())?;
+
+    let key = SearchKey::new(
+        subvol.id().get(),
+        (
+            ioctl::BTRFS_FIRST_FREE_OBJECTID + 1,
+            ioctl::BTRFS_INODE_ITEM_KEY,
+            0,
+        ),
+        (u64::MAX, ioctl::BTRFS_INODE_ITEM_KEY, u64::MAX),
+    );
+
+    let mut inodes: HashMap<u64, InodeGen> = HashMap::new();
+    ioctl::tree_search(file.as_raw_fd(), key, |header, item| {
+        if header.item_type == ioctl::BTRFS_INODE_ITEM_KEY && item.len() >= 16 {
+            inodes.insert(
+                header.objectid,
+                InodeGen {
+                    generation: ioctl::le_u64(item, 0),
+                    transid: ioctl::le_u64(item, 8),
+                },
+            );
+        }
+        true
+    })?;
+
+    Ok(inodes)
+}
@@ -0,0 +1,158 @@
+use crate::BtrfsUtilError;
+
+use std::fmt;
+
+/// Result of a batch operation: every item's result if all of them succeeded, or a [BatchError]
+/// holding both the successes and the failures otherwise.
+///
+/// [BatchError]: struct.BatchError.html
+pub type BatchResult<K, T> = std::result::Result<Vec<(K, T)>, BatchError<K, T>>;
+
+/// Per-item failures of a batch operation, together with the partial successes.
+///
+/// Items are keyed by whatever identifies them in the batch, usually a path or a subvolume id.
+/// Iterating over a [BatchError] yields the failures.
+///
+/// [BatchError]: struct.BatchError.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchError<K, T> {
+    succeeded: Vec<(K, T)>,
+    failed: Vec<(K, BtrfsUtilError)>,
+}
+
+impl<K, T> BatchError<K, T> {
+    /// Create an empty batch.
+    pub fn new() -> Self {
+        Self {
+            succeeded: Vec::new(),
+            failed: Vec::new(),
+        }
+    }
+
+    /// Record the result of one item.
+    pub fn push(&mut self, key: K, result: crate::Result<T>) {
+        match result {
+            Ok(val) => self.succeeded.push((key, val)),
+            Err(err) => self.failed.push((key, err)),
+        }
+    }
+
+    /// Get the items that succeeded.
+    #[inline]
+    pub fn succeeded(&self) -> &[(K, T)] {
+        &self.succeeded
+    }
+
+    /// Get the items that failed.
+    #[inline]
+    pub fn failed(&self) -> &[(K, BtrfsUtilError)] {
+        &self.failed
+    }
+
+    /// Check whether any item failed.
+    #[inline]
+    pub fn has_failures(&self) -> bool {
+        !self.failed.is_empty()
+    }
+
+    /// Turn the batch into a [BatchResult], which is an error only if any item failed.
+    ///
+    /// [BatchResult]: type.BatchResult.html
+    pub fn into_result(self) -> BatchResult<K, T> {
+        if self.has_failures() {
+            Err(self)
+        } else {
+            Ok(self.succeeded)
+        }
+    }
+}
+
+impl<K, T> Default for BatchError<K, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, T> FromIterator<(K, crate::Result<T>)> for BatchError<K, T> {
+    fn from_iter<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = (K, crate::Result<T>)>,
+    {
+        let mut batch = Self::new();
+        for (key, result) in iter {
+            batch.push(key, result);
+        }
+        batch
+    }
+}
+
+impl<K, T> IntoIterator for BatchError<K, T> {
+    type Item = (K, BtrfsUtilError);
+    type IntoIter = std::vec::IntoIter<(K, BtrfsUtilError)>;
+
+    /// Iterate over the failures.
+    fn into_iter(self) -> Self::IntoIter {
+        self.failed.into_iter()
+    }
+}
+
+impl<'a, K, T> IntoIterator for &'a BatchError<K, T> {
+    type Item = &'a (K, BtrfsUtilError);
+    type IntoIter = std::slice::Iter<'a, (K, BtrfsUtilError)>;
+
+    /// Iterate over the failures.
+    fn into_iter(self) -> Self::IntoIter {
+        self.failed.iter()
+    }
+}
+
+impl<K, T> fmt::Display for BatchError<K, T>
+where
+    K: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} operations failed",
+            self.failed.len(),
+            self.failed.len() + self.succeeded.len()
+        )?;
+        for (key, err) in &self.failed {
+            write!(f, "; {:?}: {}", key, err)?;
+        }
+        Ok(())
+    }
+}
+
+impl<K, T> std::error::Error for BatchError<K, T>
+where
+    K: fmt::Debug,
+    T: fmt::Debug,
+{
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::error::LibError;
+
+    #[test]
+    fn batch_display_and_result() {
+        let batch: BatchError<u64, ()> = vec![
+            (256, Ok(())),
+            (257, Err(LibError::SubvolumeNotFound.into())),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            batch.to_string(),
+            "1 of 2 operations failed; 257: Subvolume not found"
+        );
+
+        let err = batch.into_result().unwrap_err();
+        assert_eq!(err.succeeded(), &[(256, ())]);
+        assert_eq!(err.into_iter().map(|(id, _)| id).collect::<Vec<_>>(), [257]);
+    }
+}
@@ -0,0 +1,57 @@
+use crate::BtrfsUtilError;
+use crate::Result;
+
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// The operation an error happened in and the paths it was done on.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ErrorContext {
+    /// Name of the public function that failed, such as `Subvolume::snapshot`.
+    pub operation: &'static str,
+    /// Paths the operation was done on, in the order the function takes them.
+    pub paths: Vec<PathBuf>,
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.operation)?;
+        for (i, path) in self.paths.iter().enumerate() {
+            let sep = if i == 0 { " on " } else { ", " };
+            write!(f, "{}{}", sep, path.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Attaching an [ErrorContext] to the error of a result.
+///
+/// [ErrorContext]: struct.ErrorContext.html
+pub(crate) trait ResultExt<T> {
+    /// Attach the operation and the paths it was done on to the error.
+    fn context(self, operation: &'static str, paths: &[&Path]) -> Result<T>;
+}
+
+impl<T> ResultExt<T> for Result<T> {
+    #[inline]
+    fn context(self, operation: &'static str, paths: &[&Path]) -> Result<T> {
+        self.map_err(|err| err.with_context(operation, paths))
+    }
+}
+
+impl BtrfsUtilError {
+    /// Attach the operation and the paths it was done on.
+    ///
+    /// An error which already has a context, from a public function called by another one, is
+    /// wrapped again, so the message reads from the outermost operation to the innermost.
+    pub(crate) fn with_context(self, operation: &'static str, paths: &[&Path]) -> Self {
+        BtrfsUtilError::Context {
+            context: ErrorContext {
+                operation,
+                paths: paths.iter().map(|path| path.to_path_buf()).collect(),
+            },
+            error: Box::new(self),
+        }
+    }
+}
@@ -1,16 +1,22 @@
 use crate::error::LibErrorCode;
 
 use std::ffi::NulError;
+use std::io;
 use std::path::PathBuf;
 use std::str::Utf8Error;
 
 use thiserror::Error;
+#[cfg(feature = "uuid")]
 use uuid::Error as UuidError;
 
 /// Errors that can be raised by the glue between this Rust library and the original [libbtrfsutil]
 /// C library.
 ///
+/// Errors wrapping another error expose it as their [source]; with the `enable-glue-errors`
+/// feature, their message also includes it.
+///
 /// [libbtrfsutil]: https://github.com/kdave/btrfs-progs/tree/master/libbtrfsutil
+/// [source]: https://doc.rust-lang.org/stable/std/error/trait.Error.html#method.source
 #[derive(Clone, Debug, Eq, Error, PartialEq)]
 pub enum GlueError {
     /// Unknown errno.
@@ -25,8 +31,9 @@ pub enum GlueError {
     /// [std::str::Utf8Error]: https://doc.rust-lang.org/stable/std/str/struct.Utf8Error.html
     /// [CString]: https://doc.rust-lang.org/stable/std/ffi/struct.CString.html
     /// [String]: https://doc.rust-lang.org/stable/std/string/struct.String.html
-    #[error("{0}")]
-    Utf8Error(Utf8Error),
+    #[cfg_attr(feature = "enable-glue-errors", error("Invalid UTF-8 received: {0}"))]
+    #[cfg_attr(not(feature = "enable-glue-errors"), error("Invalid UTF-8 received"))]
+    Utf8Error(#[from] Utf8Error),
     /// Bad path. May arise when a conversion from a [PathBuf] into a [&str] fails.
     ///
     /// [PathBuf]: https://doc.rust-lang.org/stable/std/path/struct.PathBuf.html
@@ -39,52 +46,69 @@ pub enum GlueError {
     /// [std::ffi::NulError]: https://doc.rust-lang.org/stable/std/ffi/struct.NulError.html
     /// [CString]: https://doc.rust-lang.org/stable/std/ffi/struct.CString.html
     /// [&str]: https://doc.rust-lang.org/stable/std/primitive.str.html
-    #[error("{0}")]
-    NulError(NulError),
-    /// UuidError. Wrapper around [uuid::Error]. May arise when trying to create a [Uuid] for a
-    /// [SubvolumeInfo] from a byte array.
+    #[cfg_attr(feature = "enable-glue-errors", error("Null byte in string: {0}"))]
+    #[cfg_attr(not(feature = "enable-glue-errors"), error("Null byte in string"))]
+    NulError(#[from] NulError),
+    /// UuidError. Wrapper around [uuid::Error]. Converted from errors of the `uuid` crate.
     ///
     /// [uuid::Error]: https://docs.rs/uuid/0.8.1/uuid/struct.Error.html
-    /// [Uuid]: https://docs.rs/uuid/0.8.1/uuid/struct.Uuid.html
-    /// [SubvolumeInfo]: ../subvolume/struct.SubvolumeInfo.html
-    #[error("{0}")]
-    UuidError(UuidError),
-    /// Bad timespec. May arise when a conversion from a [timespec] to a [NaiveDateTime] fails. The
+    #[cfg(feature = "uuid")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "uuid")))]
+    #[cfg_attr(feature = "enable-glue-errors", error("Invalid UUID received: {0}"))]
+    #[cfg_attr(not(feature = "enable-glue-errors"), error("Invalid UUID received"))]
+    UuidError(#[from] UuidError),
+    /// Bad timespec. May arise when a conversion from a [timespec] to a [SystemTime] fails. The
     /// error message contains a debug-formatted representation of the timespec struct.
     ///
     /// [timespec]: ../bindings/struct.timespec.html
-    /// [NaiveDateTime]: https://docs.rs/chrono/0.4.11/chrono/naive/struct.NaiveDateTime.html
+    /// [SystemTime]: https://doc.rust-lang.org/stable/std/time/struct.SystemTime.html
     #[error("Bad timespec: {0}")]
     BadTimespec(String),
-    /// Bad id. May arise when an id is smaller than [BTRFS_FS_TREE_OBJECTID].
+    /// Bad id. May arise when a [SubvolumeId] is created from an id reserved for the internal
+    /// trees of the filesystem.
     ///
-    /// [BTRFS_FS_TREE_OBJECTID]: ../bindings/constant.BTRFS_FS_TREE_OBJECTID.html
+    /// [SubvolumeId]: ../subvolume/struct.SubvolumeId.html
     #[error("Bad id: {0}")]
     BadId(u64),
+    /// Bad UUID. May arise when parsing a [RawUuid] from a string which is neither hyphenated nor
+    /// 32 hex digits.
+    ///
+    /// [RawUuid]: ../struct.RawUuid.html
+    #[error("Bad UUID: {0}")]
+    BadUuid(String),
+    /// A blocking operation was cancelled before it ran. May arise with the async wrappers of
+    /// the [asynchronous] module when the tokio runtime is shut down.
+    ///
+    /// [asynchronous]: ../asynchronous/index.html
+    #[error("Operation cancelled")]
+    Cancelled,
 }
 
-/// Macro for handling a potential glue error.
-#[cfg(feature = "enable-glue-errors")]
-macro_rules! glue_error {
-    ($condition: expr, $glue_err: expr) => {
-        if $condition {
-            return crate::Result::Err(crate::BtrfsUtilError::Glue($glue_err.into()));
+impl GlueError {
+    /// Get the [io::ErrorKind] of this error: invalid input for what the caller passed in,
+    /// invalid data for what the C library returned and interrupted for cancelled operations.
+    ///
+    /// [io::ErrorKind]: https://doc.rust-lang.org/stable/std/io/enum.ErrorKind.html
+    pub(crate) fn io_error_kind(&self) -> io::ErrorKind {
+        match self {
+            GlueError::BadPath(_)
+            | GlueError::NulError(_)
+            | GlueError::BadId(_)
+            | GlueError::BadUuid(_) => io::ErrorKind::InvalidInput,
+            GlueError::Cancelled => io::ErrorKind::Interrupted,
+            _ => io::ErrorKind::InvalidData,
         }
-    };
-    ($glue_err: expr) => {
-        return crate::Result::Err(crate::BtrfsUtilError::Glue($glue_err.into()))
-    };
+    }
 }
 
-/// Macro for handling a potential glue error.
-#[cfg(not(feature = "enable-glue-errors"))]
+/// Macro for handling a potential glue error, returning it from the calling function.
 macro_rules! glue_error {
     ($condition: expr, $glue_err: expr) => {
         if $condition {
-            panic!("Glue error: {}", $glue_err)
+            return crate::Result::Err(crate::BtrfsUtilError::Glue($glue_err.into()).traced());
         }
     };
     ($glue_err: expr) => {
-        panic!("Glue error: {}", $glue_err)
+        return crate::Result::Err(crate::BtrfsUtilError::Glue($glue_err.into()).traced())
     };
 }
@@ -2,11 +2,8 @@ use crate::error::GlueError;
 use crate::BtrfsUtilError;
 use crate::Result;
 
-#[cfg(feature = "enable-glue-errors")]
-use std::convert::Into;
 use std::convert::TryFrom;
-use std::ffi::CStr;
-use std::os::raw::c_char;
+use std::io;
 
 use thiserror::Error;
 
@@ -15,6 +12,12 @@ use thiserror::Error;
 /// [libbtrfsutil]: https://github.com/kdave/btrfs-progs/tree/master/libbtrfsutil
 pub(crate) type LibErrorCode = u32;
 
+/// First error code used for errors raised by this library itself rather than by [libbtrfsutil],
+/// such as failures of the system calls it issues directly.
+///
+/// [libbtrfsutil]: https://github.com/kdave/btrfs-progs/tree/master/libbtrfsutil
+const CRATE_ERROR_BASE: isize = 1000;
+
 /// Errors that can be raised by the [libbtrfsutil] C library itself.
 ///
 /// [libbtrfsutil]: https://github.com/kdave/btrfs-progs/tree/master/libbtrfsutil
@@ -22,94 +25,229 @@ pub(crate) type LibErrorCode = u32;
 pub enum LibError {
     /// Success
     #[error("Success")]
-    Ok = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_OK as isize,
+    Ok = 0,
     /// Stop iteration
     #[error("Stop iteration")]
-    StopIteration = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_STOP_ITERATION as isize,
+    StopIteration = 1,
     /// Cannot allocate memory
     #[error("Cannot allocate memory")]
-    NoMemory = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_NO_MEMORY as isize,
+    NoMemory = 2,
     /// Invalid argument
     #[error("Invalid argument")]
-    InvalidArgument = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_INVALID_ARGUMENT as isize,
+    InvalidArgument = 3,
     /// Not a Btrfs filesystem
     #[error("Not a Btrfs filesystem")]
-    NotBtrfs = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_NOT_BTRFS as isize,
+    NotBtrfs = 4,
     /// Not a Btrfs subvolume
     #[error("Not a Btrfs subvolume")]
-    NotSubvolume = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_NOT_SUBVOLUME as isize,
+    NotSubvolume = 5,
     /// Subvolume not found
     #[error("Subvolume not found")]
-    SubvolumeNotFound =
-        btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_SUBVOLUME_NOT_FOUND as isize,
+    SubvolumeNotFound = 6,
     /// Could not open
     #[error("Could not open")]
-    OpenFailed = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_OPEN_FAILED as isize,
+    OpenFailed = 7,
     /// Could nor rmdir
     #[error("Could not rmdir")]
-    RmdirFailed = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_RMDIR_FAILED as isize,
+    RmdirFailed = 8,
     /// Could not unlink
     #[error("Could not unlink")]
-    UnlinkFailed = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_UNLINK_FAILED as isize,
+    UnlinkFailed = 9,
     /// Could not stat
     #[error("Could not stat")]
-    StatFailed = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_STAT_FAILED as isize,
+    StatFailed = 10,
     /// Could not statfs
     #[error("Could not statfs")]
-    StatfsFailed = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_STATFS_FAILED as isize,
+    StatfsFailed = 11,
     /// Could not search B-tree
     #[error("Could not search B-tree")]
-    SearchFailed = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_SEARCH_FAILED as isize,
+    SearchFailed = 12,
     /// Could not lookup inode
     #[error("Could not lookup inode")]
-    InoLookupFailed = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_INO_LOOKUP_FAILED as isize,
+    InoLookupFailed = 13,
     /// Could not get subvolume flags
     #[error("Could not get subvolume flags")]
-    SubvolGetflagsFailed =
-        btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_SUBVOL_GETFLAGS_FAILED as isize,
+    SubvolGetflagsFailed = 14,
     /// Could not set subvolume flags
     #[error("Could not set subvolume flags")]
-    SubvolSetflagsFailed =
-        btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_SUBVOL_SETFLAGS_FAILED as isize,
+    SubvolSetflagsFailed = 15,
     /// Could not create subvolume
     #[error("Could not create subvolume")]
-    SubvolCreateFailed =
-        btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_SUBVOL_CREATE_FAILED as isize,
+    SubvolCreateFailed = 16,
     /// Could not create snapshot
     #[error("Could not create snapshot")]
-    SnapCreateFailed = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_SNAP_CREATE_FAILED as isize,
+    SnapCreateFailed = 17,
     /// Could not destroy subvolume/snapshot
     #[error("Could not destroy subvolume/snapshot")]
-    SnapDestroyFailed =
-        btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_SNAP_DESTROY_FAILED as isize,
+    SnapDestroyFailed = 18,
     /// Could not set default subvolume
     #[error("Could not set default subvolume")]
-    DefaultSubvolFailed =
-        btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_DEFAULT_SUBVOL_FAILED as isize,
+    DefaultSubvolFailed = 19,
     /// Could not sync filesystem
     #[error("Could not sync filesystem")]
-    SyncFailed = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_SYNC_FAILED as isize,
+    SyncFailed = 20,
     /// Could not start filesystem sync
     #[error("Could not start filesystem sync")]
-    StartSyncFailed = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_START_SYNC_FAILED as isize,
+    StartSyncFailed = 21,
     /// Could not wait for filesystem sync
     #[error("Could not wait for filesystem sync")]
-    WaitSyncFailed = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_WAIT_SYNC_FAILED as isize,
+    WaitSyncFailed = 22,
     /// Could not get subvolume information with BTRFS_IOC_GET_SUBVOL_INFO
     #[error("Could not get subvolume information with BTRFS_IOC_GET_SUBVOL_INFO")]
-    GetSubvolInfoFailed =
-        btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_GET_SUBVOL_INFO_FAILED as isize,
+    GetSubvolInfoFailed = 23,
     /// Could not get rootref information with BTRFS_IOC_GET_SUBVOL_ROOTREF
     #[error("Could not get rootref information with BTRFS_IOC_GET_SUBVOL_ROOTREF")]
-    GetSubvolRootrefFailed =
-        btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_GET_SUBVOL_ROOTREF_FAILED as isize,
+    GetSubvolRootrefFailed = 24,
     /// Could not resolve subvolume path with BTRFS_IOC_INO_LOOKUP_USER
     #[error("Could not resolve subvolume path with BTRFS_IOC_INO_LOOKUP_USER")]
-    InoLookupUserFailed =
-        btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_INO_LOOKUP_USER_FAILED as isize,
+    InoLookupUserFailed = 25,
     /// Could not get filesystem information
     #[error("Could not get filesystem information")]
-    FsInfoFailed = btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_FS_INFO_FAILED as isize,
+    FsInfoFailed = 26,
+    /// Could not add device
+    #[error("Could not add device")]
+    AddDevFailed = CRATE_ERROR_BASE,
+    /// Could not remove device
+    #[error("Could not remove device")]
+    RmDevFailed = CRATE_ERROR_BASE + 1,
+    /// Could not get extended attribute
+    #[error("Could not get extended attribute")]
+    GetXattrFailed = CRATE_ERROR_BASE + 2,
+    /// Could not set extended attribute
+    #[error("Could not set extended attribute")]
+    SetXattrFailed = CRATE_ERROR_BASE + 3,
+    /// Could not replace device
+    #[error("Could not replace device")]
+    DevReplaceFailed = CRATE_ERROR_BASE + 4,
+    /// Could not resize filesystem
+    #[error("Could not resize filesystem")]
+    ResizeFailed = CRATE_ERROR_BASE + 5,
+    /// Too many heavy operations running on the filesystem
+    #[error("Too many heavy operations running on the filesystem")]
+    Busy = CRATE_ERROR_BASE + 6,
+    /// Could not balance filesystem
+    #[error("Could not balance filesystem")]
+    BalanceFailed = CRATE_ERROR_BASE + 7,
+    /// Could not control balance
+    #[error("Could not control balance")]
+    BalanceCtlFailed = CRATE_ERROR_BASE + 8,
+    /// Could not get balance progress
+    #[error("Could not get balance progress")]
+    BalanceProgressFailed = CRATE_ERROR_BASE + 9,
+    /// Could not get device information
+    #[error("Could not get device information")]
+    DevInfoFailed = CRATE_ERROR_BASE + 10,
+    /// Could not scrub filesystem
+    #[error("Could not scrub filesystem")]
+    ScrubFailed = CRATE_ERROR_BASE + 11,
+    /// Could not cancel scrub
+    #[error("Could not cancel scrub")]
+    ScrubCancelFailed = CRATE_ERROR_BASE + 12,
+    /// Could not get scrub progress
+    #[error("Could not get scrub progress")]
+    ScrubProgressFailed = CRATE_ERROR_BASE + 13,
+    /// Could not clone file
+    #[error("Could not clone file")]
+    CloneFailed = CRATE_ERROR_BASE + 14,
+    /// Could not restore file
+    #[error("Could not restore file")]
+    RestoreFailed = CRATE_ERROR_BASE + 15,
+    /// Could not read file
+    #[error("Could not read file")]
+    ReadFailed = CRATE_ERROR_BASE + 16,
+    /// Could not trim filesystem
+    #[error("Could not trim filesystem")]
+    TrimFailed = CRATE_ERROR_BASE + 17,
+    /// Could not read stream
+    #[error("Could not read stream")]
+    StreamReadFailed = CRATE_ERROR_BASE + 18,
+    /// Could not access chunk store
+    #[error("Could not access chunk store")]
+    ChunkStoreFailed = CRATE_ERROR_BASE + 19,
+    /// Chunk does not match its id
+    #[error("Chunk does not match its id")]
+    ChunkCorrupted = CRATE_ERROR_BASE + 20,
+    /// Invalid chunk manifest
+    #[error("Invalid chunk manifest")]
+    ManifestInvalid = CRATE_ERROR_BASE + 21,
+    /// Could not resolve logical address
+    #[error("Could not resolve logical address")]
+    LogicalInoFailed = CRATE_ERROR_BASE + 22,
+    /// Could not resolve inode paths
+    #[error("Could not resolve inode paths")]
+    InoPathsFailed = CRATE_ERROR_BASE + 23,
+    /// No known-good subvolume to roll back to
+    #[error("No known-good subvolume to roll back to")]
+    NoGoodSubvolume = CRATE_ERROR_BASE + 24,
+    /// Snapshots are not read-only snapshots of the same subvolume
+    #[error("Snapshots are not read-only snapshots of the same subvolume")]
+    SnapshotsUnrelated = CRATE_ERROR_BASE + 25,
+    /// Could not get or set filesystem label
+    #[error("Could not get or set filesystem label")]
+    LabelFailed = CRATE_ERROR_BASE + 26,
+    /// Could not read or write snapshot group manifest
+    #[error("Could not read or write snapshot group manifest")]
+    GroupManifestFailed = CRATE_ERROR_BASE + 27,
+    /// Invalid snapshot group manifest
+    #[error("Invalid snapshot group manifest")]
+    GroupManifestInvalid = CRATE_ERROR_BASE + 28,
+    /// Could not map file extents
+    #[error("Could not map file extents")]
+    FiemapFailed = CRATE_ERROR_BASE + 29,
+    /// Could not deduplicate file range
+    #[error("Could not deduplicate file range")]
+    DedupeFailed = CRATE_ERROR_BASE + 30,
+    /// Could not read or write scheduler state
+    #[error("Could not read or write scheduler state")]
+    SchedulerStateFailed = CRATE_ERROR_BASE + 31,
+    /// Could not write support bundle
+    #[error("Could not write support bundle")]
+    BundleWriteFailed = CRATE_ERROR_BASE + 32,
+    /// Could not set received subvolume
+    #[error("Could not set received subvolume")]
+    SetReceivedFailed = CRATE_ERROR_BASE + 33,
+    /// Timed out waiting for deleted subvolumes to be cleaned up
+    #[error("Timed out waiting for deleted subvolumes to be cleaned up")]
+    CleanupTimedOut = CRATE_ERROR_BASE + 34,
+    /// Could not create directory
+    #[error("Could not create directory")]
+    MkdirFailed = CRATE_ERROR_BASE + 35,
+    /// Could not rename
+    #[error("Could not rename")]
+    RenameFailed = CRATE_ERROR_BASE + 36,
+    /// Could not send subvolume
+    #[error("Could not send subvolume")]
+    SendFailed = CRATE_ERROR_BASE + 37,
+    /// Could not receive subvolume
+    #[error("Could not receive subvolume")]
+    ReceiveFailed = CRATE_ERROR_BASE + 38,
+    /// Received subvolume does not match the sent one
+    #[error("Received subvolume does not match the sent one")]
+    BackupVerifyFailed = CRATE_ERROR_BASE + 39,
+    /// Missing privileges
+    #[error("Missing privileges")]
+    NotPrivileged = CRATE_ERROR_BASE + 40,
+    /// Could not get the kernel version
+    #[error("Could not get the kernel version")]
+    UnameFailed = CRATE_ERROR_BASE + 41,
+    /// Quotas are not enabled
+    #[error("Quotas are not enabled")]
+    QuotaDisabled = CRATE_ERROR_BASE + 42,
+    /// Quota group not found
+    #[error("Quota group not found")]
+    QgroupNotFound = CRATE_ERROR_BASE + 43,
+    /// Could not mount the filesystem
+    #[error("Could not mount the filesystem")]
+    MountFailed = CRATE_ERROR_BASE + 44,
+    /// Could not unmount the filesystem
+    #[error("Could not unmount the filesystem")]
+    UnmountFailed = CRATE_ERROR_BASE + 45,
+    /// Timed out waiting for the transaction to be committed
+    #[error("Timed out waiting for the transaction to be committed")]
+    SyncTimedOut = CRATE_ERROR_BASE + 46,
+    /// Could not defragment file
+    #[error("Could not defragment file")]
+    DefragFailed = CRATE_ERROR_BASE + 47,
 }
 
 impl LibError {
@@ -125,19 +263,100 @@ impl LibError {
     /// [thiserror]: https://docs.rs/thiserror/1.0.16/thiserror/
     /// [libbtrfsutil]: https://github.com/kdave/btrfs-progs/tree/master/libbtrfsutil
     pub fn strerror(&self) -> Result<&'static str> {
-        let err_str_ptr: *const c_char;
-
-        let errno = self.clone() as LibErrorCode;
-        unsafe {
-            err_str_ptr = btrfsutil_sys::btrfs_util_strerror(errno);
+        if let Some(val) = self.crate_strerror() {
+            return Ok(val);
         }
 
-        glue_error!(err_str_ptr.is_null(), GlueError::NullPointerReceived);
+        crate::sys::strerror(self.clone() as LibErrorCode)
+    }
 
-        let cstr: &CStr = unsafe { CStr::from_ptr(err_str_ptr) };
-        match cstr.to_str() {
-            Ok(val) => Ok(val),
-            Err(e) => glue_error!(GlueError::Utf8Error(e)),
+    /// Get the string description of the errors raised by this library itself, which
+    /// [libbtrfsutil] knows nothing about.
+    ///
+    /// [libbtrfsutil]: https://github.com/kdave/btrfs-progs/tree/master/libbtrfsutil
+    fn crate_strerror(&self) -> Option<&'static str> {
+        match self {
+            LibError::AddDevFailed => Some("Could not add device"),
+            LibError::RmDevFailed => Some("Could not remove device"),
+            LibError::GetXattrFailed => Some("Could not get extended attribute"),
+            LibError::SetXattrFailed => Some("Could not set extended attribute"),
+            LibError::DevReplaceFailed => Some("Could not replace device"),
+            LibError::ResizeFailed => Some("Could not resize filesystem"),
+            LibError::Busy => Some("Too many heavy operations running on the filesystem"),
+            LibError::BalanceFailed => Some("Could not balance filesystem"),
+            LibError::BalanceCtlFailed => Some("Could not control balance"),
+            LibError::BalanceProgressFailed => Some("Could not get balance progress"),
+            LibError::DevInfoFailed => Some("Could not get device information"),
+            LibError::ScrubFailed => Some("Could not scrub filesystem"),
+            LibError::ScrubCancelFailed => Some("Could not cancel scrub"),
+            LibError::ScrubProgressFailed => Some("Could not get scrub progress"),
+            LibError::CloneFailed => Some("Could not clone file"),
+            LibError::RestoreFailed => Some("Could not restore file"),
+            LibError::ReadFailed => Some("Could not read file"),
+            LibError::TrimFailed => Some("Could not trim filesystem"),
+            LibError::StreamReadFailed => Some("Could not read stream"),
+            LibError::ChunkStoreFailed => Some("Could not access chunk store"),
+            LibError::ChunkCorrupted => Some("Chunk does not match its id"),
+            LibError::ManifestInvalid => Some("Invalid chunk manifest"),
+            LibError::LogicalInoFailed => Some("Could not resolve logical address"),
+            LibError::InoPathsFailed => Some("Could not resolve inode paths"),
+            LibError::NoGoodSubvolume => Some("No known-good subvolume to roll back to"),
+            LibError::LabelFailed => Some("Could not get or set filesystem label"),
+            LibError::GroupManifestFailed => {
+                Some("Could not read or write snapshot group manifest")
+            }
+            LibError::GroupManifestInvalid => Some("Invalid snapshot group manifest"),
+            LibError::FiemapFailed => Some("Could not map file extents"),
+            LibError::DedupeFailed => Some("Could not deduplicate file range"),
+            LibError::SchedulerStateFailed => Some("Could not read or write scheduler state"),
+            LibError::BundleWriteFailed => Some("Could not write support bundle"),
+            LibError::SetReceivedFailed => Some("Could not set received subvolume"),
+            LibError::MkdirFailed => Some("Could not create directory"),
+            LibError::RenameFailed => Some("Could not rename"),
+            LibError::SendFailed => Some("Could not send subvolume"),
+            LibError::ReceiveFailed => Some("Could not receive subvolume"),
+            LibError::BackupVerifyFailed => Some("Received subvolume does not match the sent one"),
+            LibError::NotPrivileged => Some("Missing privileges"),
+            LibError::UnameFailed => Some("Could not get the kernel version"),
+            LibError::QuotaDisabled => Some("Quotas are not enabled"),
+            LibError::QgroupNotFound => Some("Quota group not found"),
+            LibError::MountFailed => Some("Could not mount the filesystem"),
+            LibError::UnmountFailed => Some("Could not unmount the filesystem"),
+            LibError::SyncTimedOut => Some("Timed out waiting for the transaction to be committed"),
+            LibError::DefragFailed => Some("Could not defragment file"),
+            LibError::CleanupTimedOut => {
+                Some("Timed out waiting for deleted subvolumes to be cleaned up")
+            }
+            LibError::SnapshotsUnrelated => {
+                Some("Snapshots are not read-only snapshots of the same subvolume")
+            }
+            _ => None,
+        }
+    }
+
+    /// Get the [io::ErrorKind] of the errors saying what was wrong rather than what failed,
+    /// regardless of the OS error.
+    ///
+    /// [io::ErrorKind]: https://doc.rust-lang.org/stable/std/io/enum.ErrorKind.html
+    pub(crate) fn io_error_kind(&self) -> Option<io::ErrorKind> {
+        match self {
+            LibError::NoMemory => Some(io::ErrorKind::OutOfMemory),
+            LibError::InvalidArgument
+            | LibError::NotBtrfs
+            | LibError::NotSubvolume
+            | LibError::SnapshotsUnrelated => Some(io::ErrorKind::InvalidInput),
+            LibError::SubvolumeNotFound | LibError::NoGoodSubvolume | LibError::QgroupNotFound => {
+                Some(io::ErrorKind::NotFound)
+            }
+            LibError::QuotaDisabled => Some(io::ErrorKind::Unsupported),
+            LibError::Busy => Some(io::ErrorKind::ResourceBusy),
+            LibError::NotPrivileged => Some(io::ErrorKind::PermissionDenied),
+            LibError::CleanupTimedOut | LibError::SyncTimedOut => Some(io::ErrorKind::TimedOut),
+            LibError::ChunkCorrupted
+            | LibError::ManifestInvalid
+            | LibError::GroupManifestInvalid
+            | LibError::BackupVerifyFailed => Some(io::ErrorKind::InvalidData),
+            _ => None,
         }
     }
 }
@@ -145,91 +364,56 @@ impl LibError {
 impl TryFrom<LibErrorCode> for LibError {
     type Error = BtrfsUtilError;
     fn try_from(errno: LibErrorCode) -> Result<Self> {
-        glue_error!(errno > 26, GlueError::UnknownErrno(errno));
-        match errno {
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_OK => Ok(LibError::Ok),
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_STOP_ITERATION => {
-                Ok(LibError::StopIteration)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_NO_MEMORY => Ok(LibError::NoMemory),
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_INVALID_ARGUMENT => {
-                Ok(LibError::InvalidArgument)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_NOT_BTRFS => Ok(LibError::NotBtrfs),
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_NOT_SUBVOLUME => {
-                Ok(LibError::NotSubvolume)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_SUBVOLUME_NOT_FOUND => {
-                Ok(LibError::SubvolumeNotFound)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_OPEN_FAILED => {
-                Ok(LibError::OpenFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_RMDIR_FAILED => {
-                Ok(LibError::RmdirFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_UNLINK_FAILED => {
-                Ok(LibError::UnlinkFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_STAT_FAILED => {
-                Ok(LibError::StatFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_STATFS_FAILED => {
-                Ok(LibError::StatfsFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_SEARCH_FAILED => {
-                Ok(LibError::SearchFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_INO_LOOKUP_FAILED => {
-                Ok(LibError::InoLookupFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_SUBVOL_GETFLAGS_FAILED => {
-                Ok(LibError::SubvolGetflagsFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_SUBVOL_SETFLAGS_FAILED => {
-                Ok(LibError::SubvolSetflagsFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_SUBVOL_CREATE_FAILED => {
-                Ok(LibError::SubvolCreateFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_SNAP_CREATE_FAILED => {
-                Ok(LibError::SnapCreateFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_SNAP_DESTROY_FAILED => {
-                Ok(LibError::SnapDestroyFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_DEFAULT_SUBVOL_FAILED => {
-                Ok(LibError::DefaultSubvolFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_SYNC_FAILED => {
-                Ok(LibError::SyncFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_START_SYNC_FAILED => {
-                Ok(LibError::StartSyncFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_WAIT_SYNC_FAILED => {
-                Ok(LibError::WaitSyncFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_GET_SUBVOL_INFO_FAILED => {
-                Ok(LibError::GetSubvolInfoFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_GET_SUBVOL_ROOTREF_FAILED => {
-                Ok(LibError::GetSubvolRootrefFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_INO_LOOKUP_USER_FAILED => {
-                Ok(LibError::InoLookupUserFailed)
-            }
-            btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_FS_INFO_FAILED => {
-                Ok(LibError::FsInfoFailed)
-            }
-            _ => glue_error!(GlueError::UnknownErrno(errno)),
+        // the errors of libbtrfsutil, by error code
+        const ERRORS: [LibError; 27] = [
+            LibError::Ok,
+            LibError::StopIteration,
+            LibError::NoMemory,
+            LibError::InvalidArgument,
+            LibError::NotBtrfs,
+            LibError::NotSubvolume,
+            LibError::SubvolumeNotFound,
+            LibError::OpenFailed,
+            LibError::RmdirFailed,
+            LibError::UnlinkFailed,
+            LibError::StatFailed,
+            LibError::StatfsFailed,
+            LibError::SearchFailed,
+            LibError::InoLookupFailed,
+            LibError::SubvolGetflagsFailed,
+            LibError::SubvolSetflagsFailed,
+            LibError::SubvolCreateFailed,
+            LibError::SnapCreateFailed,
+            LibError::SnapDestroyFailed,
+            LibError::DefaultSubvolFailed,
+            LibError::SyncFailed,
+            LibError::StartSyncFailed,
+            LibError::WaitSyncFailed,
+            LibError::GetSubvolInfoFailed,
+            LibError::GetSubvolRootrefFailed,
+            LibError::InoLookupUserFailed,
+            LibError::FsInfoFailed,
+        ];
+
+        match ERRORS.get(errno as usize) {
+            Some(err) => Ok(err.clone()),
+            None => glue_error!(GlueError::UnknownErrno(errno)),
         }
     }
 }
 
-#[cfg(feature = "enable-glue-errors")]
-impl Into<BtrfsUtilError> for LibError {
-    fn into(self) -> BtrfsUtilError {
-        BtrfsUtilError::Lib(self)
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn error_codes_round_trip() {
+        for code in 0..27 {
+            assert_eq!(
+                LibError::try_from(code).map(|err| err as LibErrorCode),
+                Ok(code)
+            );
+        }
+        assert!(LibError::try_from(27).is_err());
     }
 }
@@ -1,33 +1,387 @@
 //! Library errors
 
-#[cfg(feature = "enable-glue-errors")]
+use std::io;
+use std::path::PathBuf;
+
 use thiserror::Error;
 
+pub(crate) mod batch;
+pub(crate) mod context;
 #[macro_use]
 pub(crate) mod glue;
 pub(crate) mod lib;
+#[cfg(feature = "backtrace")]
+pub(crate) mod trace;
 
+pub use crate::Result;
+pub use batch::BatchError;
+pub use batch::BatchResult;
+pub use context::ErrorContext;
+pub(crate) use context::ResultExt;
 pub use glue::GlueError;
 pub use lib::LibError;
 pub(crate) use lib::LibErrorCode;
+#[cfg(feature = "backtrace")]
+pub use trace::ErrorBacktrace;
 
 /// Generic library error type. May be either a [LibError] or a [GlueError].
 ///
-/// [GlueError]: enum.LibError.html
+/// Library errors carry the OS error that caused them when there is one, such as the `errno`
+/// libbtrfsutil or an ioctl failed with, which tells apart a lack of permissions from a full
+/// disk behind the same [LibError]. The message of a glue error and the [source] are those of
+/// the wrapped error.
+///
+/// Errors returned by public functions doing something on paths are wrapped in an
+/// [ErrorContext] naming the function and the paths. Its message is the context alone and the
+/// wrapped error is its [source], so reporters walking the chain print each cause once. The
+/// accessors look through it.
+///
+/// Operations undoing their partial work when they fail, such as creating a group of snapshots,
+/// wrap the error in a `Rollback` listing what could not be undone, with the same message and
+/// source layout.
+///
+/// With the `backtrace` feature, errors raised by libbtrfsutil, by ioctls and by the glue carry
+/// the backtrace of where they were raised, when backtraces are enabled through the
+/// `RUST_LIB_BACKTRACE` or `RUST_BACKTRACE` environment variables. The accessors and comparisons
+/// look through it as well, so whether an error was traced never changes its equality.
+///
+/// [LibError]: enum.LibError.html
+/// [ErrorContext]: struct.ErrorContext.html
 /// [GlueError]: enum.GlueError.html
-#[cfg(feature = "enable-glue-errors")]
-#[derive(Clone, Debug, Eq, Error, PartialEq)]
+/// [source]: https://doc.rust-lang.org/stable/std/error/trait.Error.html#method.source
+#[derive(Clone, Debug, Eq, Error)]
 pub enum BtrfsUtilError {
     /// Glue error
-    #[error("{0}")]
-    Glue(GlueError),
+    #[error(transparent)]
+    Glue(#[from] GlueError),
     /// Library error
-    #[error("{0}")]
-    Lib(LibError),
+    #[error("{error}{}", errno_suffix(.errno))]
+    Lib {
+        /// The error
+        error: LibError,
+        /// The OS error that caused it, if known
+        errno: Option<i32>,
+    },
+    /// Error with the operation and the paths it happened on
+    #[error("{context}")]
+    Context {
+        /// What was being done
+        context: ErrorContext,
+        /// The error
+        #[source]
+        error: Box<BtrfsUtilError>,
+    },
+    /// Error after which undoing the partial work failed
+    #[error("could not roll back, left behind {}", display_paths(.left_behind))]
+    Rollback {
+        /// The error that caused the rollback
+        #[source]
+        error: Box<BtrfsUtilError>,
+        /// What could not be undone, with why
+        left_behind: Vec<(PathBuf, BtrfsUtilError)>,
+    },
+    /// Error with the backtrace of where it was raised
+    #[cfg(feature = "backtrace")]
+    #[error("{error}")]
+    Traced {
+        /// The error
+        error: Box<BtrfsUtilError>,
+        /// Where it was raised
+        trace: ErrorBacktrace,
+    },
 }
 
-#[cfg(not(feature = "enable-glue-errors"))]
-/// Generic library error type. If [GlueError]s happen, they will panic.
-///
-/// [GlueError]: enum.GlueError.html
-pub type BtrfsUtilError = LibError;
+impl BtrfsUtilError {
+    /// Create a library error caused by an OS error.
+    pub(crate) fn os(error: LibError, errno: i32) -> Self {
+        BtrfsUtilError::Lib {
+            error,
+            // zero is no error at all
+            errno: Some(errno).filter(|errno| *errno != 0),
+        }
+        .traced()
+    }
+
+    /// Create a library error caused by an I/O error, keeping its OS error code if it has one.
+    pub(crate) fn from_io(error: LibError, err: &io::Error) -> Self {
+        Self::os(error, err.raw_os_error().unwrap_or(0))
+    }
+
+    /// Create a library error caused by the last OS error of this thread.
+    pub(crate) fn last_os_error(error: LibError) -> Self {
+        Self::os(
+            error,
+            io::Error::last_os_error().raw_os_error().unwrap_or(0),
+        )
+    }
+
+    /// Get the [LibError], if this is a library error.
+    ///
+    /// [LibError]: enum.LibError.html
+    pub fn lib_error(&self) -> Option<&LibError> {
+        match self {
+            BtrfsUtilError::Lib { error, .. } => Some(error),
+            BtrfsUtilError::Context { error, .. } => error.lib_error(),
+            BtrfsUtilError::Rollback { error, .. } => error.lib_error(),
+            #[cfg(feature = "backtrace")]
+            BtrfsUtilError::Traced { error, .. } => error.lib_error(),
+            BtrfsUtilError::Glue(_) => None,
+        }
+    }
+
+    /// Get the [GlueError], if this is a glue error.
+    ///
+    /// [GlueError]: enum.GlueError.html
+    pub fn glue_error(&self) -> Option<&GlueError> {
+        match self {
+            BtrfsUtilError::Glue(error) => Some(error),
+            BtrfsUtilError::Context { error, .. } => error.glue_error(),
+            BtrfsUtilError::Rollback { error, .. } => error.glue_error(),
+            #[cfg(feature = "backtrace")]
+            BtrfsUtilError::Traced { error, .. } => error.glue_error(),
+            BtrfsUtilError::Lib { .. } => None,
+        }
+    }
+
+    /// Get the OS error code that caused this error, if known.
+    pub fn errno(&self) -> Option<i32> {
+        match self {
+            BtrfsUtilError::Lib { errno, .. } => *errno,
+            BtrfsUtilError::Context { error, .. } => error.errno(),
+            BtrfsUtilError::Rollback { error, .. } => error.errno(),
+            #[cfg(feature = "backtrace")]
+            BtrfsUtilError::Traced { error, .. } => error.errno(),
+            BtrfsUtilError::Glue(_) => None,
+        }
+    }
+
+    /// Get the OS error that caused this error, if known.
+    pub fn os_error(&self) -> Option<io::Error> {
+        self.errno().map(io::Error::from_raw_os_error)
+    }
+
+    /// Get the [io::ErrorKind] closest to this error.
+    ///
+    /// Errors saying what was wrong map to a fixed kind, such as [LibError::NotBtrfs] to
+    /// `InvalidInput`. Others map from their OS error, such as [LibError::OpenFailed] with
+    /// `EPERM` to `PermissionDenied`, or to `Other` without one.
+    ///
+    /// [io::ErrorKind]: https://doc.rust-lang.org/stable/std/io/enum.ErrorKind.html
+    /// [LibError::NotBtrfs]: enum.LibError.html#variant.NotBtrfs
+    /// [LibError::OpenFailed]: enum.LibError.html#variant.OpenFailed
+    pub fn to_io_error_kind(&self) -> io::ErrorKind {
+        match self {
+            BtrfsUtilError::Glue(error) => error.io_error_kind(),
+            BtrfsUtilError::Lib { error, errno } => error
+                .io_error_kind()
+                .or_else(|| errno.map(|errno| io::Error::from_raw_os_error(errno).kind()))
+                .unwrap_or(io::ErrorKind::Other),
+            BtrfsUtilError::Context { error, .. } => error.to_io_error_kind(),
+            BtrfsUtilError::Rollback { error, .. } => error.to_io_error_kind(),
+            #[cfg(feature = "backtrace")]
+            BtrfsUtilError::Traced { error, .. } => error.to_io_error_kind(),
+        }
+    }
+
+    /// Get the operation and the paths this error happened on, if known.
+    ///
+    /// This is the context of the public function that was called, which may have failed in
+    /// another one with its own context.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            BtrfsUtilError::Context { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// Get the backtrace of where this error was raised, if one was captured.
+    #[cfg(feature = "backtrace")]
+    pub fn backtrace(&self) -> Option<&std::backtrace::Backtrace> {
+        match self {
+            BtrfsUtilError::Traced { trace, .. } => Some(trace.backtrace()),
+            BtrfsUtilError::Context { error, .. } => error.backtrace(),
+            BtrfsUtilError::Rollback { error, .. } => error.backtrace(),
+            _ => None,
+        }
+    }
+
+    /// Get the error without the backtrace it was raised with, if any.
+    fn untraced(&self) -> &Self {
+        match self {
+            #[cfg(feature = "backtrace")]
+            BtrfsUtilError::Traced { error, .. } => error.untraced(),
+            _ => self,
+        }
+    }
+
+    /// Without the `backtrace` feature, errors are not traced.
+    #[cfg(not(feature = "backtrace"))]
+    #[inline]
+    pub(crate) fn traced(self) -> Self {
+        self
+    }
+}
+
+impl From<LibError> for BtrfsUtilError {
+    /// A library error without an OS error, traced like the others.
+    fn from(error: LibError) -> Self {
+        BtrfsUtilError::Lib { error, errno: None }.traced()
+    }
+}
+
+impl PartialEq for BtrfsUtilError {
+    /// Errors are equal regardless of the backtraces they were raised with.
+    fn eq(&self, other: &Self) -> bool {
+        match (self.untraced(), other.untraced()) {
+            (BtrfsUtilError::Glue(this), BtrfsUtilError::Glue(other)) => this == other,
+            (
+                BtrfsUtilError::Lib { error, errno },
+                BtrfsUtilError::Lib {
+                    error: other_error,
+                    errno: other_errno,
+                },
+            ) => error == other_error && errno == other_errno,
+            (
+                BtrfsUtilError::Context { context, error },
+                BtrfsUtilError::Context {
+                    context: other_context,
+                    error: other_error,
+                },
+            ) => context == other_context && error == other_error,
+            (
+                BtrfsUtilError::Rollback { error, left_behind },
+                BtrfsUtilError::Rollback {
+                    error: other_error,
+                    left_behind: other_left_behind,
+                },
+            ) => error == other_error && left_behind == other_left_behind,
+            _ => false,
+        }
+    }
+}
+
+impl PartialEq<LibError> for BtrfsUtilError {
+    /// A library error equals a [LibError] regardless of its OS error.
+    ///
+    /// [LibError]: enum.LibError.html
+    fn eq(&self, other: &LibError) -> bool {
+        self.lib_error() == Some(other)
+    }
+}
+
+impl From<BtrfsUtilError> for io::Error {
+    /// Convert to an [io::Error] of the [kind] closest to the error, keeping the error as its
+    /// inner error, so it can be downcast back.
+    ///
+    /// [io::Error]: https://doc.rust-lang.org/stable/std/io/struct.Error.html
+    /// [kind]: enum.BtrfsUtilError.html#method.to_io_error_kind
+    fn from(err: BtrfsUtilError) -> Self {
+        io::Error::new(err.to_io_error_kind(), err)
+    }
+}
+
+/// Format the paths left behind by a rollback.
+fn display_paths(left_behind: &[(PathBuf, BtrfsUtilError)]) -> String {
+    left_behind
+        .iter()
+        .map(|(path, _)| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Format an OS error to follow the message of a library error.
+fn errno_suffix(errno: &Option<i32>) -> String {
+    errno
+        .map(|errno| format!(": {}", io::Error::from_raw_os_error(errno)))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::path::Path;
+
+    #[test]
+    fn errno_is_carried() {
+        let err = BtrfsUtilError::os(LibError::OpenFailed, libc::EACCES);
+        assert_eq!(err, LibError::OpenFailed);
+        assert_eq!(err.errno(), Some(libc::EACCES));
+        assert_eq!(
+            err.os_error().map(|err| err.kind()),
+            Some(io::ErrorKind::PermissionDenied)
+        );
+        assert!(err.to_string().starts_with("Could not open: "));
+
+        let err = BtrfsUtilError::os(LibError::NotBtrfs, 0);
+        assert_eq!(err, BtrfsUtilError::from(LibError::NotBtrfs));
+        assert_eq!(err.to_string(), "Not a Btrfs filesystem");
+    }
+
+    #[test]
+    fn io_error_kinds() {
+        let err = BtrfsUtilError::os(LibError::OpenFailed, libc::EPERM);
+        assert_eq!(err.to_io_error_kind(), io::ErrorKind::PermissionDenied);
+        let err = BtrfsUtilError::os(LibError::NotBtrfs, libc::EPERM)
+            .with_context("Subvolume::get", &[Path::new("/mnt")]);
+        assert_eq!(err.to_io_error_kind(), io::ErrorKind::InvalidInput);
+        let err = BtrfsUtilError::from(LibError::SyncFailed);
+        assert_eq!(err.to_io_error_kind(), io::ErrorKind::Other);
+
+        let io_err = io::Error::from(BtrfsUtilError::from(LibError::SubvolumeNotFound));
+        assert_eq!(io_err.kind(), io::ErrorKind::NotFound);
+        let inner = io_err
+            .into_inner()
+            .and_then(|err| err.downcast::<BtrfsUtilError>().ok());
+        assert_eq!(
+            inner.as_deref(),
+            Some(&BtrfsUtilError::from(LibError::SubvolumeNotFound))
+        );
+    }
+
+    #[test]
+    fn context_is_attached() {
+        let err = BtrfsUtilError::os(LibError::OpenFailed, libc::ENOENT)
+            .with_context("Subvolume::get", &[Path::new("/mnt/a")])
+            .with_context(
+                "Subvolume::snapshot",
+                &[Path::new("/mnt"), Path::new("/mnt/b")],
+            );
+        assert_eq!(err, LibError::OpenFailed);
+        assert_eq!(err.errno(), Some(libc::ENOENT));
+        assert_eq!(
+            err.context().map(|context| context.operation),
+            Some("Subvolume::snapshot")
+        );
+        assert_eq!(err.to_string(), "Subvolume::snapshot on /mnt, /mnt/b");
+        let source = std::error::Error::source(&err).map(ToString::to_string);
+        assert_eq!(source.as_deref(), Some("Subvolume::get on /mnt/a"));
+    }
+
+    #[test]
+    fn rollback_lists_left_behind() {
+        let err = BtrfsUtilError::Rollback {
+            error: Box::new(BtrfsUtilError::os(LibError::WaitSyncFailed, libc::EIO)),
+            left_behind: vec![
+                (
+                    PathBuf::from("/mnt/a"),
+                    BtrfsUtilError::from(LibError::SnapDestroyFailed),
+                ),
+                (
+                    PathBuf::from("/mnt/b"),
+                    BtrfsUtilError::from(LibError::SnapDestroyFailed),
+                ),
+            ],
+        };
+        assert_eq!(err, LibError::WaitSyncFailed);
+        assert_eq!(err.errno(), Some(libc::EIO));
+        assert_eq!(
+            err.to_string(),
+            "could not roll back, left behind /mnt/a, /mnt/b"
+        );
+        let source = std::error::Error::source(&err).map(ToString::to_string);
+        assert!(
+            source.is_some_and(|source| source.starts_with("Could not wait for filesystem sync"))
+        );
+    }
+}
@@ -0,0 +1,77 @@
+use crate::BtrfsUtilError;
+
+use std::backtrace::Backtrace;
+use std::backtrace::BacktraceStatus;
+use std::fmt;
+use std::sync::Arc;
+
+/// The backtrace of where an error was raised.
+///
+/// Errors are cloned and compared, which backtraces cannot be, so the backtrace is shared
+/// between clones and left out of comparisons.
+#[derive(Clone)]
+pub struct ErrorBacktrace(Arc<Backtrace>);
+
+impl ErrorBacktrace {
+    /// Get the backtrace.
+    #[inline]
+    pub fn backtrace(&self) -> &Backtrace {
+        &self.0
+    }
+}
+
+impl fmt::Debug for ErrorBacktrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl fmt::Display for ErrorBacktrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&*self.0, f)
+    }
+}
+
+impl PartialEq for ErrorBacktrace {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for ErrorBacktrace {}
+
+impl BtrfsUtilError {
+    /// Capture a backtrace for the error, if backtraces are enabled through the
+    /// `RUST_LIB_BACKTRACE` or `RUST_BACKTRACE` environment variables.
+    pub(crate) fn traced(self) -> Self {
+        let backtrace = Backtrace::capture();
+        if backtrace.status() != BacktraceStatus::Captured {
+            return self;
+        }
+        BtrfsUtilError::Traced {
+            error: Box::new(self),
+            trace: ErrorBacktrace(Arc::new(backtrace)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::LibError;
+
+    #[test]
+    fn traced_errors_compare_untraced() {
+        let untraced = BtrfsUtilError::Lib {
+            error: LibError::OpenFailed,
+            errno: None,
+        };
+        let traced = BtrfsUtilError::Traced {
+            error: Box::new(untraced.clone()),
+            trace: ErrorBacktrace(Arc::new(Backtrace::force_capture())),
+        };
+        assert_eq!(traced, untraced);
+        assert_eq!(untraced, traced);
+        assert_ne!(traced, BtrfsUtilError::from(LibError::NotBtrfs));
+    }
+}
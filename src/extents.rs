@@ -0,0 +1,115 @@
+//! File extent maps
+//!
+//! The physical layout of files as `FS_IOC_FIEMAP` reports it, for deduplication and diagnostic
+//! tools. On btrfs, physical addresses are logical addresses of the filesystem, which the chunk
+//! tree maps to the devices; they are the same for every file sharing an extent.
+
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::ioctl;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+bitflags! {
+    /// Flags of an [Extent].
+    ///
+    /// [Extent]: struct.Extent.html
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct ExtentFlags: u32 {
+        /// The last extent of the file.
+        const LAST = ioctl::FIEMAP_EXTENT_LAST;
+        /// The location of the extent is not known yet.
+        const UNKNOWN = ioctl::FIEMAP_EXTENT_UNKNOWN;
+        /// The extent is waiting to be allocated, its location is not known yet.
+        const DELALLOC = ioctl::FIEMAP_EXTENT_DELALLOC;
+        /// The extent is not stored as is, for example because it is compressed.
+        const ENCODED = ioctl::FIEMAP_EXTENT_ENCODED;
+        /// The extent is encrypted.
+        const DATA_ENCRYPTED = ioctl::FIEMAP_EXTENT_DATA_ENCRYPTED;
+        /// The extent is not aligned to blocks.
+        const NOT_ALIGNED = ioctl::FIEMAP_EXTENT_NOT_ALIGNED;
+        /// The data is stored in the metadata, inline.
+        const DATA_INLINE = ioctl::FIEMAP_EXTENT_DATA_INLINE;
+        /// The data is packed with the tails of other files.
+        const DATA_TAIL = ioctl::FIEMAP_EXTENT_DATA_TAIL;
+        /// The extent is allocated but not written, it reads back as zeroes.
+        const UNWRITTEN = ioctl::FIEMAP_EXTENT_UNWRITTEN;
+        /// The extent was merged from several ones by the filesystem.
+        const MERGED = ioctl::FIEMAP_EXTENT_MERGED;
+        /// The extent is shared with other files or snapshots.
+        const SHARED = ioctl::FIEMAP_EXTENT_SHARED;
+    }
+}
+
+/// An extent of a file, as listed by [map].
+///
+/// [map]: fn.map.html
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Extent {
+    /// Offset of the extent in the file.
+    pub logical: u64,
+    /// Address of the extent in the filesystem.
+    pub physical: u64,
+    /// Number of bytes of the file the extent holds.
+    pub length: u64,
+    /// Flags of the extent.
+    pub flags: ExtentFlags,
+}
+
+impl Extent {
+    /// Check whether the extent is shared with other files or snapshots.
+    #[inline]
+    pub fn is_shared(&self) -> bool {
+        self.flags.contains(ExtentFlags::SHARED)
+    }
+
+    /// Check whether the data is stored inline in the metadata.
+    #[inline]
+    pub fn is_inline(&self) -> bool {
+        self.flags.contains(ExtentFlags::DATA_INLINE)
+    }
+
+    /// Check whether the extent is compressed.
+    #[inline]
+    pub fn is_compressed(&self) -> bool {
+        self.flags.contains(ExtentFlags::ENCODED)
+    }
+
+    /// Check whether the extent is waiting to be allocated.
+    #[inline]
+    pub fn is_delalloc(&self) -> bool {
+        self.flags.contains(ExtentFlags::DELALLOC)
+    }
+}
+
+/// Get the extents of a file, ordered by their offset in the file.
+///
+/// Dirty data is flushed first, so delayed allocations only show up for data written in the
+/// meantime. Holes have no extent.
+pub fn map<P>(path: P) -> Result<Vec<Extent>>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    map_impl(path).context("extents::map", &[path])
+}
+
+fn map_impl(path: &Path) -> Result<Vec<Extent>> {
+    let file =
+        File::open(path).map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?;
+
+    Ok(ioctl::fiemap(file.as_raw_fd())?
+        .into_iter()
+        .map(|extent| Extent {
+            logical: extent.logical,
+            physical: extent.physical,
+            length: extent.length,
+            flags: ExtentFlags::from_bits_truncate(extent.flags),
+        })
+        .collect())
+}
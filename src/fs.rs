@@ -0,0 +1,655 @@
+//! Btrfs filesystem-wide operations
+
+use crate::common;
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::ioctl;
+use crate::ioctl::DevReplaceArgs;
+use crate::ioctl::FstrimRange;
+use crate::ioctl::VolArgs;
+use crate::ioctl::VolArgsV2;
+use crate::limiter::Limiter;
+use crate::subvolume::Subvolume;
+use crate::subvolume::SubvolumeId;
+use crate::subvolume::SubvolumeIterator;
+use crate::RawUuid;
+pub use crate::Result;
+
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+bitflags! {
+    /// Device replace flags.
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct ReplaceFlags: u64 {
+        /// Only read from the source device when no other mirror has the data, for replacing a
+        /// device that is failing.
+        const AVOID_SOURCE = 1;
+    }
+}
+
+/// A handle to a mounted btrfs filesystem.
+///
+/// Heavy operations issued through a handle with a [Limiter] attached count against the
+/// limiter's cap.
+///
+/// [Limiter]: ../limiter/struct.Limiter.html
+#[derive(Clone, Debug)]
+pub struct Filesystem {
+    path: PathBuf,
+    limiter: Option<Arc<Limiter>>,
+}
+
+impl Filesystem {
+    /// Create a handle to the filesystem a path belongs to.
+    pub fn new<P>(path: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            limiter: None,
+        }
+    }
+
+    /// Attach a limiter to this handle.
+    pub fn with_limiter(mut self, limiter: Arc<Limiter>) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+
+    /// Get the path this handle was created from.
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the limiter attached to this handle.
+    #[inline]
+    pub fn limiter(&self) -> Option<&Arc<Limiter>> {
+        self.limiter.as_ref()
+    }
+
+    /// Same as [add_device].
+    ///
+    /// [add_device]: fn.add_device.html
+    pub fn add_device<D>(&self, device: D) -> Result<()>
+    where
+        D: AsRef<Path>,
+    {
+        let device = device.as_ref();
+        add_device_impl(&self.path, device).context("Filesystem::add_device", &[&self.path, device])
+    }
+
+    /// Same as [remove_device], counted as a heavy operation.
+    ///
+    /// [remove_device]: fn.remove_device.html
+    pub fn remove_device<'a, D>(&self, device: D) -> Result<()>
+    where
+        D: Into<DeviceSpec<'a>>,
+    {
+        self.heavy(|| remove_device_impl(&self.path, device.into()))
+            .context("Filesystem::remove_device", &[&self.path])
+    }
+
+    /// Same as [replace_device], counted as a heavy operation.
+    ///
+    /// [replace_device]: fn.replace_device.html
+    pub fn replace_device<'a, S, T, F>(&self, source: S, target: T, flags: F) -> Result<()>
+    where
+        S: Into<DeviceSpec<'a>>,
+        T: AsRef<Path>,
+        F: Into<Option<ReplaceFlags>>,
+    {
+        let target = target.as_ref();
+        self.heavy(|| replace_device_impl(&self.path, source.into(), target, flags.into()))
+            .context("Filesystem::replace_device", &[&self.path, target])
+    }
+
+    /// Same as [resize].
+    ///
+    /// [resize]: fn.resize.html
+    pub fn resize<D>(&self, spec: ResizeSpec, devid: D) -> Result<()>
+    where
+        D: Into<Option<u64>>,
+    {
+        resize_impl(&self.path, spec, devid.into()).context("Filesystem::resize", &[&self.path])
+    }
+
+    /// Same as [trim].
+    ///
+    /// [trim]: fn.trim.html
+    pub fn trim(&self, range: TrimRange) -> Result<u64> {
+        trim_impl(&self.path, range).context("Filesystem::trim", &[&self.path])
+    }
+
+    /// Make the subvolume at a path the default subvolume of this filesystem and get the
+    /// previous default, so the change can be undone with [AdminSubvolume::set_default].
+    ///
+    /// Unlike [AdminSubvolume::set_default], this checks that the path is the root of a subvolume,
+    /// failing with [LibError::NotSubvolume] otherwise, and that it is on this filesystem,
+    /// failing with [LibError::InvalidArgument] otherwise. The default is read back afterwards
+    /// and [LibError::DefaultSubvolFailed] is returned if it did not change.
+    ///
+    /// The path of the previous default is the path of this handle.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    ///
+    /// [AdminSubvolume::set_default]: ../subvolume/struct.AdminSubvolume.html#method.set_default
+    /// [LibError::NotSubvolume]: ../error/enum.LibError.html#variant.NotSubvolume
+    /// [LibError::InvalidArgument]: ../error/enum.LibError.html#variant.InvalidArgument
+    /// [LibError::DefaultSubvolFailed]: ../error/enum.LibError.html#variant.DefaultSubvolFailed
+    pub fn set_default_by_path<P>(&self, path: P) -> Result<Subvolume>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        self.set_default_by_path_impl(path)
+            .context("Filesystem::set_default_by_path", &[&self.path, path])
+    }
+
+    fn set_default_by_path_impl(&self, path: &Path) -> Result<Subvolume> {
+        let target = Subvolume::get(path)?;
+        let previous = Subvolume::get_default(self.path())?;
+        if !previous.same_filesystem(&target)? {
+            return Err(LibError::InvalidArgument.into());
+        }
+
+        target.clone().elevate()?.set_default()?;
+        if Subvolume::get_default(self.path())?.id() != target.id() {
+            return Err(LibError::DefaultSubvolFailed.into());
+        }
+
+        Ok(previous)
+    }
+
+    /// Check whether there are no subvolumes below the subvolume the path of this handle belongs
+    /// to, as on a freshly created filesystem mounted at its top level.
+    ///
+    /// Only the first subvolume is looked up, so this is cheap regardless of how many there are.
+    /// Listing subvolumes of other users may require **CAP_SYS_ADMIN**.
+    pub fn is_empty_of_subvolumes(&self) -> Result<bool> {
+        let mut iter = SubvolumeIterator::new(self.path(), None)?;
+        match iter.next_borrowed() {
+            Some(subvol) => subvol.map(|_| false),
+            None => Ok(true),
+        }
+        .context("Filesystem::is_empty_of_subvolumes", &[&self.path])
+    }
+
+    /// Run a heavy operation, holding a slot of the attached limiter for its duration.
+    pub(crate) fn heavy<T, F>(&self, op: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        let _permit = match &self.limiter {
+            Some(limiter) => Some(limiter.acquire()?),
+            None => None,
+        };
+        op()
+    }
+}
+
+/// A device of a btrfs filesystem, either by its path or by its device id.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeviceSpec<'a> {
+    /// Path of the block device.
+    Path(&'a Path),
+    /// Id of the device within the filesystem.
+    Id(u64),
+}
+
+impl<'a> From<&'a Path> for DeviceSpec<'a> {
+    fn from(path: &'a Path) -> Self {
+        DeviceSpec::Path(path)
+    }
+}
+
+impl From<u64> for DeviceSpec<'_> {
+    fn from(id: u64) -> Self {
+        DeviceSpec::Id(id)
+    }
+}
+
+/// Add a device to the filesystem mounted at a path.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn add_device<P, D>(path: P, device: D) -> Result<()>
+where
+    P: AsRef<Path>,
+    D: AsRef<Path>,
+{
+    let path = path.as_ref();
+    let device = device.as_ref();
+    add_device_impl(path, device).context("fs::add_device", &[path, device])
+}
+
+fn add_device_impl(path: &Path, device: &Path) -> Result<()> {
+    let file = ioctl::open(path)?;
+    let mut args = VolArgs::with_name(ioctl::path_bytes(device))?;
+
+    unsafe {
+        ioctl::ioctl(
+            file.as_raw_fd(),
+            ioctl::BTRFS_IOC_ADD_DEV,
+            &mut args,
+            LibError::AddDevFailed,
+        )
+    }
+}
+
+/// Remove a device from the filesystem mounted at a path.
+///
+/// Data on the device is relocated to the remaining devices first, which may take a long time.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn remove_device<'b, P, D>(path: P, device: D) -> Result<()>
+where
+    P: AsRef<Path>,
+    D: Into<DeviceSpec<'b>>,
+{
+    let path = path.as_ref();
+    remove_device_impl(path, device.into()).context("fs::remove_device", &[path])
+}
+
+fn remove_device_impl(path: &Path, device: DeviceSpec) -> Result<()> {
+    let file = ioctl::open(path)?;
+    let mut args = match device {
+        DeviceSpec::Path(device) => VolArgsV2::with_name(ioctl::path_bytes(device))?,
+        DeviceSpec::Id(id) => VolArgsV2::with_id(ioctl::BTRFS_DEVICE_SPEC_BY_ID, id),
+    };
+
+    unsafe {
+        ioctl::ioctl(
+            file.as_raw_fd(),
+            ioctl::BTRFS_IOC_RM_DEV_V2,
+            &mut args,
+            LibError::RmDevFailed,
+        )
+    }
+}
+
+/// Replace a device of the filesystem mounted at a path with another one.
+///
+/// This blocks until the replace finishes, is canceled, or fails. Use [replace_status] from
+/// another thread to follow its progress and [cancel_replace] to stop it.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+///
+/// [replace_status]: fn.replace_status.html
+/// [cancel_replace]: fn.cancel_replace.html
+pub fn replace_device<'b, P, S, T, F>(path: P, source: S, target: T, flags: F) -> Result<()>
+where
+    P: AsRef<Path>,
+    S: Into<DeviceSpec<'b>>,
+    T: AsRef<Path>,
+    F: Into<Option<ReplaceFlags>>,
+{
+    let path = path.as_ref();
+    let target = target.as_ref();
+    replace_device_impl(path, source.into(), target, flags.into())
+        .context("fs::replace_device", &[path, target])
+}
+
+fn replace_device_impl(
+    path: &Path,
+    source: DeviceSpec,
+    target: &Path,
+    flags: Option<ReplaceFlags>,
+) -> Result<()> {
+    let file = ioctl::open(path)?;
+    let flags_val = flags.map(|v| v.bits()).unwrap_or(0);
+    let mut args = match source {
+        DeviceSpec::Path(source) => DevReplaceArgs::start(
+            0,
+            ioctl::path_bytes(source),
+            ioctl::path_bytes(target),
+            flags_val,
+        )?,
+        DeviceSpec::Id(id) => DevReplaceArgs::start(id, &[], ioctl::path_bytes(target), flags_val)?,
+    };
+
+    unsafe {
+        ioctl::ioctl(
+            file.as_raw_fd(),
+            ioctl::BTRFS_IOC_DEV_REPLACE,
+            &mut args,
+            LibError::DevReplaceFailed,
+        )
+    }?;
+
+    if args.result != ioctl::BTRFS_IOCTL_DEV_REPLACE_RESULT_NO_ERROR {
+        return Err(LibError::DevReplaceFailed.into());
+    }
+
+    Ok(())
+}
+
+/// State of a device replace.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReplaceState {
+    /// No replace was ever started on this filesystem.
+    NeverStarted,
+    /// A replace is running.
+    Started,
+    /// The last replace finished.
+    Finished,
+    /// The last replace was canceled.
+    Canceled,
+    /// A replace was interrupted by an unmount and resumes on the next mount.
+    Suspended,
+}
+
+/// Status of the current or last device replace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplaceStatus {
+    /// State of the replace.
+    pub state: ReplaceState,
+    /// Progress, between 0.0 and 1.0.
+    pub progress: f64,
+    /// Time when the replace was started.
+    pub started: Option<SystemTime>,
+    /// Time when the replace stopped, if it is not running.
+    pub stopped: Option<SystemTime>,
+    /// Number of errors writing to the target device.
+    pub write_errors: u64,
+    /// Number of read errors that could not be corrected from another mirror.
+    pub uncorrectable_read_errors: u64,
+}
+
+/// Get the status of the current or last device replace of the filesystem mounted at a path.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn replace_status<P>(path: P) -> Result<ReplaceStatus>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    replace_status_impl(path).context("fs::replace_status", &[path])
+}
+
+fn replace_status_impl(path: &Path) -> Result<ReplaceStatus> {
+    let file = ioctl::open(path)?;
+    let mut args = DevReplaceArgs::new(ioctl::BTRFS_IOCTL_DEV_REPLACE_CMD_STATUS);
+
+    unsafe {
+        ioctl::ioctl(
+            file.as_raw_fd(),
+            ioctl::BTRFS_IOC_DEV_REPLACE,
+            &mut args,
+            LibError::DevReplaceFailed,
+        )
+    }?;
+
+    let status = unsafe { args.params.status };
+    let state = match status.replace_state {
+        0 => ReplaceState::NeverStarted,
+        1 => ReplaceState::Started,
+        2 => ReplaceState::Finished,
+        3 => ReplaceState::Canceled,
+        4 => ReplaceState::Suspended,
+        _ => return Err(LibError::DevReplaceFailed.into()),
+    };
+    let timestamp = |secs: u64| -> Option<SystemTime> {
+        if secs == 0 {
+            None
+        } else {
+            UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+        }
+    };
+
+    Ok(ReplaceStatus {
+        state,
+        progress: status.progress_1000 as f64 / 1000.0,
+        started: timestamp(status.time_started),
+        stopped: timestamp(status.time_stopped),
+        write_errors: status.num_write_errors,
+        uncorrectable_read_errors: status.num_uncorrectable_read_errors,
+    })
+}
+
+/// Cancel the running device replace of the filesystem mounted at a path.
+///
+/// Returns false if no replace was running.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn cancel_replace<P>(path: P) -> Result<bool>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    cancel_replace_impl(path).context("fs::cancel_replace", &[path])
+}
+
+fn cancel_replace_impl(path: &Path) -> Result<bool> {
+    let file = ioctl::open(path)?;
+    let mut args = DevReplaceArgs::new(ioctl::BTRFS_IOCTL_DEV_REPLACE_CMD_CANCEL);
+
+    unsafe {
+        ioctl::ioctl(
+            file.as_raw_fd(),
+            ioctl::BTRFS_IOC_DEV_REPLACE,
+            &mut args,
+            LibError::DevReplaceFailed,
+        )
+    }?;
+
+    match args.result {
+        ioctl::BTRFS_IOCTL_DEV_REPLACE_RESULT_NO_ERROR => Ok(true),
+        ioctl::BTRFS_IOCTL_DEV_REPLACE_RESULT_NOT_STARTED => Ok(false),
+        _ => Err(LibError::DevReplaceFailed.into()),
+    }
+}
+
+/// New size of a filesystem device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ResizeSpec {
+    /// Grow to the full size of the underlying device.
+    Max,
+    /// Grow by a number of bytes.
+    Grow(u64),
+    /// Shrink by a number of bytes.
+    Shrink(u64),
+    /// Set to a number of bytes.
+    Set(u64),
+}
+
+impl ResizeSpec {
+    /// Format the spec the way `BTRFS_IOC_RESIZE` expects it: `[devid:]{max|[+-]bytes}`.
+    fn to_arg(self, devid: Option<u64>) -> String {
+        let size = match self {
+            ResizeSpec::Max => "max".to_owned(),
+            ResizeSpec::Grow(bytes) => format!("+{}", bytes),
+            ResizeSpec::Shrink(bytes) => format!("-{}", bytes),
+            ResizeSpec::Set(bytes) => bytes.to_string(),
+        };
+        match devid {
+            Some(devid) => format!("{}:{}", devid, size),
+            None => size,
+        }
+    }
+}
+
+/// Resize a device of the filesystem mounted at a path.
+///
+/// Without a device id, the device with id 1 is resized.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn resize<P, D>(path: P, spec: ResizeSpec, devid: D) -> Result<()>
+where
+    P: AsRef<Path>,
+    D: Into<Option<u64>>,
+{
+    let path = path.as_ref();
+    resize_impl(path, spec, devid.into()).context("fs::resize", &[path])
+}
+
+fn resize_impl(path: &Path, spec: ResizeSpec, devid: Option<u64>) -> Result<()> {
+    let file = ioctl::open(path)?;
+    let mut args = VolArgs::with_name(spec.to_arg(devid).as_bytes())?;
+
+    unsafe {
+        ioctl::ioctl(
+            file.as_raw_fd(),
+            ioctl::BTRFS_IOC_RESIZE,
+            &mut args,
+            LibError::ResizeFailed,
+        )
+    }
+}
+
+/// Range of the filesystem to discard unused space in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TrimRange {
+    /// Logical byte offset to start at.
+    pub start: u64,
+    /// Number of bytes to cover.
+    pub len: u64,
+    /// Skip free extents smaller than this many bytes.
+    pub min_len: u64,
+}
+
+impl TrimRange {
+    /// Create a range of `len` bytes starting at `start`.
+    pub fn new(start: u64, len: u64) -> Self {
+        Self {
+            start,
+            len,
+            min_len: 0,
+        }
+    }
+
+    /// Set the minimum length of the free extents to discard.
+    pub fn min_len(mut self, min_len: u64) -> Self {
+        self.min_len = min_len;
+        self
+    }
+}
+
+impl Default for TrimRange {
+    /// The whole filesystem.
+    fn default() -> Self {
+        Self::new(0, u64::MAX)
+    }
+}
+
+/// Discard unused space of the filesystem mounted at a path, returning the number of bytes
+/// trimmed.
+///
+/// The devices must support discard.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn trim<P>(path: P, range: TrimRange) -> Result<u64>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    trim_impl(path, range).context("fs::trim", &[path])
+}
+
+fn trim_impl(path: &Path, range: TrimRange) -> Result<u64> {
+    let file = ioctl::open(path)?;
+    let mut args = FstrimRange {
+        start: range.start,
+        len: range.len,
+        minlen: range.min_len,
+    };
+
+    unsafe {
+        ioctl::ioctl(
+            file.as_raw_fd(),
+            ioctl::FITRIM,
+            &mut args,
+            LibError::TrimFailed,
+        )?;
+    }
+
+    // the kernel reports the number of bytes trimmed in len
+    Ok(args.len)
+}
+
+/// A mounted btrfs filesystem, as listed by [list_mounted].
+///
+/// [list_mounted]: fn.list_mounted.html
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MountedBtrfs {
+    /// Where the filesystem is mounted.
+    pub mount_point: PathBuf,
+    /// The device the filesystem was mounted from, one of its devices when it has several.
+    pub device: PathBuf,
+    /// Id of the mounted subvolume, or `None` if the kernel does not report it.
+    pub subvol_id: Option<SubvolumeId>,
+    /// Path of the mounted subvolume relative to the top-level subvolume, starting with `/`.
+    pub subvol_path: PathBuf,
+    /// The fsid of the filesystem, or `None` if the mount point could not be opened.
+    pub fsid: Option<RawUuid>,
+}
+
+/// List the mounted btrfs filesystems, one entry per mount in the order they were mounted in.
+///
+/// A filesystem mounted several times, such as with different subvolumes, is listed once per
+/// mount, and its entries share the same fsid. Bind mounts of directories are listed with the
+/// subvolume the directory belongs to.
+pub fn list_mounted() -> Result<Vec<MountedBtrfs>> {
+    list_mounted_impl().context("fs::list_mounted", &[Path::new("/proc/self/mountinfo")])
+}
+
+fn list_mounted_impl() -> Result<Vec<MountedBtrfs>> {
+    Ok(common::btrfs_mounts()?
+        .into_iter()
+        .map(MountedBtrfs::from)
+        .collect())
+}
+
+impl From<common::Mount> for MountedBtrfs {
+    fn from(mount: common::Mount) -> Self {
+        let fsid = ioctl::open(&mount.mount_point)
+            .and_then(|file| ioctl::fs_info(file.as_raw_fd()))
+            .ok()
+            .map(|info| RawUuid(info.fsid));
+
+        MountedBtrfs {
+            mount_point: mount.mount_point,
+            device: mount.source,
+            subvol_id: mount.subvol_id,
+            // the root is a directory rather than the subvolume for bind mounts
+            subvol_path: mount.subvol.unwrap_or(mount.root),
+            fsid,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resize_spec_arg() {
+        assert_eq!(ResizeSpec::Max.to_arg(None), "max");
+        assert_eq!(ResizeSpec::Grow(4096).to_arg(None), "+4096");
+        assert_eq!(ResizeSpec::Shrink(1 << 30).to_arg(Some(2)), "2:-1073741824");
+        assert_eq!(ResizeSpec::Set(1 << 20).to_arg(Some(1)), "1:1048576");
+    }
+
+    #[test]
+    fn mount_options() {
+        let mount = common::parse_mountinfo(
+            "36 22 0:32 /@home/user /nonexistent/home\\040dir rw - btrfs /dev/sda2 \
+             rw,space_cache=v2,subvolid=257,subvol=/@home",
+        )
+        .pop()
+        .map(MountedBtrfs::from)
+        .unwrap();
+        assert_eq!(mount.mount_point, Path::new("/nonexistent/home dir"));
+        assert_eq!(mount.device, Path::new("/dev/sda2"));
+        assert_eq!(mount.subvol_id, Some(SubvolumeId::from_raw(257)));
+        assert_eq!(mount.subvol_path, Path::new("/@home"));
+        assert_eq!(mount.fsid, None);
+    }
+}
@@ -0,0 +1,173 @@
+//! File history across snapshots
+//!
+//! Looks a file up in a set of snapshots of the subvolume it lives in, describing the version
+//! each snapshot holds. This is what a "previous versions" view needs to list and tell apart
+//! the versions of a file.
+
+use crate::common;
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::ioctl;
+use crate::subvolume::Subvolume;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// The version of a file held by a snapshot.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileVersion {
+    /// Size in bytes.
+    pub size: u64,
+    /// Modification time.
+    pub mtime: SystemTime,
+    /// Generation the inode was created in.
+    ///
+    /// Versions with different generations are different files that happen to share a path.
+    pub generation: u64,
+    /// Hash of the contents, if requested.
+    pub hash: Option<u64>,
+}
+
+/// A snapshot and the version of a file it holds.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryEntry {
+    /// The snapshot.
+    pub snapshot: Subvolume,
+    /// Path of the file within the snapshot.
+    pub path: PathBuf,
+    /// The version of the file, or None if the snapshot does not have it.
+    pub version: Option<FileVersion>,
+}
+
+impl HistoryEntry {
+    /// Check whether the snapshot has the file.
+    #[inline]
+    pub fn exists(&self) -> bool {
+        self.version.is_some()
+    }
+}
+
+/// Describe the versions of a file held by snapshots of the subvolume it lives in.
+///
+/// Entries are returned in the order of the snapshots given.
+pub fn of<P>(path: P, snapshots: &[Subvolume]) -> Result<Vec<HistoryEntry>>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    of_impl(path, snapshots, None).context("history::of", &[path])
+}
+
+/// Same as [of], also hashing the contents of every version with a fresh `H`.
+///
+/// Hashes are only comparable within one call unless `H` is stable across processes.
+///
+/// [of]: fn.of.html
+pub fn of_hashed<P, H>(path: P, snapshots: &[Subvolume]) -> Result<Vec<HistoryEntry>>
+where
+    P: AsRef<Path>,
+    H: Hasher + Default,
+{
+    let path = path.as_ref();
+    of_impl(path, snapshots, Some(hash_file::<H>)).context("history::of_hashed", &[path])
+}
+
+fn of_impl(
+    path: &Path,
+    snapshots: &[Subvolume],
+    hash: Option<fn(&mut File) -> Result<u64>>,
+) -> Result<Vec<HistoryEntry>> {
+    let rel = subvolume_relative(path)?;
+
+    snapshots
+        .iter()
+        .map(|snapshot| {
+            let path = snapshot.path().join(&rel);
+            let version = version(&path, hash)?;
+            Ok(HistoryEntry {
+                snapshot: snapshot.clone(),
+                path,
+                version,
+            })
+        })
+        .collect()
+}
+
+/// Get the path of a file relative to the root of the subvolume it lives in.
+fn subvolume_relative(path: &Path) -> Result<PathBuf> {
+    let path = path
+        .canonicalize()
+        .map_err(|err| BtrfsUtilError::from_io(LibError::StatFailed, &err))?;
+
+    for root in path.ancestors() {
+        if Subvolume::is_subvolume(root).is_ok() {
+            // unwrapping here is safe since root is an ancestor of path
+            return Ok(path.strip_prefix(root).unwrap().to_path_buf());
+        }
+    }
+
+    Err(LibError::NotBtrfs.into())
+}
+
+fn version(path: &Path, hash: Option<fn(&mut File) -> Result<u64>>) -> Result<Option<FileVersion>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(BtrfsUtilError::from_io(LibError::OpenFailed, &err)),
+    };
+    let metadata = file
+        .metadata()
+        .map_err(|err| BtrfsUtilError::from_io(LibError::StatFailed, &err))?;
+
+    // btrfs writes an int, whatever the request number says
+    let mut generation: u32 = 0;
+    if unsafe {
+        libc::ioctl(
+            file.as_raw_fd(),
+            ioctl::FS_IOC_GETVERSION as _,
+            &mut generation,
+        )
+    } < 0
+    {
+        return Err(LibError::NotBtrfs.into());
+    }
+
+    let hash = match hash {
+        Some(hash) if metadata.is_file() => Some(hash(&mut file)?),
+        _ => None,
+    };
+
+    Ok(Some(FileVersion {
+        size: metadata.size(),
+        mtime: common::timespec_to_system_time(metadata.mtime(), metadata.mtime_nsec())
+            .ok_or(LibError::StatFailed)?,
+        generation: generation as u64,
+        hash,
+    }))
+}
+
+fn hash_file<H>(file: &mut File) -> Result<u64>
+where
+    H: Hasher + Default,
+{
+    let mut hasher = H::default();
+    let mut buf: Vec<u8> = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(read) => hasher.write(&buf[..read]),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(BtrfsUtilError::from_io(LibError::ReadFailed, &err)),
+        }
+    }
+    Ok(hasher.finish())
+}
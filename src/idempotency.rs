@@ -0,0 +1,149 @@
+//! Idempotency keys for operations that must survive a restart of the process issuing them.
+//!
+//! Once an operation produced something at a path, its key is recorded for that path. When the
+//! operation is retried with the same key, for example by a daemon restarted in the middle of it,
+//! the record is found and the work is not done twice.
+//!
+//! Keys are recorded as extended attributes of the directory containing the path, never on what
+//! the operation produced: a key stored in a snapshot would end up in its send streams, and
+//! storing one after the fact would change a read-only snapshot. Records stay behind when what
+//! they are for is deleted, until [remove]d or replaced by a new record for the same path.
+//!
+//! [Subvolume::create_idempotent], [Subvolume::snapshot_idempotent], [restore::files_idempotent]
+//! and [BackupPipeline::run_idempotent] use these keys; [get], [set] and [remove] let callers
+//! apply the same scheme to their own operations.
+//!
+//! [Subvolume::create_idempotent]: ../subvolume/struct.Subvolume.html#method.create_idempotent
+//! [Subvolume::snapshot_idempotent]: ../subvolume/struct.Subvolume.html#method.snapshot_idempotent
+//! [restore::files_idempotent]: ../restore/fn.files_idempotent.html
+//! [BackupPipeline::run_idempotent]: ../backup/struct.BackupPipeline.html#method.run_idempotent
+//! [get]: fn.get.html
+//! [set]: fn.set.html
+//! [remove]: fn.remove.html
+
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::xattr;
+pub use crate::Result;
+
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+use sha2::Digest;
+use sha2::Sha256;
+
+/// Prefix of the names of the extended attributes holding idempotency keys, followed by the
+/// SHA-256 of the file name the key is recorded for, in lowercase hex.
+pub const XATTR_PREFIX: &str = "user.btrfsutil.idempotency.";
+
+/// A caller-chosen key identifying one logical operation.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct IdempotencyKey(String);
+
+impl IdempotencyKey {
+    /// Create a key.
+    pub fn new<S>(key: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self(key.into())
+    }
+
+    /// Get the key as a string.
+    #[inline]
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for IdempotencyKey {
+    fn from(key: &str) -> Self {
+        Self::new(key)
+    }
+}
+
+impl From<String> for IdempotencyKey {
+    fn from(key: String) -> Self {
+        Self::new(key)
+    }
+}
+
+/// Get the idempotency key recorded for a path, if any.
+pub fn get<P>(path: P) -> Result<Option<IdempotencyKey>>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    get_impl(path).context("idempotency::get", &[path])
+}
+
+pub(crate) fn get_impl(path: &Path) -> Result<Option<IdempotencyKey>> {
+    let (dir, name) = record_location(path)?;
+    Ok(xattr::get_string(&dir, &name)?.map(IdempotencyKey))
+}
+
+/// Record an idempotency key for a path, replacing any previous one.
+pub fn set<P>(path: P, key: &IdempotencyKey) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    set_impl(path, key).context("idempotency::set", &[path])
+}
+
+pub(crate) fn set_impl(path: &Path, key: &IdempotencyKey) -> Result<()> {
+    let (dir, name) = record_location(path)?;
+    xattr::set(&dir, &name, key.0.as_bytes())
+}
+
+/// Remove the idempotency key recorded for a path. Removing one that is not recorded is not an
+/// error.
+pub fn remove<P>(path: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    remove_impl(path).context("idempotency::remove", &[path])
+}
+
+pub(crate) fn remove_impl(path: &Path) -> Result<()> {
+    let (dir, name) = record_location(path)?;
+    xattr::remove(&dir, &name)
+}
+
+/// Get the directory the key of a path is recorded on, and the name of the extended attribute.
+///
+/// File names are hashed since they can be as long as the longest extended attribute name.
+fn record_location(path: &Path) -> Result<(PathBuf, String)> {
+    let name = path.file_name().ok_or(LibError::InvalidArgument)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let digest = Sha256::digest(name.as_bytes());
+    let mut xattr_name = String::from(XATTR_PREFIX);
+    for byte in digest {
+        xattr_name.push_str(&format!("{:02x}", byte));
+    }
+    Ok((dir, xattr_name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records_on_the_directory() {
+        let (dir, name) = record_location(Path::new("/mnt/.snapshots/daily")).unwrap();
+        assert_eq!(dir, Path::new("/mnt/.snapshots"));
+        assert!(name.starts_with(XATTR_PREFIX));
+        assert_eq!(name.len(), XATTR_PREFIX.len() + 64);
+
+        let (dir, other) = record_location(Path::new("daily")).unwrap();
+        assert_eq!(dir, Path::new("."));
+        assert_eq!(other, name);
+        assert!(record_location(Path::new("/")).is_err());
+    }
+}
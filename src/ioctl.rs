@@ -0,0 +1,1280 @@
+//! Raw btrfs ioctl definitions for operations not covered by libbtrfsutil.
+//!
+//! Layouts mirror [linux/btrfs.h] and [linux/btrfs_tree.h].
+//!
+//! [linux/btrfs.h]: https://github.com/torvalds/linux/blob/master/include/uapi/linux/btrfs.h
+//! [linux/btrfs_tree.h]: https://github.com/torvalds/linux/blob/master/include/uapi/linux/btrfs_tree.h
+
+use crate::error::LibError;
+#[cfg(feature = "pure-rust")]
+use crate::sys::RawSubvolumeInfo;
+#[cfg(feature = "pure-rust")]
+use crate::sys::Timespec;
+use crate::BtrfsUtilError;
+use crate::Result;
+
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::path::PathBuf;
+
+use libc::c_ulong;
+
+pub(crate) const BTRFS_IOCTL_MAGIC: u32 = 0x94;
+
+const IOC_WRITE: u32 = 1;
+const IOC_READ: u32 = 2;
+
+/// Compute a btrfs ioctl request number the way the generic `_IOC` macro does.
+const fn ioc(dir: u32, nr: u32, size: usize) -> c_ulong {
+    ioc_type(dir, BTRFS_IOCTL_MAGIC, nr, size)
+}
+
+/// Compute an ioctl request number for any ioctl type, for the VFS ioctls btrfs implements.
+const fn ioc_type(dir: u32, ty: u32, nr: u32, size: usize) -> c_ulong {
+    ((dir << 30) | ((size as u32) << 16) | (ty << 8) | nr) as c_ulong
+}
+
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_ROOT_TREE_OBJECTID: u64 = 1;
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_FS_TREE_OBJECTID: u64 = 5;
+pub(crate) const BTRFS_CSUM_TREE_OBJECTID: u64 = 7;
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_ORPHAN_OBJECTID: u64 = -5i64 as u64;
+pub(crate) const BTRFS_EXTENT_CSUM_OBJECTID: u64 = -10i64 as u64;
+
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_ROOT_TREE_DIR_OBJECTID: u64 = 6;
+pub(crate) const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+
+pub(crate) const BTRFS_INODE_ITEM_KEY: u32 = 1;
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_ORPHAN_ITEM_KEY: u32 = 48;
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_DIR_ITEM_KEY: u32 = 84;
+pub(crate) const BTRFS_EXTENT_DATA_KEY: u32 = 108;
+pub(crate) const BTRFS_EXTENT_CSUM_KEY: u32 = 128;
+pub(crate) const BTRFS_ROOT_ITEM_KEY: u32 = 132;
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_ROOT_BACKREF_KEY: u32 = 144;
+pub(crate) const BTRFS_ROOT_REF_KEY: u32 = 156;
+pub(crate) const BTRFS_QGROUP_INFO_KEY: u32 = 242;
+pub(crate) const BTRFS_UUID_KEY_RECEIVED_SUBVOL: u32 = 252;
+
+pub(crate) const BTRFS_FILE_EXTENT_INLINE: u8 = 0;
+pub(crate) const BTRFS_FILE_EXTENT_REG: u8 = 1;
+pub(crate) const BTRFS_FILE_EXTENT_PREALLOC: u8 = 2;
+
+pub(crate) const BTRFS_CSUM_TYPE_CRC32: u16 = 0;
+
+pub(crate) const BTRFS_FS_INFO_FLAG_CSUM_INFO: u64 = 1 << 0;
+
+pub(crate) const BTRFS_ROOT_SUBVOL_RDONLY: u64 = 1 << 0;
+
+/// `struct btrfs_ioctl_search_key`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SearchKey {
+    pub(crate) tree_id: u64,
+    pub(crate) min_objectid: u64,
+    pub(crate) max_objectid: u64,
+    pub(crate) min_offset: u64,
+    pub(crate) max_offset: u64,
+    pub(crate) min_transid: u64,
+    pub(crate) max_transid: u64,
+    pub(crate) min_type: u32,
+    pub(crate) max_type: u32,
+    pub(crate) nr_items: u32,
+    unused: u32,
+    unused1: u64,
+    unused2: u64,
+    unused3: u64,
+    unused4: u64,
+}
+
+impl SearchKey {
+    /// Search key for the compound key range `min..=max` of (objectid, type, offset), across all
+    /// transids.
+    ///
+    /// A tree id of 0 searches the tree of the subvolume the file descriptor belongs to.
+    pub(crate) fn new(tree_id: u64, min: (u64, u32, u64), max: (u64, u32, u64)) -> Self {
+        Self {
+            tree_id,
+            min_objectid: min.0,
+            min_type: min.1,
+            min_offset: min.2,
+            max_objectid: max.0,
+            max_type: max.1,
+            max_offset: max.2,
+            max_transid: u64::MAX,
+            ..Default::default()
+        }
+    }
+}
+
+/// `struct btrfs_ioctl_search_header`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct SearchHeader {
+    pub(crate) transid: u64,
+    pub(crate) objectid: u64,
+    pub(crate) offset: u64,
+    pub(crate) item_type: u32,
+    pub(crate) len: u32,
+}
+
+const SEARCH_KEY_SIZE: usize = std::mem::size_of::<SearchKey>();
+const SEARCH_HEADER_SIZE: usize = std::mem::size_of::<SearchHeader>();
+
+/// `BTRFS_IOC_TREE_SEARCH_V2`, sized for `struct btrfs_ioctl_search_args_v2` without its
+/// flexible buffer.
+const BTRFS_IOC_TREE_SEARCH_V2: c_ulong = ioc(IOC_READ | IOC_WRITE, 17, SEARCH_KEY_SIZE + 8);
+
+/// Size of the result buffer handed to the kernel on each search call.
+const SEARCH_BUF_SIZE: usize = 64 * 1024;
+
+/// `struct btrfs_ioctl_fs_info_args`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct FsInfoArgs {
+    pub(crate) max_id: u64,
+    pub(crate) num_devices: u64,
+    pub(crate) fsid: [u8; 16],
+    pub(crate) nodesize: u32,
+    pub(crate) sectorsize: u32,
+    pub(crate) clone_alignment: u32,
+    pub(crate) csum_type: u16,
+    pub(crate) csum_size: u16,
+    pub(crate) flags: u64,
+    pub(crate) generation: u64,
+    pub(crate) metadata_uuid: [u8; 16],
+    reserved: [u8; 944],
+}
+
+const BTRFS_IOC_FS_INFO: c_ulong = ioc(IOC_READ, 31, std::mem::size_of::<FsInfoArgs>());
+
+const BTRFS_PATH_NAME_MAX: usize = 4087;
+const BTRFS_SUBVOL_NAME_MAX: usize = 4039;
+
+pub(crate) const BTRFS_DEVICE_SPEC_BY_ID: u64 = 1 << 3;
+pub(crate) const BTRFS_SUBVOL_SPEC_BY_ID: u64 = 1 << 4;
+
+/// `struct btrfs_ioctl_vol_args`
+#[repr(C)]
+pub(crate) struct VolArgs {
+    pub(crate) fd: i64,
+    pub(crate) name: [u8; BTRFS_PATH_NAME_MAX + 1],
+}
+
+impl VolArgs {
+    /// Arguments carrying a NUL-terminated name.
+    pub(crate) fn with_name(name: &[u8]) -> Result<Self> {
+        let mut args = Self {
+            fd: 0,
+            name: [0; BTRFS_PATH_NAME_MAX + 1],
+        };
+        copy_name(&mut args.name, name)?;
+        Ok(args)
+    }
+}
+
+/// `struct btrfs_ioctl_vol_args_v2`
+///
+/// The `size`/`qgroup_inherit` union is kept as raw words, and the `name`/`devid`/`subvolid`
+/// union as raw bytes.
+#[repr(C)]
+pub(crate) struct VolArgsV2 {
+    pub(crate) fd: i64,
+    pub(crate) transid: u64,
+    pub(crate) flags: u64,
+    pub(crate) unused: [u64; 4],
+    pub(crate) name: [u8; BTRFS_SUBVOL_NAME_MAX + 1],
+}
+
+impl VolArgsV2 {
+    /// Arguments carrying a NUL-terminated name.
+    pub(crate) fn with_name(name: &[u8]) -> Result<Self> {
+        let mut args = Self::zeroed();
+        copy_name(&mut args.name, name)?;
+        Ok(args)
+    }
+
+    /// Arguments carrying an id (devid or subvolid) in place of the name.
+    pub(crate) fn with_id(flags: u64, id: u64) -> Self {
+        let mut args = Self::zeroed();
+        args.flags = flags;
+        args.name[..8].copy_from_slice(&id.to_ne_bytes());
+        args
+    }
+
+    fn zeroed() -> Self {
+        Self {
+            fd: 0,
+            transid: 0,
+            flags: 0,
+            unused: [0; 4],
+            name: [0; BTRFS_SUBVOL_NAME_MAX + 1],
+        }
+    }
+}
+
+/// `struct btrfs_ioctl_timespec`
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub(crate) struct IoctlTimespec {
+    pub(crate) sec: u64,
+    pub(crate) nsec: u32,
+}
+
+/// `struct btrfs_ioctl_received_subvol_args`
+#[repr(C)]
+pub(crate) struct ReceivedSubvolArgs {
+    pub(crate) uuid: [u8; 16],
+    pub(crate) stransid: u64,
+    pub(crate) rtransid: u64,
+    pub(crate) stime: IoctlTimespec,
+    pub(crate) rtime: IoctlTimespec,
+    pub(crate) flags: u64,
+    reserved: [u64; 16],
+}
+
+impl ReceivedSubvolArgs {
+    pub(crate) fn new(uuid: [u8; 16], stransid: u64, stime: IoctlTimespec) -> Self {
+        Self {
+            uuid,
+            stransid,
+            rtransid: 0,
+            stime,
+            rtime: IoctlTimespec::default(),
+            flags: 0,
+            reserved: [0; 16],
+        }
+    }
+}
+
+/// `struct btrfs_ioctl_send_args`
+#[repr(C)]
+pub(crate) struct SendArgs {
+    pub(crate) send_fd: i64,
+    pub(crate) clone_sources_count: u64,
+    pub(crate) clone_sources: *mut u64,
+    pub(crate) parent_root: u64,
+    pub(crate) flags: u64,
+    pub(crate) version: u32,
+    reserved: [u8; 28],
+}
+
+impl SendArgs {
+    /// Arguments for a send into `send_fd`, incremental if `parent_root` is not zero.
+    ///
+    /// `clone_sources` must outlive the ioctl.
+    pub(crate) fn new(send_fd: RawFd, parent_root: u64, clone_sources: &mut [u64]) -> Self {
+        Self {
+            send_fd: send_fd as i64,
+            clone_sources_count: clone_sources.len() as u64,
+            clone_sources: clone_sources.as_mut_ptr(),
+            parent_root,
+            flags: 0,
+            version: 0,
+            reserved: [0; 28],
+        }
+    }
+}
+
+/// Copy a name into a fixed size buffer, leaving room for the NUL terminator.
+fn copy_name(buf: &mut [u8], name: &[u8]) -> Result<()> {
+    if name.len() >= buf.len() || name.contains(&0) {
+        return Err(LibError::InvalidArgument.into());
+    }
+    buf[..name.len()].copy_from_slice(name);
+    Ok(())
+}
+
+pub(crate) const BTRFS_IOC_RESIZE: c_ulong = ioc(IOC_WRITE, 3, std::mem::size_of::<VolArgs>());
+pub(crate) const BTRFS_IOC_ADD_DEV: c_ulong = ioc(IOC_WRITE, 10, std::mem::size_of::<VolArgs>());
+pub(crate) const BTRFS_IOC_RM_DEV_V2: c_ulong =
+    ioc(IOC_WRITE, 58, std::mem::size_of::<VolArgsV2>());
+pub(crate) const BTRFS_IOC_SNAP_DESTROY_V2: c_ulong =
+    ioc(IOC_WRITE, 63, std::mem::size_of::<VolArgsV2>());
+
+pub(crate) const BTRFS_IOC_SET_RECEIVED_SUBVOL: c_ulong = ioc(
+    IOC_READ | IOC_WRITE,
+    37,
+    std::mem::size_of::<ReceivedSubvolArgs>(),
+);
+pub(crate) const BTRFS_IOC_SEND: c_ulong = ioc(IOC_WRITE, 38, std::mem::size_of::<SendArgs>());
+
+const BTRFS_DEVICE_PATH_NAME_MAX: usize = 1024;
+
+pub(crate) const BTRFS_IOCTL_DEV_REPLACE_CMD_START: u64 = 0;
+pub(crate) const BTRFS_IOCTL_DEV_REPLACE_CMD_STATUS: u64 = 1;
+pub(crate) const BTRFS_IOCTL_DEV_REPLACE_CMD_CANCEL: u64 = 2;
+
+pub(crate) const BTRFS_IOCTL_DEV_REPLACE_RESULT_NO_ERROR: u64 = 0;
+pub(crate) const BTRFS_IOCTL_DEV_REPLACE_RESULT_NOT_STARTED: u64 = 1;
+
+/// `struct btrfs_ioctl_dev_replace_start_params`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct DevReplaceStartParams {
+    pub(crate) srcdevid: u64,
+    pub(crate) cont_reading_from_srcdev_mode: u64,
+    pub(crate) srcdev_name: [u8; BTRFS_DEVICE_PATH_NAME_MAX + 1],
+    pub(crate) tgtdev_name: [u8; BTRFS_DEVICE_PATH_NAME_MAX + 1],
+}
+
+/// `struct btrfs_ioctl_dev_replace_status_params`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct DevReplaceStatusParams {
+    pub(crate) replace_state: u64,
+    pub(crate) progress_1000: u64,
+    pub(crate) time_started: u64,
+    pub(crate) time_stopped: u64,
+    pub(crate) num_write_errors: u64,
+    pub(crate) num_uncorrectable_read_errors: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) union DevReplaceParams {
+    pub(crate) start: DevReplaceStartParams,
+    pub(crate) status: DevReplaceStatusParams,
+}
+
+/// `struct btrfs_ioctl_dev_replace_args`
+#[repr(C)]
+pub(crate) struct DevReplaceArgs {
+    pub(crate) cmd: u64,
+    pub(crate) result: u64,
+    pub(crate) params: DevReplaceParams,
+    spare: [u64; 64],
+}
+
+impl DevReplaceArgs {
+    /// Arguments for a command without parameters.
+    pub(crate) fn new(cmd: u64) -> Self {
+        let mut args: Self = unsafe { std::mem::zeroed() };
+        args.cmd = cmd;
+        args
+    }
+
+    /// Arguments for starting a replace of `srcdevid`, or of the `srcdev_name` device if the id
+    /// is 0, by `tgtdev_name`.
+    pub(crate) fn start(
+        srcdevid: u64,
+        srcdev_name: &[u8],
+        tgtdev_name: &[u8],
+        cont_reading_from_srcdev_mode: u64,
+    ) -> Result<Self> {
+        let mut args = Self::new(BTRFS_IOCTL_DEV_REPLACE_CMD_START);
+        let mut start = DevReplaceStartParams {
+            srcdevid,
+            cont_reading_from_srcdev_mode,
+            srcdev_name: [0; BTRFS_DEVICE_PATH_NAME_MAX + 1],
+            tgtdev_name: [0; BTRFS_DEVICE_PATH_NAME_MAX + 1],
+        };
+        copy_name(&mut start.srcdev_name, srcdev_name)?;
+        copy_name(&mut start.tgtdev_name, tgtdev_name)?;
+        args.params.start = start;
+        Ok(args)
+    }
+}
+
+pub(crate) const BTRFS_IOC_DEV_REPLACE: c_ulong = ioc(
+    IOC_READ | IOC_WRITE,
+    53,
+    std::mem::size_of::<DevReplaceArgs>(),
+);
+
+pub(crate) const BTRFS_BALANCE_DATA: u64 = 1 << 0;
+pub(crate) const BTRFS_BALANCE_SYSTEM: u64 = 1 << 1;
+pub(crate) const BTRFS_BALANCE_METADATA: u64 = 1 << 2;
+pub(crate) const BTRFS_BALANCE_FORCE: u64 = 1 << 3;
+pub(crate) const BTRFS_BALANCE_RESUME: u64 = 1 << 4;
+
+pub(crate) const BTRFS_BALANCE_ARGS_PROFILES: u64 = 1 << 0;
+pub(crate) const BTRFS_BALANCE_ARGS_DEVID: u64 = 1 << 2;
+pub(crate) const BTRFS_BALANCE_ARGS_DRANGE: u64 = 1 << 3;
+pub(crate) const BTRFS_BALANCE_ARGS_VRANGE: u64 = 1 << 4;
+pub(crate) const BTRFS_BALANCE_ARGS_LIMIT_RANGE: u64 = 1 << 6;
+pub(crate) const BTRFS_BALANCE_ARGS_STRIPES_RANGE: u64 = 1 << 7;
+pub(crate) const BTRFS_BALANCE_ARGS_CONVERT: u64 = 1 << 8;
+pub(crate) const BTRFS_BALANCE_ARGS_SOFT: u64 = 1 << 9;
+pub(crate) const BTRFS_BALANCE_ARGS_USAGE_RANGE: u64 = 1 << 10;
+
+pub(crate) const BTRFS_BALANCE_CTL_PAUSE: libc::c_int = 1;
+pub(crate) const BTRFS_BALANCE_CTL_CANCEL: libc::c_int = 2;
+
+/// `struct btrfs_balance_args`
+///
+/// The `usage` and `limit` unions are kept in their min/max form.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct BalanceArgs {
+    pub(crate) profiles: u64,
+    pub(crate) usage_min: u32,
+    pub(crate) usage_max: u32,
+    pub(crate) devid: u64,
+    pub(crate) pstart: u64,
+    pub(crate) pend: u64,
+    pub(crate) vstart: u64,
+    pub(crate) vend: u64,
+    pub(crate) target: u64,
+    pub(crate) flags: u64,
+    pub(crate) limit_min: u32,
+    pub(crate) limit_max: u32,
+    pub(crate) stripes_min: u32,
+    pub(crate) stripes_max: u32,
+    unused: [u64; 6],
+}
+
+/// `struct btrfs_balance_progress`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct BalanceProgress {
+    pub(crate) expected: u64,
+    pub(crate) considered: u64,
+    pub(crate) completed: u64,
+}
+
+/// `struct btrfs_ioctl_balance_args`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct IoctlBalanceArgs {
+    pub(crate) flags: u64,
+    pub(crate) state: u64,
+    pub(crate) data: BalanceArgs,
+    pub(crate) meta: BalanceArgs,
+    pub(crate) sys: BalanceArgs,
+    pub(crate) stat: BalanceProgress,
+    unused: [u64; 72],
+}
+
+impl IoctlBalanceArgs {
+    pub(crate) fn new(flags: u64) -> Self {
+        let mut args: Self = unsafe { std::mem::zeroed() };
+        args.flags = flags;
+        args
+    }
+}
+
+pub(crate) const BTRFS_IOC_BALANCE_V2: c_ulong = ioc(
+    IOC_READ | IOC_WRITE,
+    32,
+    std::mem::size_of::<IoctlBalanceArgs>(),
+);
+pub(crate) const BTRFS_IOC_BALANCE_CTL: c_ulong =
+    ioc(IOC_WRITE, 33, std::mem::size_of::<libc::c_int>());
+pub(crate) const BTRFS_IOC_BALANCE_PROGRESS: c_ulong =
+    ioc(IOC_READ, 34, std::mem::size_of::<IoctlBalanceArgs>());
+
+/// `struct btrfs_ioctl_dev_info_args`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct DevInfoArgs {
+    pub(crate) devid: u64,
+    pub(crate) uuid: [u8; 16],
+    pub(crate) bytes_used: u64,
+    pub(crate) total_bytes: u64,
+    unused: [u64; 379],
+    pub(crate) path: [u8; BTRFS_DEVICE_PATH_NAME_MAX],
+}
+
+const BTRFS_IOC_DEV_INFO: c_ulong =
+    ioc(IOC_READ | IOC_WRITE, 30, std::mem::size_of::<DevInfoArgs>());
+
+/// Get the ids of all devices of a filesystem.
+pub(crate) fn device_ids(fd: RawFd) -> Result<Vec<u64>> {
+    let max_id = fs_info(fd)?.max_id;
+    let mut ids = Vec::new();
+
+    // device ids may have holes left by removed devices
+    for devid in 1..=max_id {
+        if dev_info(fd, devid)?.is_some() {
+            ids.push(devid);
+        }
+    }
+
+    Ok(ids)
+}
+
+/// Query `BTRFS_IOC_DEV_INFO` for a device, or None if there is no device with that id.
+pub(crate) fn dev_info(fd: RawFd, devid: u64) -> Result<Option<DevInfoArgs>> {
+    let mut args: DevInfoArgs = unsafe { std::mem::zeroed() };
+    args.devid = devid;
+    match unsafe { ioctl_errno(fd, BTRFS_IOC_DEV_INFO, &mut args) } {
+        Ok(()) => Ok(Some(args)),
+        Err(libc::ENODEV) => Ok(None),
+        Err(errno) => Err(BtrfsUtilError::os(LibError::DevInfoFailed, errno)),
+    }
+}
+
+/// Number of error counters of `struct btrfs_ioctl_get_dev_stats`.
+pub(crate) const BTRFS_DEV_STAT_VALUES_MAX: usize = 5;
+
+/// `struct btrfs_ioctl_get_dev_stats`
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct GetDevStats {
+    pub(crate) devid: u64,
+    pub(crate) nr_items: u64,
+    pub(crate) flags: u64,
+    pub(crate) values: [u64; BTRFS_DEV_STAT_VALUES_MAX],
+    unused: [u64; 128 - 2 - BTRFS_DEV_STAT_VALUES_MAX],
+}
+
+const _: () = assert!(std::mem::size_of::<GetDevStats>() == 1032);
+
+const BTRFS_IOC_GET_DEV_STATS: c_ulong =
+    ioc(IOC_READ | IOC_WRITE, 52, std::mem::size_of::<GetDevStats>());
+
+/// Query `BTRFS_IOC_GET_DEV_STATS` for a device, without resetting the counters.
+pub(crate) fn dev_stats(fd: RawFd, devid: u64) -> Result<GetDevStats> {
+    let mut args: GetDevStats = unsafe { std::mem::zeroed() };
+    args.devid = devid;
+    args.nr_items = BTRFS_DEV_STAT_VALUES_MAX as u64;
+
+    unsafe {
+        ioctl(
+            fd,
+            BTRFS_IOC_GET_DEV_STATS,
+            &mut args,
+            LibError::DevInfoFailed,
+        )?
+    };
+
+    Ok(args)
+}
+
+pub(crate) const BTRFS_SCRUB_READONLY: u64 = 1;
+
+/// `struct btrfs_scrub_progress`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ScrubProgress {
+    pub(crate) data_extents_scrubbed: u64,
+    pub(crate) tree_extents_scrubbed: u64,
+    pub(crate) data_bytes_scrubbed: u64,
+    pub(crate) tree_bytes_scrubbed: u64,
+    pub(crate) read_errors: u64,
+    pub(crate) csum_errors: u64,
+    pub(crate) verify_errors: u64,
+    pub(crate) no_csum: u64,
+    pub(crate) csum_discards: u64,
+    pub(crate) super_errors: u64,
+    pub(crate) malloc_errors: u64,
+    pub(crate) uncorrectable_errors: u64,
+    pub(crate) corrected_errors: u64,
+    pub(crate) last_physical: u64,
+    pub(crate) unverified_errors: u64,
+}
+
+/// `struct btrfs_ioctl_scrub_args`
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct ScrubArgs {
+    pub(crate) devid: u64,
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+    pub(crate) flags: u64,
+    pub(crate) progress: ScrubProgress,
+    unused: [u64; 109],
+}
+
+impl ScrubArgs {
+    pub(crate) fn new(devid: u64, start: u64, flags: u64) -> Self {
+        let mut args: Self = unsafe { std::mem::zeroed() };
+        args.devid = devid;
+        args.start = start;
+        args.end = u64::MAX;
+        args.flags = flags;
+        args
+    }
+}
+
+pub(crate) const BTRFS_IOC_SCRUB: c_ulong =
+    ioc(IOC_READ | IOC_WRITE, 27, std::mem::size_of::<ScrubArgs>());
+pub(crate) const BTRFS_IOC_SCRUB_CANCEL: c_ulong = ioc(0, 28, 0);
+pub(crate) const BTRFS_IOC_SCRUB_PROGRESS: c_ulong =
+    ioc(IOC_READ | IOC_WRITE, 29, std::mem::size_of::<ScrubArgs>());
+
+/// `struct btrfs_data_container` without its flexible array of values.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct DataContainer {
+    pub(crate) bytes_left: u32,
+    pub(crate) bytes_missing: u32,
+    pub(crate) elem_cnt: u32,
+    pub(crate) elem_missed: u32,
+}
+
+pub(crate) const DATA_CONTAINER_SIZE: usize = std::mem::size_of::<DataContainer>();
+
+/// `struct btrfs_ioctl_ino_path_args`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct InoPathArgs {
+    pub(crate) inum: u64,
+    pub(crate) size: u64,
+    reserved: [u64; 4],
+    pub(crate) fspath: u64,
+}
+
+/// `struct btrfs_ioctl_logical_ino_args`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct LogicalInoArgs {
+    pub(crate) logical: u64,
+    pub(crate) size: u64,
+    reserved: [u64; 3],
+    pub(crate) flags: u64,
+    pub(crate) inodes: u64,
+}
+
+impl InoPathArgs {
+    pub(crate) fn new(inum: u64, buf: &mut [u64]) -> Self {
+        Self {
+            inum,
+            size: (buf.len() * 8) as u64,
+            fspath: buf.as_mut_ptr() as u64,
+            ..Default::default()
+        }
+    }
+}
+
+impl LogicalInoArgs {
+    pub(crate) fn new(logical: u64, flags: u64, buf: &mut [u64]) -> Self {
+        Self {
+            logical,
+            size: (buf.len() * 8) as u64,
+            flags,
+            inodes: buf.as_mut_ptr() as u64,
+            ..Default::default()
+        }
+    }
+}
+
+pub(crate) const BTRFS_LOGICAL_INO_ARGS_IGNORE_OFFSET: u64 = 1 << 0;
+
+pub(crate) const BTRFS_IOC_INO_PATHS: c_ulong =
+    ioc(IOC_READ | IOC_WRITE, 35, std::mem::size_of::<InoPathArgs>());
+pub(crate) const BTRFS_IOC_LOGICAL_INO_V2: c_ulong = ioc(
+    IOC_READ | IOC_WRITE,
+    59,
+    std::mem::size_of::<LogicalInoArgs>(),
+);
+
+/// `BTRFS_VOL_NAME_MAX`
+const BTRFS_VOL_NAME_MAX: usize = 255;
+/// `BTRFS_INO_LOOKUP_USER_PATH_MAX`
+const BTRFS_INO_LOOKUP_USER_PATH_MAX: usize = 4080 - BTRFS_VOL_NAME_MAX - 1;
+
+/// An entry of `struct btrfs_ioctl_get_subvol_rootref_args`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Rootref {
+    pub(crate) treeid: u64,
+    pub(crate) dirid: u64,
+}
+
+/// `struct btrfs_ioctl_get_subvol_rootref_args`
+#[repr(C)]
+struct GetSubvolRootrefArgs {
+    min_treeid: u64,
+    rootref: [Rootref; 255],
+    num_items: u8,
+    align: [u8; 7],
+}
+
+/// `struct btrfs_ioctl_ino_lookup_user_args`
+#[repr(C)]
+struct InoLookupUserArgs {
+    dirid: u64,
+    treeid: u64,
+    name: [u8; BTRFS_VOL_NAME_MAX + 1],
+    path: [u8; BTRFS_INO_LOOKUP_USER_PATH_MAX],
+}
+
+const BTRFS_IOC_GET_SUBVOL_ROOTREF: c_ulong = ioc(
+    IOC_READ | IOC_WRITE,
+    61,
+    std::mem::size_of::<GetSubvolRootrefArgs>(),
+);
+const BTRFS_IOC_INO_LOOKUP_USER: c_ulong = ioc(
+    IOC_READ | IOC_WRITE,
+    62,
+    std::mem::size_of::<InoLookupUserArgs>(),
+);
+
+/// `BTRFS_INO_LOOKUP_PATH_MAX`
+#[cfg(feature = "pure-rust")]
+const BTRFS_INO_LOOKUP_PATH_MAX: usize = 4080;
+
+/// `struct btrfs_ioctl_ino_lookup_args`
+#[cfg(feature = "pure-rust")]
+#[repr(C)]
+pub(crate) struct InoLookupArgs {
+    pub(crate) treeid: u64,
+    pub(crate) objectid: u64,
+    name: [u8; BTRFS_INO_LOOKUP_PATH_MAX],
+}
+
+#[cfg(feature = "pure-rust")]
+impl InoLookupArgs {
+    pub(crate) fn new(treeid: u64, objectid: u64) -> Self {
+        Self {
+            treeid,
+            objectid,
+            name: [0; BTRFS_INO_LOOKUP_PATH_MAX],
+        }
+    }
+
+    /// The path of the inode filled in by the kernel, ending with a slash unless it is empty.
+    pub(crate) fn name(&self) -> &[u8] {
+        let len = self
+            .name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(self.name.len());
+        &self.name[..len]
+    }
+}
+
+/// `struct btrfs_ioctl_get_subvol_info_args`
+#[cfg(feature = "pure-rust")]
+#[repr(C)]
+pub(crate) struct GetSubvolInfoArgs {
+    pub(crate) treeid: u64,
+    name: [u8; BTRFS_VOL_NAME_MAX + 1],
+    pub(crate) parent_id: u64,
+    pub(crate) dirid: u64,
+    pub(crate) generation: u64,
+    pub(crate) flags: u64,
+    pub(crate) uuid: [u8; 16],
+    pub(crate) parent_uuid: [u8; 16],
+    pub(crate) received_uuid: [u8; 16],
+    pub(crate) ctransid: u64,
+    pub(crate) otransid: u64,
+    pub(crate) stransid: u64,
+    pub(crate) rtransid: u64,
+    pub(crate) ctime: IoctlTimespec,
+    pub(crate) otime: IoctlTimespec,
+    pub(crate) stime: IoctlTimespec,
+    pub(crate) rtime: IoctlTimespec,
+    reserved: [u64; 8],
+}
+
+/// `BTRFS_SUBVOL_RDONLY`, the subvolume flag of `BTRFS_IOC_SUBVOL_GETFLAGS` and
+/// `BTRFS_IOC_SNAP_CREATE_V2`.
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_SUBVOL_RDONLY: u64 = 1 << 1;
+/// `BTRFS_SUBVOL_QGROUP_INHERIT`, telling `BTRFS_IOC_SUBVOL_CREATE_V2` and
+/// `BTRFS_IOC_SNAP_CREATE_V2` that a `struct btrfs_qgroup_inherit` is passed along.
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_SUBVOL_QGROUP_INHERIT: u64 = 1 << 2;
+
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_IOC_SNAP_DESTROY: c_ulong =
+    ioc(IOC_WRITE, 15, std::mem::size_of::<VolArgs>());
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_IOC_INO_LOOKUP: c_ulong = ioc(
+    IOC_READ | IOC_WRITE,
+    18,
+    std::mem::size_of::<InoLookupArgs>(),
+);
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_IOC_SYNC: c_ulong = ioc(0, 8, 0);
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_IOC_DEFAULT_SUBVOL: c_ulong = ioc(IOC_WRITE, 19, 8);
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_IOC_WAIT_SYNC: c_ulong = ioc(IOC_WRITE, 22, 8);
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_IOC_SNAP_CREATE_V2: c_ulong =
+    ioc(IOC_WRITE, 23, std::mem::size_of::<VolArgsV2>());
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_IOC_START_SYNC: c_ulong = ioc(IOC_READ, 24, 8);
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_IOC_SUBVOL_CREATE_V2: c_ulong =
+    ioc(IOC_WRITE, 24, std::mem::size_of::<VolArgsV2>());
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_IOC_SUBVOL_GETFLAGS: c_ulong = ioc(IOC_READ, 25, 8);
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_IOC_SUBVOL_SETFLAGS: c_ulong = ioc(IOC_WRITE, 26, 8);
+#[cfg(feature = "pure-rust")]
+pub(crate) const BTRFS_IOC_GET_SUBVOL_INFO: c_ulong =
+    ioc(IOC_READ, 60, std::mem::size_of::<GetSubvolInfoArgs>());
+
+/// `FICLONE`, the generic reflink ioctl that originated as `BTRFS_IOC_CLONE`.
+pub(crate) const FICLONE: c_ulong = ioc(IOC_WRITE, 9, std::mem::size_of::<libc::c_int>());
+
+/// `struct file_clone_range`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct FileCloneRange {
+    pub(crate) src_fd: i64,
+    pub(crate) src_offset: u64,
+    pub(crate) src_length: u64,
+    pub(crate) dest_offset: u64,
+}
+
+/// `FICLONERANGE`, which originated as `BTRFS_IOC_CLONE_RANGE`.
+pub(crate) const FICLONERANGE: c_ulong = ioc(IOC_WRITE, 13, std::mem::size_of::<FileCloneRange>());
+
+/// `FS_IOC_GETVERSION`, which btrfs answers with the generation an inode was created in.
+pub(crate) const FS_IOC_GETVERSION: c_ulong = ioc_type(
+    IOC_READ,
+    b'v' as u32,
+    1,
+    std::mem::size_of::<libc::c_long>(),
+);
+
+/// `struct btrfs_ioctl_defrag_range_args`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct DefragRangeArgs {
+    pub(crate) start: u64,
+    pub(crate) len: u64,
+    pub(crate) flags: u64,
+    pub(crate) extent_thresh: u32,
+    pub(crate) compress_type: u32,
+    unused: [u32; 4],
+}
+
+/// `BTRFS_DEFRAG_RANGE_COMPRESS`, compressing the rewritten extents with `compress_type`.
+pub(crate) const BTRFS_DEFRAG_RANGE_COMPRESS: u64 = 1 << 0;
+/// `BTRFS_DEFRAG_RANGE_START_IO`, writing the rewritten extents out before returning.
+pub(crate) const BTRFS_DEFRAG_RANGE_START_IO: u64 = 1 << 1;
+
+pub(crate) const BTRFS_IOC_DEFRAG_RANGE: c_ulong =
+    ioc(IOC_WRITE, 16, std::mem::size_of::<DefragRangeArgs>());
+
+/// `struct fstrim_range`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct FstrimRange {
+    pub(crate) start: u64,
+    pub(crate) len: u64,
+    pub(crate) minlen: u64,
+}
+
+pub(crate) const FITRIM: c_ulong = ioc_type(
+    IOC_READ | IOC_WRITE,
+    b'X' as u32,
+    121,
+    std::mem::size_of::<FstrimRange>(),
+);
+
+/// `struct fiemap`, without its trailing extents.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+}
+
+/// `struct fiemap_extent`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct FiemapExtent {
+    pub(crate) logical: u64,
+    pub(crate) physical: u64,
+    pub(crate) length: u64,
+    reserved64: [u64; 2],
+    pub(crate) flags: u32,
+    reserved: [u32; 3],
+}
+
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+pub(crate) const FIEMAP_EXTENT_LAST: u32 = 0x1;
+pub(crate) const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+pub(crate) const FIEMAP_EXTENT_DELALLOC: u32 = 0x4;
+pub(crate) const FIEMAP_EXTENT_ENCODED: u32 = 0x8;
+pub(crate) const FIEMAP_EXTENT_DATA_ENCRYPTED: u32 = 0x80;
+pub(crate) const FIEMAP_EXTENT_NOT_ALIGNED: u32 = 0x100;
+pub(crate) const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x200;
+pub(crate) const FIEMAP_EXTENT_DATA_TAIL: u32 = 0x400;
+pub(crate) const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x800;
+pub(crate) const FIEMAP_EXTENT_MERGED: u32 = 0x1000;
+pub(crate) const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+
+/// Number of extents asked for per `FS_IOC_FIEMAP` call.
+const FIEMAP_BATCH: usize = 256;
+
+const FS_IOC_FIEMAP: c_ulong = ioc_type(
+    IOC_READ | IOC_WRITE,
+    b'f' as u32,
+    11,
+    std::mem::size_of::<Fiemap>(),
+);
+
+/// `struct file_dedupe_range`, without its trailing infos.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct FileDedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+}
+
+/// `struct file_dedupe_range_info`
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct FileDedupeRangeInfo {
+    dest_fd: i64,
+    dest_offset: u64,
+    pub(crate) bytes_deduped: u64,
+    pub(crate) status: i32,
+    reserved: u32,
+}
+
+impl FileDedupeRange {
+    pub(crate) fn new(src_offset: u64, src_length: u64, dest_count: u16) -> Self {
+        Self {
+            src_offset,
+            src_length,
+            dest_count,
+            ..Default::default()
+        }
+    }
+}
+
+impl FileDedupeRangeInfo {
+    pub(crate) fn new(dest_fd: RawFd, dest_offset: u64) -> Self {
+        Self {
+            dest_fd: dest_fd as i64,
+            dest_offset,
+            ..Default::default()
+        }
+    }
+}
+
+pub(crate) const FILE_DEDUPE_RANGE_SAME: i32 = 0;
+pub(crate) const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+
+pub(crate) const FIDEDUPERANGE: c_ulong = ioc(
+    IOC_READ | IOC_WRITE,
+    54,
+    std::mem::size_of::<FileDedupeRange>(),
+);
+
+/// `FSLABEL_MAX`, the size of the label buffer including its terminating nul.
+pub(crate) const FSLABEL_MAX: usize = 256;
+
+pub(crate) const FS_IOC_GETFSLABEL: c_ulong = ioc(IOC_READ, 49, FSLABEL_MAX);
+pub(crate) const FS_IOC_SETFSLABEL: c_ulong = ioc(IOC_WRITE, 50, FSLABEL_MAX);
+
+/// Open a file or directory on a btrfs filesystem to issue ioctls on.
+pub(crate) fn open(path: &Path) -> Result<File> {
+    File::open(path).map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))
+}
+
+/// Get the bytes of a path, for passing it as a name to an ioctl.
+#[inline]
+pub(crate) fn path_bytes(path: &Path) -> &[u8] {
+    path.as_os_str().as_bytes()
+}
+
+/// Issue an ioctl, mapping any failure to `err`.
+///
+/// # Safety
+///
+/// `arg` must point to the argument structure `request` expects.
+pub(crate) unsafe fn ioctl<T>(
+    fd: RawFd,
+    request: c_ulong,
+    arg: *mut T,
+    err: LibError,
+) -> Result<()> {
+    ioctl_errno(fd, request, arg).map_err(|errno| BtrfsUtilError::os(err, errno))
+}
+
+/// Issue an ioctl, returning the errno it failed with.
+///
+/// Some btrfs ioctls report failures as positive `BTRFS_ERROR_DEV_*` codes, so anything but zero
+/// is a failure; those are returned as is.
+///
+/// # Safety
+///
+/// `arg` must point to the argument structure `request` expects.
+pub(crate) unsafe fn ioctl_errno<T>(
+    fd: RawFd,
+    request: c_ulong,
+    arg: *mut T,
+) -> std::result::Result<(), i32> {
+    match libc::ioctl(fd, request as _, arg) {
+        0 => Ok(()),
+        ret if ret > 0 => Err(ret),
+        _ => Err(std::io::Error::last_os_error()
+            .raw_os_error()
+            .unwrap_or(libc::EIO)),
+    }
+}
+
+/// Query `BTRFS_IOC_FS_INFO`, asking for checksum information.
+pub(crate) fn fs_info(fd: RawFd) -> Result<FsInfoArgs> {
+    let mut args: FsInfoArgs = unsafe { std::mem::zeroed() };
+    args.flags = BTRFS_FS_INFO_FLAG_CSUM_INFO;
+
+    if unsafe { libc::ioctl(fd, BTRFS_IOC_FS_INFO as _, &mut args) } < 0 {
+        return Err(BtrfsUtilError::last_os_error(LibError::FsInfoFailed));
+    }
+
+    // kernels older than 5.5 ignore the flag and only know about crc32c
+    if args.flags & BTRFS_FS_INFO_FLAG_CSUM_INFO == 0 {
+        args.csum_type = BTRFS_CSUM_TYPE_CRC32;
+        args.csum_size = 4;
+    }
+
+    Ok(args)
+}
+
+/// List the subvolumes directly below the subvolume `fd` belongs to with
+/// `BTRFS_IOC_GET_SUBVOL_ROOTREF`, which does not require any privileges.
+pub(crate) fn subvol_rootrefs(fd: RawFd) -> Result<Vec<Rootref>> {
+    let mut args: GetSubvolRootrefArgs = unsafe { std::mem::zeroed() };
+    let mut rootrefs: Vec<Rootref> = Vec::new();
+    loop {
+        let ret = unsafe { ioctl_errno(fd, BTRFS_IOC_GET_SUBVOL_ROOTREF, &mut args) };
+        // EOVERFLOW means the batch is full and more are left, starting at min_treeid
+        match ret {
+            Ok(()) | Err(libc::EOVERFLOW) => {}
+            Err(errno) => return Err(BtrfsUtilError::os(LibError::GetSubvolRootrefFailed, errno)),
+        }
+        rootrefs.extend_from_slice(&args.rootref[..usize::from(args.num_items)]);
+        if ret.is_ok() {
+            return Ok(rootrefs);
+        }
+    }
+}
+
+/// Get the path of the subvolume `treeid`, whose root is in the directory `dirid`, relative to
+/// `fd` with `BTRFS_IOC_INO_LOOKUP_USER`, which does not require any privileges.
+///
+/// `fd` has to be in the subvolume containing `dirid`, above it, and every directory in between
+/// has to be readable and searchable.
+pub(crate) fn ino_lookup_user(fd: RawFd, treeid: u64, dirid: u64) -> Result<PathBuf> {
+    let mut args: InoLookupUserArgs = unsafe { std::mem::zeroed() };
+    args.treeid = treeid;
+    args.dirid = dirid;
+    unsafe {
+        ioctl(
+            fd,
+            BTRFS_IOC_INO_LOOKUP_USER,
+            &mut args,
+            LibError::InoLookupUserFailed,
+        )?
+    };
+
+    let until_nul = |buf: &[u8]| -> PathBuf {
+        let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+        PathBuf::from(OsStr::from_bytes(&buf[..len]))
+    };
+    Ok(until_nul(&args.path).join(until_nul(&args.name)))
+}
+
+/// Get the id of the subvolume `fd` belongs to with `BTRFS_IOC_INO_LOOKUP`, which does not
+/// require any privileges for this lookup.
+#[cfg(feature = "pure-rust")]
+pub(crate) fn subvolume_id(fd: RawFd) -> Result<u64> {
+    let mut args = InoLookupArgs::new(0, BTRFS_FIRST_FREE_OBJECTID);
+    unsafe {
+        ioctl(
+            fd,
+            BTRFS_IOC_INO_LOOKUP,
+            &mut args,
+            LibError::InoLookupFailed,
+        )?
+    };
+    Ok(args.treeid)
+}
+
+/// Get the flags of the subvolume `fd` belongs to with `BTRFS_IOC_SUBVOL_GETFLAGS`.
+#[cfg(feature = "pure-rust")]
+pub(crate) fn subvolume_flags(fd: RawFd) -> Result<u64> {
+    let mut flags: u64 = 0;
+    unsafe {
+        ioctl(
+            fd,
+            BTRFS_IOC_SUBVOL_GETFLAGS,
+            &mut flags,
+            LibError::SubvolGetflagsFailed,
+        )?
+    };
+    Ok(flags)
+}
+
+/// Get information about the subvolume `fd` belongs to with `BTRFS_IOC_GET_SUBVOL_INFO`, which
+/// does not require any privileges.
+#[cfg(feature = "pure-rust")]
+pub(crate) fn subvolume_info(fd: RawFd) -> Result<RawSubvolumeInfo> {
+    let mut args: GetSubvolInfoArgs = unsafe { std::mem::zeroed() };
+    unsafe {
+        ioctl(
+            fd,
+            BTRFS_IOC_GET_SUBVOL_INFO,
+            &mut args,
+            LibError::GetSubvolInfoFailed,
+        )?
+    };
+
+    let to_timespec = |ts: &IoctlTimespec| Timespec {
+        sec: ts.sec as i64,
+        nsec: i64::from(ts.nsec),
+    };
+    Ok(RawSubvolumeInfo {
+        id: args.treeid,
+        parent_id: args.parent_id,
+        dir_id: args.dirid,
+        flags: args.flags,
+        uuid: args.uuid,
+        parent_uuid: args.parent_uuid,
+        received_uuid: args.received_uuid,
+        generation: args.generation,
+        ctransid: args.ctransid,
+        otransid: args.otransid,
+        stransid: args.stransid,
+        rtransid: args.rtransid,
+        ctime: to_timespec(&args.ctime),
+        otime: to_timespec(&args.otime),
+        stime: to_timespec(&args.stime),
+        rtime: to_timespec(&args.rtime),
+    })
+}
+
+/// Map the extents of a file with `FS_IOC_FIEMAP`, after flushing its dirty data.
+pub(crate) fn fiemap(fd: RawFd) -> Result<Vec<FiemapExtent>> {
+    let words =
+        (std::mem::size_of::<Fiemap>() + FIEMAP_BATCH * std::mem::size_of::<FiemapExtent>()) / 8;
+    // u64 words keep the buffer aligned for the header and the extents
+    let mut buf: Vec<u64> = vec![0; words];
+    let mut extents: Vec<FiemapExtent> = Vec::new();
+    let mut start: u64 = 0;
+
+    loop {
+        let header = Fiemap {
+            start,
+            length: u64::MAX - start,
+            flags: FIEMAP_FLAG_SYNC,
+            extent_count: FIEMAP_BATCH as u32,
+            ..Default::default()
+        };
+        unsafe { std::ptr::write(buf.as_mut_ptr() as *mut Fiemap, header) };
+
+        if unsafe { libc::ioctl(fd, FS_IOC_FIEMAP as _, buf.as_mut_ptr()) } < 0 {
+            return Err(BtrfsUtilError::last_os_error(LibError::FiemapFailed));
+        }
+
+        let mapped = unsafe { std::ptr::read(buf.as_ptr() as *const Fiemap) }.mapped_extents;
+        let batch: &[FiemapExtent] = unsafe {
+            std::slice::from_raw_parts(
+                (buf.as_ptr() as *const u8).add(std::mem::size_of::<Fiemap>())
+                    as *const FiemapExtent,
+                (mapped as usize).min(FIEMAP_BATCH),
+            )
+        };
+        extents.extend_from_slice(batch);
+
+        match batch.last() {
+            Some(last) if last.flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = last.logical + last.length;
+            }
+            _ => return Ok(extents),
+        }
+    }
+}
+
+/// Run `BTRFS_IOC_TREE_SEARCH_V2` over the whole key range, calling `f` for every item found.
+///
+/// The callback returns `false` to stop the search early.
+pub(crate) fn tree_search<F>(fd: RawFd, mut key: SearchKey, mut f: F) -> Result<()>
+where
+    F: FnMut(&SearchHeader, &[u8]) -> bool,
+{
+    // u64 words keep the buffer aligned for the key and the item headers
+    let mut buf: Vec<u64> = vec![0; (SEARCH_KEY_SIZE + 8 + SEARCH_BUF_SIZE) / 8];
+
+    loop {
+        key.nr_items = u32::MAX;
+        unsafe {
+            std::ptr::write(buf.as_mut_ptr() as *mut SearchKey, key);
+            std::ptr::write(
+                (buf.as_mut_ptr() as *mut u8).add(SEARCH_KEY_SIZE) as *mut u64,
+                SEARCH_BUF_SIZE as u64,
+            );
+        }
+
+        if unsafe { libc::ioctl(fd, BTRFS_IOC_TREE_SEARCH_V2 as _, buf.as_mut_ptr()) } < 0 {
+            return Err(BtrfsUtilError::last_os_error(LibError::SearchFailed));
+        }
+
+        let nr_items = unsafe { std::ptr::read(buf.as_ptr() as *const SearchKey) }.nr_items;
+        if nr_items == 0 {
+            return Ok(());
+        }
+
+        let data: &[u8] = unsafe {
+            std::slice::from_raw_parts(
+                (buf.as_ptr() as *const u8).add(SEARCH_KEY_SIZE + 8),
+                SEARCH_BUF_SIZE,
+            )
+        };
+
+        let mut pos = 0;
+        let mut last = SearchHeader::default();
+        for _ in 0..nr_items {
+            if pos + SEARCH_HEADER_SIZE > data.len() {
+                return Err(LibError::SearchFailed.into());
+            }
+            let header: SearchHeader =
+                unsafe { std::ptr::read_unaligned(data[pos..].as_ptr() as *const SearchHeader) };
+            pos += SEARCH_HEADER_SIZE;
+            let item = data
+                .get(pos..pos + header.len as usize)
+                .ok_or(LibError::SearchFailed)?;
+            pos += header.len as usize;
+
+            if !f(&header, item) {
+                return Ok(());
+            }
+            last = header;
+        }
+
+        // advance past the last item returned, carrying into type and objectid
+        key.min_objectid = last.objectid;
+        key.min_type = last.item_type;
+        key.min_offset = last.offset;
+        if key.min_offset < u64::MAX {
+            key.min_offset += 1;
+        } else if key.min_type < u8::MAX as u32 {
+            key.min_offset = 0;
+            key.min_type += 1;
+        } else if key.min_objectid < u64::MAX {
+            key.min_offset = 0;
+            key.min_type = 0;
+            key.min_objectid += 1;
+        } else {
+            return Ok(());
+        }
+
+        if key.min_objectid > key.max_objectid {
+            return Ok(());
+        }
+    }
+}
+
+/// Read a little-endian u64 out of an on-disk item.
+#[inline]
+pub(crate) fn le_u64(item: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(item[offset..offset + 8].try_into().unwrap())
+}
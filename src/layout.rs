@@ -0,0 +1,198 @@
+//! Subvolume layouts
+//!
+//! A [Layout] declares the subvolumes of a root filesystem, by their paths relative to the
+//! top-level subvolume and where they are meant to be mounted, and which of them to make the
+//! default subvolume. [Layout::flat] and [Layout::nested] are the two common layouts around the
+//! `@` naming convention; applying a layout to a fresh filesystem creates its subvolumes.
+//!
+//! [Layout]: struct.Layout.html
+//! [Layout::flat]: struct.Layout.html#method.flat
+//! [Layout::nested]: struct.Layout.html#method.nested
+
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::subvolume::Subvolume;
+use crate::subvolume::SubvolumeId;
+pub use crate::Result;
+
+use std::collections::HashSet;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// A subvolume of a [Layout].
+///
+/// [Layout]: struct.Layout.html
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LayoutSubvolume {
+    /// Path of the subvolume, relative to the top-level subvolume.
+    pub path: PathBuf,
+    /// Where the subvolume is meant to be mounted, for generating mount units or an fstab.
+    pub mount_point: Option<PathBuf>,
+}
+
+/// The subvolumes of a root filesystem.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Layout {
+    subvolumes: Vec<LayoutSubvolume>,
+    default: Option<PathBuf>,
+}
+
+impl Layout {
+    /// Create a layout without subvolumes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The flat layout: `@`, `@home` and `@snapshots` next to each other in the top-level
+    /// subvolume, mounted at `/`, `/home` and `/.snapshots`.
+    ///
+    /// Snapshots of `@` leave the other subvolumes out, and each one can be rolled back on its
+    /// own.
+    pub fn flat() -> Self {
+        Self::new()
+            .mounted_subvolume("@", "/")
+            .mounted_subvolume("@home", "/home")
+            .mounted_subvolume("@snapshots", "/.snapshots")
+    }
+
+    /// The nested layout: `@` in the top-level subvolume, mounted at `/`, with `home` and
+    /// `.snapshots` nested in it.
+    ///
+    /// Only `@` needs to be mounted; snapshots of it leave the nested subvolumes out.
+    pub fn nested() -> Self {
+        Self::new()
+            .mounted_subvolume("@", "/")
+            .subvolume("@/home")
+            .subvolume("@/.snapshots")
+    }
+
+    /// Add a subvolume at a path relative to the top-level subvolume.
+    pub fn subvolume<P>(mut self, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.subvolumes.push(LayoutSubvolume {
+            path: path.into(),
+            mount_point: None,
+        });
+        self
+    }
+
+    /// Add a subvolume at a path relative to the top-level subvolume, meant to be mounted at
+    /// `mount_point`.
+    pub fn mounted_subvolume<P, M>(mut self, path: P, mount_point: M) -> Self
+    where
+        P: Into<PathBuf>,
+        M: Into<PathBuf>,
+    {
+        self.subvolumes.push(LayoutSubvolume {
+            path: path.into(),
+            mount_point: Some(mount_point.into()),
+        });
+        self
+    }
+
+    /// Make the subvolume at a path relative to the top-level subvolume the default subvolume.
+    pub fn default_subvolume<P>(mut self, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.default = Some(path.into());
+        self
+    }
+
+    /// Get the subvolumes of the layout, in the order they were added.
+    #[inline]
+    pub fn subvolumes(&self) -> &[LayoutSubvolume] {
+        &self.subvolumes
+    }
+
+    /// Get the path of the subvolume made the default subvolume, if any.
+    #[inline]
+    pub fn default_path(&self) -> Option<&Path> {
+        self.default.as_deref()
+    }
+
+    /// Check that the paths are relative and stay inside the top-level subvolume, that no
+    /// subvolume is declared twice, and that the default subvolume is one of the subvolumes.
+    pub fn validate(&self) -> Result<()> {
+        let mut seen = HashSet::new();
+        for subvolume in &self.subvolumes {
+            let inside = !subvolume.path.as_os_str().is_empty()
+                && subvolume
+                    .path
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)));
+            if !inside || !seen.insert(subvolume.path.as_path()) {
+                return Err(LibError::InvalidArgument.into());
+            }
+        }
+        match &self.default {
+            Some(default) if !seen.contains(default.as_path()) => {
+                Err(LibError::InvalidArgument.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Create the subvolumes of the layout below the top-level subvolume mounted at `fs_root`,
+    /// and set the default subvolume if the layout has one.
+    ///
+    /// Subvolumes are created parents first, along with any missing directories leading to
+    /// them, and returned in that order. Existing subvolumes are not reused, so applying a
+    /// layout twice fails.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn apply<P>(&self, fs_root: P) -> Result<Vec<Subvolume>>
+    where
+        P: AsRef<Path>,
+    {
+        let fs_root = fs_root.as_ref();
+        self.apply_impl(fs_root)
+            .context("Layout::apply", &[fs_root])
+    }
+
+    fn apply_impl(&self, fs_root: &Path) -> Result<Vec<Subvolume>> {
+        self.validate()?;
+        if Subvolume::get(fs_root)?.id() != SubvolumeId::FS_TREE {
+            return Err(LibError::InvalidArgument.into());
+        }
+
+        let mut paths: Vec<&Path> = self.subvolumes.iter().map(|s| s.path.as_path()).collect();
+        // a path sorts before the paths below it
+        paths.sort();
+
+        let mut created = Vec::with_capacity(paths.len());
+        for path in paths {
+            created.push(Subvolume::create_with_parents(fs_root.join(path), None)?);
+        }
+
+        if let Some(default) = &self.default {
+            let path = fs_root.join(default);
+            if let Some(subvolume) = created.iter().find(|s| s.path() == path) {
+                subvolume.clone().elevate()?.set_default()?;
+            }
+        }
+
+        Ok(created)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn layout_validation() {
+        assert!(Layout::flat().validate().is_ok());
+        assert!(Layout::nested().default_subvolume("@").validate().is_ok());
+
+        assert!(Layout::flat().default_subvolume("@var").validate().is_err());
+        assert!(Layout::new().subvolume("/@").validate().is_err());
+        assert!(Layout::new().subvolume("@/../x").validate().is_err());
+        assert!(Layout::flat().subvolume("@home").validate().is_err());
+    }
+}
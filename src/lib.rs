@@ -6,18 +6,68 @@
 #[macro_use]
 extern crate bitflags;
 
+#[cfg(not(any(feature = "libbtrfsutil", feature = "pure-rust")))]
+compile_error!("either the libbtrfsutil or the pure-rust feature has to be enabled");
+
 #[macro_use]
 pub mod error;
 #[macro_use]
 mod common;
+pub mod analytics;
+#[cfg(feature = "async")]
+#[cfg_attr(docsrs, doc(cfg(feature = "async")))]
+pub mod asynchronous;
+pub mod backend;
+pub mod backup;
+pub mod balance;
+pub mod bootenv;
+pub mod dedupe;
+pub mod diff;
+pub mod extents;
+pub mod fs;
+pub mod history;
+pub mod idempotency;
+mod ioctl;
+pub mod layout;
+pub mod limiter;
+pub mod maintenance;
+#[cfg(feature = "chrono")]
+#[cfg_attr(docsrs, doc(cfg(feature = "chrono")))]
+pub mod manager;
+pub mod mount;
+#[cfg(feature = "chrono")]
+#[cfg_attr(docsrs, doc(cfg(feature = "chrono")))]
+pub mod naming;
+pub mod privileges;
+pub mod properties;
 pub mod qgroup;
+mod raw_uuid;
+pub mod reflink;
+pub mod report;
+pub mod resolve;
+pub mod restore;
+#[cfg(feature = "chrono")]
+#[cfg_attr(docsrs, doc(cfg(feature = "chrono")))]
+pub mod retention;
+pub mod rollback;
+pub mod scrub;
+pub mod sendstream;
+pub mod snapshots;
+pub mod stats;
 pub mod subvolume;
+pub mod support;
 pub mod sync;
+mod sys;
+pub mod tree_search;
+pub mod verify;
+mod xattr;
 
-#[cfg(test)]
-mod testing;
+#[cfg(any(test, feature = "test-util"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "test-util")))]
+pub mod testing;
 
 pub use error::BtrfsUtilError;
+pub use raw_uuid::RawUuid;
 
 /// Result type used by this library.
 pub type Result<T> = std::result::Result<T, BtrfsUtilError>;
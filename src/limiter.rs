@@ -0,0 +1,138 @@
+//! Concurrency limits for heavy filesystem operations.
+//!
+//! Operations such as balance, scrub, defragmentation, deduplication, device removal or replace
+//! read and rewrite large parts of a filesystem. A [Limiter] attached to a [Filesystem] handle caps how many of
+//! them run at once through the methods of that handle; share one limiter between all handles of
+//! a filesystem to enforce the cap across them. The free functions of the same operations are
+//! not limited.
+//!
+//! [Limiter]: struct.Limiter.html
+//! [Filesystem]: ../fs/struct.Filesystem.html
+
+use crate::error::LibError;
+pub use crate::Result;
+
+use std::sync::Condvar;
+use std::sync::Mutex;
+
+/// What to do when a heavy operation is started while the limit is reached.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BusyPolicy {
+    /// Wait in line for a running operation to finish.
+    Wait,
+    /// Fail right away with [LibError::Busy].
+    ///
+    /// [LibError::Busy]: ../error/enum.LibError.html#variant.Busy
+    Fail,
+}
+
+/// Caps the number of heavy operations running at the same time.
+#[derive(Debug)]
+pub struct Limiter {
+    max: usize,
+    policy: BusyPolicy,
+    running: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Limiter {
+    /// Create a limiter allowing up to `max` concurrent heavy operations.
+    ///
+    /// A limit of zero is treated as one.
+    pub fn new(max: usize, policy: BusyPolicy) -> Self {
+        Self {
+            max: max.max(1),
+            policy,
+            running: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Get the maximum number of concurrent heavy operations.
+    #[inline]
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Get the policy applied when the limit is reached.
+    #[inline]
+    pub fn policy(&self) -> BusyPolicy {
+        self.policy
+    }
+
+    /// Get the number of heavy operations currently running.
+    pub fn running(&self) -> usize {
+        *self.lock()
+    }
+
+    /// Reserve a slot for a heavy operation, following the limiter's [BusyPolicy].
+    ///
+    /// The slot is released when the returned [Permit] is dropped.
+    ///
+    /// [BusyPolicy]: enum.BusyPolicy.html
+    /// [Permit]: struct.Permit.html
+    pub fn acquire(&self) -> Result<Permit<'_>> {
+        match self.policy {
+            BusyPolicy::Fail => self.try_acquire(),
+            BusyPolicy::Wait => {
+                let mut running = self.lock();
+                while *running >= self.max {
+                    running = self
+                        .released
+                        .wait(running)
+                        .unwrap_or_else(|e| e.into_inner());
+                }
+                *running += 1;
+                Ok(Permit(self))
+            }
+        }
+    }
+
+    /// Reserve a slot for a heavy operation without waiting, regardless of the policy.
+    ///
+    /// Fails with [LibError::Busy] if the limit is reached.
+    ///
+    /// [LibError::Busy]: ../error/enum.LibError.html#variant.Busy
+    pub fn try_acquire(&self) -> Result<Permit<'_>> {
+        let mut running = self.lock();
+        if *running >= self.max {
+            return Err(LibError::Busy.into());
+        }
+        *running += 1;
+        Ok(Permit(self))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, usize> {
+        // the counter stays consistent even if a holder panicked
+        self.running.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A reserved slot for a heavy operation, released on drop.
+#[derive(Debug)]
+pub struct Permit<'a>(&'a Limiter);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.lock() -= 1;
+        self.0.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn limiter_fail_policy() {
+        let limiter = Limiter::new(1, BusyPolicy::Fail);
+
+        let permit = limiter.acquire().unwrap();
+        assert_eq!(limiter.running(), 1);
+        assert_eq!(limiter.acquire().unwrap_err(), LibError::Busy);
+
+        drop(permit);
+        assert_eq!(limiter.running(), 0);
+        assert!(limiter.acquire().is_ok());
+    }
+}
@@ -0,0 +1,651 @@
+//! Maintenance scheduling
+//!
+//! Helpers for automated maintenance jobs to find the work that is worth doing and to
+//! [defrag] files, and a [Scheduler] running such jobs periodically, the engine of a
+//! btrfsmaintenance-like daemon.
+//!
+//! [defrag]: fn.defrag.html
+//! [Scheduler]: struct.Scheduler.html
+
+use crate::analytics;
+use crate::analytics::Fragmentation;
+use crate::balance::BalanceOptions;
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::fs::Filesystem;
+use crate::fs::TrimRange;
+use crate::ioctl;
+use crate::ioctl::DefragRangeArgs;
+use crate::properties::Compression;
+use crate::scrub::ScrubFlags;
+use crate::subvolume::Subvolume;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+/// Longest the scheduler sleeps at once, so it notices a stop request in time.
+const MAX_SLEEP: Duration = Duration::from_secs(1);
+
+/// A file that would benefit from defragmentation, as found by [defrag_candidates].
+///
+/// [defrag_candidates]: fn.defrag_candidates.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct DefragCandidate {
+    /// Path of the file.
+    pub path: PathBuf,
+    /// How fragmented the file is.
+    pub fragmentation: Fragmentation,
+}
+
+/// Find the regular files of a subvolume whose [fragmentation score] is at least `threshold`,
+/// worst first.
+///
+/// Nested subvolumes are not descended into. Files that cannot be opened or mapped are left
+/// out.
+///
+/// [fragmentation score]: ../analytics/struct.Fragmentation.html#method.score
+pub fn defrag_candidates(subvol: &Subvolume, threshold: f64) -> Result<Vec<DefragCandidate>> {
+    defrag_candidates_impl(subvol, threshold)
+        .context("maintenance::defrag_candidates", &[subvol.path()])
+}
+
+fn defrag_candidates_impl(subvol: &Subvolume, threshold: f64) -> Result<Vec<DefragCandidate>> {
+    let root_dev = std::fs::symlink_metadata(subvol.path())
+        .map_err(|err| BtrfsUtilError::from_io(LibError::StatFailed, &err))?
+        .dev();
+
+    let mut candidates: Vec<DefragCandidate> = Vec::new();
+    let mut dirs: VecDeque<PathBuf> = VecDeque::new();
+    dirs.push_back(subvol.path().to_path_buf());
+
+    while let Some(dir) = dirs.pop_front() {
+        let entries = std::fs::read_dir(&dir)
+            .map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?;
+        for entry in entries {
+            let path = entry
+                .map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?
+                .path();
+            let metadata = std::fs::symlink_metadata(&path)
+                .map_err(|err| BtrfsUtilError::from_io(LibError::StatFailed, &err))?;
+            // nested subvolumes have their own anonymous device
+            if metadata.dev() != root_dev {
+                continue;
+            }
+
+            if metadata.is_dir() {
+                dirs.push_back(path);
+            } else if metadata.is_file() {
+                match analytics::fragmentation_score_impl(&path) {
+                    Ok(fragmentation) if fragmentation.score() >= threshold => {
+                        candidates.push(DefragCandidate {
+                            path,
+                            fragmentation,
+                        })
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    candidates.sort_by(|a, b| {
+        b.fragmentation
+            .score()
+            .total_cmp(&a.fragmentation.score())
+            .then_with(|| a.path.cmp(&b.path))
+    });
+
+    Ok(candidates)
+}
+
+/// How [defrag] rewrites a file.
+///
+/// The default covers the whole file with the kernel's extent size threshold and the compression
+/// the file would be written with anyway.
+///
+/// [defrag]: fn.defrag.html
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DefragOptions {
+    /// Byte offset to start at.
+    pub start: u64,
+    /// Number of bytes to cover, up to the end of the file if `None`.
+    pub len: Option<u64>,
+    /// Leave extents at least this many bytes long alone, 32 MiB if `None`.
+    pub extent_threshold: Option<u32>,
+    /// Compress the rewritten extents with this algorithm, at its default level.
+    pub compress: Option<Compression>,
+    /// Write the rewritten extents out before returning.
+    pub flush: bool,
+}
+
+impl DefragOptions {
+    fn to_args(self) -> Result<DefragRangeArgs> {
+        let mut args = DefragRangeArgs::default();
+        args.start = self.start;
+        args.len = self.len.unwrap_or(u64::MAX);
+        // zero is the kernel's default threshold
+        args.extent_thresh = self.extent_threshold.unwrap_or(0);
+        if let Some(compress) = self.compress {
+            args.flags |= ioctl::BTRFS_DEFRAG_RANGE_COMPRESS;
+            args.compress_type = match compress {
+                Compression::Zlib(None) => 1,
+                Compression::Lzo => 2,
+                Compression::Zstd(None) => 3,
+                // the ioctl takes neither levels nor the absence of compression
+                _ => return Err(LibError::InvalidArgument.into()),
+            };
+        }
+        if self.flush {
+            args.flags |= ioctl::BTRFS_DEFRAG_RANGE_START_IO;
+        }
+        Ok(args)
+    }
+}
+
+/// Defragment a file, such as one found by [defrag_candidates], through
+/// `BTRFS_IOC_DEFRAG_RANGE`.
+///
+/// Defragmenting unshares the extents the file shares with snapshots and reflinked copies, which
+/// takes up more space. Fails with [LibError::InvalidArgument] if [DefragOptions::compress] has
+/// a level or is [Compression::Disabled].
+///
+/// Files the caller cannot write to require **CAP_SYS_ADMIN**.
+///
+/// [defrag_candidates]: fn.defrag_candidates.html
+/// [LibError::InvalidArgument]: ../error/enum.LibError.html#variant.InvalidArgument
+/// [DefragOptions::compress]: struct.DefragOptions.html#structfield.compress
+/// [Compression::Disabled]: ../properties/enum.Compression.html#variant.Disabled
+pub fn defrag<P>(path: P, options: DefragOptions) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    defrag_impl(path, options).context("maintenance::defrag", &[path])
+}
+
+fn defrag_impl(path: &Path, options: DefragOptions) -> Result<()> {
+    let mut args = options.to_args()?;
+    let file = ioctl::open(path)?;
+
+    unsafe {
+        ioctl::ioctl(
+            file.as_raw_fd(),
+            ioctl::BTRFS_IOC_DEFRAG_RANGE,
+            &mut args,
+            LibError::DefragFailed,
+        )
+    }
+}
+
+impl Filesystem {
+    /// Same as [defrag], counted as a heavy operation.
+    ///
+    /// [defrag]: ../maintenance/fn.defrag.html
+    pub fn defrag<P>(&self, path: P, options: DefragOptions) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        self.heavy(|| defrag_impl(path, options))
+            .context("Filesystem::defrag", &[self.path(), path])
+    }
+}
+
+/// A caller-provided maintenance task.
+pub type CustomTask = Box<dyn FnMut(&Filesystem) -> Result<()> + Send>;
+/// A condition deciding whether a due task runs.
+type Condition = Box<dyn FnMut(&Filesystem) -> Result<bool> + Send>;
+/// A hook called on scheduler events.
+type Hook = Box<dyn FnMut(&Event) + Send>;
+
+/// A maintenance task run by a [Scheduler].
+///
+/// [Scheduler]: struct.Scheduler.html
+pub enum Task {
+    /// Scrub the filesystem.
+    Scrub(Option<ScrubFlags>),
+    /// Balance the filesystem.
+    Balance(Box<BalanceOptions>),
+    /// Discard unused space.
+    Trim(TrimRange),
+    /// Run a caller-provided task, such as pruning old snapshots, under a name.
+    Custom(String, CustomTask),
+}
+
+impl Task {
+    /// Get the name of the task, used to record when it last ran.
+    pub fn name(&self) -> &str {
+        match self {
+            Task::Scrub(_) => "scrub",
+            Task::Balance(_) => "balance",
+            Task::Trim(_) => "trim",
+            Task::Custom(name, _) => name,
+        }
+    }
+
+    fn run(&mut self, fs: &Filesystem) -> Result<()> {
+        match self {
+            Task::Scrub(flags) => fs.scrub(*flags).and_then(|status| {
+                status
+                    .failed
+                    .into_iter()
+                    .next()
+                    .map_or(Ok(()), |(_, err)| Err(err))
+            }),
+            Task::Balance(options) => fs.balance(options).map(|_| ()),
+            Task::Trim(range) => fs.trim(*range).map(|_| ()),
+            Task::Custom(_, f) => f(fs),
+        }
+    }
+}
+
+impl fmt::Debug for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Task::Scrub(flags) => f.debug_tuple("Scrub").field(flags).finish(),
+            Task::Balance(options) => f.debug_tuple("Balance").field(options).finish(),
+            Task::Trim(range) => f.debug_tuple("Trim").field(range).finish(),
+            Task::Custom(name, _) => f.debug_tuple("Custom").field(name).finish(),
+        }
+    }
+}
+
+/// When to run a [Task].
+///
+/// [Task]: enum.Task.html
+pub struct Policy {
+    task: Task,
+    interval: Duration,
+    jitter: Duration,
+    condition: Option<Condition>,
+}
+
+impl Policy {
+    /// Run a task every `interval`.
+    pub fn new(task: Task, interval: Duration) -> Self {
+        Self {
+            task,
+            interval,
+            jitter: Duration::ZERO,
+            condition: None,
+        }
+    }
+
+    /// Scrub every 30 days, with up to a day of jitter.
+    pub fn scrub_monthly() -> Self {
+        Self::new(Task::Scrub(None), 30 * DAY).jitter(DAY)
+    }
+
+    /// Trim the whole filesystem every week, with up to an hour of jitter.
+    pub fn trim_weekly() -> Self {
+        Self::new(Task::Trim(TrimRange::default()), 7 * DAY).jitter(HOUR)
+    }
+
+    /// Delay each run by a random duration of up to `jitter`, so that machines or filesystems
+    /// sharing a policy do not all run at once.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Only run the task when a condition holds once it is due, such as an advisor saying a
+    /// balance is worth it. A task whose condition does not hold is checked again after another
+    /// interval.
+    pub fn when<F>(mut self, condition: F) -> Self
+    where
+        F: FnMut(&Filesystem) -> Result<bool> + Send + 'static,
+    {
+        self.condition = Some(Box::new(condition));
+        self
+    }
+
+    /// Get the task.
+    #[inline]
+    pub fn task(&self) -> &Task {
+        &self.task
+    }
+
+    /// Get the interval between runs.
+    #[inline]
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+impl fmt::Debug for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Policy")
+            .field("task", &self.task)
+            .field("interval", &self.interval)
+            .field("jitter", &self.jitter)
+            .field("condition", &self.condition.is_some())
+            .finish()
+    }
+}
+
+/// Something that happened to a scheduled task, passed to the hooks of a [Scheduler].
+///
+/// [Scheduler]: struct.Scheduler.html
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// The task is about to run.
+    Started {
+        /// The filesystem the task runs on.
+        fs: &'a Filesystem,
+        /// The name of the task.
+        task: &'a str,
+    },
+    /// The task was due but its condition did not hold.
+    Skipped {
+        /// The filesystem the task would have run on.
+        fs: &'a Filesystem,
+        /// The name of the task.
+        task: &'a str,
+    },
+    /// The task finished.
+    Finished {
+        /// The filesystem the task ran on.
+        fs: &'a Filesystem,
+        /// The name of the task.
+        task: &'a str,
+        /// How the task went.
+        result: &'a Result<()>,
+    },
+}
+
+/// A policy applied to one filesystem.
+#[derive(Debug)]
+struct Job {
+    fs: Filesystem,
+    policy: Policy,
+    next_due: SystemTime,
+}
+
+impl Job {
+    fn key(&self) -> String {
+        format!("{}\t{}", self.fs.path().display(), self.policy.task.name())
+    }
+}
+
+/// Runs maintenance tasks on filesystems according to their policies.
+///
+/// The time each task last ran is kept in a state file, if one is set, so a restarted daemon
+/// does not run every task again right away. Tasks run one at a time on the thread driving the
+/// scheduler; heavy tasks also count against the [Limiter] of their [Filesystem] handle.
+///
+/// [Limiter]: ../limiter/struct.Limiter.html
+/// [Filesystem]: ../fs/struct.Filesystem.html
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: Vec<Job>,
+    state_file: Option<PathBuf>,
+    last_runs: HashMap<String, SystemTime>,
+    hooks: Vec<Hook>,
+}
+
+impl Scheduler {
+    /// Create a scheduler without any policy.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the time each task last ran in a file, loading the times already recorded in it.
+    pub fn with_state_file<P>(mut self, path: P) -> Result<Self>
+    where
+        P: Into<PathBuf>,
+    {
+        let path = path.into();
+        self.last_runs = load_state(&path)?;
+        self.state_file = Some(path);
+        for job in &mut self.jobs {
+            job.next_due = next_due(self.last_runs.get(&job.key()).copied(), &job.policy);
+        }
+        Ok(self)
+    }
+
+    /// Apply a policy to a filesystem.
+    pub fn add(mut self, fs: Filesystem, policy: Policy) -> Self {
+        let mut job = Job {
+            fs,
+            policy,
+            next_due: UNIX_EPOCH,
+        };
+        job.next_due = next_due(self.last_runs.get(&job.key()).copied(), &job.policy);
+        self.jobs.push(job);
+        self
+    }
+
+    /// Call a hook whenever a task starts, is skipped or finishes.
+    pub fn on_event<F>(mut self, hook: F) -> Self
+    where
+        F: FnMut(&Event) + Send + 'static,
+    {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Get the earliest time a task is due, or None if there is no policy.
+    pub fn next_due(&self) -> Option<SystemTime> {
+        self.jobs.iter().map(|job| job.next_due).min()
+    }
+
+    /// Run every task that is due now, and get the number of tasks run.
+    ///
+    /// Failures of the tasks themselves are reported to the hooks; only failing to record the
+    /// time a task ran is an error.
+    pub fn run_pending(&mut self) -> Result<usize> {
+        let now = SystemTime::now();
+        let mut ran = 0;
+
+        for i in 0..self.jobs.len() {
+            if self.jobs[i].next_due > now {
+                continue;
+            }
+
+            let key = self.jobs[i].key();
+            let job = &mut self.jobs[i];
+            let task = job.policy.task.name().to_owned();
+            let run = match &mut job.policy.condition {
+                // a failing condition is treated as not holding
+                Some(condition) => condition(&job.fs).unwrap_or(false),
+                None => true,
+            };
+
+            if run {
+                emit(
+                    &mut self.hooks,
+                    &Event::Started {
+                        fs: &job.fs,
+                        task: &task,
+                    },
+                );
+                let result = job.policy.task.run(&job.fs);
+                emit(
+                    &mut self.hooks,
+                    &Event::Finished {
+                        fs: &job.fs,
+                        task: &task,
+                        result: &result,
+                    },
+                );
+                ran += 1;
+            } else {
+                emit(
+                    &mut self.hooks,
+                    &Event::Skipped {
+                        fs: &job.fs,
+                        task: &task,
+                    },
+                );
+            }
+
+            // skipped tasks are checked again after an interval, same as tasks that ran
+            let finished = SystemTime::now();
+            job.next_due = next_due(Some(finished), &job.policy);
+            self.last_runs.insert(key, finished);
+            if let Some(path) = &self.state_file {
+                save_state(path, &self.last_runs)?;
+            }
+        }
+
+        Ok(ran)
+    }
+
+    /// Run tasks as they become due until `stop` is set.
+    pub fn run(&mut self, stop: &AtomicBool) -> Result<()> {
+        while !stop.load(Ordering::Relaxed) {
+            self.run_pending()?;
+            let wait = self
+                .next_due()
+                .and_then(|due| due.duration_since(SystemTime::now()).ok())
+                .unwrap_or(MAX_SLEEP);
+            std::thread::sleep(wait.min(MAX_SLEEP));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scheduler")
+            .field("jobs", &self.jobs)
+            .field("state_file", &self.state_file)
+            .field("last_runs", &self.last_runs)
+            .field("hooks", &self.hooks.len())
+            .finish()
+    }
+}
+
+fn emit(hooks: &mut [Hook], event: &Event) {
+    for hook in hooks {
+        hook(event);
+    }
+}
+
+/// Compute when a task is next due, given when it last ran.
+fn next_due(last_run: Option<SystemTime>, policy: &Policy) -> SystemTime {
+    let jitter = match policy.jitter.as_secs() {
+        0 => Duration::ZERO,
+        secs => Duration::from_secs(RandomState::new().build_hasher().finish() % (secs + 1)),
+    };
+    match last_run {
+        Some(last_run) => last_run + policy.interval + jitter,
+        // never ran, so it is overdue; jitter still spreads the first runs
+        None => SystemTime::now() + jitter,
+    }
+}
+
+/// Load the state file: one line per task, the time it last ran in seconds since the epoch,
+/// then a space and its key.
+fn load_state(path: &Path) -> Result<HashMap<String, SystemTime>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(err) => {
+            return Err(BtrfsUtilError::from_io(
+                LibError::SchedulerStateFailed,
+                &err,
+            ))
+        }
+    };
+
+    text.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (secs, key) = line.split_once(' ').ok_or(LibError::SchedulerStateFailed)?;
+            let secs: u64 = secs.parse().map_err(|_| LibError::SchedulerStateFailed)?;
+            Ok((key.to_owned(), UNIX_EPOCH + Duration::from_secs(secs)))
+        })
+        .collect()
+}
+
+fn save_state(path: &Path, last_runs: &HashMap<String, SystemTime>) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let write = || -> std::io::Result<()> {
+        let mut file = fs::File::create(&tmp)?;
+        for (key, time) in last_runs {
+            let secs = time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            writeln!(file, "{} {}", secs, key)?;
+        }
+        file.sync_all()?;
+        // renaming makes the new state appear whole or not at all
+        fs::rename(&tmp, path)
+    };
+    write().map_err(|err| BtrfsUtilError::from_io(LibError::SchedulerStateFailed, &err))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn defrag_args() {
+        let args = DefragOptions::default().to_args().unwrap();
+        assert_eq!((args.start, args.len, args.flags), (0, u64::MAX, 0));
+
+        let args = DefragOptions {
+            compress: Some(Compression::Zstd(None)),
+            flush: true,
+            ..Default::default()
+        }
+        .to_args()
+        .unwrap();
+        assert_eq!(
+            args.flags,
+            ioctl::BTRFS_DEFRAG_RANGE_COMPRESS | ioctl::BTRFS_DEFRAG_RANGE_START_IO
+        );
+        assert_eq!(args.compress_type, 3);
+
+        for compress in [Compression::Disabled, Compression::Zlib(Some(3))] {
+            let options = DefragOptions {
+                compress: Some(compress),
+                ..Default::default()
+            };
+            assert_eq!(options.to_args().unwrap_err(), LibError::InvalidArgument);
+        }
+    }
+
+    #[test]
+    fn scheduler_state_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state");
+        assert!(load_state(&path).unwrap().is_empty());
+
+        let mut last_runs = HashMap::new();
+        last_runs.insert(
+            "/mnt\tscrub".to_owned(),
+            UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        );
+        last_runs.insert("/mnt\tprune snapshots".to_owned(), UNIX_EPOCH);
+        save_state(&path, &last_runs).unwrap();
+        assert_eq!(load_state(&path).unwrap(), last_runs);
+
+        let policy = Policy::new(Task::Scrub(None), DAY);
+        assert_eq!(
+            next_due(Some(UNIX_EPOCH), &policy),
+            UNIX_EPOCH + policy.interval()
+        );
+    }
+}
@@ -0,0 +1,218 @@
+//! Managed snapshots
+//!
+//! A [SnapshotManager] takes timestamped snapshots of a subvolume into a directory, lists them
+//! and prunes them according to a [RetentionPolicy], covering the common case of periodic
+//! snapshots without assembling the pieces by hand.
+//!
+//! Snapshots are named as described in the [naming] module, and recognized by their names, so
+//! snapshots taken by other tools with compatible names are managed too.
+//!
+//! [SnapshotManager]: struct.SnapshotManager.html
+//! [RetentionPolicy]: ../retention/struct.RetentionPolicy.html
+//! [naming]: ../naming/index.html
+
+use crate::error::BatchError;
+use crate::error::BatchResult;
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::naming::SnapshotName;
+use crate::retention::RetentionPolicy;
+use crate::subvolume::SnapshotFlags;
+use crate::subvolume::Subvolume;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::cmp::Reverse;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+
+use chrono::DateTime;
+use chrono::Local;
+
+/// A snapshot found by a [SnapshotManager].
+///
+/// [SnapshotManager]: struct.SnapshotManager.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManagedSnapshot {
+    /// The parsed name of the snapshot.
+    pub name: SnapshotName,
+    /// The snapshot.
+    pub subvolume: Subvolume,
+}
+
+/// Takes, lists and prunes the snapshots of a subvolume kept in a directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotManager {
+    source: Subvolume,
+    dir: PathBuf,
+    name: String,
+    tag: Option<String>,
+    read_only: bool,
+    policy: RetentionPolicy,
+}
+
+impl SnapshotManager {
+    /// Manage the snapshots of `source` in `dir`.
+    ///
+    /// Snapshots are named after the last component of the path of the source and are
+    /// read-only. The retention policy keeps nothing until one is set.
+    pub fn new<P>(source: Subvolume, dir: P) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        let name = source
+            .path()
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "root".to_owned());
+        Self {
+            source,
+            dir: dir.as_ref().to_path_buf(),
+            name,
+            tag: None,
+            read_only: true,
+            policy: RetentionPolicy::new(),
+        }
+    }
+
+    /// Name the snapshots after `name` instead.
+    pub fn name<S>(mut self, name: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.name = name.into();
+        self
+    }
+
+    /// Tag the snapshots, and only manage snapshots with this tag.
+    pub fn tag<S>(mut self, tag: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.tag = Some(tag.into());
+        self
+    }
+
+    /// Take writable snapshots instead.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Prune according to `policy`.
+    pub fn retention(mut self, policy: RetentionPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Get the directory the snapshots are kept in.
+    #[inline]
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Take a snapshot now, creating the directory if missing.
+    ///
+    /// Names only have whole seconds, so taking two snapshots within the same second fails.
+    pub fn take(&self) -> Result<ManagedSnapshot> {
+        self.take_impl()
+            .context("SnapshotManager::take", &[self.source.path(), &self.dir])
+    }
+
+    fn take_impl(&self) -> Result<ManagedSnapshot> {
+        fs::create_dir_all(&self.dir)
+            .map_err(|err| BtrfsUtilError::from_io(LibError::MkdirFailed, &err))?;
+
+        let mut name = SnapshotName::now(self.name.as_str());
+        name.tag = self.tag.clone();
+        let flags = if self.read_only {
+            SnapshotFlags::READ_ONLY
+        } else {
+            SnapshotFlags::empty()
+        };
+        let path = self.dir.join(name.to_string());
+        let subvolume = self.source.snapshot(&path, flags, None)?;
+
+        Ok(ManagedSnapshot { name, subvolume })
+    }
+
+    /// List the managed snapshots, newest first.
+    ///
+    /// Entries of the directory whose names do not parse, belong to another source or tag, or
+    /// are not subvolumes are left out.
+    pub fn list(&self) -> Result<Vec<ManagedSnapshot>> {
+        self.list_impl()
+            .context("SnapshotManager::list", &[&self.dir])
+    }
+
+    fn list_impl(&self) -> Result<Vec<ManagedSnapshot>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            // nothing taken yet
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(BtrfsUtilError::from_io(LibError::OpenFailed, &err)),
+        };
+
+        let mut snapshots = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?;
+            let name: SnapshotName = match entry.file_name().to_str().map(str::parse) {
+                Some(Ok(name)) => name,
+                _ => continue,
+            };
+            if name.source != self.name || name.tag != self.tag {
+                continue;
+            }
+            if let Ok(subvolume) = Subvolume::get(entry.path()) {
+                snapshots.push(ManagedSnapshot { name, subvolume });
+            }
+        }
+        snapshots.sort_by_key(|snapshot| Reverse(snapshot.name.timestamp));
+
+        Ok(snapshots)
+    }
+
+    /// Get the snapshots the retention policy does not keep, newest first, by the times in
+    /// their names.
+    pub fn expired(&self) -> Result<Vec<ManagedSnapshot>> {
+        let snapshots = self.list()?;
+        let times: Vec<DateTime<Local>> = snapshots
+            .iter()
+            .map(|snapshot| snapshot.name.timestamp.with_timezone(&Local))
+            .collect();
+        let keep = self.policy.select(&times, Local::now());
+
+        Ok(snapshots
+            .into_iter()
+            .zip(keep)
+            .filter(|(_, keep)| !keep)
+            .map(|(snapshot, _)| snapshot)
+            .collect())
+    }
+
+    /// Delete the snapshots the retention policy does not keep.
+    ///
+    /// The result maps the path of each deleted snapshot to its outcome, or the directory to
+    /// the error if the snapshots could not be listed.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn prune(&self) -> BatchResult<PathBuf, ()> {
+        let mut batch = BatchError::new();
+        match self.expired() {
+            Ok(expired) => {
+                for snapshot in expired {
+                    let path = snapshot.subvolume.path().to_path_buf();
+                    let result = snapshot
+                        .subvolume
+                        .elevate()
+                        .and_then(|subvolume| subvolume.delete(None));
+                    batch.push(path, result);
+                }
+            }
+            Err(err) => batch.push(self.dir.clone(), Err(err)),
+        }
+        batch.into_result()
+    }
+}
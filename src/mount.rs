@@ -0,0 +1,235 @@
+//! Mounting subvolumes
+//!
+//! Subvolumes that are not mounted anywhere, such as old snapshots to restore files from, can be
+//! mounted for as long as a [MountGuard] lives, without shelling out to `mount`.
+//!
+//! [MountGuard]: struct.MountGuard.html
+
+use crate::common;
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::subvolume::SubvolumeId;
+use crate::BtrfsUtilError;
+use crate::RawUuid;
+pub use crate::Result;
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+bitflags! {
+    /// Mount flags.
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct MountFlags: libc::c_ulong {
+        /// Read-only.
+        const READ_ONLY = libc::MS_RDONLY;
+        /// Do not allow executing programs.
+        const NO_EXEC = libc::MS_NOEXEC;
+        /// Ignore set-user-ID and set-group-ID bits.
+        const NO_SUID = libc::MS_NOSUID;
+        /// Do not allow accessing device files.
+        const NO_DEV = libc::MS_NODEV;
+        /// Do not update access times.
+        const NO_ATIME = libc::MS_NOATIME;
+    }
+}
+
+/// The filesystem to mount, either by the path of one of its devices or by its fsid.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MountSource<'a> {
+    /// Path of a block device of the filesystem.
+    Device(&'a Path),
+    /// The fsid of the filesystem, found through `/dev/disk/by-uuid`.
+    Uuid(RawUuid),
+}
+
+impl MountSource<'_> {
+    /// Get the path of the device to mount.
+    fn device(&self) -> PathBuf {
+        match self {
+            MountSource::Device(path) => path.to_path_buf(),
+            MountSource::Uuid(uuid) => Path::new("/dev/disk/by-uuid").join(uuid.to_string()),
+        }
+    }
+}
+
+impl<'a> From<&'a Path> for MountSource<'a> {
+    fn from(path: &'a Path) -> Self {
+        MountSource::Device(path)
+    }
+}
+
+impl From<RawUuid> for MountSource<'_> {
+    fn from(uuid: RawUuid) -> Self {
+        MountSource::Uuid(uuid)
+    }
+}
+
+/// The subvolume to mount, either by its path relative to the top-level subvolume or by its id.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SubvolumeSpec<'a> {
+    /// Path of the subvolume relative to the top-level subvolume.
+    Path(&'a Path),
+    /// Id of the subvolume.
+    Id(SubvolumeId),
+}
+
+impl SubvolumeSpec<'_> {
+    /// Get the btrfs mount option selecting the subvolume.
+    fn option(&self) -> Result<CString> {
+        let option = match self {
+            SubvolumeSpec::Path(path) => {
+                // commas separate mount options
+                if path.as_os_str().as_bytes().contains(&b',') {
+                    return Err(LibError::InvalidArgument.into());
+                }
+                [b"subvol=", path.as_os_str().as_bytes()].concat()
+            }
+            SubvolumeSpec::Id(id) => format!("subvolid={}", id.get()).into_bytes(),
+        };
+        CString::new(option).map_err(|_| LibError::InvalidArgument.into())
+    }
+}
+
+impl<'a> From<&'a Path> for SubvolumeSpec<'a> {
+    fn from(path: &'a Path) -> Self {
+        SubvolumeSpec::Path(path)
+    }
+}
+
+impl From<SubvolumeId> for SubvolumeSpec<'_> {
+    fn from(id: SubvolumeId) -> Self {
+        SubvolumeSpec::Id(id)
+    }
+}
+
+/// Mount a subvolume of a filesystem on a directory, until the returned guard is dropped.
+///
+/// The filesystem may already be mounted elsewhere, with the same or another subvolume.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn mount_subvolume<'a, 'b, S, V, T, F>(
+    source: S,
+    subvolume: V,
+    target: T,
+    flags: F,
+) -> Result<MountGuard>
+where
+    S: Into<MountSource<'a>>,
+    V: Into<SubvolumeSpec<'b>>,
+    T: AsRef<Path>,
+    F: Into<Option<MountFlags>>,
+{
+    let device = source.into().device();
+    let target = target.as_ref();
+    mount_subvolume_impl(&device, subvolume.into(), target, flags.into())
+        .context("mount::mount_subvolume", &[&device, target])
+}
+
+fn mount_subvolume_impl(
+    device: &Path,
+    subvolume: SubvolumeSpec,
+    target: &Path,
+    flags: Option<MountFlags>,
+) -> Result<MountGuard> {
+    let option = subvolume.option()?;
+    let device_cstr = common::path_to_cstr(device);
+    let target_cstr = common::path_to_cstr(target);
+    let flags_val = if let Some(val) = flags { val.bits() } else { 0 };
+
+    if unsafe {
+        libc::mount(
+            device_cstr.as_ptr(),
+            target_cstr.as_ptr(),
+            c"btrfs".as_ptr(),
+            flags_val,
+            option.as_ptr() as *const libc::c_void,
+        )
+    } < 0
+    {
+        return Err(BtrfsUtilError::last_os_error(LibError::MountFailed));
+    }
+
+    Ok(MountGuard {
+        target: target.to_path_buf(),
+        mounted: true,
+    })
+}
+
+/// Keeps a subvolume mounted while alive, see [mount_subvolume].
+///
+/// Dropping the guard lazily unmounts the directory, detaching it even if files in it are still
+/// open. Unmounting cannot report errors on drop; use [unmount] to handle them.
+///
+/// [mount_subvolume]: fn.mount_subvolume.html
+/// [unmount]: #method.unmount
+#[derive(Debug)]
+pub struct MountGuard {
+    target: PathBuf,
+    mounted: bool,
+}
+
+impl MountGuard {
+    /// Get the directory the subvolume is mounted on.
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.target
+    }
+
+    /// Unmount the subvolume now, failing with `EBUSY` if it is in use.
+    ///
+    /// On failure the guard is handed back with the error, still keeping the subvolume mounted,
+    /// so unmounting can be retried once the subvolume is no longer in use.
+    pub fn unmount(mut self) -> std::result::Result<(), (MountGuard, BtrfsUtilError)> {
+        match unmount(&self.target, 0).context("MountGuard::unmount", &[self.path()]) {
+            Ok(()) => {
+                self.mounted = false;
+                Ok(())
+            }
+            Err(err) => Err((self, err)),
+        }
+    }
+}
+
+impl Drop for MountGuard {
+    fn drop(&mut self) {
+        if self.mounted {
+            let _ = unmount(&self.target, libc::MNT_DETACH);
+        }
+    }
+}
+
+fn unmount(target: &Path, flags: libc::c_int) -> Result<()> {
+    let target_cstr = common::path_to_cstr(target);
+    if unsafe { libc::umount2(target_cstr.as_ptr(), flags) } < 0 {
+        return Err(BtrfsUtilError::last_os_error(LibError::UnmountFailed));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mount_options() {
+        assert_eq!(
+            SubvolumeSpec::from(Path::new("/@snapshots/1"))
+                .option()
+                .unwrap(),
+            CString::new("subvol=/@snapshots/1").unwrap()
+        );
+        assert_eq!(
+            SubvolumeSpec::from(SubvolumeId::from_raw(257))
+                .option()
+                .unwrap(),
+            CString::new("subvolid=257").unwrap()
+        );
+        assert!(SubvolumeSpec::from(Path::new("/a,b")).option().is_err());
+        assert_eq!(
+            MountSource::from(RawUuid([0xab; 16])).device(),
+            Path::new("/dev/disk/by-uuid/abababab-abab-abab-abab-abababababab")
+        );
+    }
+}
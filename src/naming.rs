@@ -0,0 +1,193 @@
+//! Timestamped snapshot names
+//!
+//! Snapshots are named after their source and the time they were taken, optionally followed by
+//! a tag, such as `@home-2024-05-01T12:00:00Z` or `@home-2024-05-01T12:00:00Z_daily`.
+//!
+//! Besides names in this format, parsing accepts the timestamps other tools put in their names:
+//! RFC 3339 timestamps with an offset, and the compact local times of btrbk, like
+//! `home.20240501T1200`, separated from the source by `-`, `.` or `_`.
+
+use crate::error::LibError;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::DateTime;
+use chrono::Local;
+use chrono::NaiveDate;
+use chrono::NaiveDateTime;
+use chrono::TimeZone;
+use chrono::Utc;
+
+/// Format of the timestamps in the names this module generates.
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%SZ";
+/// Separator between the timestamp and the tag in the names this module generates.
+const TAG_SEPARATOR: char = '_';
+
+/// Local time formats other tools use, with the length of the timestamps they produce.
+const LOCAL_FORMATS: [(&str, usize); 4] = [
+    ("%Y%m%dT%H%M%S", 15),
+    ("%Y%m%dT%H%M", 13),
+    ("%Y-%m-%d_%H:%M:%S", 19),
+    ("%Y-%m-%d_%H-%M-%S", 19),
+];
+
+/// The parts of a timestamped snapshot name.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SnapshotName {
+    /// Name of the snapshotted subvolume.
+    pub source: String,
+    /// When the snapshot was taken, to the second.
+    pub timestamp: DateTime<Utc>,
+    /// Optional tag, such as the schedule the snapshot was taken for.
+    pub tag: Option<String>,
+}
+
+impl SnapshotName {
+    /// Create a name for a snapshot of `source` taken at `timestamp`.
+    pub fn new<S, Tz>(source: S, timestamp: DateTime<Tz>) -> Self
+    where
+        S: Into<String>,
+        Tz: TimeZone,
+    {
+        let timestamp = timestamp.with_timezone(&Utc);
+        Self {
+            source: source.into(),
+            // the format only has whole seconds
+            timestamp: Utc
+                .timestamp_opt(timestamp.timestamp(), 0)
+                .single()
+                .unwrap_or(timestamp),
+            tag: None,
+        }
+    }
+
+    /// Create a name for a snapshot of `source` taken now.
+    pub fn now<S>(source: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self::new(source, Utc::now())
+    }
+
+    /// Add a tag to the name.
+    pub fn with_tag<T>(mut self, tag: T) -> Self
+    where
+        T: Into<String>,
+    {
+        self.tag = Some(tag.into());
+        self
+    }
+}
+
+impl fmt::Display for SnapshotName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.source,
+            self.timestamp.format(TIMESTAMP_FORMAT)
+        )?;
+        if let Some(tag) = &self.tag {
+            write!(f, "{}{}", TAG_SEPARATOR, tag)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SnapshotName {
+    type Err = BtrfsUtilError;
+
+    /// Parse a snapshot name, taking the last timestamp in it that follows a separator.
+    fn from_str(s: &str) -> Result<Self> {
+        let candidates = s
+            .char_indices()
+            .filter(|(i, c)| *i > 0 && matches!(c, '-' | '.' | '_'))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+
+        for i in candidates.into_iter().rev() {
+            let rest = &s[i + 1..];
+            let (timestamp, len) = match parse_timestamp(rest) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            let tag = match &rest[len..] {
+                "" => None,
+                tag => match tag.strip_prefix(['-', '.', '_']) {
+                    Some(tag) if !tag.is_empty() => Some(tag.to_owned()),
+                    _ => continue,
+                },
+            };
+            return Ok(Self {
+                source: s[..i].to_owned(),
+                timestamp,
+                tag,
+            });
+        }
+
+        Err(LibError::InvalidArgument.into())
+    }
+}
+
+/// Parse the timestamp at the start of a string, returning it and its length.
+fn parse_timestamp(s: &str) -> Option<(DateTime<Utc>, usize)> {
+    if let Some(ts) = s.get(..20) {
+        if let Ok(ts) = NaiveDateTime::parse_from_str(ts, TIMESTAMP_FORMAT) {
+            return Some((Utc.from_utc_datetime(&ts), 20));
+        }
+    }
+    // 2024-05-01T12:00:00+02:00
+    if let Some(ts) = s.get(..25) {
+        if let Ok(ts) = DateTime::parse_from_rfc3339(ts) {
+            return Some((ts.with_timezone(&Utc), 25));
+        }
+    }
+    for (format, len) in LOCAL_FORMATS {
+        if let Some(ts) = s.get(..len) {
+            if let Ok(ts) = NaiveDateTime::parse_from_str(ts, format) {
+                return Some((Local.from_local_datetime(&ts).earliest()?.into(), len));
+            }
+        }
+    }
+    // dates alone, 20240501
+    if let Some(ts) = s.get(..8) {
+        if let Ok(date) = NaiveDate::parse_from_str(ts, "%Y%m%d") {
+            let ts = date.and_hms_opt(0, 0, 0)?;
+            return Some((Local.from_local_datetime(&ts).earliest()?.into(), 8));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn snapshot_names() {
+        let ts = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+
+        let name = SnapshotName::new("@home", ts);
+        assert_eq!(name.to_string(), "@home-2024-05-01T12:00:00Z");
+        assert_eq!(name.to_string().parse::<SnapshotName>().unwrap(), name);
+
+        let name = name.with_tag("daily");
+        assert_eq!(name.to_string(), "@home-2024-05-01T12:00:00Z_daily");
+        assert_eq!(name.to_string().parse::<SnapshotName>().unwrap(), name);
+
+        let parsed: SnapshotName = "my-data-2024-05-01T14:00:00+02:00".parse().unwrap();
+        assert_eq!(parsed.source, "my-data");
+        assert_eq!(parsed.timestamp, ts);
+
+        let parsed: SnapshotName = "home.20240501T1200".parse().unwrap();
+        assert_eq!(parsed.source, "home");
+        assert_eq!(parsed.tag, None);
+
+        assert!("home".parse::<SnapshotName>().is_err());
+        assert!("-2024-05-01T12:00:00Z".parse::<SnapshotName>().is_err());
+    }
+}
@@ -0,0 +1,482 @@
+//! Privileges required by the operations of this library
+//!
+//! The same requirements the documentation shows as badges, as data, so front-ends can check
+//! them up front or explain them to users. [check] probes what the current process can actually
+//! do on a filesystem.
+//!
+//! [check]: fn.check.html
+
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::ioctl;
+use crate::tree_search;
+use crate::tree_search::SearchRange;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::fs;
+use std::path::Path;
+
+/// A Linux capability.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Capability {
+    /// **CAP_CHOWN**
+    Chown,
+    /// **CAP_SYS_ADMIN**
+    SysAdmin,
+}
+
+impl Capability {
+    /// Get the number of the capability, as in `linux/capability.h`.
+    pub fn number(self) -> u32 {
+        match self {
+            Capability::Chown => 0,
+            Capability::SysAdmin => 21,
+        }
+    }
+
+    /// Get the name of the capability, as in `linux/capability.h`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Chown => "CAP_CHOWN",
+            Capability::SysAdmin => "CAP_SYS_ADMIN",
+        }
+    }
+
+    /// Check whether the current thread has the capability in its effective set.
+    pub fn is_effective(self) -> Result<bool> {
+        let status = fs::read_to_string("/proc/thread-self/status")
+            .or_else(|_| fs::read_to_string("/proc/self/status"))
+            .map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?;
+
+        let cap_eff = status
+            .lines()
+            .find_map(|line| line.strip_prefix("CapEff:"))
+            .and_then(|mask| u64::from_str_radix(mask.trim(), 16).ok())
+            .ok_or(LibError::StatFailed)?;
+
+        Ok(cap_eff & (1 << self.number()) != 0)
+    }
+}
+
+/// How much of this library the current process can use on a filesystem, see [check].
+///
+/// [check]: fn.check.html
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum PrivilegeLevel {
+    /// Only operations that do not always require a capability succeed.
+    Unprivileged,
+    /// **CAP_SYS_ADMIN** is in the effective set, but the filesystem refuses privileged
+    /// operations, as in a user namespace that does not own it.
+    Namespaced,
+    /// Privileged operations succeed.
+    Admin,
+}
+
+impl PrivilegeLevel {
+    /// Check whether an operation can be offered at this level.
+    ///
+    /// Operations only sometimes requiring a capability are allowed, as they may still succeed.
+    pub fn allows(self, operation: Operation) -> bool {
+        self == PrivilegeLevel::Admin || operation.required_privileges().capabilities.is_empty()
+    }
+}
+
+/// Probe whether the current process can perform privileged operations on the filesystem a
+/// path belongs to.
+///
+/// Looks at the effective capabilities of the current thread, then tries a tree search, which
+/// the kernel refuses without **CAP_SYS_ADMIN** and which changes nothing.
+pub fn check<P>(path: P) -> Result<PrivilegeLevel>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    check_impl(path).context("privileges::check", &[path])
+}
+
+fn check_impl(path: &Path) -> Result<PrivilegeLevel> {
+    let effective = Capability::SysAdmin.is_effective()?;
+    let range = SearchRange::object(
+        tree_search::ROOT_TREE_OBJECTID,
+        tree_search::FS_TREE_OBJECTID,
+        ioctl::BTRFS_ROOT_ITEM_KEY as u8,
+    );
+    match tree_search::for_each(path, &range, |_| false) {
+        Ok(()) => Ok(PrivilegeLevel::Admin),
+        Err(err) if err.errno() == Some(libc::EPERM) => Ok(if effective {
+            PrivilegeLevel::Namespaced
+        } else {
+            PrivilegeLevel::Unprivileged
+        }),
+        Err(err) => Err(err),
+    }
+}
+
+/// What an operation requires to succeed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Privileges {
+    /// Capabilities always required.
+    pub capabilities: &'static [Capability],
+    /// Capabilities required only in some cases, such as with some options or on files owned by
+    /// other users.
+    pub sometimes: &'static [Capability],
+    /// Mount options that lift the need for [capabilities](#structfield.capabilities).
+    pub unless_mounted_with: &'static [&'static str],
+}
+
+impl Privileges {
+    /// No privileges beyond regular file permissions.
+    pub const NONE: Privileges = Privileges {
+        capabilities: &[],
+        sometimes: &[],
+        unless_mounted_with: &[],
+    };
+
+    const SYS_ADMIN: Privileges = Privileges {
+        capabilities: &[Capability::SysAdmin],
+        sometimes: &[],
+        unless_mounted_with: &[],
+    };
+
+    /// Check whether the operation can run without any capability.
+    pub fn is_unprivileged(&self) -> bool {
+        self.capabilities.is_empty() && self.sometimes.is_empty()
+    }
+
+    /// Check whether the current thread has every capability always required.
+    ///
+    /// Mount options are not looked at, so this may be false for an operation that would still
+    /// succeed.
+    pub fn check(&self) -> Result<bool> {
+        for cap in self.capabilities {
+            if !cap.is_effective()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// The operations of this library.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Operation {
+    /// [Subvolume::get](../subvolume/struct.Subvolume.html#method.get)
+    SubvolumeGet,
+    /// [Subvolume::get_anyway](../subvolume/struct.Subvolume.html#method.get_anyway)
+    SubvolumeGetAnyway,
+    /// [Subvolume::create](../subvolume/struct.Subvolume.html#method.create)
+    SubvolumeCreate,
+    /// [AdminSubvolume::delete](../subvolume/struct.AdminSubvolume.html#method.delete)
+    SubvolumeDelete,
+    /// [AdminSubvolume::delete_by_id](../subvolume/struct.AdminSubvolume.html#method.delete_by_id)
+    SubvolumeDeleteById,
+    /// [AdminSubvolume::deleted](../subvolume/struct.AdminSubvolume.html#method.deleted) and
+    /// [Subvolume::wait_for_cleanup](../subvolume/struct.Subvolume.html#method.wait_for_cleanup)
+    SubvolumeDeleted,
+    /// [Subvolume::get_default](../subvolume/struct.Subvolume.html#method.get_default)
+    SubvolumeGetDefault,
+    /// [AdminSubvolume::set_default](../subvolume/struct.AdminSubvolume.html#method.set_default) and
+    /// [Filesystem::set_default_by_path](../fs/struct.Filesystem.html#method.set_default_by_path)
+    SubvolumeSetDefault,
+    /// [Subvolume::is_ro](../subvolume/struct.Subvolume.html#method.is_ro)
+    SubvolumeIsRo,
+    /// [AdminSubvolume::set_ro](../subvolume/struct.AdminSubvolume.html#method.set_ro) and
+    /// [AdminSubvolume::ro_guard](../subvolume/struct.AdminSubvolume.html#method.ro_guard)
+    SubvolumeSetRo,
+    /// [Subvolume::info](../subvolume/struct.Subvolume.html#method.info) and
+    /// [Subvolume::changed_since](../subvolume/struct.Subvolume.html#method.changed_since)
+    SubvolumeInfo,
+    /// [Subvolume::parent](../subvolume/struct.Subvolume.html#method.parent)
+    SubvolumeParent,
+    /// [Subvolume::rel_path](../subvolume/struct.Subvolume.html#method.rel_path) and
+    /// [Subvolume::mount_points](../subvolume/struct.Subvolume.html#method.mount_points)
+    SubvolumePath,
+    /// [Subvolume::find_received](../subvolume/struct.Subvolume.html#method.find_received)
+    SubvolumeFindReceived,
+    /// [AdminSubvolume::set_received_uuid](../subvolume/struct.AdminSubvolume.html#method.set_received_uuid)
+    SubvolumeSetReceived,
+    /// [Subvolume::snapshot](../subvolume/struct.Subvolume.html#method.snapshot)
+    SubvolumeSnapshot,
+    /// [SubvolumeIterator](../subvolume/struct.SubvolumeIterator.html), always privileged when
+    /// created with a [top](../subvolume/struct.SubvolumeIterator.html#method.with_top),
+    /// [SubvolumeIterator::par_collect_info](../subvolume/struct.SubvolumeIterator.html#method.par_collect_info),
+    /// [SubvolumeTree::build](../subvolume/struct.SubvolumeTree.html#method.build),
+    /// [Subvolume::children](../subvolume/struct.Subvolume.html#method.children) and
+    /// [Subvolume::snapshots](../subvolume/struct.Subvolume.html#method.snapshots)
+    SubvolumeIterate,
+    /// [Subvolume::changed_files_since](../subvolume/struct.Subvolume.html#method.changed_files_since)
+    SubvolumeChangedFiles,
+    /// The [sync](../sync/index.html) functions
+    Sync,
+    /// [fs::list_mounted](../fs/fn.list_mounted.html)
+    ListMounted,
+    /// [mount::mount_subvolume](../mount/fn.mount_subvolume.html)
+    MountSubvolume,
+    /// [fs::add_device](../fs/fn.add_device.html)
+    AddDevice,
+    /// [fs::remove_device](../fs/fn.remove_device.html)
+    RemoveDevice,
+    /// [fs::replace_device](../fs/fn.replace_device.html),
+    /// [fs::replace_status](../fs/fn.replace_status.html) and
+    /// [fs::cancel_replace](../fs/fn.cancel_replace.html)
+    ReplaceDevice,
+    /// [fs::resize](../fs/fn.resize.html)
+    Resize,
+    /// [fs::trim](../fs/fn.trim.html)
+    Trim,
+    /// The [balance](../balance/index.html) functions
+    Balance,
+    /// The [scrub](../scrub/index.html) functions
+    Scrub,
+    /// The [verify](../verify/index.html) functions
+    VerifyChecksums,
+    /// [restore::files](../restore/fn.files.html) and
+    /// [restore::files_idempotent](../restore/fn.files_idempotent.html)
+    RestoreFiles,
+    /// [history::of](../history/fn.of.html)
+    History,
+    /// [analytics::churn](../analytics/fn.churn.html)
+    Churn,
+    /// [tree_search::for_each](../tree_search/fn.for_each.html)
+    TreeSearch,
+    /// The [resolve](../resolve/index.html) functions
+    Resolve,
+    /// The [idempotency](../idempotency/index.html) functions
+    IdempotencyKeys,
+    /// The [bootenv::ab](../bootenv/ab/index.html) functions marking roots
+    BootMarks,
+    /// [bootenv::ab::perform_rollback](../bootenv/ab/fn.perform_rollback.html)
+    BootRollback,
+    /// [diff::snapshots](../diff/fn.snapshots.html)
+    Diff,
+    /// The [properties](../properties/index.html) getters
+    GetProperty,
+    /// The [properties](../properties/index.html) setters
+    SetProperty,
+    /// The [snapshots](../snapshots/index.html) functions
+    SnapshotSet,
+    /// The [reflink](../reflink/index.html) functions
+    Reflink,
+    /// [dedupe::dedupe_ranges](../dedupe/fn.dedupe_ranges.html)
+    Dedupe,
+    /// [analytics::fragmentation_score](../analytics/fn.fragmentation_score.html) and
+    /// [maintenance::defrag_candidates](../maintenance/fn.defrag_candidates.html)
+    Fragmentation,
+    /// [maintenance::defrag](../maintenance/fn.defrag.html)
+    Defrag,
+    /// [extents::map](../extents/fn.map.html)
+    ExtentMap,
+    /// [support::bundle](../support/fn.bundle.html)
+    SupportBundle,
+    /// [stats::compression](../stats/fn.compression.html)
+    CompressionStats,
+    /// [stats::shared_between](../stats/fn.shared_between.html)
+    SharedStats,
+    /// [qgroup::usage](../qgroup/fn.usage.html) and
+    /// [Subvolume::usage](../subvolume/struct.Subvolume.html#method.usage)
+    QgroupUsage,
+    /// [report::filesystem](../report/fn.filesystem.html)
+    Report,
+    /// The [rollback](../rollback/index.html) plans
+    Rollback,
+    /// [RetentionPlan::execute](../retention/struct.RetentionPlan.html#method.execute) and
+    /// [SnapshotManager::prune](../manager/struct.SnapshotManager.html#method.prune)
+    Prune,
+    /// The [backup](../backup/index.html) pipelines
+    Backup,
+    /// [Layout::apply](../layout/struct.Layout.html#method.apply)
+    ApplyLayout,
+}
+
+impl Operation {
+    /// Every operation.
+    pub const ALL: &'static [Operation] = &[
+        Operation::SubvolumeGet,
+        Operation::SubvolumeGetAnyway,
+        Operation::SubvolumeCreate,
+        Operation::SubvolumeDelete,
+        Operation::SubvolumeDeleteById,
+        Operation::SubvolumeDeleted,
+        Operation::SubvolumeGetDefault,
+        Operation::SubvolumeSetDefault,
+        Operation::SubvolumeIsRo,
+        Operation::SubvolumeSetRo,
+        Operation::SubvolumeInfo,
+        Operation::SubvolumeParent,
+        Operation::SubvolumePath,
+        Operation::SubvolumeFindReceived,
+        Operation::SubvolumeSetReceived,
+        Operation::SubvolumeSnapshot,
+        Operation::SubvolumeIterate,
+        Operation::SubvolumeChangedFiles,
+        Operation::Sync,
+        Operation::ListMounted,
+        Operation::MountSubvolume,
+        Operation::AddDevice,
+        Operation::RemoveDevice,
+        Operation::ReplaceDevice,
+        Operation::Resize,
+        Operation::Trim,
+        Operation::Balance,
+        Operation::Scrub,
+        Operation::VerifyChecksums,
+        Operation::RestoreFiles,
+        Operation::History,
+        Operation::Churn,
+        Operation::TreeSearch,
+        Operation::Resolve,
+        Operation::IdempotencyKeys,
+        Operation::BootMarks,
+        Operation::BootRollback,
+        Operation::Diff,
+        Operation::GetProperty,
+        Operation::SetProperty,
+        Operation::SnapshotSet,
+        Operation::Reflink,
+        Operation::Dedupe,
+        Operation::Fragmentation,
+        Operation::Defrag,
+        Operation::ExtentMap,
+        Operation::SupportBundle,
+        Operation::CompressionStats,
+        Operation::SharedStats,
+        Operation::QgroupUsage,
+        Operation::Report,
+        Operation::Rollback,
+        Operation::Prune,
+        Operation::Backup,
+        Operation::ApplyLayout,
+    ];
+
+    /// Get what the operation requires to succeed, beyond regular file permissions.
+    pub fn required_privileges(self) -> Privileges {
+        match self {
+            Operation::SubvolumeGet
+            | Operation::SubvolumeCreate
+            | Operation::SubvolumeIsRo
+            | Operation::SubvolumeSnapshot
+            | Operation::SnapshotSet
+            | Operation::ListMounted
+            | Operation::History
+            | Operation::IdempotencyKeys
+            | Operation::BootMarks
+            | Operation::GetProperty
+            | Operation::Reflink
+            | Operation::Dedupe
+            | Operation::Fragmentation
+            | Operation::ExtentMap => Privileges::NONE,
+            // libbtrfsutil only searches the trees directly when running as root
+            Operation::SubvolumeInfo | Operation::SubvolumeIterate | Operation::SupportBundle => {
+                Privileges {
+                    capabilities: &[],
+                    sometimes: &[Capability::SysAdmin],
+                    unless_mounted_with: &[],
+                }
+            }
+            // only files the caller cannot write to need it
+            Operation::Defrag => Privileges {
+                capabilities: &[],
+                sometimes: &[Capability::SysAdmin],
+                unless_mounted_with: &[],
+            },
+            // without it, the subvolume root is looked for among the parent directories
+            Operation::SubvolumeGetAnyway => Privileges {
+                capabilities: &[],
+                sometimes: &[Capability::SysAdmin],
+                unless_mounted_with: &[],
+            },
+            // ro and label need it, compression does not
+            Operation::SetProperty => Privileges {
+                capabilities: &[],
+                sometimes: &[Capability::SysAdmin],
+                unless_mounted_with: &[],
+            },
+            // the owner of the subvolume does not need it
+            Operation::SubvolumeSetReceived => Privileges {
+                capabilities: &[],
+                sometimes: &[Capability::SysAdmin],
+                unless_mounted_with: &[],
+            },
+            // only waiting with a timeout needs it, to look up the last committed transaction
+            Operation::Sync => Privileges {
+                capabilities: &[],
+                sometimes: &[Capability::SysAdmin],
+                unless_mounted_with: &[],
+            },
+            // only setting the default subvolume needs it
+            Operation::ApplyLayout => Privileges {
+                capabilities: &[],
+                sometimes: &[Capability::SysAdmin],
+                unless_mounted_with: &[],
+            },
+            Operation::SubvolumeDelete | Operation::Prune => Privileges {
+                capabilities: &[Capability::SysAdmin],
+                sometimes: &[],
+                unless_mounted_with: &["user_subvol_rm_allowed"],
+            },
+            Operation::RestoreFiles => Privileges {
+                capabilities: &[],
+                sometimes: &[Capability::Chown],
+                unless_mounted_with: &[],
+            },
+            Operation::SubvolumeDeleteById
+            | Operation::SubvolumeDeleted
+            | Operation::SubvolumeGetDefault
+            | Operation::SubvolumeSetDefault
+            | Operation::SubvolumeSetRo
+            | Operation::SubvolumeParent
+            | Operation::SubvolumePath
+            | Operation::SubvolumeFindReceived
+            | Operation::SubvolumeChangedFiles
+            | Operation::MountSubvolume
+            | Operation::AddDevice
+            | Operation::RemoveDevice
+            | Operation::ReplaceDevice
+            | Operation::Resize
+            | Operation::Trim
+            | Operation::Balance
+            | Operation::Scrub
+            | Operation::VerifyChecksums
+            | Operation::Churn
+            | Operation::TreeSearch
+            | Operation::Resolve
+            | Operation::BootRollback
+            | Operation::Diff
+            | Operation::QgroupUsage
+            | Operation::CompressionStats
+            | Operation::SharedStats
+            | Operation::Report
+            | Operation::Rollback
+            | Operation::Backup => Privileges::SYS_ADMIN,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn operation_privileges() {
+        assert!(Operation::SubvolumeCreate
+            .required_privileges()
+            .is_unprivileged());
+        assert!(!Operation::Balance.required_privileges().is_unprivileged());
+        assert_eq!(
+            Operation::SubvolumeDelete
+                .required_privileges()
+                .unless_mounted_with,
+            &["user_subvol_rm_allowed"]
+        );
+    }
+
+    #[test]
+    fn level_allows() {
+        assert!(PrivilegeLevel::Unprivileged.allows(Operation::SubvolumeCreate));
+        assert!(!PrivilegeLevel::Unprivileged.allows(Operation::SubvolumeDelete));
+        assert!(PrivilegeLevel::Namespaced.allows(Operation::SubvolumeInfo));
+        assert!(!PrivilegeLevel::Namespaced.allows(Operation::SubvolumeSetDefault));
+        assert!(PrivilegeLevel::Admin.allows(Operation::SubvolumeSetDefault));
+    }
+}
@@ -0,0 +1,212 @@
+//! Btrfs properties
+//!
+//! Typed access to the properties `btrfs property` manages:
+//!
+//! * `ro`, on subvolumes, through the subvolume flags
+//! * `compression`, on files and directories, through the `btrfs.compression` extended attribute
+//! * `label`, on the filesystem, through `FS_IOC_GETFSLABEL` and `FS_IOC_SETFSLABEL`
+
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::ioctl;
+use crate::subvolume::Subvolume;
+use crate::xattr;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::fmt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Name of the extended attribute holding the compression property.
+pub const COMPRESSION_XATTR_NAME: &str = "btrfs.compression";
+
+/// Value of the compression property.
+///
+/// Levels are stored as `algorithm:level`; kernels that do not support per-file levels use the
+/// default level of the algorithm.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Compression {
+    /// Never compress, even if the filesystem is mounted with compression.
+    Disabled,
+    /// zlib, with an optional level from 1 to 9.
+    Zlib(Option<u8>),
+    /// LZO.
+    Lzo,
+    /// Zstandard, with an optional level from 1 to 15.
+    Zstd(Option<u8>),
+}
+
+impl fmt::Display for Compression {
+    /// Format the value as `btrfs property` does.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Disabled => write!(f, "no"),
+            Compression::Zlib(None) => write!(f, "zlib"),
+            Compression::Zlib(Some(level)) => write!(f, "zlib:{}", level),
+            Compression::Lzo => write!(f, "lzo"),
+            Compression::Zstd(None) => write!(f, "zstd"),
+            Compression::Zstd(Some(level)) => write!(f, "zstd:{}", level),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = BtrfsUtilError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (algorithm, level) = match s.split_once(':') {
+            Some((algorithm, level)) => (
+                algorithm,
+                Some(level.parse().map_err(|_| LibError::InvalidArgument)?),
+            ),
+            None => (s, None),
+        };
+        match (algorithm, level) {
+            ("no" | "none", None) => Ok(Compression::Disabled),
+            ("zlib", Some(1..=9) | None) => Ok(Compression::Zlib(level)),
+            ("lzo", None) => Ok(Compression::Lzo),
+            ("zstd", Some(1..=15) | None) => Ok(Compression::Zstd(level)),
+            _ => Err(LibError::InvalidArgument.into()),
+        }
+    }
+}
+
+/// Check whether a subvolume is read-only.
+pub fn get_ro<P>(path: P) -> Result<bool>
+where
+    P: AsRef<Path>,
+{
+    Subvolume::get(path)?.is_ro()
+}
+
+/// Set whether a subvolume is read-only.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn set_ro<P>(path: P, ro: bool) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    Subvolume::get(path)?.elevate()?.set_ro(ro)
+}
+
+/// Get the compression property of a file or directory, or None if it is not set and the
+/// filesystem-wide setting applies.
+pub fn get_compression<P>(path: P) -> Result<Option<Compression>>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    get_compression_impl(path).context("properties::get_compression", &[path])
+}
+
+fn get_compression_impl(path: &Path) -> Result<Option<Compression>> {
+    match xattr::get_string(path, COMPRESSION_XATTR_NAME)? {
+        Some(val) if !val.is_empty() => val.parse().map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Set the compression property of a file or directory, or reset it with None.
+///
+/// It applies to data written afterwards; for directories, it is inherited by files created in
+/// them.
+pub fn set_compression<P, C>(path: P, compression: C) -> Result<()>
+where
+    P: AsRef<Path>,
+    C: Into<Option<Compression>>,
+{
+    let path = path.as_ref();
+    set_compression_impl(path, compression.into()).context("properties::set_compression", &[path])
+}
+
+fn set_compression_impl(path: &Path, compression: Option<Compression>) -> Result<()> {
+    match compression {
+        Some(val) => xattr::set(path, COMPRESSION_XATTR_NAME, val.to_string().as_bytes()),
+        None => xattr::remove(path, COMPRESSION_XATTR_NAME),
+    }
+}
+
+/// Get the label of the filesystem a path belongs to.
+pub fn get_label<P>(path: P) -> Result<String>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    get_label_impl(path).context("properties::get_label", &[path])
+}
+
+fn get_label_impl(path: &Path) -> Result<String> {
+    let file = ioctl::open(path)?;
+    let mut buf = [0u8; ioctl::FSLABEL_MAX];
+
+    unsafe {
+        ioctl::ioctl(
+            file.as_raw_fd(),
+            ioctl::FS_IOC_GETFSLABEL,
+            buf.as_mut_ptr(),
+            LibError::LabelFailed,
+        )?;
+    }
+
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).map_err(|_| LibError::LabelFailed.into())
+}
+
+/// Set the label of the filesystem a path belongs to.
+///
+/// Fails with [LibError::InvalidArgument] if the label is 256 bytes or longer or contains a nul
+/// byte.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+///
+/// [LibError::InvalidArgument]: ../error/enum.LibError.html#variant.InvalidArgument
+pub fn set_label<P>(path: P, label: &str) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    set_label_impl(path, label).context("properties::set_label", &[path])
+}
+
+fn set_label_impl(path: &Path, label: &str) -> Result<()> {
+    if label.len() >= ioctl::FSLABEL_MAX || label.contains('\0') {
+        return Err(LibError::InvalidArgument.into());
+    }
+
+    let file = ioctl::open(path)?;
+    let mut buf = [0u8; ioctl::FSLABEL_MAX];
+    buf[..label.len()].copy_from_slice(label.as_bytes());
+
+    unsafe {
+        ioctl::ioctl(
+            file.as_raw_fd(),
+            ioctl::FS_IOC_SETFSLABEL,
+            buf.as_mut_ptr(),
+            LibError::LabelFailed,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compression_roundtrip() {
+        for val in &[
+            Compression::Disabled,
+            Compression::Zlib(None),
+            Compression::Zlib(Some(9)),
+            Compression::Lzo,
+            Compression::Zstd(None),
+            Compression::Zstd(Some(3)),
+        ] {
+            assert_eq!(val.to_string().parse::<Compression>().unwrap(), *val);
+        }
+        assert!("zstd:0".parse::<Compression>().is_err());
+        assert!("lzo:1".parse::<Compression>().is_err());
+        assert!("brotli".parse::<Compression>().is_err());
+    }
+}
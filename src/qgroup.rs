@@ -1,32 +1,28 @@
 //! Btrfs quota groups
 
-use crate::Result;
+use crate::error::ResultExt;
+use crate::ioctl;
+use crate::tree_search;
+use crate::tree_search::SearchRange;
+pub use crate::Result;
 
-use btrfsutil_sys::btrfs_util_create_qgroup_inherit;
-use btrfsutil_sys::btrfs_util_destroy_qgroup_inherit;
-use btrfsutil_sys::btrfs_util_qgroup_inherit;
-use btrfsutil_sys::btrfs_util_qgroup_inherit_add_group;
-use btrfsutil_sys::btrfs_util_qgroup_inherit_get_groups;
-
-use libc::c_void;
-use libc::free;
+use std::path::Path;
 
 /// Qgroup inheritance specifier.
 ///
-/// Wrapper around [btrfs_util_qgroup_inherit].
+/// The quota groups a new subvolume or snapshot is added to, as held by
+/// [btrfs_util_qgroup_inherit].
 ///
-/// [btrfs_util_qgroup_inherit]: ../bindings/struct.btrfs_util_qgroup_inherit.html
-#[derive(Clone, Debug)]
-pub struct QgroupInherit(*mut btrfs_util_qgroup_inherit);
+/// [btrfs_util_qgroup_inherit]: https://docs.rs/btrfsutil-sys/1.2.1/btrfsutil_sys/struct.btrfs_util_qgroup_inherit.html
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct QgroupInherit {
+    groups: Vec<u64>,
+}
 
 impl QgroupInherit {
     /// Create a quota group inheritance specifier.
     pub fn create() -> Result<Self> {
-        let mut qgroup_ptr: *mut btrfs_util_qgroup_inherit = std::ptr::null_mut();
-
-        unsafe_wrapper!({ btrfs_util_create_qgroup_inherit(0, &mut qgroup_ptr) })?;
-
-        Ok(Self(qgroup_ptr))
+        Ok(Self::default())
     }
 
     /// Add inheritance from a qgroup to a qgroup inheritance specifier.
@@ -34,59 +30,109 @@ impl QgroupInherit {
     where
         U: Into<u64>,
     {
-        self.add_impl(qgroup_id.into())
-    }
-
-    fn add_impl(&mut self, qgroup_id: u64) -> Result<()> {
-        let qgroup_ptr_initial: *mut btrfs_util_qgroup_inherit = self.as_ptr();
-        let mut qgroup_ptr: *mut btrfs_util_qgroup_inherit = self.as_ptr();
-
-        unsafe_wrapper!({ btrfs_util_qgroup_inherit_add_group(&mut qgroup_ptr, qgroup_id) })?;
-
-        if qgroup_ptr != qgroup_ptr_initial {
-            self.0 = qgroup_ptr;
-        }
-
+        self.groups.push(qgroup_id.into());
         Ok(())
     }
 
     /// Get the qgroup ids contained by this inheritance specifier.
     pub fn get_groups(&self) -> Result<Vec<u64>> {
-        let qgroup_ptr: *const btrfs_util_qgroup_inherit = self.as_ptr();
-        let mut qgroup_ids_ptr: *const u64 = std::ptr::null();
-        let mut qgroup_ids_count: usize = 0;
+        Ok(self.groups.clone())
+    }
 
-        unsafe {
-            btrfs_util_qgroup_inherit_get_groups(
-                qgroup_ptr,
-                &mut qgroup_ids_ptr,
-                &mut qgroup_ids_count,
-            );
-        }
+    /// Get the qgroup ids, as passed to the kernel.
+    #[inline]
+    pub(crate) fn groups(&self) -> &[u64] {
+        &self.groups
+    }
+}
 
-        if qgroup_ids_count == 0 {
-            return Ok(Vec::new());
-        }
+/// Space accounted to a quota group, as of the last commit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QgroupUsage {
+    /// Id of the qgroup, with the level in the upper 16 bits.
+    pub qgroupid: u64,
+    /// Bytes referenced by the qgroup.
+    pub referenced: u64,
+    /// Bytes referenced by the qgroup, as stored on disk after compression.
+    pub referenced_compressed: u64,
+    /// Bytes referenced by the qgroup only, which deleting everything in it would free.
+    pub exclusive: u64,
+    /// Bytes referenced by the qgroup only, as stored on disk after compression.
+    pub exclusive_compressed: u64,
+}
 
-        let ids: Vec<u64> = {
-            let slice = unsafe { std::slice::from_raw_parts(qgroup_ids_ptr, qgroup_ids_count) };
-            let vec = slice.to_vec();
-            unsafe { free(qgroup_ids_ptr as *mut c_void) };
-            vec
-        };
-        Ok(ids)
+impl QgroupUsage {
+    /// Get the level of the qgroup, zero for the qgroups of subvolumes.
+    #[inline]
+    pub fn level(&self) -> u16 {
+        (self.qgroupid >> 48) as u16
     }
 
+    /// Get the id of the qgroup within its level, the subvolume id for level zero.
     #[inline]
-    pub(crate) fn as_ptr(&self) -> *mut btrfs_util_qgroup_inherit {
-        self.0
+    pub fn id(&self) -> u64 {
+        self.qgroupid & ((1 << 48) - 1)
     }
 }
 
-impl Drop for QgroupInherit {
-    fn drop(&mut self) {
-        unsafe {
-            btrfs_util_destroy_qgroup_inherit(self.0);
-        }
+/// Get the usage of all quota groups of a filesystem, or `None` if quotas are not enabled.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn usage<P>(fs_root: P) -> Result<Option<Vec<QgroupUsage>>>
+where
+    P: AsRef<Path>,
+{
+    let fs_root = fs_root.as_ref();
+    usage_impl(fs_root).context("qgroup::usage", &[fs_root])
+}
+
+fn usage_impl(fs_root: &Path) -> Result<Option<Vec<QgroupUsage>>> {
+    // the quota tree only exists while quotas are enabled, and searching it fails otherwise
+    let mut enabled = false;
+    tree_search::for_each(
+        fs_root,
+        &SearchRange::object(
+            tree_search::ROOT_TREE_OBJECTID,
+            tree_search::QUOTA_TREE_OBJECTID,
+            ioctl::BTRFS_ROOT_ITEM_KEY as u8,
+        ),
+        |_| {
+            enabled = true;
+            false
+        },
+    )?;
+    if !enabled {
+        return Ok(None);
     }
+
+    let mut usage = Vec::new();
+    tree_search::for_each(
+        fs_root,
+        &SearchRange::object(
+            tree_search::QUOTA_TREE_OBJECTID,
+            0,
+            ioctl::BTRFS_QGROUP_INFO_KEY as u8,
+        ),
+        |item| {
+            // generation, rfer, rfer_cmpr, excl, excl_cmpr
+            if let (Some(rfer), Some(rfer_cmpr), Some(excl), Some(excl_cmpr)) = (
+                item.le_u64(8),
+                item.le_u64(16),
+                item.le_u64(24),
+                item.le_u64(32),
+            ) {
+                usage.push(QgroupUsage {
+                    qgroupid: item.key().offset,
+                    referenced: rfer,
+                    referenced_compressed: rfer_cmpr,
+                    exclusive: excl,
+                    exclusive_compressed: excl_cmpr,
+                });
+            }
+            true
+        },
+    )?;
+
+    Ok(Some(usage))
 }
@@ -0,0 +1,181 @@
+use crate::error::GlueError;
+use crate::BtrfsUtilError;
+use crate::Result;
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// A UUID, as the 16 bytes Btrfs stores for subvolumes and filesystems.
+///
+/// Displayed hyphenated, like `4b0e2f5c-5d1a-4c8e-9b6a-3f1e2d4c5b6a`, and as 32 hex digits with
+/// the `{:x}` and `{:X}` formats. With the `uuid` feature, it converts to and from a
+/// [uuid::Uuid].
+///
+/// [uuid::Uuid]: https://docs.rs/uuid/0.8.1/uuid/struct.Uuid.html
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "String", into = "String")
+)]
+pub struct RawUuid(pub [u8; 16]);
+
+impl RawUuid {
+    /// The nil UUID, all zeroes, which Btrfs stores for UUIDs that are not set.
+    pub const NIL: Self = Self([0; 16]);
+
+    /// Create a UUID from its bytes.
+    #[inline]
+    pub const fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+
+    /// Get the bytes of the UUID.
+    #[inline]
+    pub const fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    /// Check whether this is the nil UUID.
+    #[inline]
+    pub fn is_nil(&self) -> bool {
+        *self == Self::NIL
+    }
+
+    /// Parse a UUID, hyphenated or as 32 hex digits.
+    pub fn parse(s: &str) -> Result<Self> {
+        let bad = || BtrfsUtilError::from(GlueError::BadUuid(s.to_owned()));
+        let digits: Vec<u8> = match s.len() {
+            32 => s.bytes().collect(),
+            36 if [8, 13, 18, 23].iter().all(|i| s.as_bytes()[*i] == b'-') => {
+                s.bytes().filter(|b| *b != b'-').collect()
+            }
+            _ => return Err(bad()),
+        };
+        if digits.len() != 32 || !digits.iter().all(u8::is_ascii_hexdigit) {
+            return Err(bad());
+        }
+
+        let mut bytes = [0; 16];
+        for (byte, pair) in bytes.iter_mut().zip(digits.chunks_exact(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| bad())?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| bad())?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl fmt::Display for RawUuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if matches!(i, 4 | 6 | 8 | 10) {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::LowerHex for RawUuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+
+impl fmt::UpperHex for RawUuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{:02X}", byte))
+    }
+}
+
+impl FromStr for RawUuid {
+    type Err = BtrfsUtilError;
+
+    /// Same as [RawUuid::parse].
+    ///
+    /// [RawUuid::parse]: struct.RawUuid.html#method.parse
+    #[inline]
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+
+impl From<[u8; 16]> for RawUuid {
+    #[inline]
+    fn from(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<RawUuid> for [u8; 16] {
+    #[inline]
+    fn from(uuid: RawUuid) -> Self {
+        uuid.0
+    }
+}
+
+impl From<RawUuid> for String {
+    #[inline]
+    fn from(uuid: RawUuid) -> Self {
+        uuid.to_string()
+    }
+}
+
+impl TryFrom<String> for RawUuid {
+    type Error = BtrfsUtilError;
+
+    #[inline]
+    fn try_from(s: String) -> Result<Self> {
+        Self::parse(&s)
+    }
+}
+
+#[cfg(feature = "uuid")]
+#[cfg_attr(docsrs, doc(cfg(feature = "uuid")))]
+impl From<uuid::Uuid> for RawUuid {
+    #[inline]
+    fn from(uuid: uuid::Uuid) -> Self {
+        Self(*uuid.as_bytes())
+    }
+}
+
+#[cfg(feature = "uuid")]
+#[cfg_attr(docsrs, doc(cfg(feature = "uuid")))]
+impl From<RawUuid> for uuid::Uuid {
+    #[inline]
+    fn from(uuid: RawUuid) -> Self {
+        uuid::Uuid::from_bytes(uuid.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn uuid_formats() {
+        let uuid = RawUuid::from_bytes(0x4b0e2f5c_5d1a_4c8e_9b6a_3f1e2d4c5b6a_u128.to_be_bytes());
+        let hyphenated = "4b0e2f5c-5d1a-4c8e-9b6a-3f1e2d4c5b6a";
+
+        assert_eq!(uuid.to_string(), hyphenated);
+        assert_eq!(format!("{:x}", uuid), "4b0e2f5c5d1a4c8e9b6a3f1e2d4c5b6a");
+        assert_eq!(format!("{:X}", uuid), "4B0E2F5C5D1A4C8E9B6A3F1E2D4C5B6A");
+        assert_eq!(hyphenated.parse::<RawUuid>().unwrap(), uuid);
+        assert_eq!(RawUuid::parse(&format!("{:X}", uuid)).unwrap(), uuid);
+        assert!(RawUuid::NIL.is_nil());
+
+        for bad in [
+            "",
+            "4b0e2f5c5d1a4c8e9b6a3f1e2d4c5b6",
+            "4b0e2f5c-5d1a-4c8e-9b6a3f1e2d4c5b6a0",
+        ] {
+            assert_eq!(
+                RawUuid::parse(bad),
+                Err(GlueError::BadUuid(bad.to_owned()).into())
+            );
+        }
+        assert!(RawUuid::parse("4b0e2f5c-5d1a-4c8e-9b6a-3f1e2d4c5b6g").is_err());
+    }
+}
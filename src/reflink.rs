@@ -0,0 +1,112 @@
+//! Reflink copies
+//!
+//! A reflink copy, of a whole file or of a range of it, shares the extents of its source instead
+//! of duplicating the data, so it is instant and takes no space until either file is modified.
+//! Both files must be on the same filesystem.
+
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::ioctl;
+use crate::ioctl::FileCloneRange;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// Make `dst` a reflink copy of the whole of `src`, creating it if needed.
+///
+/// Any previous contents of `dst` are replaced. A `dst` created here is removed again if the
+/// copy fails.
+pub fn clone_file<P, Q>(src: P, dst: Q) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let src = src.as_ref();
+    let dst = dst.as_ref();
+    clone_file_impl(src, dst).context("reflink::clone_file", &[src, dst])
+}
+
+fn clone_file_impl(src: &Path, dst: &Path) -> Result<()> {
+    let src_file =
+        File::open(src).map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?;
+    let existed = dst.symlink_metadata().is_ok();
+    let dst_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dst)
+        .map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?;
+
+    clone_fd(&src_file, &dst_file).inspect_err(|_| {
+        drop(dst_file);
+        if !existed {
+            let _ = fs::remove_file(dst);
+        }
+    })
+}
+
+/// Share `len` bytes of `src` starting at `src_offset` into `dst` at `dst_offset`, creating
+/// `dst` if needed.
+///
+/// A `len` of zero shares everything from `src_offset` to the end of `src`. Offsets and length
+/// must be multiples of the filesystem block size, except for a range ending at the end of
+/// `src`. The rest of `dst` is left untouched and it grows as needed.
+pub fn clone_range<P, Q>(src: P, src_offset: u64, len: u64, dst: Q, dst_offset: u64) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let src = src.as_ref();
+    let dst = dst.as_ref();
+    clone_range_impl(src, src_offset, len, dst, dst_offset)
+        .context("reflink::clone_range", &[src, dst])
+}
+
+fn clone_range_impl(
+    src: &Path,
+    src_offset: u64,
+    len: u64,
+    dst: &Path,
+    dst_offset: u64,
+) -> Result<()> {
+    let src_file =
+        File::open(src).map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?;
+    let dst_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(dst)
+        .map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?;
+
+    let mut args = FileCloneRange {
+        src_fd: src_file.as_raw_fd() as i64,
+        src_offset,
+        src_length: len,
+        dest_offset: dst_offset,
+    };
+
+    unsafe {
+        ioctl::ioctl(
+            dst_file.as_raw_fd(),
+            ioctl::FICLONERANGE,
+            &mut args,
+            LibError::CloneFailed,
+        )
+    }
+}
+
+/// Make an open file a reflink copy of another one.
+pub(crate) fn clone_fd(src: &File, dst: &File) -> Result<()> {
+    // the source descriptor is passed by value
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), ioctl::FICLONE as _, src.as_raw_fd()) };
+    if ret < 0 {
+        return Err(LibError::CloneFailed.into());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,136 @@
+//! Filesystem reports
+//!
+//! Aggregates the subvolumes of a filesystem, the snapshot relations between them and their
+//! quota usage into a single [FilesystemReport], so front-ends do not have to rebuild it from
+//! separate listings. With the `serde` feature enabled, reports can be serialized directly, for
+//! example as JSON output of a command line tool.
+//!
+//! [FilesystemReport]: struct.FilesystemReport.html
+
+use crate::error::ResultExt;
+use crate::qgroup;
+use crate::qgroup::QgroupUsage;
+use crate::subvolume::Subvolume;
+use crate::subvolume::SubvolumeId;
+use crate::subvolume::SubvolumeInfo;
+use crate::subvolume::SubvolumeIterator;
+pub use crate::Result;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// A report on a filesystem.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FilesystemReport {
+    /// The path the report was built from.
+    pub path: PathBuf,
+    /// Id of the default subvolume.
+    pub default_subvolume: SubvolumeId,
+    /// Whether quotas are enabled. If not, no subvolume has its usage reported.
+    pub quotas_enabled: bool,
+    /// The subvolume the path belongs to, followed by all subvolumes below it in iteration
+    /// order.
+    pub subvolumes: Vec<SubvolumeReport>,
+}
+
+/// A subvolume in a [FilesystemReport].
+///
+/// [FilesystemReport]: struct.FilesystemReport.html
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SubvolumeReport {
+    /// Information about the subvolume.
+    pub info: SubvolumeInfo,
+    /// Id of the subvolume this one is a snapshot of, if it is a snapshot and its source is
+    /// part of the report.
+    pub snapshot_of: Option<SubvolumeId>,
+    /// Ids of the snapshots of this subvolume in the report.
+    pub snapshots: Vec<SubvolumeId>,
+    /// Usage of the qgroup of the subvolume, if quotas are enabled.
+    pub usage: Option<QgroupUsage>,
+}
+
+impl FilesystemReport {
+    /// Get the report of a subvolume by its id.
+    pub fn subvolume(&self, id: SubvolumeId) -> Option<&SubvolumeReport> {
+        self.subvolumes.iter().find(|subvol| subvol.info.id == id)
+    }
+
+    /// Get the ids of the subvolumes a subvolume descends from through snapshots, nearest
+    /// first.
+    pub fn ancestry(&self, id: SubvolumeId) -> Vec<SubvolumeId> {
+        let mut ancestry = Vec::new();
+        let mut current = self.subvolume(id).and_then(|subvol| subvol.snapshot_of);
+        while let Some(id) = current {
+            // snapshot relations cannot form cycles, but a corrupted report could
+            if ancestry.contains(&id) {
+                break;
+            }
+            ancestry.push(id);
+            current = self.subvolume(id).and_then(|subvol| subvol.snapshot_of);
+        }
+        ancestry
+    }
+}
+
+/// Build a report on a filesystem from the subvolume a path belongs to, usually the top-level
+/// subvolume to cover the whole filesystem.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn filesystem<P>(path: P) -> Result<FilesystemReport>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    filesystem_impl(path).context("report::filesystem", &[path])
+}
+
+fn filesystem_impl(path: &Path) -> Result<FilesystemReport> {
+    let mut infos = vec![Subvolume::get(path)?.info()?];
+    for subvol in SubvolumeIterator::new(path, None)? {
+        infos.push(subvol?.info()?);
+    }
+
+    let usage = qgroup::usage(path)?;
+    let quotas_enabled = usage.is_some();
+    let mut usage: HashMap<u64, QgroupUsage> = usage
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|usage| usage.level() == 0)
+        .map(|usage| (usage.id(), usage))
+        .collect();
+
+    let by_uuid: HashMap<_, SubvolumeId> = infos.iter().map(|info| (info.uuid, info.id)).collect();
+    let mut subvolumes: Vec<SubvolumeReport> = infos
+        .into_iter()
+        .map(|info| SubvolumeReport {
+            snapshot_of: info
+                .parent_uuid
+                .and_then(|uuid| by_uuid.get(&uuid).copied()),
+            snapshots: Vec::new(),
+            usage: usage.remove(&info.id.get()),
+            info,
+        })
+        .collect();
+
+    let index: HashMap<SubvolumeId, usize> = subvolumes
+        .iter()
+        .enumerate()
+        .map(|(i, subvol)| (subvol.info.id, i))
+        .collect();
+    for i in 0..subvolumes.len() {
+        if let Some(source) = subvolumes[i].snapshot_of {
+            let id = subvolumes[i].info.id;
+            subvolumes[index[&source]].snapshots.push(id);
+        }
+    }
+
+    Ok(FilesystemReport {
+        path: path.to_path_buf(),
+        default_subvolume: Subvolume::get_default(path)?.id(),
+        quotas_enabled,
+        subvolumes,
+    })
+}
@@ -0,0 +1,163 @@
+//! Resolution of logical addresses and inodes
+//!
+//! Maps the logical addresses reported by scrub or the kernel log back to the inodes using
+//! them, and inodes to their paths.
+
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::ioctl;
+use crate::ioctl::DataContainer;
+use crate::ioctl::InoPathArgs;
+use crate::ioctl::LogicalInoArgs;
+pub use crate::Result;
+
+use std::ffi::CStr;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Initial size of the buffer for inode references.
+const LOGICAL_INO_BUF_SIZE: usize = 64 * 1024;
+/// Largest buffer `BTRFS_IOC_LOGICAL_INO_V2` accepts.
+const LOGICAL_INO_BUF_MAX: usize = 16 * 1024 * 1024;
+/// Size of the buffer for paths; the kernel does not fill more than this.
+const INO_PATHS_BUF_SIZE: usize = 4096;
+
+bitflags! {
+    /// Logical address resolution flags.
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct ResolveFlags: u64 {
+        /// Return every inode referencing the extent holding the address, not only those
+        /// referencing the address itself.
+        const IGNORE_OFFSET = ioctl::BTRFS_LOGICAL_INO_ARGS_IGNORE_OFFSET;
+    }
+}
+
+/// A reference from an inode to a logical address.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct InodeRef {
+    /// Inode number.
+    pub inode: u64,
+    /// Offset within the file.
+    pub offset: u64,
+    /// Id of the subvolume the inode belongs to.
+    pub root: u64,
+}
+
+/// Get the inodes referencing a logical address on the filesystem a path belongs to.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn logical_to_inodes<P, F>(path: P, logical: u64, flags: F) -> Result<Vec<InodeRef>>
+where
+    P: AsRef<Path>,
+    F: Into<Option<ResolveFlags>>,
+{
+    let path = path.as_ref();
+    logical_to_inodes_impl(path, logical, flags.into())
+        .context("resolve::logical_to_inodes", &[path])
+}
+
+fn logical_to_inodes_impl(
+    path: &Path,
+    logical: u64,
+    flags: Option<ResolveFlags>,
+) -> Result<Vec<InodeRef>> {
+    let file = ioctl::open(path)?;
+    let flags_val = flags.map(|v| v.bits()).unwrap_or(0);
+    let mut size = LOGICAL_INO_BUF_SIZE;
+
+    loop {
+        // u64 words keep the values aligned
+        let mut buf: Vec<u64> = vec![0; size / 8];
+        let mut args = LogicalInoArgs::new(logical, flags_val, &mut buf);
+
+        unsafe {
+            ioctl::ioctl(
+                file.as_raw_fd(),
+                ioctl::BTRFS_IOC_LOGICAL_INO_V2,
+                &mut args,
+                LibError::LogicalInoFailed,
+            )?;
+        }
+
+        let container = data_container(&buf);
+        if container.bytes_missing > 0 && size < LOGICAL_INO_BUF_MAX {
+            size = (size + container.bytes_missing as usize).min(LOGICAL_INO_BUF_MAX);
+            continue;
+        }
+
+        // values come in (inode, offset, root) triples
+        let values = values(&buf, &container);
+        return Ok(values
+            .chunks_exact(3)
+            .map(|triple| InodeRef {
+                inode: triple[0],
+                offset: triple[1],
+                root: triple[2],
+            })
+            .collect());
+    }
+}
+
+/// Get the paths of an inode of the subvolume a path belongs to.
+///
+/// Paths are relative to the root of the subvolume. Only as many paths as fit in 4 KiB are
+/// returned for inodes with many hard links.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn inode_to_paths<P>(path: P, inode: u64) -> Result<Vec<PathBuf>>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    inode_to_paths_impl(path, inode).context("resolve::inode_to_paths", &[path])
+}
+
+fn inode_to_paths_impl(path: &Path, inode: u64) -> Result<Vec<PathBuf>> {
+    let file = ioctl::open(path)?;
+
+    let mut buf: Vec<u64> = vec![0; INO_PATHS_BUF_SIZE / 8];
+    let mut args = InoPathArgs::new(inode, &mut buf);
+
+    unsafe {
+        ioctl::ioctl(
+            file.as_raw_fd(),
+            ioctl::BTRFS_IOC_INO_PATHS,
+            &mut args,
+            LibError::InoPathsFailed,
+        )?;
+    }
+
+    let container = data_container(&buf);
+    let bytes: &[u8] = unsafe {
+        std::slice::from_raw_parts(
+            (buf.as_ptr() as *const u8).add(ioctl::DATA_CONTAINER_SIZE),
+            INO_PATHS_BUF_SIZE - ioctl::DATA_CONTAINER_SIZE,
+        )
+    };
+
+    // values are offsets of nul-terminated paths, counted from the first value
+    values(&buf, &container)
+        .iter()
+        .map(|offset| {
+            let name = bytes
+                .get(*offset as usize..)
+                .and_then(|rest| CStr::from_bytes_until_nul(rest).ok())
+                .ok_or(LibError::InoPathsFailed)?;
+            Ok(PathBuf::from(OsStr::from_bytes(name.to_bytes())))
+        })
+        .collect()
+}
+
+fn data_container(buf: &[u64]) -> DataContainer {
+    unsafe { std::ptr::read(buf.as_ptr() as *const DataContainer) }
+}
+
+/// Get the values of a data container, as many as the kernel filled in.
+fn values<'a>(buf: &'a [u64], container: &DataContainer) -> &'a [u64] {
+    let start = ioctl::DATA_CONTAINER_SIZE / 8;
+    let count = (container.elem_cnt as usize).min(buf.len() - start);
+    &buf[start..start + count]
+}
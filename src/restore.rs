@@ -0,0 +1,256 @@
+//! Restoring files from snapshots
+//!
+//! Files are reflinked out of the snapshot, so restoring them is cheap and takes no extra space
+//! until either copy is modified. The snapshot and the destination must be on the same
+//! filesystem.
+
+use crate::common;
+use crate::error::BatchError;
+use crate::error::BatchResult;
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::idempotency;
+use crate::idempotency::IdempotencyKey;
+use crate::reflink;
+use crate::subvolume::Subvolume;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::fs;
+use std::fs::File;
+use std::fs::Metadata;
+use std::fs::OpenOptions;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Options for [files].
+///
+/// [files]: fn.files.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RestoreFileOptions {
+    /// Replace files already present at the destination. Directories are merged.
+    pub overwrite: bool,
+    /// Restore the owner and group, which requires **CAP_CHOWN** for files owned by others.
+    pub preserve_ownership: bool,
+    /// Restore the access and modification times.
+    pub preserve_times: bool,
+}
+
+impl Default for RestoreFileOptions {
+    fn default() -> Self {
+        Self {
+            overwrite: false,
+            preserve_ownership: true,
+            preserve_times: true,
+        }
+    }
+}
+
+/// Restore files and directories from a snapshot.
+///
+/// Each path is taken relative to the root of the snapshot, or may be an absolute path inside
+/// it, and is restored to the same relative path under `dest`, usually the root of the live
+/// subvolume the snapshot was taken of. Directories are restored with all their contents.
+///
+/// Every path is restored on its own; the result maps each of them to where it was restored.
+pub fn files<S, D>(
+    snapshot: &Subvolume,
+    paths: &[S],
+    dest: D,
+    options: RestoreFileOptions,
+) -> BatchResult<PathBuf, PathBuf>
+where
+    S: AsRef<Path>,
+    D: AsRef<Path>,
+{
+    let dest = dest.as_ref();
+    let mut batch = BatchError::new();
+
+    for path in paths {
+        let path = path.as_ref();
+        batch.push(
+            path.to_owned(),
+            restore_path(snapshot.path(), path, dest, &options, None)
+                .context("restore::files", &[snapshot.path(), path, dest]),
+        );
+    }
+
+    batch.into_result()
+}
+
+/// Restore files and directories from a snapshot, skipping those already restored with the same
+/// idempotency key, see [files].
+///
+/// The key is recorded for each path once it is restored, so a retry after an interruption only
+/// restores the paths that were not. A directory interrupted halfway is partly restored already
+/// and needs `overwrite` to be restored again.
+///
+/// [files]: fn.files.html
+pub fn files_idempotent<S, D>(
+    snapshot: &Subvolume,
+    paths: &[S],
+    dest: D,
+    options: RestoreFileOptions,
+    key: &IdempotencyKey,
+) -> BatchResult<PathBuf, PathBuf>
+where
+    S: AsRef<Path>,
+    D: AsRef<Path>,
+{
+    let dest = dest.as_ref();
+    let mut batch = BatchError::new();
+
+    for path in paths {
+        let path = path.as_ref();
+        batch.push(
+            path.to_owned(),
+            restore_path(snapshot.path(), path, dest, &options, Some(key))
+                .context("restore::files_idempotent", &[snapshot.path(), path, dest]),
+        );
+    }
+
+    batch.into_result()
+}
+
+fn restore_path(
+    snapshot: &Path,
+    path: &Path,
+    dest: &Path,
+    options: &RestoreFileOptions,
+    key: Option<&IdempotencyKey>,
+) -> Result<PathBuf> {
+    let rel = if path.is_absolute() {
+        path.strip_prefix(snapshot)
+            .map_err(|_| LibError::InvalidArgument)?
+    } else {
+        path
+    };
+    // anything else could escape the snapshot or the destination
+    if !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(LibError::InvalidArgument.into());
+    }
+
+    let src = snapshot.join(rel);
+    let dst = dest.join(rel);
+    if let Some(key) = key {
+        if common::path_exists(&dst)? && idempotency::get_impl(&dst)?.as_ref() == Some(key) {
+            return Ok(dst);
+        }
+    }
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|err| BtrfsUtilError::from_io(LibError::RestoreFailed, &err))?;
+    }
+
+    restore_entry(&src, &dst, options)?;
+    if let Some(key) = key {
+        idempotency::set_impl(&dst, key)?;
+    }
+
+    Ok(dst)
+}
+
+fn restore_entry(src: &Path, dst: &Path, options: &RestoreFileOptions) -> Result<()> {
+    let meta = src
+        .symlink_metadata()
+        .map_err(|err| BtrfsUtilError::from_io(LibError::StatFailed, &err))?;
+    let file_type = meta.file_type();
+
+    if let Ok(existing) = dst.symlink_metadata() {
+        let merge = file_type.is_dir() && existing.is_dir();
+        if !options.overwrite || (existing.is_dir() && !merge) {
+            return Err(LibError::RestoreFailed.into());
+        }
+        if !merge {
+            fs::remove_file(dst)
+                .map_err(|err| BtrfsUtilError::from_io(LibError::UnlinkFailed, &err))?;
+        }
+    }
+
+    if file_type.is_dir() {
+        if !dst.is_dir() {
+            fs::create_dir(dst)
+                .map_err(|err| BtrfsUtilError::from_io(LibError::RestoreFailed, &err))?;
+        }
+        for entry in
+            fs::read_dir(src).map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?
+        {
+            let entry =
+                entry.map_err(|err| BtrfsUtilError::from_io(LibError::RestoreFailed, &err))?;
+            restore_entry(&entry.path(), &dst.join(entry.file_name()), options)?;
+        }
+    } else if file_type.is_symlink() {
+        let target = fs::read_link(src)
+            .map_err(|err| BtrfsUtilError::from_io(LibError::RestoreFailed, &err))?;
+        std::os::unix::fs::symlink(target, dst)
+            .map_err(|err| BtrfsUtilError::from_io(LibError::RestoreFailed, &err))?;
+    } else if file_type.is_file() {
+        clone_file(src, dst)?;
+    } else {
+        // device nodes, fifos and sockets have no contents to restore
+        return Err(LibError::RestoreFailed.into());
+    }
+
+    // attributes go last so that restoring the contents does not touch the times
+    set_attributes(dst, &meta, options)
+}
+
+fn clone_file(src: &Path, dst: &Path) -> Result<()> {
+    let src_file =
+        File::open(src).map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?;
+    let dst_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dst)
+        .map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?;
+
+    reflink::clone_fd(&src_file, &dst_file).inspect_err(|_| {
+        drop(dst_file);
+        let _ = fs::remove_file(dst);
+    })
+}
+
+fn set_attributes(dst: &Path, meta: &Metadata, options: &RestoreFileOptions) -> Result<()> {
+    let path_cstr = common::path_to_cstr(dst);
+
+    if options.preserve_ownership
+        && unsafe { libc::lchown(path_cstr.as_ptr(), meta.uid(), meta.gid()) } < 0
+    {
+        return Err(BtrfsUtilError::last_os_error(LibError::RestoreFailed));
+    }
+
+    // symlinks have no permissions of their own
+    if !meta.file_type().is_symlink() {
+        fs::set_permissions(dst, fs::Permissions::from_mode(meta.mode()))
+            .map_err(|err| BtrfsUtilError::from_io(LibError::RestoreFailed, &err))?;
+    }
+
+    if options.preserve_times {
+        let times = [
+            libc::timespec {
+                tv_sec: meta.atime(),
+                tv_nsec: meta.atime_nsec(),
+            },
+            libc::timespec {
+                tv_sec: meta.mtime(),
+                tv_nsec: meta.mtime_nsec(),
+            },
+        ];
+        let ret = unsafe {
+            libc::utimensat(
+                libc::AT_FDCWD,
+                path_cstr.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if ret < 0 {
+            return Err(BtrfsUtilError::last_os_error(LibError::RestoreFailed));
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,228 @@
+//! Snapshot retention
+//!
+//! A [RetentionPolicy] declares which snapshots to keep: the most recent ones, the newest of
+//! each of the last hours, days, weeks, months and years that have snapshots, and all
+//! snapshots younger than a minimum age. [RetentionPolicy::plan] applies it to snapshots by
+//! their creation time, giving a [RetentionPlan] that can be inspected and then executed.
+//!
+//! A snapshot is kept if any rule keeps it; a policy keeping nothing deletes every snapshot.
+//!
+//! [RetentionPolicy]: struct.RetentionPolicy.html
+//! [RetentionPolicy::plan]: struct.RetentionPolicy.html#method.plan
+//! [RetentionPlan]: struct.RetentionPlan.html
+
+use crate::error::BatchError;
+use crate::error::BatchResult;
+use crate::subvolume::Subvolume;
+pub use crate::Result;
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::DateTime;
+use chrono::Datelike;
+use chrono::Local;
+use chrono::Timelike;
+
+/// Maps a time to the period it falls in, for one kind of period.
+type Bucket = fn(&DateTime<Local>) -> (i32, u32, u32);
+
+/// Which snapshots to keep.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RetentionPolicy {
+    last: usize,
+    hourly: usize,
+    daily: usize,
+    weekly: usize,
+    monthly: usize,
+    yearly: usize,
+    min_age: Duration,
+}
+
+/// The outcome of applying a [RetentionPolicy] to snapshots.
+///
+/// [RetentionPolicy]: struct.RetentionPolicy.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetentionPlan {
+    /// Snapshots to keep, newest first.
+    pub keep: Vec<Subvolume>,
+    /// Snapshots to delete, newest first.
+    pub delete: Vec<Subvolume>,
+}
+
+impl RetentionPolicy {
+    /// Create a policy keeping nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the `count` most recent snapshots.
+    pub fn keep_last(mut self, count: usize) -> Self {
+        self.last = count;
+        self
+    }
+
+    /// Keep the newest snapshot of each of the last `count` hours with snapshots.
+    pub fn hourly(mut self, count: usize) -> Self {
+        self.hourly = count;
+        self
+    }
+
+    /// Keep the newest snapshot of each of the last `count` days with snapshots.
+    pub fn daily(mut self, count: usize) -> Self {
+        self.daily = count;
+        self
+    }
+
+    /// Keep the newest snapshot of each of the last `count` ISO weeks with snapshots.
+    pub fn weekly(mut self, count: usize) -> Self {
+        self.weekly = count;
+        self
+    }
+
+    /// Keep the newest snapshot of each of the last `count` months with snapshots.
+    pub fn monthly(mut self, count: usize) -> Self {
+        self.monthly = count;
+        self
+    }
+
+    /// Keep the newest snapshot of each of the last `count` years with snapshots.
+    pub fn yearly(mut self, count: usize) -> Self {
+        self.yearly = count;
+        self
+    }
+
+    /// Keep all snapshots younger than `min_age`.
+    pub fn min_age(mut self, min_age: Duration) -> Self {
+        self.min_age = min_age;
+        self
+    }
+
+    /// Apply the policy to snapshots as of now, by their creation time.
+    pub fn plan(&self, snapshots: &[Subvolume]) -> Result<RetentionPlan> {
+        self.plan_at(snapshots, Local::now())
+    }
+
+    /// Apply the policy to snapshots as of a given time, by their creation time.
+    pub fn plan_at(&self, snapshots: &[Subvolume], now: DateTime<Local>) -> Result<RetentionPlan> {
+        let mut dated: Vec<(DateTime<Local>, &Subvolume)> = Vec::with_capacity(snapshots.len());
+        for snapshot in snapshots {
+            dated.push((snapshot.info()?.otime_local(), snapshot));
+        }
+        // newest first, ties broken by id so plans are stable
+        dated.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.id().cmp(&a.1.id())));
+
+        let times: Vec<DateTime<Local>> = dated.iter().map(|(otime, _)| *otime).collect();
+        let kept = self.select(&times, now);
+
+        let mut plan = RetentionPlan {
+            keep: Vec::new(),
+            delete: Vec::new(),
+        };
+        for ((_, snapshot), keep) in dated.into_iter().zip(kept) {
+            if keep {
+                plan.keep.push(snapshot.clone());
+            } else {
+                plan.delete.push(snapshot.clone());
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Decide which of the given creation times, sorted newest first, to keep.
+    pub(crate) fn select(&self, times: &[DateTime<Local>], now: DateTime<Local>) -> Vec<bool> {
+        let mut keep = vec![false; times.len()];
+
+        for (i, time) in times.iter().enumerate() {
+            let age = now
+                .signed_duration_since(*time)
+                .to_std()
+                .unwrap_or_default();
+            keep[i] = i < self.last || age < self.min_age;
+        }
+
+        let buckets: [(usize, Bucket); 5] = [
+            (self.hourly, |t| (t.year(), t.ordinal(), t.hour())),
+            (self.daily, |t| (t.year(), t.ordinal(), 0)),
+            (self.weekly, |t| {
+                let week = t.iso_week();
+                (week.year(), week.week(), 0)
+            }),
+            (self.monthly, |t| (t.year(), t.month(), 0)),
+            (self.yearly, |t| (t.year(), 0, 0)),
+        ];
+        for (count, bucket) in buckets {
+            let mut seen = HashSet::new();
+            for (i, time) in times.iter().enumerate() {
+                if seen.len() >= count {
+                    break;
+                }
+                // the first snapshot seen in a bucket is its newest
+                if seen.insert(bucket(time)) {
+                    keep[i] = true;
+                }
+            }
+        }
+
+        keep
+    }
+}
+
+impl RetentionPlan {
+    /// Delete the snapshots the plan does not keep.
+    ///
+    /// The result maps the path of each deleted snapshot to its outcome.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn execute(&self) -> BatchResult<PathBuf, ()> {
+        let mut batch = BatchError::new();
+        for snapshot in &self.delete {
+            let result = snapshot
+                .clone()
+                .elevate()
+                .and_then(|snapshot| snapshot.delete(None));
+            batch.push(snapshot.path().to_path_buf(), result);
+        }
+        batch.into_result()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    #[test]
+    fn retention_select() {
+        let now = Local.with_ymd_and_hms(2020, 6, 15, 12, 0, 0).unwrap();
+        // every 6 hours over 10 days, newest first
+        let times: Vec<DateTime<Local>> = (0..40)
+            .map(|i| now - chrono::Duration::hours(6 * i))
+            .collect();
+
+        let keep = RetentionPolicy::new().keep_last(2).select(&times, now);
+        assert_eq!(keep.iter().filter(|keep| **keep).count(), 2);
+        assert!(keep[0] && keep[1]);
+
+        let keep = RetentionPolicy::new().daily(3).select(&times, now);
+        // 12:00 on the 15th, then 18:00 on the 14th and 13th
+        assert_eq!(
+            keep.iter()
+                .enumerate()
+                .filter(|(_, keep)| **keep)
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>(),
+            vec![0, 3, 7]
+        );
+
+        let keep = RetentionPolicy::new()
+            .min_age(Duration::from_secs(24 * 3600))
+            .select(&times, now);
+        assert_eq!(keep.iter().filter(|keep| **keep).count(), 4);
+
+        let keep = RetentionPolicy::new().select(&times, now);
+        assert!(keep.iter().all(|keep| !keep));
+    }
+}
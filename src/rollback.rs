@@ -0,0 +1,259 @@
+//! Rolling back the root filesystem
+//!
+//! Rolling back to a snapshot of the default subvolume takes several steps: the current state
+//! is kept in a snapshot in case the rollback has to be undone, a writable snapshot of the chosen
+//! snapshot is made to boot into, and it is set as the default subvolume. Optionally the old
+//! root is renamed out of the way first, so the new root takes its place and mount options
+//! naming it by path keep working.
+//!
+//! A [RollbackPlan] lists these steps as [RollbackStep]s before anything is done, so they can
+//! be shown to the user for confirmation, and then executes them in order.
+//!
+//! [RollbackPlan]: struct.RollbackPlan.html
+//! [RollbackStep]: enum.RollbackStep.html
+
+use crate::common;
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::subvolume::AdminSubvolume;
+use crate::subvolume::SnapshotFlags;
+use crate::subvolume::Subvolume;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// A step of a rollback.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RollbackStep {
+    /// Take a read-only snapshot of the current default subvolume.
+    SnapshotCurrent {
+        /// Path of the current default subvolume.
+        source: PathBuf,
+        /// Path of the snapshot.
+        snapshot: PathBuf,
+    },
+    /// Rename the current default subvolume.
+    RenameOldRoot {
+        /// Path of the current default subvolume.
+        from: PathBuf,
+        /// Path it is renamed to.
+        to: PathBuf,
+    },
+    /// Take a writable snapshot of the chosen snapshot, to become the new default subvolume.
+    SnapshotTarget {
+        /// Path of the chosen snapshot.
+        source: PathBuf,
+        /// Path of the snapshot.
+        snapshot: PathBuf,
+    },
+    /// Set a subvolume as the default subvolume.
+    SetDefault {
+        /// Path of the subvolume.
+        subvolume: PathBuf,
+    },
+}
+
+/// The subvolumes a rollback ended up with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RollbackOutcome {
+    /// Snapshot of the state before the rollback.
+    pub backup: Subvolume,
+    /// The new default subvolume.
+    pub new_default: Subvolume,
+}
+
+/// The steps of a rollback to a snapshot, inspectable before being executed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RollbackPlan {
+    current: Subvolume,
+    target: Subvolume,
+    backup: PathBuf,
+    new_root: Option<PathBuf>,
+    rename_old_root: Option<PathBuf>,
+}
+
+impl RollbackPlan {
+    /// Plan a rollback of the filesystem `fs_root` belongs to, usually the mount point of its
+    /// top-level subvolume, to a snapshot.
+    ///
+    /// By default, the current state is kept next to the current default subvolume, named after
+    /// it with a `.pre-rollback-` suffix and the current time, and a read-only snapshot is
+    /// rolled back to through a writable snapshot named the same way with a `.rollback-` suffix. A
+    /// writable snapshot is rolled back to directly.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn new<P>(fs_root: P, target: &Subvolume) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let fs_root = fs_root.as_ref();
+        Self::new_impl(fs_root, target).context("Rollback::new", &[fs_root])
+    }
+
+    fn new_impl(fs_root: &Path, target: &Subvolume) -> Result<Self> {
+        let base = Subvolume::get(fs_root)?;
+        if !base.same_filesystem(target)? {
+            return Err(LibError::InvalidArgument.into());
+        }
+        let current = Subvolume::get_default(fs_root)?;
+        if current.id() == target.id() {
+            return Err(LibError::InvalidArgument.into());
+        }
+
+        let stamp = common::format_local_time(SystemTime::now(), c"%Y%m%d-%H%M%S");
+        // a read-only root would not boot
+        let new_root = if target.is_ro()? {
+            Some(sibling(current.path(), &format!("rollback-{}", stamp))?)
+        } else {
+            None
+        };
+        Ok(Self {
+            backup: sibling(current.path(), &format!("pre-rollback-{}", stamp))?,
+            new_root,
+            target: target.clone(),
+            current,
+            rename_old_root: None,
+        })
+    }
+
+    /// Keep the current state in a snapshot at this path instead.
+    pub fn backup_path<P>(mut self, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.backup = path.into();
+        self
+    }
+
+    /// Take the writable snapshot to boot into at this path instead, also if the snapshot rolled
+    /// back to is writable itself.
+    pub fn new_root_path<P>(mut self, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.new_root = Some(path.into());
+        self
+    }
+
+    /// Rename the current default subvolume to this path, and take the writable snapshot to boot
+    /// into at its former path.
+    ///
+    /// The current default subvolume must not be a mount point, so the filesystem is usually
+    /// planned from the mount point of its top-level subvolume.
+    pub fn rename_old_root<P>(mut self, path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.rename_old_root = Some(path.into());
+        self.new_root = Some(self.current.path().to_path_buf());
+        self
+    }
+
+    /// Get the current default subvolume.
+    #[inline]
+    pub fn current(&self) -> &Subvolume {
+        &self.current
+    }
+
+    /// Get the snapshot rolled back to.
+    #[inline]
+    pub fn target(&self) -> &Subvolume {
+        &self.target
+    }
+
+    /// Get the steps the rollback takes, in order.
+    pub fn steps(&self) -> Vec<RollbackStep> {
+        let mut steps = vec![RollbackStep::SnapshotCurrent {
+            source: self.current.path().to_path_buf(),
+            snapshot: self.backup.clone(),
+        }];
+        if let Some(to) = &self.rename_old_root {
+            steps.push(RollbackStep::RenameOldRoot {
+                from: self.current.path().to_path_buf(),
+                to: to.clone(),
+            });
+        }
+        match self.new_root_snapshot() {
+            Some(snapshot) => {
+                steps.push(RollbackStep::SnapshotTarget {
+                    source: self.target.path().to_path_buf(),
+                    snapshot: snapshot.to_path_buf(),
+                });
+                steps.push(RollbackStep::SetDefault {
+                    subvolume: snapshot.to_path_buf(),
+                });
+            }
+            None => steps.push(RollbackStep::SetDefault {
+                subvolume: self.target.path().to_path_buf(),
+            }),
+        }
+        steps
+    }
+
+    /// Execute the rollback.
+    ///
+    /// Steps already taken are not undone if a later one fails. The new default subvolume is
+    /// used from the next mount of the filesystem without a `subvol` option, usually the next
+    /// boot.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn execute(&self) -> Result<RollbackOutcome> {
+        // fail before the first step rather than at the last one
+        let target = self.target.clone().elevate()?;
+        let backup = self
+            .current
+            .snapshot(&self.backup, SnapshotFlags::READ_ONLY, None)?;
+
+        if let Some(to) = &self.rename_old_root {
+            fs::rename(self.current.path(), to)
+                .map_err(|err| BtrfsUtilError::from_io(LibError::RenameFailed, &err))?;
+        }
+
+        let new_default = match self.new_root_snapshot() {
+            // on the filesystem of the target, whose privileges have been checked
+            Some(snapshot) => AdminSubvolume::new(self.target.snapshot(snapshot, None, None)?),
+            None => target,
+        };
+        new_default.set_default()?;
+
+        Ok(RollbackOutcome {
+            backup,
+            new_default: new_default.into_inner(),
+        })
+    }
+
+    /// Where the writable snapshot of the target is taken, if one is needed.
+    fn new_root_snapshot(&self) -> Option<&Path> {
+        self.new_root.as_deref()
+    }
+}
+
+/// Get a path next to another one, named after it with a suffix.
+fn sibling(path: &Path, suffix: &str) -> Result<PathBuf> {
+    let mut name: OsString = path
+        .file_name()
+        .ok_or(LibError::InvalidArgument)?
+        .to_os_string();
+    name.push(".");
+    name.push(suffix);
+    Ok(path.with_file_name(name))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sibling_paths() {
+        assert_eq!(
+            sibling(Path::new("/mnt/@"), "rollback-1").unwrap(),
+            PathBuf::from("/mnt/@.rollback-1")
+        );
+        assert!(sibling(Path::new("/"), "rollback-1").is_err());
+    }
+}
@@ -0,0 +1,494 @@
+//! Btrfs scrub
+//!
+//! A scrub reads all data and metadata of a filesystem, verifies their checksums and repairs
+//! damaged copies from good ones where the profile allows it. Each device is scrubbed on its
+//! own; the functions here drive all devices of a filesystem at once.
+
+use crate::error::BatchError;
+use crate::error::BatchResult;
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::fs::Filesystem;
+use crate::ioctl;
+use crate::ioctl::ScrubArgs;
+use crate::ioctl::ScrubProgress;
+use crate::ioctl::SearchKey;
+use crate::resolve;
+use crate::subvolume::Subvolume;
+use crate::verify;
+use crate::verify::Checksummer;
+use crate::verify::Crc32c;
+use crate::verify::CsumReport;
+use crate::verify::PathRange;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::collections::BTreeSet;
+use std::ops::AddAssign;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+
+bitflags! {
+    /// Scrub flags.
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct ScrubFlags: u64 {
+        /// Only report errors, do not repair them.
+        const READ_ONLY = ioctl::BTRFS_SCRUB_READONLY;
+    }
+}
+
+/// Statistics of a scrub.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ScrubStats {
+    /// Number of data extents scrubbed.
+    pub data_extents_scrubbed: u64,
+    /// Number of tree blocks scrubbed.
+    pub tree_extents_scrubbed: u64,
+    /// Number of data bytes scrubbed.
+    pub data_bytes_scrubbed: u64,
+    /// Number of metadata bytes scrubbed.
+    pub tree_bytes_scrubbed: u64,
+    /// Number of read errors.
+    pub read_errors: u64,
+    /// Number of checksum errors.
+    pub csum_errors: u64,
+    /// Number of metadata blocks failing verification.
+    pub verify_errors: u64,
+    /// Number of data blocks without a checksum.
+    pub no_csum: u64,
+    /// Number of checksums without data.
+    pub csum_discards: u64,
+    /// Number of bad superblock copies.
+    pub super_errors: u64,
+    /// Number of errors that could not be corrected.
+    pub uncorrectable_errors: u64,
+    /// Number of corrected errors.
+    pub corrected_errors: u64,
+    /// Number of errors that went away on a second read.
+    pub unverified_errors: u64,
+}
+
+impl ScrubStats {
+    /// Get the number of bytes scrubbed, data and metadata.
+    #[inline]
+    pub fn bytes_scrubbed(&self) -> u64 {
+        self.data_bytes_scrubbed + self.tree_bytes_scrubbed
+    }
+
+    /// Get the number of errors found, corrected or not.
+    #[inline]
+    pub fn errors(&self) -> u64 {
+        self.read_errors + self.csum_errors + self.verify_errors + self.super_errors
+    }
+}
+
+impl AddAssign for ScrubStats {
+    fn add_assign(&mut self, other: Self) {
+        self.data_extents_scrubbed += other.data_extents_scrubbed;
+        self.tree_extents_scrubbed += other.tree_extents_scrubbed;
+        self.data_bytes_scrubbed += other.data_bytes_scrubbed;
+        self.tree_bytes_scrubbed += other.tree_bytes_scrubbed;
+        self.read_errors += other.read_errors;
+        self.csum_errors += other.csum_errors;
+        self.verify_errors += other.verify_errors;
+        self.no_csum += other.no_csum;
+        self.csum_discards += other.csum_discards;
+        self.super_errors += other.super_errors;
+        self.uncorrectable_errors += other.uncorrectable_errors;
+        self.corrected_errors += other.corrected_errors;
+        self.unverified_errors += other.unverified_errors;
+    }
+}
+
+impl From<&ScrubProgress> for ScrubStats {
+    fn from(progress: &ScrubProgress) -> Self {
+        Self {
+            data_extents_scrubbed: progress.data_extents_scrubbed,
+            tree_extents_scrubbed: progress.tree_extents_scrubbed,
+            data_bytes_scrubbed: progress.data_bytes_scrubbed,
+            tree_bytes_scrubbed: progress.tree_bytes_scrubbed,
+            read_errors: progress.read_errors,
+            csum_errors: progress.csum_errors,
+            verify_errors: progress.verify_errors,
+            no_csum: progress.no_csum,
+            csum_discards: progress.csum_discards,
+            super_errors: progress.super_errors,
+            uncorrectable_errors: progress.uncorrectable_errors,
+            corrected_errors: progress.corrected_errors,
+            unverified_errors: progress.unverified_errors,
+        }
+    }
+}
+
+/// Scrub state of one device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DeviceScrub {
+    /// Id of the device.
+    pub devid: u64,
+    /// Statistics of the device.
+    pub stats: ScrubStats,
+    /// Physical offset the scrub got to on the device.
+    pub last_physical: u64,
+    /// Whether the scrub of the device was canceled before it finished.
+    pub canceled: bool,
+}
+
+impl DeviceScrub {
+    fn new(args: &ScrubArgs, canceled: bool) -> Self {
+        Self {
+            devid: args.devid,
+            stats: ScrubStats::from(&args.progress),
+            last_physical: args.progress.last_physical,
+            canceled,
+        }
+    }
+}
+
+/// Scrub state of a filesystem.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ScrubStatus {
+    /// State of each device scrubbed.
+    pub devices: Vec<DeviceScrub>,
+    /// Devices whose scrub failed, with the reason.
+    pub failed: Vec<(u64, BtrfsUtilError)>,
+}
+
+impl ScrubStatus {
+    /// Get the statistics of all devices added up.
+    pub fn total(&self) -> ScrubStats {
+        let mut total = ScrubStats::default();
+        for device in &self.devices {
+            total += device.stats;
+        }
+        total
+    }
+
+    /// Check whether the scrub of any device was canceled.
+    pub fn canceled(&self) -> bool {
+        self.devices.iter().any(|device| device.canceled)
+    }
+
+    /// Check whether the scrub of any device failed.
+    pub fn has_failures(&self) -> bool {
+        !self.failed.is_empty()
+    }
+
+    /// Convert into a result mapping each device id to its state, failing if any device failed.
+    pub fn into_result(self) -> BatchResult<u64, DeviceScrub> {
+        let mut batch = BatchError::new();
+        for device in self.devices {
+            batch.push(device.devid, Ok(device));
+        }
+        for (devid, err) in self.failed {
+            batch.push(devid, Err(err));
+        }
+        batch.into_result()
+    }
+}
+
+/// Scrub all devices of the filesystem mounted at a path.
+///
+/// This blocks until every device is scrubbed or the scrub is canceled. Use [status] from
+/// another thread to follow it.
+///
+/// Devices that fail are listed in [ScrubStatus::failed] with the statistics of the others
+/// kept. A missing device does not stop the others, any other failure cancels them.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+///
+/// [status]: fn.status.html
+/// [ScrubStatus::failed]: struct.ScrubStatus.html#structfield.failed
+pub fn start<P, F>(path: P, flags: F) -> Result<ScrubStatus>
+where
+    P: AsRef<Path>,
+    F: Into<Option<ScrubFlags>>,
+{
+    let path = path.as_ref();
+    start_impl(path, flags.into()).context("scrub::start", &[path])
+}
+
+fn start_impl(path: &Path, flags: Option<ScrubFlags>) -> Result<ScrubStatus> {
+    let file = ioctl::open(path)?;
+    let devids = ioctl::device_ids(file.as_raw_fd())?;
+
+    run(path, devids.into_iter().map(|devid| (devid, 0)), flags)
+}
+
+/// Resume a canceled scrub of the filesystem mounted at a path.
+///
+/// The kernel does not remember where a canceled scrub stopped, so the status it returned is
+/// needed to pick up from there. Devices that were scrubbed to the end are skipped.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn resume<P, F>(path: P, from: &ScrubStatus, flags: F) -> Result<ScrubStatus>
+where
+    P: AsRef<Path>,
+    F: Into<Option<ScrubFlags>>,
+{
+    let path = path.as_ref();
+    resume_impl(path, from, flags.into()).context("scrub::resume", &[path])
+}
+
+fn resume_impl(path: &Path, from: &ScrubStatus, flags: Option<ScrubFlags>) -> Result<ScrubStatus> {
+    let devices = from
+        .devices
+        .iter()
+        .filter(|device| device.canceled)
+        .map(|device| (device.devid, device.last_physical));
+
+    run(path, devices, flags)
+}
+
+fn run<I>(path: &Path, devices: I, flags: Option<ScrubFlags>) -> Result<ScrubStatus>
+where
+    I: Iterator<Item = (u64, u64)>,
+{
+    let flags_val = flags.map(|v| v.bits()).unwrap_or(0);
+    let (sender, receiver) = mpsc::channel();
+
+    // the scrub ioctl blocks until its device is done, so devices are scrubbed in parallel
+    let handles: Vec<_> = devices
+        .map(|(devid, start)| {
+            let path = path.to_owned();
+            let sender = sender.clone();
+            let handle = thread::spawn(move || {
+                let result = scrub_device(&path, devid, start, flags_val);
+                // a missing device leaves the others alone, any other failure cancels the whole
+                // scrub so that no device keeps going on its own
+                let fatal = matches!(&result, Err(err) if err.errno() != Some(libc::ENODEV));
+                let _ = sender.send(fatal);
+                result
+            });
+            (devid, handle)
+        })
+        .collect();
+    drop(sender);
+
+    if receiver.iter().any(|fatal| fatal) {
+        let _ = cancel_impl(path);
+    }
+
+    let mut status = ScrubStatus::default();
+    for (devid, handle) in handles {
+        match handle.join() {
+            Ok(Ok(device)) => status.devices.push(device),
+            Ok(Err(err)) => status.failed.push((devid, err)),
+            Err(_) => status.failed.push((devid, LibError::ScrubFailed.into())),
+        }
+    }
+
+    Ok(status)
+}
+
+fn scrub_device(path: &Path, devid: u64, start: u64, flags: u64) -> Result<DeviceScrub> {
+    let file = ioctl::open(path)?;
+    let mut args = ScrubArgs::new(devid, start, flags);
+    match unsafe { ioctl::ioctl_errno(file.as_raw_fd(), ioctl::BTRFS_IOC_SCRUB, &mut args) } {
+        Ok(()) => Ok(DeviceScrub::new(&args, false)),
+        Err(libc::ECANCELED) => Ok(DeviceScrub::new(&args, true)),
+        Err(errno) => Err(BtrfsUtilError::os(LibError::ScrubFailed, errno)),
+    }
+}
+
+/// Cancel the running scrub of the filesystem mounted at a path.
+///
+/// Returns false if no scrub was running.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn cancel<P>(path: P) -> Result<bool>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    cancel_impl(path).context("scrub::cancel", &[path])
+}
+
+fn cancel_impl(path: &Path) -> Result<bool> {
+    let file = ioctl::open(path)?;
+
+    if unsafe { libc::ioctl(file.as_raw_fd(), ioctl::BTRFS_IOC_SCRUB_CANCEL as _) } < 0 {
+        return match std::io::Error::last_os_error().raw_os_error() {
+            Some(libc::ENOTCONN) => Ok(false),
+            _ => Err(BtrfsUtilError::last_os_error(LibError::ScrubCancelFailed)),
+        };
+    }
+
+    Ok(true)
+}
+
+/// Get the progress of the running scrub of the filesystem mounted at a path.
+///
+/// Only devices being scrubbed are listed. Returns None if no scrub is running.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn status<P>(path: P) -> Result<Option<ScrubStatus>>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    status_impl(path).context("scrub::status", &[path])
+}
+
+fn status_impl(path: &Path) -> Result<Option<ScrubStatus>> {
+    let file = ioctl::open(path)?;
+    let mut status = ScrubStatus::default();
+
+    for devid in ioctl::device_ids(file.as_raw_fd())? {
+        let mut args = ScrubArgs::new(devid, 0, 0);
+        match unsafe {
+            ioctl::ioctl_errno(file.as_raw_fd(), ioctl::BTRFS_IOC_SCRUB_PROGRESS, &mut args)
+        } {
+            Ok(()) => status.devices.push(DeviceScrub::new(&args, false)),
+            // no scrub running on this device
+            Err(libc::ENOTCONN) => continue,
+            Err(errno) => return Err(BtrfsUtilError::os(LibError::ScrubProgressFailed, errno)),
+        }
+    }
+
+    if status.devices.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(status))
+}
+
+/// Outcome of verifying the data of one subvolume, as done by [subvolume].
+///
+/// [subvolume]: fn.subvolume.html
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SubvolumeScrub {
+    /// Reports of the files verified.
+    pub files: Vec<CsumReport>,
+    /// Inodes owning data extents that have no path left, e.g. because they are being deleted.
+    pub unreachable: Vec<u64>,
+}
+
+impl SubvolumeScrub {
+    /// Get the number of sectors whose checksum matched.
+    pub fn verified(&self) -> u64 {
+        self.files.iter().map(|report| report.verified).sum()
+    }
+
+    /// Get the number of sectors that could not be verified.
+    pub fn skipped(&self) -> u64 {
+        self.files.iter().map(|report| report.skipped).sum()
+    }
+
+    /// Get the number of sectors whose checksum did not match.
+    pub fn mismatches(&self) -> usize {
+        self.files
+            .iter()
+            .map(|report| report.mismatches.len())
+            .sum()
+    }
+
+    /// Check whether no mismatches were found.
+    pub fn is_ok(&self) -> bool {
+        self.files.iter().all(CsumReport::is_ok)
+    }
+}
+
+/// Verify the data checksums of every file of a subvolume using [Crc32c], without scrubbing the
+/// rest of the filesystem.
+///
+/// The files are those owning data extents in the tree of the subvolume, so nested subvolumes are
+/// left out. Unlike [start], nothing is repaired and metadata is not verified; extents shared by
+/// several files are verified once per file.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+///
+/// [Crc32c]: ../verify/struct.Crc32c.html
+/// [start]: fn.start.html
+pub fn subvolume(subvol: &Subvolume) -> Result<SubvolumeScrub> {
+    subvolume_with(subvol, &Crc32c)
+}
+
+/// Verify the data checksums of every file of a subvolume using a custom [Checksummer].
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+///
+/// [Checksummer]: ../verify/trait.Checksummer.html
+pub fn subvolume_with<C>(subvol: &Subvolume, checksummer: &C) -> Result<SubvolumeScrub>
+where
+    C: Checksummer,
+{
+    subvolume_with_impl(subvol, checksummer).context("scrub::subvolume_with", &[subvol.path()])
+}
+
+fn subvolume_with_impl<C>(subvol: &Subvolume, checksummer: &C) -> Result<SubvolumeScrub>
+where
+    C: Checksummer,
+{
+    let mut scrub = SubvolumeScrub::default();
+    for inode in data_inodes(subvol)? {
+        let path = match resolve::inode_to_paths(subvol.path(), inode)?
+            .into_iter()
+            .next()
+        {
+            Some(path) => subvol.path().join(path),
+            None => {
+                scrub.unreachable.push(inode);
+                continue;
+            }
+        };
+        scrub.files.push(verify::csums_impl(
+            PathRange::from(path.as_path()),
+            checksummer,
+        )?);
+    }
+    Ok(scrub)
+}
+
+/// Get the inodes of a subvolume owning regular data extents.
+fn data_inodes(subvol: &Subvolume) -> Result<BTreeSet<u64>> {
+    let file = ioctl::open(subvol.path())?;
+
+    let key = SearchKey::new(
+        subvol.id().get(),
+        (0, ioctl::BTRFS_EXTENT_DATA_KEY, 0),
+        (u64::MAX, ioctl::BTRFS_EXTENT_DATA_KEY, u64::MAX),
+    );
+
+    let mut inodes: BTreeSet<u64> = BTreeSet::new();
+    ioctl::tree_search(file.as_raw_fd(), key, |header, item| {
+        // holes have no disk extent, inline extents are not checksummed on their own
+        if header.item_type == ioctl::BTRFS_EXTENT_DATA_KEY
+            && item.len() >= 29
+            && item[20] == ioctl::BTRFS_FILE_EXTENT_REG
+            && ioctl::le_u64(item, 21) != 0
+        {
+            inodes.insert(header.objectid);
+        }
+        true
+    })?;
+
+    Ok(inodes)
+}
+
+impl Filesystem {
+    /// Same as [start], counted as a heavy operation.
+    ///
+    /// [start]: ../scrub/fn.start.html
+    pub fn scrub<F>(&self, flags: F) -> Result<ScrubStatus>
+    where
+        F: Into<Option<ScrubFlags>>,
+    {
+        let flags = flags.into();
+        self.heavy(|| start_impl(self.path(), flags))
+            .context("Filesystem::scrub", &[self.path()])
+    }
+
+    /// Same as [resume], counted as a heavy operation.
+    ///
+    /// [resume]: ../scrub/fn.resume.html
+    pub fn resume_scrub<F>(&self, from: &ScrubStatus, flags: F) -> Result<ScrubStatus>
+    where
+        F: Into<Option<ScrubFlags>>,
+    {
+        let flags = flags.into();
+        self.heavy(|| resume_impl(self.path(), from, flags))
+            .context("Filesystem::resume_scrub", &[self.path()])
+    }
+}
@@ -0,0 +1,394 @@
+//! Chunked storage of send streams
+//!
+//! A send stream is cut into fixed-size chunks named by the SHA-256 of their contents, so
+//! identical chunks of different streams are stored once. A [Manifest] lists the chunks of one
+//! stream in order and is all that is needed to put the stream back together.
+//!
+//! Where chunks are kept is up to the caller: implement [ChunkStore] on top of an object store,
+//! or use [MemoryStore].
+//!
+//! [Manifest]: struct.Manifest.html
+//! [ChunkStore]: trait.ChunkStore.html
+//! [MemoryStore]: struct.MemoryStore.html
+
+use crate::error::LibError;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::str::FromStr;
+
+use sha2::Digest;
+use sha2::Sha256;
+
+const MANIFEST_HEADER: &str = "btrfsutil-sendstream-manifest 1";
+
+/// Content address of a chunk: the SHA-256 of its contents.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ChunkId([u8; 32]);
+
+impl ChunkId {
+    /// Compute the id of some contents.
+    pub fn of(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+
+    /// Get the raw digest.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for ChunkId {
+    /// Format the id as lowercase hex, suitable as an object key.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ChunkId {
+    type Err = BtrfsUtilError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(LibError::ManifestInvalid.into());
+        }
+        let mut id = [0u8; 32];
+        for (i, byte) in id.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+                .map_err(|_| LibError::ManifestInvalid)?;
+        }
+        Ok(Self(id))
+    }
+}
+
+/// A chunk of a stream.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Chunk {
+    /// Id of the chunk.
+    pub id: ChunkId,
+    /// Contents of the chunk.
+    pub data: Vec<u8>,
+}
+
+/// The chunks making up one stream, in order.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Manifest {
+    /// Size of every chunk but the last one.
+    pub chunk_size: usize,
+    /// Length of the whole stream.
+    pub len: u64,
+    /// Ids of the chunks.
+    pub chunks: Vec<ChunkId>,
+}
+
+impl Manifest {
+    /// Write the manifest in its text form: a header, the chunk size and the stream length,
+    /// then one chunk id per line.
+    pub fn write_to<W>(&self, mut writer: W) -> Result<()>
+    where
+        W: Write,
+    {
+        let write = |writer: &mut W| -> io::Result<()> {
+            writeln!(writer, "{}", MANIFEST_HEADER)?;
+            writeln!(writer, "{} {}", self.chunk_size, self.len)?;
+            for id in &self.chunks {
+                writeln!(writer, "{}", id)?;
+            }
+            Ok(())
+        };
+        write(&mut writer).map_err(|err| BtrfsUtilError::from_io(LibError::ChunkStoreFailed, &err))
+    }
+
+    /// Read a manifest written by [write_to].
+    ///
+    /// [write_to]: #method.write_to
+    pub fn read_from<R>(reader: R) -> Result<Self>
+    where
+        R: BufRead,
+    {
+        let mut lines = reader.lines();
+        let mut next_line = || -> Result<Option<String>> {
+            lines
+                .next()
+                .transpose()
+                .map_err(|err| BtrfsUtilError::from_io(LibError::ChunkStoreFailed, &err))
+        };
+
+        if next_line()?.as_deref() != Some(MANIFEST_HEADER) {
+            return Err(LibError::ManifestInvalid.into());
+        }
+        let sizes = next_line()?.ok_or(LibError::ManifestInvalid)?;
+        let (chunk_size, len) = sizes
+            .split_once(' ')
+            .and_then(|(size, len)| Some((size.parse().ok()?, len.parse().ok()?)))
+            .ok_or(LibError::ManifestInvalid)?;
+
+        let mut chunks = Vec::new();
+        while let Some(line) = next_line()? {
+            chunks.push(line.parse()?);
+        }
+
+        Ok(Self {
+            chunk_size,
+            len,
+            chunks,
+        })
+    }
+}
+
+/// Somewhere to keep chunks, such as an object store bucket.
+pub trait ChunkStore {
+    /// Check whether the store already has a chunk.
+    fn contains(&self, id: &ChunkId) -> io::Result<bool>;
+
+    /// Store a chunk.
+    fn put(&mut self, chunk: &Chunk) -> io::Result<()>;
+
+    /// Fetch the contents of a chunk.
+    fn get(&self, id: &ChunkId) -> io::Result<Vec<u8>>;
+}
+
+/// A [ChunkStore] keeping chunks in memory.
+///
+/// [ChunkStore]: trait.ChunkStore.html
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStore(HashMap<ChunkId, Vec<u8>>);
+
+impl MemoryStore {
+    /// Create an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the number of chunks stored.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Check whether the store is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl ChunkStore for MemoryStore {
+    fn contains(&self, id: &ChunkId) -> io::Result<bool> {
+        Ok(self.0.contains_key(id))
+    }
+
+    fn put(&mut self, chunk: &Chunk) -> io::Result<()> {
+        self.0.insert(chunk.id, chunk.data.clone());
+        Ok(())
+    }
+
+    fn get(&self, id: &ChunkId) -> io::Result<Vec<u8>> {
+        self.0
+            .get(id)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+}
+
+/// Iterator cutting a stream into chunks, built by [chunker].
+///
+/// [chunker]: fn.chunker.html
+#[derive(Debug)]
+pub struct Chunker<R> {
+    reader: R,
+    chunk_size: usize,
+    len: u64,
+    chunks: Vec<ChunkId>,
+    done: bool,
+}
+
+/// Cut a stream into chunks of `chunk_size` bytes; the last one may be shorter.
+///
+/// Once the iterator is exhausted, [Chunker::into_manifest] gives the manifest of the stream.
+///
+/// # Panics
+///
+/// Panics if `chunk_size` is zero.
+///
+/// [Chunker::into_manifest]: struct.Chunker.html#method.into_manifest
+pub fn chunker<R>(reader: R, chunk_size: usize) -> Chunker<R>
+where
+    R: Read,
+{
+    assert!(chunk_size > 0, "chunk size must not be zero");
+    Chunker {
+        reader,
+        chunk_size,
+        len: 0,
+        chunks: Vec::new(),
+        done: false,
+    }
+}
+
+impl<R> Chunker<R> {
+    /// Get the manifest of the chunks produced so far.
+    pub fn into_manifest(self) -> Manifest {
+        Manifest {
+            chunk_size: self.chunk_size,
+            len: self.len,
+            chunks: self.chunks,
+        }
+    }
+}
+
+impl<R> Iterator for Chunker<R>
+where
+    R: Read,
+{
+    type Item = Result<Chunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let mut data: Vec<u8> = Vec::with_capacity(self.chunk_size);
+        match (&mut self.reader)
+            .take(self.chunk_size as u64)
+            .read_to_end(&mut data)
+        {
+            Ok(_) => (),
+            Err(err) => {
+                self.done = true;
+                return Some(Err(BtrfsUtilError::from_io(
+                    LibError::StreamReadFailed,
+                    &err,
+                )));
+            }
+        }
+        if data.len() < self.chunk_size {
+            self.done = true;
+        }
+        if data.is_empty() {
+            return None;
+        }
+
+        let id = ChunkId::of(&data);
+        self.len += data.len() as u64;
+        self.chunks.push(id);
+        Some(Ok(Chunk { id, data }))
+    }
+}
+
+/// Cut a stream into chunks and put those the store does not have yet into it.
+pub fn store<R, S>(reader: R, chunk_size: usize, store: &mut S) -> Result<Manifest>
+where
+    R: Read,
+    S: ChunkStore,
+{
+    let mut chunks = chunker(reader, chunk_size);
+    for chunk in &mut chunks {
+        let chunk = chunk?;
+        if !store
+            .contains(&chunk.id)
+            .map_err(|err| BtrfsUtilError::from_io(LibError::ChunkStoreFailed, &err))?
+        {
+            store
+                .put(&chunk)
+                .map_err(|err| BtrfsUtilError::from_io(LibError::ChunkStoreFailed, &err))?;
+        }
+    }
+    Ok(chunks.into_manifest())
+}
+
+/// Reader putting a stream back together from its chunks, built by [assembler].
+///
+/// [assembler]: fn.assembler.html
+#[derive(Debug)]
+pub struct Assembler<'a, S> {
+    manifest: &'a Manifest,
+    store: &'a S,
+    next: usize,
+    current: Vec<u8>,
+    pos: usize,
+}
+
+/// Read back the stream described by a manifest.
+///
+/// Every chunk is checked against its id as it is fetched; a mismatch fails the read with
+/// [io::ErrorKind::InvalidData].
+///
+/// [io::ErrorKind::InvalidData]: https://doc.rust-lang.org/std/io/enum.ErrorKind.html#variant.InvalidData
+pub fn assembler<'a, S>(manifest: &'a Manifest, store: &'a S) -> Assembler<'a, S>
+where
+    S: ChunkStore,
+{
+    Assembler {
+        manifest,
+        store,
+        next: 0,
+        current: Vec::new(),
+        pos: 0,
+    }
+}
+
+impl<S> Read for Assembler<'_, S>
+where
+    S: ChunkStore,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.current.len() {
+            let id = match self.manifest.chunks.get(self.next) {
+                Some(id) => id,
+                None => return Ok(0),
+            };
+            let data = self.store.get(id)?;
+            if ChunkId::of(&data) != *id {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    LibError::ChunkCorrupted,
+                ));
+            }
+            self.current = data;
+            self.pos = 0;
+            self.next += 1;
+        }
+
+        let read = buf.len().min(self.current.len() - self.pos);
+        buf[..read].copy_from_slice(&self.current[self.pos..self.pos + read]);
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sendstream_roundtrip() {
+        let stream: Vec<u8> = (0..10_000u32).map(|i| (i % 7) as u8).collect();
+        let mut mem = MemoryStore::new();
+
+        let manifest = store(&stream[..], 1024, &mut mem).unwrap();
+        assert_eq!(manifest.len, stream.len() as u64);
+        assert_eq!(manifest.chunks.len(), 10);
+
+        let mut text: Vec<u8> = Vec::new();
+        manifest.write_to(&mut text).unwrap();
+        let parsed = Manifest::read_from(&text[..]).unwrap();
+        assert_eq!(parsed, manifest);
+
+        let mut assembled: Vec<u8> = Vec::new();
+        assembler(&parsed, &mem)
+            .read_to_end(&mut assembled)
+            .unwrap();
+        assert_eq!(assembled, stream);
+    }
+}
@@ -0,0 +1,550 @@
+//! Groups of snapshots
+//!
+//! Btrfs commits every snapshot in a transaction of its own, so snapshots of several subvolumes
+//! taken one after the other may see writes the previous ones did not. [atomic_set_with] lets the
+//! application pause its writers around the whole group, giving a crash-consistent set such as
+//! a database and its write-ahead log kept on different subvolumes.
+//!
+//! [snapshot_many] snapshots subvolumes of any filesystems back to back, with the same
+//! all-or-nothing behaviour.
+//!
+//! Freezing the filesystem is not an option, since snapshots cannot be created while it is
+//! frozen.
+//!
+//! A [SnapshotGroup] records which snapshots were taken together in a manifest file, so the
+//! group can later be listed, checked for completeness and deleted as one unit.
+//!
+//! A [TempSnapshot] is deleted when dropped, for snapshots that only live as long as a backup
+//! reads from them.
+//!
+//! [atomic_set_with]: fn.atomic_set_with.html
+//! [snapshot_many]: fn.snapshot_many.html
+//! [SnapshotGroup]: struct.SnapshotGroup.html
+//! [TempSnapshot]: struct.TempSnapshot.html
+
+use crate::error::BatchError;
+use crate::error::BatchResult;
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::subvolume::SnapshotFlags;
+use crate::subvolume::Subvolume;
+use crate::sync;
+use crate::sync::TransactionId;
+use crate::BtrfsUtilError;
+use crate::RawUuid;
+pub use crate::Result;
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::path::PathBuf;
+
+const GROUP_MANIFEST_HEADER: &str = "btrfsutil-snapshot-group 1";
+/// Extension of snapshot group manifest files.
+pub const GROUP_MANIFEST_EXTENSION: &str = "group";
+
+/// Snapshots created together by [atomic_set] or [atomic_set_with].
+///
+/// [atomic_set]: fn.atomic_set.html
+/// [atomic_set_with]: fn.atomic_set_with.html
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotSet {
+    /// The snapshots, in the order they were requested.
+    pub snapshots: Vec<Subvolume>,
+    /// Transaction the first snapshot was created in.
+    pub first_transid: u64,
+    /// Transaction the last snapshot was created in.
+    pub last_transid: u64,
+}
+
+impl SnapshotSet {
+    /// Check whether all snapshots were created in the same transaction.
+    #[inline]
+    pub fn is_single_transaction(&self) -> bool {
+        self.first_transid == self.last_transid
+    }
+}
+
+/// Snapshot several subvolumes of one filesystem as a group.
+///
+/// Same as [atomic_set_with] without pausing any writer: the snapshots are only as close in
+/// time as the filesystem allows.
+///
+/// [atomic_set_with]: fn.atomic_set_with.html
+pub fn atomic_set<F>(pairs: &[(&Subvolume, &Path)], flags: F) -> Result<SnapshotSet>
+where
+    F: Into<Option<SnapshotFlags>>,
+{
+    atomic_set_with(pairs, flags, || Ok(()))
+}
+
+/// Snapshot several subvolumes of one filesystem as a group, each to its destination path.
+///
+/// Dirty data is flushed first so the snapshots follow each other quickly. Then `quiesce` is
+/// called to pause the writers of the subvolumes; the guard it returns is held while the
+/// snapshots are created and dropped right after, which is when writers should resume.
+///
+/// Either all snapshots are created or none is: if one fails or its transaction does not commit,
+/// those already created are deleted again, and any that cannot be are listed in a
+/// [BtrfsUtilError::Rollback] wrapping the error. Fails with [LibError::InvalidArgument] if the
+/// subvolumes are not all on the same filesystem.
+///
+/// [BtrfsUtilError::Rollback]: ../error/enum.BtrfsUtilError.html#variant.Rollback
+/// [LibError::InvalidArgument]: ../error/enum.LibError.html#variant.InvalidArgument
+pub fn atomic_set_with<F, Q, G>(
+    pairs: &[(&Subvolume, &Path)],
+    flags: F,
+    quiesce: Q,
+) -> Result<SnapshotSet>
+where
+    F: Into<Option<SnapshotFlags>>,
+    Q: FnOnce() -> Result<G>,
+{
+    let flags = flags.into();
+    let first = match pairs.first() {
+        Some((subvol, _)) => *subvol,
+        None => return Err(LibError::InvalidArgument.into()),
+    };
+    for (subvol, _) in &pairs[1..] {
+        if !first.same_filesystem(subvol)? {
+            return Err(LibError::InvalidArgument.into());
+        }
+    }
+
+    sync::sync(first.path())?;
+
+    let guard = quiesce()?;
+    let snapshots = create_all(pairs, flags, true, guard)?;
+
+    let mut transids: Vec<u64> = Vec::with_capacity(snapshots.len());
+    for snapshot in &snapshots {
+        transids.push(snapshot.info()?.otransid);
+    }
+
+    Ok(SnapshotSet {
+        first_transid: transids.iter().copied().min().unwrap_or(0),
+        last_transid: transids.iter().copied().max().unwrap_or(0),
+        snapshots,
+    })
+}
+
+/// Snapshot several subvolumes back to back, each to its destination path.
+///
+/// Unlike [atomic_set], the subvolumes may be on different filesystems. With `single_sync`, the
+/// transactions creating the snapshots are only waited on once all of them are created, keeping
+/// the snapshots close together in time; otherwise each snapshot is committed before the next
+/// one is created.
+///
+/// Either all snapshots are created or none is: if one fails or its transaction does not commit,
+/// those already created are deleted again, and any that cannot be are listed in a
+/// [BtrfsUtilError::Rollback] wrapping the error.
+///
+/// [atomic_set]: fn.atomic_set.html
+/// [BtrfsUtilError::Rollback]: ../error/enum.BtrfsUtilError.html#variant.Rollback
+pub fn snapshot_many<F>(
+    pairs: &[(&Subvolume, &Path)],
+    flags: F,
+    single_sync: bool,
+) -> Result<Vec<Subvolume>>
+where
+    F: Into<Option<SnapshotFlags>>,
+{
+    create_all(pairs, flags.into(), single_sync, ())
+}
+
+/// Create the snapshots of `pairs`, deleting them all if one fails or is not committed.
+///
+/// `guard` is dropped once the snapshots are created, before waiting on their transactions or
+/// cleaning up.
+fn create_all<G>(
+    pairs: &[(&Subvolume, &Path)],
+    flags: Option<SnapshotFlags>,
+    single_sync: bool,
+    guard: G,
+) -> Result<Vec<Subvolume>> {
+    let mut snapshots: Vec<Subvolume> = Vec::with_capacity(pairs.len());
+    let mut transactions: Vec<TransactionId> = Vec::new();
+    for (subvol, dest) in pairs {
+        let result = if single_sync {
+            // waiting once at the end keeps the snapshots as close together as possible
+            subvol
+                .snapshot_async(*dest, flags, None)
+                .map(|(snapshot, transaction)| {
+                    transactions.push(transaction);
+                    snapshot
+                })
+        } else {
+            subvol.snapshot(*dest, flags, None)
+        };
+        match result {
+            Ok(snapshot) => snapshots.push(snapshot),
+            Err(err) => {
+                drop(guard);
+                return Err(roll_back(snapshots, err));
+            }
+        }
+    }
+    drop(guard);
+    // each filesystem has its own transactions, waiting on all of them covers every snapshot
+    for transaction in transactions {
+        if let Err(err) = transaction.wait() {
+            return Err(roll_back(snapshots, err));
+        }
+    }
+
+    Ok(snapshots)
+}
+
+/// Delete the snapshots created before `err`, listing those that could not be deleted with it.
+fn roll_back(snapshots: Vec<Subvolume>, err: BtrfsUtilError) -> BtrfsUtilError {
+    let left_behind: Vec<(PathBuf, BtrfsUtilError)> = snapshots
+        .into_iter()
+        .filter_map(|snapshot| {
+            let path = snapshot.path().to_path_buf();
+            snapshot.delete(None).err().map(|err| (path, err))
+        })
+        .collect();
+    if left_behind.is_empty() {
+        err
+    } else {
+        BtrfsUtilError::Rollback {
+            error: Box::new(err),
+            left_behind,
+        }
+    }
+}
+
+/// A snapshot of a [SnapshotGroup].
+///
+/// [SnapshotGroup]: struct.SnapshotGroup.html
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GroupMember {
+    /// Path of the snapshot.
+    pub path: PathBuf,
+    /// UUID of the snapshot, telling it apart from anything created at the same path later.
+    pub uuid: RawUuid,
+}
+
+impl GroupMember {
+    /// Check whether the snapshot still exists at its path.
+    pub fn exists(&self) -> Result<bool> {
+        if self.path.symlink_metadata().is_err() {
+            return Ok(false);
+        }
+        match Subvolume::get(&self.path) {
+            Ok(subvol) => Ok(subvol.info()?.uuid == self.uuid),
+            Err(_) => Ok(false),
+        }
+    }
+}
+
+/// Snapshots taken together, persisted as a manifest file named after the group.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SnapshotGroup {
+    /// Name of the group, also the stem of its manifest file.
+    pub name: String,
+    /// The snapshots of the group.
+    pub members: Vec<GroupMember>,
+}
+
+impl SnapshotGroup {
+    /// Create a group from a set of snapshots.
+    ///
+    /// Fails with [LibError::InvalidArgument] if the name is empty or contains a slash or a
+    /// line break.
+    ///
+    /// [LibError::InvalidArgument]: ../error/enum.LibError.html#variant.InvalidArgument
+    pub fn from_set<S>(name: S, set: &SnapshotSet) -> Result<Self>
+    where
+        S: Into<String>,
+    {
+        let name = name.into();
+        if name.is_empty() || name.contains(['/', '\n']) {
+            return Err(LibError::InvalidArgument.into());
+        }
+
+        let members = set
+            .snapshots
+            .iter()
+            .map(|snapshot| {
+                Ok(GroupMember {
+                    path: snapshot.path().to_path_buf(),
+                    uuid: snapshot.info()?.uuid,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self { name, members })
+    }
+
+    /// Write the manifest in its text form: a header and the group name, then the UUID and path
+    /// of one member per line.
+    pub fn write_to<W>(&self, mut writer: W) -> Result<()>
+    where
+        W: Write,
+    {
+        if self
+            .members
+            .iter()
+            .any(|member| member.path.as_os_str().as_bytes().contains(&b'\n'))
+        {
+            return Err(LibError::InvalidArgument.into());
+        }
+
+        let write = |writer: &mut W| -> io::Result<()> {
+            writeln!(writer, "{}", GROUP_MANIFEST_HEADER)?;
+            writeln!(writer, "{}", self.name)?;
+            for member in &self.members {
+                write!(writer, "{} ", member.uuid)?;
+                writer.write_all(member.path.as_os_str().as_bytes())?;
+                writeln!(writer)?;
+            }
+            Ok(())
+        };
+        write(&mut writer)
+            .map_err(|err| BtrfsUtilError::from_io(LibError::GroupManifestFailed, &err))
+    }
+
+    /// Read a manifest written by [write_to].
+    ///
+    /// [write_to]: #method.write_to
+    pub fn read_from<R>(mut reader: R) -> Result<Self>
+    where
+        R: BufRead,
+    {
+        let mut next_line = || -> Result<Option<Vec<u8>>> {
+            let mut line: Vec<u8> = Vec::new();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => Ok(None),
+                Ok(_) => {
+                    if line.last() == Some(&b'\n') {
+                        line.pop();
+                    }
+                    Ok(Some(line))
+                }
+                Err(err) => Err(BtrfsUtilError::from_io(LibError::GroupManifestFailed, &err)),
+            }
+        };
+
+        if next_line()?.as_deref() != Some(GROUP_MANIFEST_HEADER.as_bytes()) {
+            return Err(LibError::GroupManifestInvalid.into());
+        }
+        let name = next_line()?
+            .and_then(|name| String::from_utf8(name).ok())
+            .ok_or(LibError::GroupManifestInvalid)?;
+
+        let mut members = Vec::new();
+        while let Some(line) = next_line()? {
+            let sep = line
+                .iter()
+                .position(|b| *b == b' ')
+                .ok_or(LibError::GroupManifestInvalid)?;
+            let uuid = std::str::from_utf8(&line[..sep])
+                .ok()
+                .and_then(|uuid| RawUuid::parse(uuid).ok())
+                .ok_or(LibError::GroupManifestInvalid)?;
+            members.push(GroupMember {
+                path: PathBuf::from(OsStr::from_bytes(&line[sep + 1..])),
+                uuid,
+            });
+        }
+
+        Ok(Self { name, members })
+    }
+
+    /// Get the path of the manifest of this group in a directory.
+    pub fn manifest_path(&self, dir: &Path) -> PathBuf {
+        dir.join(&self.name)
+            .with_extension(GROUP_MANIFEST_EXTENSION)
+    }
+
+    /// Save the manifest of this group in a directory, replacing any previous one, and get its
+    /// path.
+    pub fn save(&self, dir: &Path) -> Result<PathBuf> {
+        let path = self.manifest_path(dir);
+        let tmp = path.with_extension(format!("{}.tmp", GROUP_MANIFEST_EXTENSION));
+
+        let mut file = fs::File::create(&tmp)
+            .map_err(|err| BtrfsUtilError::from_io(LibError::GroupManifestFailed, &err))?;
+        self.write_to(&mut file)?;
+        file.sync_all()
+            .map_err(|err| BtrfsUtilError::from_io(LibError::GroupManifestFailed, &err))?;
+        // renaming makes the new manifest appear whole or not at all
+        fs::rename(&tmp, &path)
+            .map_err(|err| BtrfsUtilError::from_io(LibError::GroupManifestFailed, &err))?;
+
+        Ok(path)
+    }
+
+    /// Load a manifest saved by [save].
+    ///
+    /// [save]: #method.save
+    pub fn load(path: &Path) -> Result<Self> {
+        fs::File::open(path)
+            .map_err(|err| BtrfsUtilError::from_io(LibError::GroupManifestFailed, &err))
+            .and_then(|file| Self::read_from(BufReader::new(file)))
+            .context("SnapshotGroup::load", &[path])
+    }
+
+    /// Get the members that no longer exist.
+    pub fn missing(&self) -> Result<Vec<&GroupMember>> {
+        let mut missing = Vec::new();
+        for member in &self.members {
+            if !member.exists()? {
+                missing.push(member);
+            }
+        }
+        Ok(missing)
+    }
+
+    /// Check whether every member still exists.
+    pub fn is_complete(&self) -> Result<bool> {
+        Ok(self.missing()?.is_empty())
+    }
+
+    /// Delete every member of this group, then its manifest in a directory.
+    ///
+    /// Members already gone count as deleted, so a partially failed deletion can be retried. The
+    /// manifest is kept if any member could not be deleted.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn delete(self, dir: &Path) -> BatchResult<PathBuf, ()> {
+        let mut batch = BatchError::new();
+        for member in &self.members {
+            let result = member.exists().and_then(|exists| {
+                if exists {
+                    Subvolume::get(&member.path)?.elevate()?.delete(None)
+                } else {
+                    Ok(())
+                }
+            });
+            batch.push(member.path.clone(), result);
+        }
+
+        if !batch.has_failures() {
+            let manifest = self.manifest_path(dir);
+            let result = match fs::remove_file(&manifest) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    Err(LibError::GroupManifestFailed.into())
+                }
+                _ => Ok(()),
+            };
+            batch.push(manifest, result);
+        }
+
+        batch.into_result()
+    }
+}
+
+/// List the snapshot groups whose manifests are saved in a directory, sorted by name.
+pub fn list_groups(dir: &Path) -> Result<Vec<SnapshotGroup>> {
+    list_groups_impl(dir).context("snapshots::list_groups", &[dir])
+}
+
+fn list_groups_impl(dir: &Path) -> Result<Vec<SnapshotGroup>> {
+    let entries = fs::read_dir(dir)
+        .map_err(|err| BtrfsUtilError::from_io(LibError::GroupManifestFailed, &err))?;
+
+    let mut groups = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|err| BtrfsUtilError::from_io(LibError::GroupManifestFailed, &err))?
+            .path();
+        if path.extension() == Some(OsStr::new(GROUP_MANIFEST_EXTENSION)) {
+            groups.push(SnapshotGroup::load(&path)?);
+        }
+    }
+    groups.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(groups)
+}
+
+/// A snapshot deleted when dropped, unless kept with [keep].
+///
+/// Deleting on drop cannot report errors; use [delete] to handle them.
+///
+/// [keep]: #method.keep
+/// [delete]: #method.delete
+#[derive(Debug)]
+pub struct TempSnapshot(Option<Subvolume>);
+
+impl TempSnapshot {
+    /// Create a read-only snapshot of a subvolume.
+    pub fn new<P>(src: &Subvolume, path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::with_flags(src, path, SnapshotFlags::READ_ONLY)
+    }
+
+    /// Create a snapshot of a subvolume with the given flags.
+    pub fn with_flags<P>(src: &Subvolume, path: P, flags: SnapshotFlags) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Ok(Self(Some(src.snapshot(path, flags, None)?)))
+    }
+
+    /// Get the snapshot.
+    #[inline]
+    pub fn subvolume(&self) -> &Subvolume {
+        // only taken by keep, delete and drop, which consume the guard
+        self.0.as_ref().unwrap()
+    }
+
+    /// Get the path of the snapshot.
+    #[inline]
+    pub fn path(&self) -> &Path {
+        self.subvolume().path()
+    }
+
+    /// Keep the snapshot instead of deleting it.
+    pub fn keep(mut self) -> Subvolume {
+        self.0.take().unwrap()
+    }
+
+    /// Delete the snapshot now, reporting failure.
+    pub fn delete(mut self) -> Result<()> {
+        self.0.take().unwrap().delete(None)
+    }
+}
+
+impl Drop for TempSnapshot {
+    fn drop(&mut self) {
+        if let Some(subvol) = self.0.take() {
+            let _ = subvol.delete(None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn group_manifest_roundtrip() {
+        let group = SnapshotGroup {
+            name: "db-2026-10-18".into(),
+            members: vec![
+                GroupMember {
+                    path: "/mnt/snapshots/db data".into(),
+                    uuid: RawUuid::from_bytes(1u128.to_be_bytes()),
+                },
+                GroupMember {
+                    path: "/mnt/snapshots/wal".into(),
+                    uuid: RawUuid::from_bytes(2u128.to_be_bytes()),
+                },
+            ],
+        };
+
+        let mut text: Vec<u8> = Vec::new();
+        group.write_to(&mut text).unwrap();
+        assert_eq!(SnapshotGroup::read_from(&text[..]).unwrap(), group);
+        assert!(SnapshotGroup::read_from(&b"bogus\n"[..]).is_err());
+    }
+}
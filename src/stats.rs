@@ -0,0 +1,359 @@
+//! Space statistics
+//!
+//! How much space files take on disk compared to their contents, like `compsize` reports, and
+//! how much of it snapshots share.
+
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::ioctl;
+use crate::ioctl::SearchKey;
+use crate::subvolume::Subvolume;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::ops::AddAssign;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+/// A compression algorithm extents are stored with.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Algorithm {
+    /// Not compressed.
+    None,
+    /// zlib.
+    Zlib,
+    /// LZO.
+    Lzo,
+    /// Zstandard.
+    Zstd,
+}
+
+impl Algorithm {
+    /// Get the algorithm of the compression type of a file extent item.
+    fn from_raw(compression: u8) -> Option<Self> {
+        match compression {
+            0 => Some(Algorithm::None),
+            1 => Some(Algorithm::Zlib),
+            2 => Some(Algorithm::Lzo),
+            3 => Some(Algorithm::Zstd),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    /// Format the algorithm as `compsize` does.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Algorithm::None => write!(f, "none"),
+            Algorithm::Zlib => write!(f, "zlib"),
+            Algorithm::Lzo => write!(f, "lzo"),
+            Algorithm::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// Sizes of the extents stored with one compression algorithm, or of all of them.
+///
+/// Extents shared by several files, or referenced more than once by the same file, count once in
+/// the disk and uncompressed sizes but every time in the referenced size.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompressionStats {
+    /// Bytes the extents take on disk.
+    pub disk: u64,
+    /// Bytes of the extents once uncompressed.
+    pub uncompressed: u64,
+    /// Bytes of file contents stored in the extents.
+    pub referenced: u64,
+}
+
+impl CompressionStats {
+    /// Get the disk size as a fraction of the uncompressed size, or None if there is no data.
+    pub fn ratio(&self) -> Option<f64> {
+        if self.uncompressed == 0 {
+            return None;
+        }
+        Some(self.disk as f64 / self.uncompressed as f64)
+    }
+}
+
+impl AddAssign for CompressionStats {
+    fn add_assign(&mut self, other: Self) {
+        self.disk += other.disk;
+        self.uncompressed += other.uncompressed;
+        self.referenced += other.referenced;
+    }
+}
+
+/// Compression statistics of a file or directory tree, as computed by [compression].
+///
+/// [compression]: fn.compression.html
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompressionReport {
+    /// Number of regular files examined, hard links counted once.
+    pub files: u64,
+    /// Statistics of the algorithms the extents are stored with.
+    pub algorithms: BTreeMap<Algorithm, CompressionStats>,
+}
+
+impl CompressionReport {
+    /// Get the statistics of all extents, whatever their algorithm.
+    pub fn total(&self) -> CompressionStats {
+        let mut total = CompressionStats::default();
+        for stats in self.algorithms.values() {
+            total += *stats;
+        }
+        total
+    }
+}
+
+/// Compute how much space the extents of a file, or of the regular files below a directory,
+/// take on disk for each compression algorithm.
+///
+/// Directories are walked without following symbolic links or entering other filesystems,
+/// which includes nested subvolumes, so the path of a subvolume covers just that subvolume.
+/// Holes are not counted, and neither are extents compressed with algorithms this library does
+/// not know about.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn compression<P>(path: P) -> Result<CompressionReport>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    compression_impl(path).context("stats::compression", &[path])
+}
+
+fn compression_impl(path: &Path) -> Result<CompressionReport> {
+    let metadata = fs::symlink_metadata(path)
+        .map_err(|err| BtrfsUtilError::from_io(LibError::StatFailed, &err))?;
+    // a tree id of 0 searches the subvolume the file belongs to
+    let file = ioctl::open(path)?;
+
+    let mut walk = Walk {
+        fd: file.as_raw_fd(),
+        dev: metadata.dev(),
+        inodes: HashSet::new(),
+        extents: HashSet::new(),
+        report: CompressionReport::default(),
+    };
+    walk.visit(path, &metadata)?;
+
+    Ok(walk.report)
+}
+
+/// State of a walk of a directory tree.
+struct Walk {
+    fd: RawFd,
+    dev: u64,
+    /// Inodes already counted, for hard links.
+    inodes: HashSet<u64>,
+    /// Disk addresses of the extents already counted, for shared extents.
+    extents: HashSet<u64>,
+    report: CompressionReport,
+}
+
+impl Walk {
+    fn visit(&mut self, path: &Path, metadata: &fs::Metadata) -> Result<()> {
+        if metadata.dev() != self.dev {
+            return Ok(());
+        }
+        if metadata.is_dir() {
+            for entry in fs::read_dir(path)
+                .map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?
+            {
+                let entry =
+                    entry.map_err(|err| BtrfsUtilError::from_io(LibError::ReadFailed, &err))?;
+                let metadata = entry
+                    .metadata()
+                    .map_err(|err| BtrfsUtilError::from_io(LibError::StatFailed, &err))?;
+                self.visit(&entry.path(), &metadata)?;
+            }
+        } else if metadata.is_file() && self.inodes.insert(metadata.ino()) {
+            self.report.files += 1;
+            self.count_extents(metadata.ino())?;
+        }
+        Ok(())
+    }
+
+    fn count_extents(&mut self, ino: u64) -> Result<()> {
+        let key = SearchKey::new(
+            0,
+            (ino, ioctl::BTRFS_EXTENT_DATA_KEY, 0),
+            (ino, ioctl::BTRFS_EXTENT_DATA_KEY, u64::MAX),
+        );
+        let report = &mut self.report;
+        let extents = &mut self.extents;
+        ioctl::tree_search(self.fd, key, |header, item| {
+            // generation, ram_bytes, compression, encryption, other_encoding, type
+            if header.item_type != ioctl::BTRFS_EXTENT_DATA_KEY || item.len() < 21 {
+                return true;
+            }
+            let algorithm = match Algorithm::from_raw(item[16]) {
+                Some(algorithm) => algorithm,
+                None => return true,
+            };
+            let ram_bytes = ioctl::le_u64(item, 8);
+
+            let stats = match item[20] {
+                // the data follows the header in the item, compressed as a whole
+                ioctl::BTRFS_FILE_EXTENT_INLINE => CompressionStats {
+                    disk: (item.len() - 21) as u64,
+                    uncompressed: ram_bytes,
+                    referenced: ram_bytes,
+                },
+                // disk_bytenr, disk_num_bytes, offset, num_bytes
+                _ if item.len() >= 53 => {
+                    let disk_bytenr = ioctl::le_u64(item, 21);
+                    if disk_bytenr == 0 {
+                        return true;
+                    }
+                    let referenced = ioctl::le_u64(item, 45);
+                    if extents.insert(disk_bytenr) {
+                        CompressionStats {
+                            disk: ioctl::le_u64(item, 29),
+                            uncompressed: ram_bytes,
+                            referenced,
+                        }
+                    } else {
+                        CompressionStats {
+                            referenced,
+                            ..Default::default()
+                        }
+                    }
+                }
+                _ => return true,
+            };
+            *report.algorithms.entry(algorithm).or_default() += stats;
+            true
+        })
+    }
+}
+
+/// Space two subvolumes share and hold on their own, as computed by [shared_between].
+///
+/// [shared_between]: fn.shared_between.html
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SharedStats {
+    /// Bytes on disk of the extents both subvolumes reference.
+    pub shared: u64,
+    /// Bytes on disk of the extents only the first subvolume references.
+    pub exclusive_a: u64,
+    /// Bytes on disk of the extents only the second subvolume references.
+    pub exclusive_b: u64,
+}
+
+/// Compute how much of the data of two subvolumes of one filesystem, usually snapshots of the
+/// same subvolume, is shared between them.
+///
+/// Extents are compared whole, as a file referencing any part of an extent keeps all of it on
+/// disk. Extents only one of the two references may still be shared with other subvolumes, so
+/// the exclusive sizes are upper bounds of what deleting that subvolume would free. Inline
+/// extents are not counted. Fails with [LibError::InvalidArgument] if the subvolumes are on
+/// different filesystems.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+///
+/// [LibError::InvalidArgument]: ../error/enum.LibError.html#variant.InvalidArgument
+pub fn shared_between(a: &Subvolume, b: &Subvolume) -> Result<SharedStats> {
+    shared_between_impl(a, b).context("stats::shared_between", &[a.path(), b.path()])
+}
+
+fn shared_between_impl(a: &Subvolume, b: &Subvolume) -> Result<SharedStats> {
+    if !a.same_filesystem(b)? {
+        return Err(LibError::InvalidArgument.into());
+    }
+    let extents_a = data_extents(a)?;
+    let extents_b = data_extents(b)?;
+
+    let mut stats = SharedStats::default();
+    for (bytenr, bytes) in &extents_a {
+        if extents_b.contains_key(bytenr) {
+            stats.shared += bytes;
+        } else {
+            stats.exclusive_a += bytes;
+        }
+    }
+    stats.exclusive_b = extents_b
+        .iter()
+        .filter(|(bytenr, _)| !extents_a.contains_key(bytenr))
+        .map(|(_, bytes)| bytes)
+        .sum();
+
+    Ok(stats)
+}
+
+/// Get the disk addresses of the extents the files of a subvolume reference, with their sizes
+/// on disk.
+fn data_extents(subvol: &Subvolume) -> Result<HashMap<u64, u64>> {
+    let file = ioctl::open(subvol.path())?;
+    let key = SearchKey::new(
+        subvol.id().get(),
+        (0, ioctl::BTRFS_EXTENT_DATA_KEY, 0),
+        (u64::MAX, ioctl::BTRFS_EXTENT_DATA_KEY, u64::MAX),
+    );
+
+    let mut extents: HashMap<u64, u64> = HashMap::new();
+    ioctl::tree_search(file.as_raw_fd(), key, |header, item| {
+        if header.item_type != ioctl::BTRFS_EXTENT_DATA_KEY
+            || item.len() < 53
+            || item[20] == ioctl::BTRFS_FILE_EXTENT_INLINE
+        {
+            return true;
+        }
+        // disk_bytenr is 0 for holes
+        let disk_bytenr = ioctl::le_u64(item, 21);
+        if disk_bytenr != 0 {
+            extents.insert(disk_bytenr, ioctl::le_u64(item, 29));
+        }
+        true
+    })?;
+
+    Ok(extents)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compression_totals() {
+        let mut report = CompressionReport::default();
+        report.algorithms.insert(
+            Algorithm::None,
+            CompressionStats {
+                disk: 4096,
+                uncompressed: 4096,
+                referenced: 8192,
+            },
+        );
+        report.algorithms.insert(
+            Algorithm::Zstd,
+            CompressionStats {
+                disk: 4096,
+                uncompressed: 131072,
+                referenced: 131072,
+            },
+        );
+
+        let total = report.total();
+        assert_eq!(total.disk, 8192);
+        assert_eq!(total.referenced, 139264);
+        assert_eq!(total.ratio(), Some(8192.0 / 135168.0));
+        assert_eq!(CompressionStats::default().ratio(), None);
+        assert_eq!(Algorithm::from_raw(3), Some(Algorithm::Zstd));
+        assert_eq!(Algorithm::from_raw(4), None);
+        assert_eq!(Algorithm::Lzo.to_string(), "lzo");
+    }
+}
@@ -0,0 +1,164 @@
+use crate::error::BatchError;
+use crate::error::BatchResult;
+use crate::subvolume::DeleteFlags;
+use crate::subvolume::ReadOnlyGuard;
+use crate::subvolume::Subvolume;
+use crate::subvolume::SubvolumeId;
+use crate::RawUuid;
+use crate::Result;
+
+use std::cmp::Reverse;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// A [Subvolume] handle for privileged operations, obtained with [Subvolume::elevate].
+///
+/// The subvolume operations requiring **CAP_SYS_ADMIN** only exist on this handle, and having one
+/// means the current process was found able to perform them on the filesystem of the subvolume.
+/// It dereferences to the [Subvolume] for everything else. The higher level operations requiring
+/// **CAP_SYS_ADMIN**, such as [RetentionPlan::execute], get this handle for the subvolumes they
+/// act on first.
+///
+/// [Subvolume]: struct.Subvolume.html
+/// [Subvolume::elevate]: struct.Subvolume.html#method.elevate
+/// [RetentionPlan::execute]: ../retention/struct.RetentionPlan.html#method.execute
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct AdminSubvolume(Subvolume);
+
+impl AdminSubvolume {
+    /// Wrap a subvolume whose privileges have been checked.
+    ///
+    /// Restricted to the crate.
+    #[inline]
+    pub(crate) fn new(subvolume: Subvolume) -> Self {
+        Self(subvolume)
+    }
+
+    /// Delete this subvolume.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn delete<D>(self, flags: D) -> Result<()>
+    where
+        D: Into<Option<DeleteFlags>>,
+    {
+        self.0.delete(flags)
+    }
+
+    /// Delete several subvolumes, going on past the ones that fail.
+    ///
+    /// Subvolumes nested in others of the set are deleted before them, so nested subvolumes can
+    /// be deleted together without [DeleteFlags::RECURSIVE]. The result maps the path of each
+    /// subvolume to its outcome.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    ///
+    /// [DeleteFlags::RECURSIVE]: struct.DeleteFlags.html#associatedconstant.RECURSIVE
+    pub fn delete_many<I, D>(subvolumes: I, flags: D) -> BatchResult<PathBuf, ()>
+    where
+        I: IntoIterator<Item = AdminSubvolume>,
+        D: Into<Option<DeleteFlags>>,
+    {
+        let flags = flags.into();
+        let mut subvolumes: Vec<AdminSubvolume> = subvolumes.into_iter().collect();
+        // deepest first, a subvolume's path is always longer than the one of its parent
+        subvolumes.sort_by_key(|subvolume| Reverse(subvolume.path().components().count()));
+
+        subvolumes
+            .into_iter()
+            .map(|subvolume| (subvolume.path().to_path_buf(), subvolume.delete(flags)))
+            .collect::<BatchError<_, _>>()
+            .into_result()
+    }
+
+    /// Delete a subvolume by its id, on the filesystem this subvolume belongs to.
+    ///
+    /// Unlike [delete](#method.delete), this does not need a path to the subvolume, so it can
+    /// remove subvolumes that are not reachable through any mount. With
+    /// [DeleteFlags::RECURSIVE], the subvolumes below it are deleted first, deepest first.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    ///
+    /// [DeleteFlags::RECURSIVE]: struct.DeleteFlags.html#associatedconstant.RECURSIVE
+    pub fn delete_by_id<D>(&self, id: SubvolumeId, flags: D) -> Result<()>
+    where
+        D: Into<Option<DeleteFlags>>,
+    {
+        Subvolume::delete_by_id(self.path(), id, flags)
+    }
+
+    /// Get a list of subvolumes which have been deleted but not yet cleaned up, on the
+    /// filesystem this subvolume belongs to.
+    ///
+    /// The list is empty when there are none, for example on a freshly mounted filesystem or
+    /// once the cleaner thread has caught up.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn deleted(&self) -> Result<Vec<Subvolume>> {
+        Subvolume::deleted(self.path())
+    }
+
+    /// Set this subvolume as the default subvolume.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn set_default(&self) -> Result<()> {
+        self.0.set_default()
+    }
+
+    /// Set whether this subvolume is read-only or not.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn set_ro(&self, ro: bool) -> Result<()> {
+        self.0.set_ro(ro)
+    }
+
+    /// Make this subvolume read-only until the returned guard is dropped, which restores the
+    /// previous state, also when unwinding from a panic.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn ro_guard(&self) -> Result<ReadOnlyGuard<'_>> {
+        self.0.ro_guard()
+    }
+
+    /// Set the received UUID, the transaction id in the sending filesystem and the time the
+    /// subvolume was sent, marking this subvolume as received from another one.
+    ///
+    /// This is what receiving a subvolume does once its contents are in place, and can repair
+    /// replication chains broken by recreating a subvolume. The kernel records the receiving
+    /// transaction and time itself. The subvolume must not be read-only yet; it is usually made
+    /// read-only right after.
+    ///
+    /// Passing a nil UUID clears the received information.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn set_received_uuid(
+        &self,
+        uuid: &RawUuid,
+        stransid: u64,
+        stime: SystemTime,
+    ) -> Result<()> {
+        self.0.set_received_uuid(uuid, stransid, stime)
+    }
+
+    /// Get the unprivileged handle back.
+    #[inline]
+    pub fn into_inner(self) -> Subvolume {
+        self.0
+    }
+}
+
+impl Deref for AdminSubvolume {
+    type Target = Subvolume;
+
+    #[inline]
+    fn deref(&self) -> &Subvolume {
+        &self.0
+    }
+}
+
+impl From<AdminSubvolume> for Subvolume {
+    #[inline]
+    fn from(subvolume: AdminSubvolume) -> Self {
+        subvolume.0
+    }
+}
@@ -0,0 +1,95 @@
+use crate::qgroup::QgroupInherit;
+use crate::subvolume::SnapshotFlags;
+use crate::subvolume::Subvolume;
+use crate::Result;
+
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Options for creating a subvolume or a snapshot at a path.
+///
+/// Created with [Subvolume::builder]. Options which do not apply to the operation are ignored.
+///
+/// [Subvolume::builder]: struct.Subvolume.html#method.builder
+#[derive(Debug)]
+pub struct SubvolumeBuilder {
+    path: PathBuf,
+    read_only: bool,
+    recursive: bool,
+    qgroup: Option<QgroupInherit>,
+    async_transid: bool,
+}
+
+impl Subvolume {
+    /// Start building a subvolume or a snapshot at a path.
+    pub fn builder<P>(path: P) -> SubvolumeBuilder
+    where
+        P: AsRef<Path>,
+    {
+        SubvolumeBuilder {
+            path: path.as_ref().to_path_buf(),
+            read_only: false,
+            recursive: false,
+            qgroup: None,
+            async_transid: false,
+        }
+    }
+}
+
+impl SubvolumeBuilder {
+    /// Make the subvolume read-only. A new subvolume is made read-only right after being
+    /// created.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Also snapshot the subvolumes nested in the source. Only applies to snapshots.
+    pub fn recursive(mut self, recursive: bool) -> Self {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Add the subvolume to quota groups.
+    pub fn qgroup(mut self, qgroup: QgroupInherit) -> Self {
+        self.qgroup = Some(qgroup);
+        self
+    }
+
+    /// Return without waiting for the transaction creating the subvolume to be committed.
+    ///
+    /// See [Subvolume::create_async] to get the transaction to wait for later.
+    ///
+    /// [Subvolume::create_async]: struct.Subvolume.html#method.create_async
+    pub fn async_transid(mut self, async_transid: bool) -> Self {
+        self.async_transid = async_transid;
+        self
+    }
+
+    /// Create a new subvolume.
+    pub fn create(self) -> Result<Subvolume> {
+        let (subvol, transid) = Subvolume::create_async(&self.path, self.qgroup)?;
+        if self.read_only {
+            subvol.set_ro(true)?;
+        }
+        if !self.async_transid {
+            transid.wait()?;
+        }
+
+        Ok(subvol)
+    }
+
+    /// Create a snapshot of a subvolume.
+    pub fn snapshot(self, src: &Subvolume) -> Result<Subvolume> {
+        let mut flags = SnapshotFlags::empty();
+        flags.set(SnapshotFlags::READ_ONLY, self.read_only);
+        flags.set(SnapshotFlags::RECURSIVE, self.recursive);
+
+        let (subvol, transid) = src.snapshot_async(&self.path, flags, self.qgroup)?;
+        if !self.async_transid {
+            transid.wait()?;
+        }
+
+        Ok(subvol)
+    }
+}
@@ -0,0 +1,116 @@
+use crate::error::ResultExt;
+use crate::ioctl;
+use crate::ioctl::SearchKey;
+use crate::resolve;
+use crate::subvolume::Subvolume;
+use crate::Result;
+
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+/// A file with data written since a generation, as found by
+/// [Subvolume::changed_files_since].
+///
+/// [Subvolume::changed_files_since]: struct.Subvolume.html#method.changed_files_since
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChangedFile {
+    /// Inode number of the file.
+    pub inode: u64,
+    /// Path of the file, or None if it no longer has one, e.g. because it was deleted since.
+    pub path: Option<PathBuf>,
+    /// Latest generation the file's data was written in.
+    pub generation: u64,
+    /// Number of file extents written since the generation.
+    pub extents: u64,
+    /// Number of bytes these extents cover.
+    pub bytes: u64,
+}
+
+impl Subvolume {
+    /// List the files of this subvolume with data written in or after a generation, like
+    /// `btrfs subvolume find-new`.
+    ///
+    /// Only data writes are found; files that were only renamed, had their metadata changed or
+    /// were deleted are not. Files are listed in inode order.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn changed_files_since(
+        &self,
+        generation: u64,
+    ) -> Result<impl Iterator<Item = ChangedFile>> {
+        self.changed_files_since_impl(generation)
+            .context("Subvolume::changed_files_since", &[self.path()])
+    }
+
+    /// Check whether anything in this subvolume changed after a generation, such as the
+    /// [ctransid] recorded the last time it was checked or backed up.
+    ///
+    /// Only the current ctransid of the subvolume is read, so nothing is diffed. Changes are
+    /// seen once the transaction they were made in is committed.
+    ///
+    /// [ctransid]: struct.SubvolumeInfo.html#structfield.ctransid
+    pub fn changed_since(&self, generation: u64) -> Result<bool> {
+        let info = self
+            .info()
+            .context("Subvolume::changed_since", &[self.path()])?;
+        Ok(info.ctransid > generation)
+    }
+
+    fn changed_files_since_impl(
+        &self,
+        generation: u64,
+    ) -> Result<impl Iterator<Item = ChangedFile>> {
+        let file = ioctl::open(self.path())?;
+
+        let mut key = SearchKey::new(
+            self.id().get(),
+            (0, ioctl::BTRFS_EXTENT_DATA_KEY, 0),
+            (u64::MAX, ioctl::BTRFS_EXTENT_DATA_KEY, u64::MAX),
+        );
+        // tree blocks older than the generation cannot hold newer extents
+        key.min_transid = generation;
+
+        let mut changed: Vec<ChangedFile> = Vec::new();
+        ioctl::tree_search(file.as_raw_fd(), key, |header, item| {
+            if header.item_type != ioctl::BTRFS_EXTENT_DATA_KEY || item.len() < 21 {
+                return true;
+            }
+            let extent_generation = ioctl::le_u64(item, 0);
+            if extent_generation < generation {
+                return true;
+            }
+            let num_bytes = match item[20] {
+                ioctl::BTRFS_FILE_EXTENT_INLINE => ioctl::le_u64(item, 8),
+                _ if item.len() >= 53 => ioctl::le_u64(item, 45),
+                _ => return true,
+            };
+
+            // items come sorted by inode, so extents of one file are next to each other
+            match changed.last_mut() {
+                Some(last) if last.inode == header.objectid => {
+                    last.generation = last.generation.max(extent_generation);
+                    last.extents += 1;
+                    last.bytes += num_bytes;
+                }
+                _ => changed.push(ChangedFile {
+                    inode: header.objectid,
+                    path: None,
+                    generation: extent_generation,
+                    extents: 1,
+                    bytes: num_bytes,
+                }),
+            }
+            true
+        })?;
+
+        for file in &mut changed {
+            // inodes deleted in the meantime have no paths left to resolve
+            file.path = resolve::inode_to_paths(self.path(), file.inode)
+                .ok()
+                .and_then(|paths| paths.into_iter().next())
+                .map(|path| self.path().join(path));
+        }
+
+        Ok(changed.into_iter())
+    }
+}
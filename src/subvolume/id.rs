@@ -0,0 +1,106 @@
+use crate::error::GlueError;
+use crate::BtrfsUtilError;
+use crate::Result;
+
+use std::convert::TryFrom;
+use std::fmt;
+
+/// The id of a Btrfs subvolume.
+///
+/// Subvolume ids are the object ids of the subvolume trees: [FS_TREE] for the top-level
+/// subvolume and the range from [FIRST_FREE] to [LAST_FREE] for all others. Ids outside of it
+/// belong to the internal trees of the filesystem and are rejected.
+///
+/// [FS_TREE]: #associatedconstant.FS_TREE
+/// [FIRST_FREE]: #associatedconstant.FIRST_FREE
+/// [LAST_FREE]: #associatedconstant.LAST_FREE
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "u64", into = "u64")
+)]
+pub struct SubvolumeId(u64);
+
+impl SubvolumeId {
+    /// The id of the top-level subvolume.
+    pub const FS_TREE: Self = Self(5);
+    /// The id of the first subvolume created on a filesystem.
+    pub const FIRST_FREE: Self = Self(256);
+    /// The largest subvolume id.
+    pub const LAST_FREE: Self = Self(u64::MAX - 255);
+
+    /// Create a subvolume id, checking that it is not reserved for the internal trees.
+    pub fn new(id: u64) -> Result<Self> {
+        if id == Self::FS_TREE.0 || (Self::FIRST_FREE.0..=Self::LAST_FREE.0).contains(&id) {
+            Ok(Self(id))
+        } else {
+            Err(GlueError::BadId(id).into())
+        }
+    }
+
+    /// Wrap an id returned by libbtrfsutil or the kernel.
+    ///
+    /// Restricted to the crate.
+    #[inline]
+    pub(crate) const fn from_raw(id: u64) -> Self {
+        Self(id)
+    }
+
+    /// Get the id as an integer.
+    #[inline]
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+
+    /// Check whether this is the id of the top-level subvolume.
+    #[inline]
+    pub fn is_fs_tree(self) -> bool {
+        self == Self::FS_TREE
+    }
+}
+
+impl fmt::Display for SubvolumeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<SubvolumeId> for u64 {
+    #[inline]
+    fn from(id: SubvolumeId) -> u64 {
+        id.0
+    }
+}
+
+impl TryFrom<u64> for SubvolumeId {
+    type Error = BtrfsUtilError;
+
+    /// Same as [SubvolumeId::new].
+    ///
+    /// [SubvolumeId::new]: struct.SubvolumeId.html#method.new
+    #[inline]
+    fn try_from(id: u64) -> Result<Self> {
+        Self::new(id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subvolume_id_ranges() {
+        assert_eq!(SubvolumeId::new(5).unwrap(), SubvolumeId::FS_TREE);
+        assert_eq!(SubvolumeId::new(256).unwrap().get(), 256);
+        assert!(SubvolumeId::new(u64::MAX - 255).is_ok());
+
+        for reserved in [0, 1, 4, 6, 255, u64::MAX - 254, u64::MAX] {
+            assert_eq!(
+                SubvolumeId::new(reserved),
+                Err(GlueError::BadId(reserved).into())
+            );
+        }
+        assert_eq!(SubvolumeId::FIRST_FREE.to_string(), "256");
+    }
+}
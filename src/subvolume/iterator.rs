@@ -1,62 +1,422 @@
-use crate::common;
+use crate::error::GlueError;
 use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::ioctl;
 use crate::subvolume::Subvolume;
+use crate::subvolume::SubvolumeId;
+use crate::subvolume::SubvolumeInfo;
+use crate::sys;
+use crate::sys::RawSubvolumeInfo;
+use crate::BtrfsUtilError;
 use crate::Result;
 
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::convert::TryInto;
-use std::ffi::CString;
+use std::ffi::OsStr;
+use std::iter::FusedIterator;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
+use std::path::PathBuf;
 
-use btrfsutil_sys::btrfs_util_create_subvolume_iterator;
-use btrfsutil_sys::btrfs_util_destroy_subvolume_iterator;
-use btrfsutil_sys::btrfs_util_subvolume_iterator;
-use btrfsutil_sys::btrfs_util_subvolume_iterator_next;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
 
 bitflags! {
     /// Subvolume iterator options
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct SubvolumeIteratorFlags: i32 {
         /// Post order
-        const POST_ORDER = btrfsutil_sys::BTRFS_UTIL_SUBVOLUME_ITERATOR_POST_ORDER as i32;
+        const POST_ORDER = 1 << 0;
     }
 }
 
 /// A subvolume iterator.
-pub struct SubvolumeIterator(*mut btrfs_util_subvolume_iterator);
+///
+/// Iterating yields a [Subvolume] per entry, which costs a few allocations and ioctls each. For
+/// maximum throughput, [entries] yields the ids and paths alone, and [next_borrowed] and
+/// [collect_borrowed] borrow the paths from a buffer instead of allocating them.
+///
+/// The subvolume the iterator was created for is not yielded, so iterating over a filesystem
+/// without any other subvolume, such as a freshly created one, yields nothing. Once exhausted,
+/// the iterator keeps returning `None` without calling into libbtrfsutil again.
+///
+/// Filters such as [read_only] apply to every way of iterating. They are checked against the
+/// information libbtrfsutil reads along with each subvolume, without looking each one up again.
+///
+/// The iterator can be moved to another thread, but not shared between threads.
+///
+/// [Subvolume]: struct.Subvolume.html
+/// [entries]: #method.entries
+/// [next_borrowed]: #method.next_borrowed
+/// [collect_borrowed]: #method.collect_borrowed
+/// [read_only]: #method.read_only
+pub struct SubvolumeIterator {
+    raw: sys::SubvolumeIter,
+    /// Path the iterator was created with.
+    base: PathBuf,
+    /// Id of the subvolume the iterator was created for, 0 for the one `base` belongs to.
+    top: u64,
+    /// Absolute path of the subvolume the iterator was created for, once looked up.
+    root: Option<PathBuf>,
+    /// Path of the last subvolume.
+    path: Vec<u8>,
+    /// Information about the last subvolume, when read.
+    info: RawSubvolumeInfo,
+    finished: bool,
+    skip_missing: bool,
+    read_only: bool,
+    snapshots_only: bool,
+    max_depth: Option<usize>,
+    /// Depth of the subvolumes listed so far, by id, when limiting the depth.
+    depths: HashMap<u64, usize>,
+}
+
+/// A subvolume id and path borrowed from a [SubvolumeIterator] or a [SubvolumeArena].
+///
+/// [SubvolumeIterator]: struct.SubvolumeIterator.html
+/// [SubvolumeArena]: struct.SubvolumeArena.html
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BorrowedSubvolume<'a> {
+    /// Id of the subvolume.
+    pub id: SubvolumeId,
+    /// Path of the subvolume, relative to the subvolume the iterator was created for.
+    pub path: &'a Path,
+}
+
+/// Subvolume ids and paths collected by [SubvolumeIterator::collect_borrowed], with all paths
+/// kept in a single buffer.
+///
+/// [SubvolumeIterator::collect_borrowed]: struct.SubvolumeIterator.html#method.collect_borrowed
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SubvolumeArena {
+    bytes: Vec<u8>,
+    /// Id and end of the path in `bytes` of each entry; a path starts where the previous ends.
+    entries: Vec<(SubvolumeId, usize)>,
+}
+
+impl SubvolumeArena {
+    fn push(&mut self, id: SubvolumeId, path: &[u8]) {
+        self.bytes.extend_from_slice(path);
+        self.entries.push((id, self.bytes.len()));
+    }
+
+    /// Get the number of subvolumes.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check whether there are no subvolumes.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get a subvolume by its position in iteration order.
+    pub fn get(&self, index: usize) -> Option<BorrowedSubvolume<'_>> {
+        let (id, end) = *self.entries.get(index)?;
+        let start = match index {
+            0 => 0,
+            _ => self.entries[index - 1].1,
+        };
+        Some(BorrowedSubvolume {
+            id,
+            path: Path::new(OsStr::from_bytes(&self.bytes[start..end])),
+        })
+    }
+
+    /// Iterate over the subvolumes in iteration order.
+    pub fn iter(&self) -> impl Iterator<Item = BorrowedSubvolume<'_>> {
+        (0..self.len()).filter_map(move |index| self.get(index))
+    }
+}
+
+/// An iterator over the ids and paths of subvolumes, created by [SubvolumeIterator::entries].
+///
+/// [SubvolumeIterator::entries]: struct.SubvolumeIterator.html#method.entries
+pub struct SubvolumeEntries(SubvolumeIterator);
+
+impl Iterator for SubvolumeEntries {
+    type Item = Result<(SubvolumeId, PathBuf)>;
+
+    fn next(&mut self) -> Option<Result<(SubvolumeId, PathBuf)>> {
+        Some(self.0.next_raw()?.map(|id| {
+            let path = PathBuf::from(OsStr::from_bytes(&self.0.path));
+            (id, path)
+        }))
+    }
+}
+
+impl FusedIterator for SubvolumeEntries {}
 
 impl SubvolumeIterator {
     /// Create a new subvolume iterator.
-    pub fn new<'a, P, F>(path: P, flags: F) -> Result<Self>
+    pub fn new<P, F>(path: P, flags: F) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        F: Into<Option<SubvolumeIteratorFlags>>,
+    {
+        let path = path.as_ref();
+        // using 0 instead of an id is intentional
+        // https://github.com/kdave/btrfs-progs/blob/11acf45eea6dd81e891564967051e2bb10bd25f7/libbtrfsutil/subvolume.c#L971
+        // if we specify an id then libbtrfsutil will use elevated privileges to search for
+        // subvolumes
+        // if we don't, then it will use elevated privileges only if the current user is root
+        Self::new_impl(path, 0, flags.into()).context("SubvolumeIterator::new", &[path])
+    }
+
+    /// Create a subvolume iterator over the subvolumes below the subvolume with id `top`, of the
+    /// filesystem a path is on, instead of below the subvolume the path belongs to.
+    ///
+    /// This lists any part of the filesystem from anywhere on it, such as everything below the
+    /// top-level subvolume, [SubvolumeId::FS_TREE], while standing inside a nested subvolume.
+    /// Paths are relative to the top subvolume.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    ///
+    /// [SubvolumeId::FS_TREE]: struct.SubvolumeId.html#associatedconstant.FS_TREE
+    pub fn with_top<P, F>(path: P, top: SubvolumeId, flags: F) -> Result<Self>
     where
-        P: Into<&'a Path>,
+        P: AsRef<Path>,
         F: Into<Option<SubvolumeIteratorFlags>>,
     {
-        Self::new_impl(path.into(), flags.into())
-    }
-
-    fn new_impl(path: &Path, flags: Option<SubvolumeIteratorFlags>) -> Result<Self> {
-        let path_cstr = common::path_to_cstr(path);
-        let flags_val = if let Some(val) = flags { val.bits() } else { 0 };
-
-        let raw_iterator_ptr: *mut btrfs_util_subvolume_iterator = {
-            let mut raw_iterator_ptr: *mut btrfs_util_subvolume_iterator = std::ptr::null_mut();
-            unsafe_wrapper!({
-                btrfs_util_create_subvolume_iterator(
-                    path_cstr.as_ptr(),
-                    0, // read below
-                    flags_val,
-                    &mut raw_iterator_ptr,
-                )
-            })?;
-            // using 0 instead of an id is intentional
-            // https://github.com/kdave/btrfs-progs/blob/11acf45eea6dd81e891564967051e2bb10bd25f7/libbtrfsutil/subvolume.c#L971
-            // if we specify an id then libbtrfsutil will use elevated privileges to search for
-            // subvolumes
-            // if we don't, then it will use elevated privileges only if the current user is root
-            raw_iterator_ptr
+        let path = path.as_ref();
+        Self::new_impl(path, top.get(), flags.into())
+            .context("SubvolumeIterator::with_top", &[path])
+    }
+
+    /// List the subvolumes below the subvolume a path belongs to in a single pass, then look up
+    /// their information in parallel on the rayon thread pool.
+    ///
+    /// This is faster than [next_info] on filesystems with thousands of subvolumes, which looks
+    /// each one up in turn. Paths are relative to the subvolume of `path`, and the subvolumes are
+    /// returned in the order they were listed.
+    ///
+    /// Looking up subvolumes by id requires **CAP_SYS_ADMIN**.
+    ///
+    /// [next_info]: #method.next_info
+    #[cfg(feature = "rayon")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rayon")))]
+    pub fn par_collect_info<P, F>(path: P, flags: F) -> Result<Vec<SubvolumeInfo>>
+    where
+        P: AsRef<Path>,
+        F: Into<Option<SubvolumeIteratorFlags>>,
+    {
+        let path = path.as_ref();
+        Self::par_collect_info_impl(path, flags.into())
+            .context("SubvolumeIterator::par_collect_info", &[path])
+    }
+
+    #[cfg(feature = "rayon")]
+    fn par_collect_info_impl(
+        path: &Path,
+        flags: Option<SubvolumeIteratorFlags>,
+    ) -> Result<Vec<SubvolumeInfo>> {
+        let entries = Self::new_impl(path, 0, flags)?
+            .entries()
+            .collect::<Result<Vec<_>>>()?;
+        entries
+            .into_par_iter()
+            .map(|(id, relative)| {
+                // only used to get the information, which does not involve the fsid
+                let subvol = Subvolume::new(id, crate::RawUuid::NIL, path.join(&relative));
+                let mut info = SubvolumeInfo::try_from(&subvol)?;
+                info.path = relative;
+                Ok(info)
+            })
+            .collect()
+    }
+
+    fn new_impl(path: &Path, top: u64, flags: Option<SubvolumeIteratorFlags>) -> Result<Self> {
+        let post_order =
+            flags.is_some_and(|flags| flags.contains(SubvolumeIteratorFlags::POST_ORDER));
+        let raw = sys::SubvolumeIter::new(path, top, post_order)?;
+
+        Ok(Self {
+            raw,
+            base: path.into(),
+            top,
+            root: None,
+            path: Vec::new(),
+            info: RawSubvolumeInfo::default(),
+            finished: false,
+            skip_missing: false,
+            read_only: false,
+            snapshots_only: false,
+            max_depth: None,
+            depths: HashMap::new(),
+        })
+    }
+}
+
+impl SubvolumeIterator {
+    /// Skip the subvolumes deleted between being listed and being opened, instead of yielding
+    /// [LibError::SubvolumeNotFound], [LibError::OpenFailed] or another error caused by a
+    /// missing file.
+    ///
+    /// Subvolumes can be deleted at any time while iterating, which long-running inventories
+    /// have to expect. This only affects iterating over [Subvolume]s, since [next_borrowed] and
+    /// [collect_borrowed] open nothing.
+    ///
+    /// [LibError::SubvolumeNotFound]: ../error/enum.LibError.html#variant.SubvolumeNotFound
+    /// [LibError::OpenFailed]: ../error/enum.LibError.html#variant.OpenFailed
+    /// [Subvolume]: struct.Subvolume.html
+    /// [next_borrowed]: #method.next_borrowed
+    /// [collect_borrowed]: #method.collect_borrowed
+    pub fn skip_missing(mut self, skip_missing: bool) -> Self {
+        self.skip_missing = skip_missing;
+        self
+    }
+
+    /// Only yield read-only subvolumes.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
+    /// Only yield snapshots, the subvolumes with a parent UUID.
+    pub fn snapshots_only(mut self) -> Self {
+        self.snapshots_only = true;
+        self
+    }
+
+    /// Only yield subvolumes at most `max_depth` subvolumes below the subvolume the iterator was
+    /// created for, its children being at depth 1.
+    ///
+    /// Depths are known from the parents of subvolumes being listed before them, so with
+    /// [SubvolumeIteratorFlags::POST_ORDER] every subvolume is taken to be at depth 1. Deeper
+    /// subvolumes are still looked at, only not yielded.
+    ///
+    /// [SubvolumeIteratorFlags::POST_ORDER]: struct.SubvolumeIteratorFlags.html#associatedconstant.POST_ORDER
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Get the information about the next subvolume, read along with it in a single search.
+    ///
+    /// The path of the information is relative to the subvolume the iterator was created for.
+    pub fn next_info(&mut self) -> Option<Result<SubvolumeInfo>> {
+        if let Err(e) = self.advance(true)? {
+            return Some(Err(e));
+        }
+        let path = PathBuf::from(OsStr::from_bytes(&self.path));
+        Some(SubvolumeInfo::from_raw(&self.info, path))
+    }
+
+    /// Iterate over the ids and paths of the subvolumes as listed by libbtrfsutil, without
+    /// opening each subvolume to yield a [Subvolume].
+    ///
+    /// Paths are relative to the subvolume the iterator was created for. Subvolumes deleted
+    /// since being listed are still yielded.
+    ///
+    /// [Subvolume]: struct.Subvolume.html
+    pub fn entries(self) -> SubvolumeEntries {
+        SubvolumeEntries(self)
+    }
+
+    /// Get the next subvolume id and path, with the path borrowed from a buffer reused across
+    /// calls.
+    pub fn next_borrowed(&mut self) -> Option<Result<BorrowedSubvolume<'_>>> {
+        let id = match self.next_raw()? {
+            Ok(id) => id,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(Ok(BorrowedSubvolume {
+            id,
+            path: Path::new(OsStr::from_bytes(&self.path)),
+        }))
+    }
+
+    /// Collect the ids and paths of all remaining subvolumes, keeping all paths in a single
+    /// buffer.
+    pub fn collect_borrowed(mut self) -> Result<SubvolumeArena> {
+        let mut arena = SubvolumeArena::default();
+        while let Some(id) = self.next_raw() {
+            arena.push(id?, &self.path);
+        }
+        Ok(arena)
+    }
+
+    /// Get the absolute path of the subvolume the iterator was created for, which the paths of
+    /// the subvolumes are relative to.
+    fn root(&mut self) -> Result<PathBuf> {
+        if let Some(root) = &self.root {
+            return Ok(root.clone());
+        }
+        let subvol = Subvolume::get_anyway(&self.base)?;
+        let root = if self.top == 0 || self.top == subvol.id().get() {
+            subvol.path().to_path_buf()
+        } else {
+            subvol
+                .resolve(SubvolumeId::from_raw(self.top))?
+                .path()
+                .to_path_buf()
         };
+        self.root = Some(root.clone());
+        Ok(root)
+    }
+
+    /// Advance the iterator, leaving the path in the buffer and returning the id.
+    fn next_raw(&mut self) -> Option<Result<SubvolumeId>> {
+        Some(self.advance(false)?.map(SubvolumeId::from_raw))
+    }
+
+    /// Advance the iterator to the next subvolume passing the filters, leaving the path in the
+    /// buffer, and the information too if asked for or needed by the filters, and returning the
+    /// id.
+    fn advance(&mut self, with_info: bool) -> Option<Result<u64>> {
+        let with_info =
+            with_info || self.read_only || self.snapshots_only || self.max_depth.is_some();
+        loop {
+            let id = match self.advance_once(with_info)? {
+                Ok(id) => id,
+                Err(e) => return Some(Err(e)),
+            };
+            if !with_info || self.matches() {
+                return Some(Ok(id));
+            }
+        }
+    }
 
-        Ok(Self(raw_iterator_ptr))
+    /// Check the last subvolume against the filters, recording its depth.
+    fn matches(&mut self) -> bool {
+        if let Some(max_depth) = self.max_depth {
+            let depth = self
+                .depths
+                .get(&self.info.parent_id)
+                .map_or(1, |depth| depth + 1);
+            self.depths.insert(self.info.id, depth);
+            if depth > max_depth {
+                return false;
+            }
+        }
+        (!self.read_only || self.info.flags & ioctl::BTRFS_ROOT_SUBVOL_RDONLY != 0)
+            && (!self.snapshots_only || self.info.parent_uuid != [0; 16])
+    }
+
+    /// Advance the iterator by one subvolume.
+    fn advance_once(&mut self, with_info: bool) -> Option<Result<u64>> {
+        // the libbtrfsutil iterator is not meant to be advanced past its end
+        if self.finished {
+            return None;
+        }
+
+        let next = if with_info {
+            self.raw.next_info(&mut self.path).map(|info| {
+                info.map(|info| {
+                    self.info = info;
+                    info.id
+                })
+            })
+        } else {
+            self.raw.next(&mut self.path)
+        };
+        if next.is_none() {
+            self.finished = true;
+        }
+        next
     }
 }
 
@@ -64,48 +424,53 @@ impl Iterator for SubvolumeIterator {
     type Item = Result<Subvolume>;
 
     fn next(&mut self) -> Option<Result<Subvolume>> {
-        let mut cstr_ptr: *mut std::os::raw::c_char = std::ptr::null_mut();
-        let mut id: u64 = 0;
-
-        if let Err(e) =
-            unsafe_wrapper!({ btrfs_util_subvolume_iterator_next(self.0, &mut cstr_ptr, &mut id) })
-        {
-            if e == LibError::StopIteration {
-                None
+        loop {
+            let id = match self.next_raw()? {
+                Ok(id) => id,
+                Err(e) => return Some(Err(e)),
+            };
+
+            let subvol = if !self.path.is_empty() {
+                self.root().and_then(|root| {
+                    Subvolume::get(root.join(Path::new(OsStr::from_bytes(&self.path))))
+                })
+            } else if id.get() != 0 {
+                Subvolume::try_from(id)
             } else {
-                Err(e).into()
+                // neither a path nor an id
+                Err(GlueError::NullPointerReceived.into())
+            };
+
+            match subvol {
+                Err(e) if self.skip_missing && is_missing(&e) => continue,
+                subvol => return Some(subvol),
             }
-        } else if !cstr_ptr.is_null() {
-            let path = common::cstr_to_path(unsafe { CString::from_raw(cstr_ptr).as_ref() });
-            Subvolume::get(path.as_path()).into()
-        } else if id != 0 {
-            Subvolume::try_from(id).into()
-        } else {
-            panic!("subvolume iterator returned both a null path")
         }
     }
 }
 
-impl Drop for SubvolumeIterator {
-    fn drop(&mut self) {
-        unsafe {
-            btrfs_util_destroy_subvolume_iterator(self.0);
-        }
-    }
+impl FusedIterator for SubvolumeIterator {}
+
+/// Check whether an error was caused by a subvolume that no longer exists.
+fn is_missing(err: &BtrfsUtilError) -> bool {
+    err == &LibError::SubvolumeNotFound
+        || err == &LibError::OpenFailed
+        || err.errno() == Some(libc::ENOENT)
 }
 
 impl TryFrom<&Subvolume> for SubvolumeIterator {
-    type Error = LibError;
+    type Error = BtrfsUtilError;
 
     /// Same as SubvolumeIterator::new with no flags.
     #[inline]
     fn try_from(src: &Subvolume) -> Result<SubvolumeIterator> {
-        SubvolumeIterator::new_impl(src.path(), None)
+        SubvolumeIterator::new_impl(src.path(), 0, None)
+            .context("SubvolumeIterator::try_from", &[src.path()])
     }
 }
 
 impl TryInto<Vec<Subvolume>> for SubvolumeIterator {
-    type Error = LibError;
+    type Error = BtrfsUtilError;
 
     /// Same as SubvolumeIterator.`collect::<Result<Vec<Subvolume>>>`.
     #[inline]
@@ -113,3 +478,53 @@ impl TryInto<Vec<Subvolume>> for SubvolumeIterator {
         self.collect::<Result<Vec<Subvolume>>>()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn iterator_is_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<SubvolumeIterator>();
+    }
+
+    #[test]
+    fn missing_subvolumes() {
+        assert!(is_missing(&LibError::SubvolumeNotFound.into()));
+        assert!(is_missing(
+            &BtrfsUtilError::os(LibError::SearchFailed, libc::ENOENT)
+                .with_context("Subvolume::get", &[Path::new("a")])
+        ));
+        assert!(!is_missing(&BtrfsUtilError::os(
+            LibError::SearchFailed,
+            libc::EPERM
+        )));
+    }
+
+    #[test]
+    fn subvolume_arena() {
+        let mut arena = SubvolumeArena::default();
+        arena.push(SubvolumeId::from_raw(256), b"home");
+        arena.push(SubvolumeId::from_raw(257), b"home/snapshots");
+        arena.push(SubvolumeId::from_raw(258), b"");
+
+        assert_eq!(arena.len(), 3);
+        assert_eq!(
+            arena.get(1),
+            Some(BorrowedSubvolume {
+                id: SubvolumeId::from_raw(257),
+                path: Path::new("home/snapshots")
+            })
+        );
+        assert_eq!(arena.get(2).unwrap().path, Path::new(""));
+        assert_eq!(arena.get(3), None);
+        assert_eq!(
+            arena
+                .iter()
+                .map(|subvol| subvol.id.get())
+                .collect::<Vec<_>>(),
+            vec![256, 257, 258]
+        );
+    }
+}
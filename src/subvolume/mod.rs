@@ -1,10 +1,21 @@
 //! Btrfs subvolumes
 
+mod admin;
+mod builder;
+mod changed;
+mod id;
 #[macro_use]
 mod iterator;
 mod subvol;
 mod subvol_info;
+mod tree;
 
+pub use crate::Result;
+pub use admin::*;
+pub use builder::*;
+pub use changed::*;
+pub use id::*;
 pub use iterator::*;
 pub use subvol::*;
 pub use subvol_info::*;
+pub use tree::*;
@@ -1,91 +1,125 @@
 use crate::common;
 use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::idempotency;
+use crate::idempotency::IdempotencyKey;
+use crate::ioctl;
+use crate::ioctl::IoctlTimespec;
+use crate::ioctl::ReceivedSubvolArgs;
+use crate::ioctl::VolArgsV2;
+use crate::privileges;
+use crate::privileges::PrivilegeLevel;
+use crate::qgroup;
 use crate::qgroup::QgroupInherit;
+use crate::qgroup::QgroupUsage;
+use crate::subvolume::AdminSubvolume;
+use crate::subvolume::SubvolumeId;
 use crate::subvolume::SubvolumeInfo;
+use crate::subvolume::SubvolumeIterator;
+use crate::sync;
+use crate::sync::TransactionId;
+use crate::sys;
+use crate::tree_search;
+use crate::tree_search::Key;
+use crate::tree_search::SearchRange;
+use crate::BtrfsUtilError;
+use crate::RawUuid;
 use crate::Result;
 
+use std::cmp::Ordering;
 use std::convert::TryFrom;
-use std::ffi::CString;
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
-use btrfsutil_sys::btrfs_util_create_snapshot;
-use btrfsutil_sys::btrfs_util_create_subvolume;
-use btrfsutil_sys::btrfs_util_delete_subvolume;
-use btrfsutil_sys::btrfs_util_deleted_subvolumes;
-use btrfsutil_sys::btrfs_util_get_default_subvolume;
-use btrfsutil_sys::btrfs_util_get_subvolume_read_only;
-use btrfsutil_sys::btrfs_util_is_subvolume;
-use btrfsutil_sys::btrfs_util_set_default_subvolume;
-use btrfsutil_sys::btrfs_util_set_subvolume_read_only;
-use btrfsutil_sys::btrfs_util_subvolume_id;
-use btrfsutil_sys::btrfs_util_subvolume_path;
-use btrfsutil_sys::btrfs_util_wait_sync;
-
-use libc::{c_void, free};
+/// Time between checks for deleted subvolumes having been cleaned up.
+const CLEANUP_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
 bitflags! {
     /// [Subvolume] delete flags.
     ///
     /// [Subvolume]:struct.Subvolume.html
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct DeleteFlags: i32 {
         /// Recursive.
-        const RECURSIVE = btrfsutil_sys::BTRFS_UTIL_DELETE_SUBVOLUME_RECURSIVE as i32;
+        const RECURSIVE = 1 << 0;
     }
 }
 bitflags! {
     /// [Subvolume] snapshot flags.
     ///
     /// [Subvolume]:struct.Subvolume.html
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct SnapshotFlags: i32 {
         /// Read-only.
-        const READ_ONLY	= btrfsutil_sys::BTRFS_UTIL_CREATE_SNAPSHOT_READ_ONLY as i32;
+        const READ_ONLY = 1 << 1;
         /// Recursive.
-        const RECURSIVE = btrfsutil_sys::BTRFS_UTIL_CREATE_SNAPSHOT_RECURSIVE as i32;
+        const RECURSIVE = 1 << 0;
     }
 }
 
 /// A Btrfs subvolume.
-#[derive(Clone, Debug, PartialEq)]
+///
+/// Subvolumes are equal when they have the same id on the same filesystem, told apart by its
+/// fsid, so the same subvolume reached through two different paths, such as two mounts, is one
+/// value. The fsid is looked up when the subvolume is. They are ordered by id, then by fsid.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Subvolume {
-    id: u64,
+    id: SubvolumeId,
+    #[cfg_attr(feature = "serde", serde(default))]
+    fsid: RawUuid,
     path: PathBuf,
 }
 
 impl Subvolume {
     /// Get a subvolume.
     ///
-    /// The path must point to the root of a subvolume.
-    pub fn get<'a, P>(path: P) -> Result<Self>
+    /// The path must point to the root of a subvolume. A relative path is made absolute against
+    /// the current working directory.
+    pub fn get<P>(path: P) -> Result<Self>
     where
-        P: Into<&'a Path>,
+        P: AsRef<Path>,
     {
-        Self::get_impl(path.into())
+        let path = path.as_ref();
+        Self::get_impl(path).context("Subvolume::get", &[path])
     }
 
     fn get_impl(path: &Path) -> Result<Self> {
-        Self::is_subvolume(path)?;
+        Self::is_subvolume_impl(path)?;
 
-        let path_cstr = common::path_to_cstr(path);
-        let id: u64 = {
-            let mut id: u64 = 0;
-            unsafe_wrapper!({ btrfs_util_subvolume_id(path_cstr.as_ptr(), &mut id) })?;
-            id
-        };
+        let id = sys::subvolume_id(path)?;
+        let path = std::path::absolute(path)
+            .map_err(|err| BtrfsUtilError::from_io(LibError::InvalidArgument, &err))?;
 
-        Ok(Subvolume::new(id, path.into()))
+        Ok(Subvolume::new(
+            SubvolumeId::from_raw(id),
+            fsid(&path)?,
+            path,
+        ))
     }
 
     /// Get a subvolume anyway.
     ///
-    /// If the path is not the root of a subvolume, attempts to use btrfs_util_subvolume_path to
-    /// get it, which requires **CAP_SYS_ADMIN**.
-    ///
-    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
-    pub fn get_anyway<'a, P>(path: P) -> Result<Self>
+    /// If the path is not the root of a subvolume, the root of the subvolume it belongs to is
+    /// looked for among its parent directories. If it is not among them, for example because a
+    /// directory of the subvolume is bind mounted on its own, it is looked for among the mounts
+    /// of the filesystem, which requires **CAP_SYS_ADMIN**.
+    pub fn get_anyway<P>(path: P) -> Result<Self>
     where
-        P: Into<&'a Path>,
+        P: AsRef<Path>,
     {
-        Self::get_anyway_impl(path.into())
+        let path = path.as_ref();
+        Self::get_anyway_impl(path).context("Subvolume::get_anyway", &[path])
     }
 
     fn get_anyway_impl(path: &Path) -> Result<Self> {
@@ -93,100 +127,232 @@ impl Subvolume {
             return Ok(subvol);
         }
 
-        let path_cstr = common::path_to_cstr(path);
-        let id: u64 = {
-            let mut id: u64 = 0;
-            unsafe_wrapper!({ btrfs_util_subvolume_id(path_cstr.as_ptr(), &mut id) })?;
-            id
-        };
-
-        let mut path_ret_ptr: *mut std::os::raw::c_char = std::ptr::null_mut();
-
-        unsafe_wrapper!({ btrfs_util_subvolume_path(path_cstr.as_ptr(), id, &mut path_ret_ptr) })?;
+        let id = sys::subvolume_id(path)?;
+        let id = SubvolumeId::from_raw(id);
 
-        let path_ret: CString = unsafe { CString::from_raw(path_ret_ptr) };
+        if let Some(subvol) = Self::root_above(path, id) {
+            return Ok(subvol);
+        }
+        let rel = subvolume_path(path, id)?;
+        let fsid = fsid(path)?;
+        mounted_path(path, &rel)
+            .map(|path| Self::new(id, fsid, path))
+            .ok_or_else(|| LibError::SubvolumeNotFound.into())
+    }
 
-        Ok(Self::new(id, common::cstr_to_path(&path_ret)))
+    /// Find the root of the subvolume `id` among the parent directories of a path inside it.
+    fn root_above(path: &Path, id: SubvolumeId) -> Option<Self> {
+        let path = fs::canonicalize(path).ok()?;
+        // the first subvolume root on the way up is the one the path belongs to
+        path.ancestors()
+            .skip(1)
+            .find_map(|ancestor| Self::get_impl(ancestor).ok())
+            .filter(|subvol| subvol.id == id)
     }
 
     /// Create a new subvolume.
-    pub fn create<'a, P, Q>(path: P, qgroup: Q) -> Result<Self>
+    pub fn create<P, Q>(path: P, qgroup: Q) -> Result<Self>
     where
-        P: Into<&'a Path>,
+        P: AsRef<Path>,
         Q: Into<Option<QgroupInherit>>,
     {
-        Self::create_impl(path.into(), qgroup.into())
+        let path = path.as_ref();
+        Self::create_impl(path, qgroup.into()).context("Subvolume::create", &[path])
     }
 
     fn create_impl(path: &Path, qgroup: Option<QgroupInherit>) -> Result<Self> {
-        let path_cstr = common::path_to_cstr(path);
-        let qgroup_ptr = qgroup.map(|v| v.as_ptr()).unwrap_or(std::ptr::null_mut());
-
-        let transid: u64 = {
-            let mut transid: u64 = 0;
-            unsafe_wrapper!({
-                btrfs_util_create_subvolume(path_cstr.as_ptr(), 0, &mut transid, qgroup_ptr)
-            })?;
-            transid
-        };
+        let (subvol, transid) = Self::create_async_impl(path, qgroup)?;
+        transid.wait()?;
+
+        Ok(subvol)
+    }
+
+    /// Create a new subvolume, creating its missing parent directories first, like `mkdir -p`.
+    ///
+    /// The parents are created as plain directories, not subvolumes.
+    pub fn create_with_parents<P, Q>(path: P, qgroup: Q) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        Q: Into<Option<QgroupInherit>>,
+    {
+        let path = path.as_ref();
+        Self::create_with_parents_impl(path, qgroup.into())
+            .context("Subvolume::create_with_parents", &[path])
+    }
+
+    fn create_with_parents_impl(path: &Path, qgroup: Option<QgroupInherit>) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|err| BtrfsUtilError::from_io(LibError::MkdirFailed, &err))?;
+        }
+
+        Self::create_impl(path, qgroup)
+    }
 
-        unsafe_wrapper!({ btrfs_util_wait_sync(path_cstr.as_ptr(), transid) })?;
+    /// Create a new subvolume without waiting for the transaction creating it to be committed.
+    ///
+    /// The subvolume can be used right away; wait on the returned transaction before relying on
+    /// it surviving a crash. When creating many subvolumes, waiting on the last transaction
+    /// covers all of them.
+    pub fn create_async<P, Q>(path: P, qgroup: Q) -> Result<(Self, TransactionId)>
+    where
+        P: AsRef<Path>,
+        Q: Into<Option<QgroupInherit>>,
+    {
+        let path = path.as_ref();
+        Self::create_async_impl(path, qgroup.into()).context("Subvolume::create_async", &[path])
+    }
 
-        Self::get(path)
+    fn create_async_impl(
+        path: &Path,
+        qgroup: Option<QgroupInherit>,
+    ) -> Result<(Self, TransactionId)> {
+        let transid = sys::create_subvolume(path, qgroup.as_ref().map(QgroupInherit::groups))?;
+
+        Ok((Self::get_impl(path)?, TransactionId::new(path, transid)))
+    }
+
+    /// Create a new subvolume, unless it was already created with the same idempotency key.
+    ///
+    /// An existing, empty subvolume without a key is assumed to be left over from an interrupted
+    /// attempt and is adopted. Anything else already at the path is an error.
+    ///
+    /// The key is recorded on the directory the subvolume is in, as described in [idempotency].
+    ///
+    /// [idempotency]: ../idempotency/index.html
+    pub fn create_idempotent<P, Q>(path: P, qgroup: Q, key: &IdempotencyKey) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        Q: Into<Option<QgroupInherit>>,
+    {
+        let path = path.as_ref();
+        Self::create_idempotent_impl(path, qgroup.into(), key)
+            .context("Subvolume::create_idempotent", &[path])
+    }
+
+    fn create_idempotent_impl(
+        path: &Path,
+        qgroup: Option<QgroupInherit>,
+        key: &IdempotencyKey,
+    ) -> Result<Self> {
+        if !common::path_exists(path)? {
+            let subvol = Self::create_impl(path, qgroup)?;
+            idempotency::set_impl(path, key)?;
+            return Ok(subvol);
+        }
+
+        let subvol = Self::get_impl(path)?;
+        match idempotency::get_impl(path)? {
+            Some(existing) if &existing == key => Ok(subvol),
+            Some(_) => Err(LibError::SubvolCreateFailed.into()),
+            None => {
+                let empty = path
+                    .read_dir()
+                    .map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?
+                    .next()
+                    .is_none();
+                if !empty {
+                    return Err(LibError::SubvolCreateFailed.into());
+                }
+                idempotency::set_impl(path, key)?;
+                Ok(subvol)
+            }
+        }
     }
 
     /// Delete a subvolume.
-    pub fn delete<D>(self, flags: D) -> Result<()>
+    ///
+    /// Restricted to the crate, public through [AdminSubvolume::delete].
+    ///
+    /// [AdminSubvolume::delete]: struct.AdminSubvolume.html#method.delete
+    pub(crate) fn delete<D>(self, flags: D) -> Result<()>
     where
         D: Into<Option<DeleteFlags>>,
     {
-        Self::delete_impl(self, flags.into())
+        let path = self.path.clone();
+        Self::delete_impl(self, flags.into()).context("Subvolume::delete", &[&path])
     }
 
     fn delete_impl(self, flags: Option<DeleteFlags>) -> Result<()> {
-        let path_cstr = common::path_to_cstr(&self.path);
-        let flags_val = flags.map(|v| v.bits()).unwrap_or(0);
+        let recursive = flags.is_some_and(|flags| flags.contains(DeleteFlags::RECURSIVE));
+        sys::delete_subvolume(&self.path, recursive)
+    }
 
-        unsafe_wrapper!({ btrfs_util_delete_subvolume(path_cstr.as_ptr(), flags_val) })?;
+    /// Delete a subvolume by its id, on the filesystem `fs_path` belongs to.
+    ///
+    /// Restricted to the crate, public through [AdminSubvolume::delete_by_id].
+    ///
+    /// [AdminSubvolume::delete_by_id]: struct.AdminSubvolume.html#method.delete_by_id
+    pub(crate) fn delete_by_id<P, D>(fs_path: P, id: SubvolumeId, flags: D) -> Result<()>
+    where
+        P: AsRef<Path>,
+        D: Into<Option<DeleteFlags>>,
+    {
+        let fs_path = fs_path.as_ref();
+        Self::delete_by_id_impl(fs_path, id, flags.into())
+            .context("Subvolume::delete_by_id", &[fs_path])
+    }
 
-        Ok(())
+    fn delete_by_id_impl(
+        fs_path: &Path,
+        id: SubvolumeId,
+        flags: Option<DeleteFlags>,
+    ) -> Result<()> {
+        if flags.is_some_and(|flags| flags.contains(DeleteFlags::RECURSIVE)) {
+            let mut children: Vec<SubvolumeId> = Vec::new();
+            tree_search::for_each(
+                fs_path,
+                &SearchRange::object(
+                    tree_search::ROOT_TREE_OBJECTID,
+                    id.get(),
+                    ioctl::BTRFS_ROOT_REF_KEY as u8,
+                ),
+                |item| {
+                    children.push(SubvolumeId::from_raw(item.key().offset));
+                    true
+                },
+            )?;
+            for child in children {
+                Self::delete_by_id_impl(fs_path, child, flags)?;
+            }
+        }
+
+        let file = ioctl::open(fs_path)?;
+        let mut args = VolArgsV2::with_id(ioctl::BTRFS_SUBVOL_SPEC_BY_ID, id.get());
+        unsafe {
+            ioctl::ioctl(
+                file.as_raw_fd(),
+                ioctl::BTRFS_IOC_SNAP_DESTROY_V2,
+                &mut args,
+                LibError::SnapDestroyFailed,
+            )
+        }
     }
 
     /// Get a list of subvolumes which have been deleted but not yet cleaned up.
     ///
-    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
-    pub fn deleted<'a, F>(fs_root: F) -> Result<Vec<Self>>
+    /// The list is empty when there are none, for example on a freshly mounted filesystem or
+    /// once the cleaner thread has caught up.
+    ///
+    /// Restricted to the crate, public through [AdminSubvolume::deleted].
+    ///
+    /// [AdminSubvolume::deleted]: struct.AdminSubvolume.html#method.deleted
+    pub(crate) fn deleted<F>(fs_root: F) -> Result<Vec<Self>>
     where
-        F: Into<&'a Path>,
+        F: AsRef<Path>,
     {
-        Self::deleted_impl(fs_root.into())
+        let fs_root = fs_root.as_ref();
+        Self::deleted_impl(fs_root).context("Subvolume::deleted", &[fs_root])
     }
 
     fn deleted_impl(fs_root: &Path) -> Result<Vec<Subvolume>> {
         // fixme 16/09/2020: you should probably just return the ids
         // since the subvolumes have been deleted, they should probably not have a path.
 
-        let path_cstr = common::path_to_cstr(fs_root);
-        let mut ids_ptr: *mut u64 = std::ptr::null_mut();
-        let mut ids_count: usize = 0;
-
-        unsafe_wrapper!({
-            btrfs_util_deleted_subvolumes(path_cstr.as_ptr(), &mut ids_ptr, &mut ids_count)
-        })?;
-
-        if ids_count == 0 {
-            return Ok(Vec::new());
-        }
-
-        let subvolume_ids: Vec<u64> = unsafe {
-            let slice = std::slice::from_raw_parts(ids_ptr, ids_count);
-            let vec = slice.to_vec();
-            free(ids_ptr as *mut c_void);
-            vec
-        };
+        let subvolume_ids = deleted_ids(fs_root)?;
 
         let subvolumes: Vec<Subvolume> = {
-            let mut subvolumes: Vec<Subvolume> = Vec::with_capacity(ids_count);
+            let mut subvolumes: Vec<Subvolume> = Vec::with_capacity(subvolume_ids.len());
             for id in subvolume_ids {
                 subvolumes.push(Subvolume::try_from(id)?);
             }
@@ -196,88 +362,205 @@ impl Subvolume {
         Ok(subvolumes)
     }
 
-    /// Get the default subvolume.
+    /// Wait until subvolumes deleted from the filesystem `fs_root` belongs to have been cleaned
+    /// up, so that the space they used is accounted as free.
+    ///
+    /// Transactions are committed while waiting, since the cleaner only runs after a commit.
+    /// Ids which are not pending cleanup are considered cleaned up. Fails with
+    /// [LibError::CleanupTimedOut] if some are still pending after `timeout`, if any.
     ///
     /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
-    pub fn get_default<'a, P>(path: P) -> Result<Self>
+    ///
+    /// [LibError::CleanupTimedOut]: ../error/enum.LibError.html#variant.CleanupTimedOut
+    pub fn wait_for_cleanup<F, T>(fs_root: F, ids: &[SubvolumeId], timeout: T) -> Result<()>
     where
-        P: Into<&'a Path>,
+        F: AsRef<Path>,
+        T: Into<Option<Duration>>,
     {
-        Self::get_default_impl(path.into())
+        let fs_root = fs_root.as_ref();
+        Self::wait_for_cleanup_impl(fs_root, ids, timeout.into())
+            .context("Subvolume::wait_for_cleanup", &[fs_root])
     }
 
-    fn get_default_impl(path: &Path) -> Result<Self> {
-        let path_cstr = common::path_to_cstr(path);
-        let mut id: u64 = 0;
-
-        unsafe_wrapper!({ btrfs_util_get_default_subvolume(path_cstr.as_ptr(), &mut id) })?;
-
-        Ok(Subvolume::new(id, path.into()))
+    fn wait_for_cleanup_impl(
+        fs_root: &Path,
+        ids: &[SubvolumeId],
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let pending = deleted_ids(fs_root)?;
+            if !ids.iter().any(|id| pending.contains(id)) {
+                return Ok(());
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(LibError::CleanupTimedOut.into());
+            }
+            sync::sync(fs_root)?;
+            thread::sleep(CLEANUP_POLL_INTERVAL);
+        }
     }
 
-    /// Set this subvolume as the default subvolume.
+    /// Get the default subvolume of the filesystem a path belongs to.
+    ///
+    /// The path of the default subvolume is derived from the one of the subvolume the path
+    /// belongs to, as for [parent], wherever the filesystem is mounted.
     ///
     /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
-    pub fn set_default(&self) -> Result<()> {
-        let path_cstr = common::path_to_cstr(&self.path);
+    ///
+    /// [parent]: #method.parent
+    pub fn get_default<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        Self::get_default_impl(path).context("Subvolume::get_default", &[path])
+    }
 
-        unsafe_wrapper!({ btrfs_util_set_default_subvolume(path_cstr.as_ptr(), self.id) })?;
+    fn get_default_impl(path: &Path) -> Result<Self> {
+        let id = sys::default_subvolume(path)?;
 
-        Ok(())
+        Self::get_anyway_impl(path)?.resolve(SubvolumeId::from_raw(id))
+    }
+
+    /// Set this subvolume as the default subvolume.
+    ///
+    /// Restricted to the crate, public through [AdminSubvolume::set_default].
+    ///
+    /// [AdminSubvolume::set_default]: struct.AdminSubvolume.html#method.set_default
+    pub(crate) fn set_default(&self) -> Result<()> {
+        sys::set_default_subvolume(&self.path, self.id.get())
+            .context("Subvolume::set_default", &[self.path()])
     }
 
     /// Check whether this subvolume is read-only.
     pub fn is_ro(&self) -> Result<bool> {
-        let path_cstr = common::path_to_cstr(&self.path);
-        let ro: bool = {
-            let mut ro = false;
-            unsafe_wrapper!({ btrfs_util_get_subvolume_read_only(path_cstr.as_ptr(), &mut ro) })?;
-            ro
-        };
-
-        Ok(ro)
+        sys::subvolume_read_only(&self.path).context("Subvolume::is_ro", &[self.path()])
     }
 
     /// Set whether this subvolume is read-only or not.
     ///
-    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
-    pub fn set_ro(&self, ro: bool) -> Result<()> {
-        let path_cstr = common::path_to_cstr(&self.path);
+    /// Restricted to the crate, public through [AdminSubvolume::set_ro].
+    ///
+    /// [AdminSubvolume::set_ro]: struct.AdminSubvolume.html#method.set_ro
+    pub(crate) fn set_ro(&self, ro: bool) -> Result<()> {
+        sys::set_subvolume_read_only(&self.path, ro).context("Subvolume::set_ro", &[self.path()])
+    }
 
-        unsafe_wrapper!({ btrfs_util_set_subvolume_read_only(path_cstr.as_ptr(), ro) })?;
+    /// Make this subvolume read-only until the returned guard is dropped, which restores the
+    /// previous state, also when unwinding from a panic.
+    ///
+    /// Restricted to the crate, public through [AdminSubvolume::ro_guard].
+    ///
+    /// [AdminSubvolume::ro_guard]: struct.AdminSubvolume.html#method.ro_guard
+    pub(crate) fn ro_guard(&self) -> Result<ReadOnlyGuard<'_>> {
+        let was_ro = self.is_ro()?;
+        if !was_ro {
+            self.set_ro(true)?;
+        }
 
-        Ok(())
+        Ok(ReadOnlyGuard {
+            subvol: self,
+            was_ro,
+            restored: false,
+        })
+    }
+
+    /// Set the received UUID, the transaction id in the sending filesystem and the time the
+    /// subvolume was sent.
+    ///
+    /// Restricted to the crate, public through [AdminSubvolume::set_received_uuid].
+    ///
+    /// [AdminSubvolume::set_received_uuid]: struct.AdminSubvolume.html#method.set_received_uuid
+    pub(crate) fn set_received_uuid(
+        &self,
+        uuid: &RawUuid,
+        stransid: u64,
+        stime: SystemTime,
+    ) -> Result<()> {
+        self.set_received_uuid_impl(uuid, stransid, stime)
+            .context("Subvolume::set_received_uuid", &[self.path()])
+    }
+
+    fn set_received_uuid_impl(
+        &self,
+        uuid: &RawUuid,
+        stransid: u64,
+        stime: SystemTime,
+    ) -> Result<()> {
+        let file = ioctl::open(&self.path)?;
+        // the kernel has no room for times before the epoch
+        let since_epoch = stime.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let stime = IoctlTimespec {
+            sec: since_epoch.as_secs(),
+            nsec: since_epoch.subsec_nanos(),
+        };
+        let mut args = ReceivedSubvolArgs::new(*uuid.as_bytes(), stransid, stime);
+
+        unsafe {
+            ioctl::ioctl(
+                file.as_raw_fd(),
+                ioctl::BTRFS_IOC_SET_RECEIVED_SUBVOL,
+                &mut args,
+                LibError::SetReceivedFailed,
+            )
+        }
     }
 
     /// Check if a path is a Btrfs subvolume.
     ///
     /// Returns Ok if it is a subvolume or Err if otherwise.
-    pub fn is_subvolume<'a, P>(path: P) -> Result<()>
+    pub fn is_subvolume<P>(path: P) -> Result<()>
     where
-        P: Into<&'a Path>,
+        P: AsRef<Path>,
     {
-        Self::is_subvolume_impl(path.into())
+        let path = path.as_ref();
+        Self::is_subvolume_impl(path).context("Subvolume::is_subvolume", &[path])
     }
 
     fn is_subvolume_impl(path: &Path) -> Result<()> {
-        let path_cstr = common::path_to_cstr(path);
-
-        unsafe_wrapper!({ btrfs_util_is_subvolume(path_cstr.as_ptr()) })
+        sys::is_subvolume(path)
     }
 
     /// Get information about this subvolume.
     pub fn info(&self) -> Result<SubvolumeInfo> {
-        SubvolumeInfo::try_from(self)
+        SubvolumeInfo::try_from(self).context("Subvolume::info", &[self.path()])
+    }
+
+    /// Get the space accounted to this subvolume by its level 0 quota group, `0/<id>`.
+    ///
+    /// The exclusive bytes are what deleting the subvolume would free. Fails with
+    /// [LibError::QuotaDisabled] if quotas are not enabled on the filesystem, and with
+    /// [LibError::QgroupNotFound] if the subvolume has no quota group.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    ///
+    /// [LibError::QuotaDisabled]: ../error/enum.LibError.html#variant.QuotaDisabled
+    /// [LibError::QgroupNotFound]: ../error/enum.LibError.html#variant.QgroupNotFound
+    pub fn usage(&self) -> Result<QgroupUsage> {
+        self.usage_impl()
+            .context("Subvolume::usage", &[self.path()])
+    }
+
+    fn usage_impl(&self) -> Result<QgroupUsage> {
+        qgroup::usage(self.path())?
+            .ok_or(LibError::QuotaDisabled)?
+            .into_iter()
+            // the qgroup of a subvolume is at level 0, with the subvolume id as id
+            .find(|usage| usage.qgroupid == self.id.get())
+            .ok_or_else(|| LibError::QgroupNotFound.into())
     }
 
     /// Create a snapshot of this subvolume.
-    pub fn snapshot<'a, P, F, Q>(&self, path: P, flags: F, qgroup: Q) -> Result<Self>
+    pub fn snapshot<P, F, Q>(&self, path: P, flags: F, qgroup: Q) -> Result<Self>
     where
-        P: Into<&'a Path>,
+        P: AsRef<Path>,
         F: Into<Option<SnapshotFlags>>,
         Q: Into<Option<QgroupInherit>>,
     {
-        self.snapshot_impl(path.into(), flags.into(), qgroup.into())
+        let path = path.as_ref();
+        self.snapshot_impl(path, flags.into(), qgroup.into())
+            .context("Subvolume::snapshot", &[self.path(), path])
     }
 
     fn snapshot_impl(
@@ -286,80 +569,465 @@ impl Subvolume {
         flags: Option<SnapshotFlags>,
         qgroup: Option<QgroupInherit>,
     ) -> Result<Self> {
-        let path_src_cstr = common::path_to_cstr(&self.path);
-        let path_dest_cstr = common::path_to_cstr(path);
-        let flags_val = flags.map(|v| v.bits()).unwrap_or(0);
-        let qgroup_ptr = qgroup.map(|v| v.as_ptr()).unwrap_or(std::ptr::null_mut());
-
-        let transid: u64 = {
-            let mut transid: u64 = 0;
-            unsafe_wrapper!({
-                btrfs_util_create_snapshot(
-                    path_src_cstr.as_ptr(),
-                    path_dest_cstr.as_ptr(),
-                    flags_val,
-                    &mut transid,
-                    qgroup_ptr,
-                )
-            })?;
-            transid
+        let (snapshot, transid) = self.snapshot_async_impl(path, flags, qgroup)?;
+        transid.wait()?;
+
+        Ok(snapshot)
+    }
+
+    /// Create a snapshot of this subvolume without waiting for the transaction creating it to be
+    /// committed.
+    ///
+    /// The snapshot can be used right away; wait on the returned transaction before relying on
+    /// it surviving a crash. When creating many snapshots, waiting on the last transaction
+    /// covers all of them.
+    pub fn snapshot_async<P, F, Q>(
+        &self,
+        path: P,
+        flags: F,
+        qgroup: Q,
+    ) -> Result<(Self, TransactionId)>
+    where
+        P: AsRef<Path>,
+        F: Into<Option<SnapshotFlags>>,
+        Q: Into<Option<QgroupInherit>>,
+    {
+        let path = path.as_ref();
+        self.snapshot_async_impl(path, flags.into(), qgroup.into())
+            .context("Subvolume::snapshot_async", &[self.path(), path])
+    }
+
+    fn snapshot_async_impl(
+        &self,
+        path: &Path,
+        flags: Option<SnapshotFlags>,
+        qgroup: Option<QgroupInherit>,
+    ) -> Result<(Self, TransactionId)> {
+        let flags = flags.unwrap_or_else(SnapshotFlags::empty);
+        let transid = sys::create_snapshot(
+            &self.path,
+            path,
+            flags.contains(SnapshotFlags::RECURSIVE),
+            flags.contains(SnapshotFlags::READ_ONLY),
+            qgroup.as_ref().map(QgroupInherit::groups),
+        )?;
+
+        Ok((Self::get_impl(path)?, TransactionId::new(path, transid)))
+    }
+
+    /// Create a snapshot of this subvolume, unless it was already created with the same
+    /// idempotency key.
+    ///
+    /// An existing snapshot of this subvolume without a key, read-only if `flags` ask for it, is
+    /// assumed to be left over from an interrupted attempt and is adopted. Anything else already
+    /// at the path is an error.
+    ///
+    /// The key is recorded on the directory the snapshot is in, as described in [idempotency],
+    /// so the snapshot is left exactly as the kernel created it.
+    ///
+    /// [idempotency]: ../idempotency/index.html
+    pub fn snapshot_idempotent<P, F, Q>(
+        &self,
+        path: P,
+        flags: F,
+        qgroup: Q,
+        key: &IdempotencyKey,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        F: Into<Option<SnapshotFlags>>,
+        Q: Into<Option<QgroupInherit>>,
+    {
+        let path = path.as_ref();
+        self.snapshot_idempotent_impl(path, flags.into(), qgroup.into(), key)
+            .context("Subvolume::snapshot_idempotent", &[self.path(), path])
+    }
+
+    fn snapshot_idempotent_impl(
+        &self,
+        path: &Path,
+        flags: Option<SnapshotFlags>,
+        qgroup: Option<QgroupInherit>,
+        key: &IdempotencyKey,
+    ) -> Result<Self> {
+        if !common::path_exists(path)? {
+            let snapshot = self.snapshot_impl(path, flags, qgroup)?;
+            idempotency::set_impl(path, key)?;
+            return Ok(snapshot);
+        }
+
+        let snapshot = Self::get_impl(path)?;
+        match idempotency::get_impl(path)? {
+            Some(existing) if &existing == key => Ok(snapshot),
+            Some(_) => Err(LibError::SnapCreateFailed.into()),
+            None => {
+                let ro = flags.is_some_and(|v| v.contains(SnapshotFlags::READ_ONLY));
+                if snapshot.info()?.parent_uuid != Some(self.info()?.uuid)
+                    || snapshot.is_ro()? != ro
+                {
+                    return Err(LibError::SnapCreateFailed.into());
+                }
+                idempotency::set_impl(path, key)?;
+                Ok(snapshot)
+            }
+        }
+    }
+
+    /// Check whether another subvolume belongs to the same filesystem as this one.
+    ///
+    /// Filesystems are told apart by their fsid, so this holds across different mount points
+    /// and bind mounts of the same filesystem.
+    pub fn same_filesystem(&self, other: &Subvolume) -> Result<bool> {
+        Ok(self.fsid == other.fsid)
+    }
+
+    /// Get the subvolume containing this subvolume, or `None` for the top-level subvolume and
+    /// orphaned subvolumes.
+    ///
+    /// The path of the parent is derived from the path of this subvolume. If the parent is not
+    /// reachable through it, for example because this subvolume is mounted on its own, it is
+    /// looked for among the mounts of the filesystem, and not found if it is not mounted.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn parent(&self) -> Result<Option<Subvolume>> {
+        let parent_id = match self.info()?.parent_id {
+            Some(id) => id,
+            None => return Ok(None),
         };
 
-        unsafe_wrapper!({ btrfs_util_wait_sync(path_dest_cstr.as_ptr(), transid) })?;
+        self.resolve(parent_id)
+            .map(Some)
+            .context("Subvolume::parent", &[self.path()])
+    }
+
+    /// Check whether this subvolume is a snapshot of another one.
+    ///
+    /// A snapshot stays one after its source has been deleted.
+    pub fn is_snapshot(&self) -> Result<bool> {
+        Ok(self.info()?.parent_uuid.is_some())
+    }
+
+    /// Get the snapshots of this subvolume among the subvolumes below `fs_path`, the root of a
+    /// subvolume, usually the top-level one to search the whole filesystem.
+    ///
+    /// Only direct snapshots are returned, not snapshots of snapshots.
+    pub fn snapshots<P>(&self, fs_path: P) -> Result<Vec<Subvolume>>
+    where
+        P: AsRef<Path>,
+    {
+        let fs_path = fs_path.as_ref();
+        self.snapshots_impl(fs_path)
+            .context("Subvolume::snapshots", &[self.path(), fs_path])
+    }
+
+    fn snapshots_impl(&self, fs_path: &Path) -> Result<Vec<Subvolume>> {
+        let uuid = self.info()?.uuid;
+        let arena = SubvolumeIterator::new(fs_path, None)?.collect_borrowed()?;
+
+        let mut snapshots: Vec<Subvolume> = Vec::new();
+        for subvol in arena.iter() {
+            let subvol = Subvolume::new(subvol.id, self.fsid, fs_path.join(subvol.path));
+            if subvol.info()?.parent_uuid == Some(uuid) {
+                snapshots.push(subvol);
+            }
+        }
+
+        Ok(snapshots)
+    }
+
+    /// Find the subvolume received from a subvolume with a given UUID, such as the local copy of
+    /// a snapshot sent from another filesystem.
+    ///
+    /// This is the parent to use on this side when receiving an incremental send based on that
+    /// snapshot. The subvolume is looked up in the UUID tree of the filesystem `fs_path`, the root
+    /// of a subvolume, belongs to, and its path is derived from `fs_path` as for [parent].
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    ///
+    /// [parent]: #method.parent
+    pub fn find_received<P>(fs_path: P, received_uuid: &RawUuid) -> Result<Option<Subvolume>>
+    where
+        P: AsRef<Path>,
+    {
+        let fs_path = fs_path.as_ref();
+        Self::find_received_impl(fs_path, received_uuid)
+            .context("Subvolume::find_received", &[fs_path])
+    }
 
-        Self::get(path)
+    fn find_received_impl(fs_path: &Path, received_uuid: &RawUuid) -> Result<Option<Subvolume>> {
+        let base = Subvolume::get(fs_path)?;
+
+        // keyed by the two halves of the UUID, each item holds the ids of the subvolumes
+        let bytes = received_uuid.as_bytes();
+        let objectid = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        let offset = u64::from_le_bytes(bytes[8..].try_into().unwrap());
+        let key = Key::new(
+            objectid,
+            ioctl::BTRFS_UUID_KEY_RECEIVED_SUBVOL as u8,
+            offset,
+        );
+
+        let mut ids: Vec<SubvolumeId> = Vec::new();
+        tree_search::for_each(
+            fs_path,
+            &SearchRange::new(tree_search::UUID_TREE_OBJECTID, key, key),
+            |item| {
+                ids.extend(
+                    item.data().chunks_exact(8).map(|id| {
+                        SubvolumeId::from_raw(u64::from_le_bytes(id.try_into().unwrap()))
+                    }),
+                );
+                true
+            },
+        )?;
+
+        // ids of subvolumes deleted but not yet cleaned up may linger
+        for id in ids {
+            if let Ok(subvol) = base.resolve(id) {
+                return Ok(Some(subvol));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Get a subvolume of the same filesystem by its id, with its path derived from the path of
+    /// this subvolume as for [parent].
+    ///
+    /// [parent]: #method.parent
+    pub(crate) fn resolve(&self, id: SubvolumeId) -> Result<Subvolume> {
+        Ok(Subvolume::new(
+            id,
+            self.fsid,
+            resolve_path(&self.path, self.id, id)?,
+        ))
+    }
+
+    /// Get the subvolumes directly below this subvolume, leaving out the ones nested in them.
+    ///
+    /// These are the subvolumes whose parent is this one. They are told apart by their paths
+    /// alone, without looking up the information of every subvolume below this one.
+    pub fn children(&self) -> Result<Vec<Subvolume>> {
+        let arena = SubvolumeIterator::new(&self.path, None)?
+            .collect_borrowed()
+            .context("Subvolume::children", &[self.path()])?;
+
+        let mut children: Vec<Subvolume> = Vec::new();
+        let mut last_child: Option<&Path> = None;
+        for subvol in arena.iter() {
+            // in pre-order, the subvolumes nested in a child directly follow it
+            if last_child.is_some_and(|child| subvol.path.starts_with(child)) {
+                continue;
+            }
+            last_child = Some(subvol.path);
+            children.push(Subvolume::new(
+                subvol.id,
+                self.fsid,
+                self.path.join(subvol.path),
+            ));
+        }
+
+        Ok(children)
+    }
+
+    /// Check whether a path lies within this subvolume.
+    ///
+    /// Paths inside subvolumes nested below this one are not contained by it.
+    pub fn contains<P>(&self, path: P) -> Result<bool>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        self.contains_impl(path)
+            .context("Subvolume::contains", &[self.path(), path])
+    }
+
+    fn contains_impl(&self, path: &Path) -> Result<bool> {
+        // subvolume ids are only unique within a filesystem
+        if self.fsid != fsid(path)? {
+            return Ok(false);
+        }
+
+        Ok(sys::subvolume_id(path)? == self.id.get())
+    }
+
+    /// Get a handle for the privileged operations on this subvolume.
+    ///
+    /// Fails with [LibError::NotPrivileged] unless the current process can perform privileged
+    /// operations on the filesystem of the subvolume, as probed by [privileges::check].
+    ///
+    /// [LibError::NotPrivileged]: ../error/enum.LibError.html#variant.NotPrivileged
+    /// [privileges::check]: ../privileges/fn.check.html
+    pub fn elevate(self) -> Result<AdminSubvolume> {
+        let path = self.path.clone();
+        Self::elevate_impl(self).context("Subvolume::elevate", &[&path])
+    }
+
+    fn elevate_impl(self) -> Result<AdminSubvolume> {
+        match privileges::check(&self.path)? {
+            PrivilegeLevel::Admin => Ok(AdminSubvolume::new(self)),
+            _ => Err(BtrfsUtilError::os(LibError::NotPrivileged, libc::EPERM)),
+        }
+    }
+
+    /// Check that the path of this subvolume still points to the root of this subvolume.
+    ///
+    /// The path is taken when the subvolume is looked up and trusted afterwards; it goes stale if
+    /// the subvolume is moved, deleted or mounted over. Fails with
+    /// [LibError::SubvolumeNotFound] if the path now belongs to another subvolume.
+    ///
+    /// [LibError::SubvolumeNotFound]: ../error/enum.LibError.html#variant.SubvolumeNotFound
+    pub fn confirm_path(&self) -> Result<()> {
+        self.confirm_path_impl()
+            .context("Subvolume::confirm_path", &[self.path()])
+    }
+
+    fn confirm_path_impl(&self) -> Result<()> {
+        if Self::get_impl(&self.path)?.id != self.id {
+            return Err(LibError::SubvolumeNotFound.into());
+        }
+        Ok(())
     }
 
     /// Get the id of this subvolume.
     #[inline]
-    pub fn id(&self) -> u64 {
+    pub fn id(&self) -> SubvolumeId {
         self.id
     }
 
+    /// Get the fsid of the filesystem this subvolume belongs to.
+    #[inline]
+    pub fn fsid(&self) -> RawUuid {
+        self.fsid
+    }
+
     /// Get the path of this subvolume.
+    ///
+    /// Paths are absolute: they are made so when getting a subvolume by a relative path, and
+    /// paths derived from other subvolumes or looked up by id go through where the filesystem is
+    /// mounted.
     #[inline]
     pub fn path(&self) -> &Path {
         &self.path
     }
 
-    /// Create a new subvolume from an id and a path.
+    /// Get the absolute path of this subvolume, the same as [path].
+    ///
+    /// [path]: #method.path
+    #[inline]
+    pub fn abs_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the path of this subvolume relative to the top-level subvolume of its filesystem,
+    /// whatever is mounted where. It is empty for the top-level subvolume itself.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn rel_path(&self) -> Result<PathBuf> {
+        subvolume_path(&self.path, self.id).context("Subvolume::rel_path", &[self.path()])
+    }
+
+    /// Get the mounts this subvolume is reachable through, because it is mounted itself or
+    /// because a subvolume or directory containing it is.
+    ///
+    /// Mounts are read from `/proc/self/mountinfo` and kept only if they are of the same
+    /// filesystem as this subvolume, as told by its fsid, so mounts of other btrfs filesystems
+    /// with the same layout are not mistaken for this one's. They are in the order they were
+    /// mounted in.
+    ///
+    /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+    pub fn mount_points(&self) -> Result<Vec<MountPoint>> {
+        self.mount_points_impl()
+            .context("Subvolume::mount_points", &[self.path()])
+    }
+
+    fn mount_points_impl(&self) -> Result<Vec<MountPoint>> {
+        let target = Path::new("/").join(subvolume_path(&self.path, self.id)?);
+
+        Ok(common::btrfs_mounts()?
+            .into_iter()
+            .filter_map(|mount| {
+                let path = mount
+                    .mount_point
+                    .join(target.strip_prefix(&mount.root).ok()?);
+                if fsid(&mount.mount_point).ok()? != self.fsid {
+                    return None;
+                }
+                Some(MountPoint {
+                    mount_point: mount.mount_point,
+                    root: mount.root,
+                    path,
+                })
+            })
+            .collect())
+    }
+
+    /// Create a new subvolume from an id, the fsid of its filesystem and a path.
     ///
     /// Restricted to the crate.
     #[inline]
-    pub(crate) fn new(id: u64, path: PathBuf) -> Self {
-        Self { id, path }
+    pub(crate) fn new(id: SubvolumeId, fsid: RawUuid, path: PathBuf) -> Self {
+        Self { id, fsid, path }
     }
 }
 
-impl From<&Subvolume> for u64 {
+impl PartialEq for Subvolume {
+    fn eq(&self, other: &Self) -> bool {
+        (self.fsid, self.id) == (other.fsid, other.id)
+    }
+}
+
+impl Eq for Subvolume {}
+
+impl Hash for Subvolume {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.fsid, self.id).hash(state);
+    }
+}
+
+impl PartialOrd for Subvolume {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Subvolume {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.id, self.fsid).cmp(&(other.id, other.fsid))
+    }
+}
+
+impl fmt::Display for Subvolume {
+    /// The id and the path, such as `subvol 259 /mnt/@home/.snapshots/1`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "subvol {} {}", self.id, self.path.display())
+    }
+}
+
+impl From<&Subvolume> for SubvolumeId {
     /// Returns the id of the subvolume.
     #[inline]
-    fn from(subvolume: &Subvolume) -> u64 {
+    fn from(subvolume: &Subvolume) -> SubvolumeId {
         subvolume.id
     }
 }
 
-impl TryFrom<u64> for Subvolume {
-    type Error = LibError;
+impl TryFrom<SubvolumeId> for Subvolume {
+    type Error = BtrfsUtilError;
 
-    /// Attempts to get a subvolume from an id.
+    /// Attempts to get a subvolume from an id, on the filesystem of the current working
+    /// directory.
+    ///
+    /// Its path is derived from the working directory as for [Subvolume::parent].
     ///
     /// This function will panic if it cannot retrieve the current working directory.
     ///
     /// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
-    fn try_from(src: u64) -> Result<Subvolume> {
-        let path_cstr: CString = common::path_to_cstr(
-            std::env::current_dir()
-                .expect("Could not get the current working directory")
-                .as_ref(),
-        );
-        let mut path_ret_ptr: *mut std::os::raw::c_char = std::ptr::null_mut();
-
-        unsafe_wrapper!({ btrfs_util_subvolume_path(path_cstr.as_ptr(), src, &mut path_ret_ptr) })?;
-
-        let path_ret: CString = unsafe { CString::from_raw(path_ret_ptr) };
+    ///
+    /// [Subvolume::parent]: struct.Subvolume.html#method.parent
+    fn try_from(src: SubvolumeId) -> Result<Subvolume> {
+        let cwd = std::env::current_dir().expect("Could not get the current working directory");
 
-        Ok(Self::new(src, common::cstr_to_path(&path_ret)))
+        Subvolume::get_anyway_impl(&cwd)?.resolve(src)
     }
 }
 
@@ -380,7 +1048,7 @@ impl<'lifetime> From<&'lifetime Subvolume> for &'lifetime Path {
 }
 
 impl TryFrom<&Path> for Subvolume {
-    type Error = LibError;
+    type Error = BtrfsUtilError;
 
     /// Attempts to get a subvolume from a path.
     #[inline]
@@ -390,7 +1058,7 @@ impl TryFrom<&Path> for Subvolume {
 }
 
 impl TryFrom<PathBuf> for Subvolume {
-    type Error = LibError;
+    type Error = BtrfsUtilError;
 
     /// Attempts to get a subvolume from a path.
     #[inline]
@@ -399,17 +1067,175 @@ impl TryFrom<PathBuf> for Subvolume {
     }
 }
 
+/// A mount a subvolume is reachable through, see [Subvolume::mount_points].
+///
+/// [Subvolume::mount_points]: struct.Subvolume.html#method.mount_points
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MountPoint {
+    /// Where the filesystem is mounted.
+    pub mount_point: PathBuf,
+    /// Path of the mounted subvolume or directory, relative to the top-level subvolume and
+    /// starting with `/`.
+    pub root: PathBuf,
+    /// Path of the subvolume through this mount.
+    pub path: PathBuf,
+}
+
+impl MountPoint {
+    /// Check whether the subvolume itself is mounted, rather than something containing it.
+    #[inline]
+    pub fn is_direct(&self) -> bool {
+        self.path == self.mount_point
+    }
+}
+
+/// Keeps a subvolume read-only while alive, see [AdminSubvolume::ro_guard].
+///
+/// Restoring on drop cannot report errors; use [restore] to handle them.
+///
+/// [AdminSubvolume::ro_guard]: struct.AdminSubvolume.html#method.ro_guard
+/// [restore]: #method.restore
+#[derive(Debug)]
+pub struct ReadOnlyGuard<'a> {
+    subvol: &'a Subvolume,
+    was_ro: bool,
+    restored: bool,
+}
+
+impl ReadOnlyGuard<'_> {
+    /// Check whether the subvolume was already read-only, in which case it is left as is.
+    #[inline]
+    pub fn was_ro(&self) -> bool {
+        self.was_ro
+    }
+
+    /// Restore the previous state now, reporting failure.
+    pub fn restore(mut self) -> Result<()> {
+        self.restored = true;
+        if self.was_ro {
+            return Ok(());
+        }
+        self.subvol.set_ro(false)
+    }
+}
+
+impl Drop for ReadOnlyGuard<'_> {
+    fn drop(&mut self) {
+        if !self.restored && !self.was_ro {
+            let _ = self.subvol.set_ro(false);
+        }
+    }
+}
+
+/// Get the ids of the subvolumes which have been deleted but not yet cleaned up.
+fn deleted_ids(fs_root: &Path) -> Result<Vec<SubvolumeId>> {
+    Ok(sys::deleted_subvolumes(fs_root)?
+        .into_iter()
+        .map(SubvolumeId::from_raw)
+        .collect())
+}
+
+/// Get the path of a subvolume relative to the top-level subvolume of the filesystem a path
+/// belongs to.
+fn subvolume_path(path: &Path, id: SubvolumeId) -> Result<PathBuf> {
+    sys::subvolume_path(path, id.get())
+}
+
+/// Get the path of a subvolume below the subvolume at `base`, without privileges.
+///
+/// Walks down from `base` with the unprivileged ioctls libbtrfsutil itself uses when not running
+/// as root, skipping the subvolumes it is not allowed to look into. Requires Linux 4.18.
+fn subvolume_path_user(base: &Path, id: SubvolumeId) -> Result<PathBuf> {
+    let mut pending = vec![base.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let file = match ioctl::open(&dir) {
+            Ok(file) => file,
+            Err(_) if dir != base => continue,
+            Err(err) => return Err(err),
+        };
+        for rootref in ioctl::subvol_rootrefs(file.as_raw_fd())? {
+            let rel = match ioctl::ino_lookup_user(file.as_raw_fd(), rootref.treeid, rootref.dirid)
+            {
+                Ok(rel) => rel,
+                Err(_) => continue,
+            };
+            if rootref.treeid == id.get() {
+                return Ok(dir.join(rel));
+            }
+            pending.push(dir.join(rel));
+        }
+    }
+    Err(LibError::SubvolumeNotFound.into())
+}
+
+/// Get the path of a subvolume as seen from another one, from the subvolume at `base` with id
+/// `base_id`.
+///
+/// Falls back to the mounts of the filesystem if the subvolume is not reachable from `base`.
+/// Without **CAP_SYS_ADMIN**, only subvolumes below `base` are found.
+fn resolve_path(base: &Path, base_id: SubvolumeId, id: SubvolumeId) -> Result<PathBuf> {
+    let base_rel = match subvolume_path(base, base_id) {
+        Ok(base_rel) => base_rel,
+        Err(err) => return subvolume_path_user(base, id).map_err(|_| err),
+    };
+    let rel = subvolume_path(base, id)?;
+
+    // below base
+    if let Ok(rest) = rel.strip_prefix(&base_rel) {
+        return Ok(base.join(rest));
+    }
+    // above base, as long as base has enough components to climb
+    if let Ok(rest) = base_rel.strip_prefix(&rel) {
+        if rest.components().count() < base.components().count() {
+            let mut path = base.to_path_buf();
+            for _ in rest.components() {
+                path.pop();
+            }
+            return Ok(path);
+        }
+    }
+    mounted_path(base, &rel).ok_or_else(|| LibError::SubvolumeNotFound.into())
+}
+
+/// Get the path a subvolume is reachable at through a mount of the filesystem a path belongs
+/// to, from its path relative to the top-level subvolume.
+///
+/// Mounts are checked in the order of `/proc/self/mountinfo`.
+fn mounted_path(path: &Path, rel: &Path) -> Option<PathBuf> {
+    let expected = fsid(path).ok()?;
+    let target = Path::new("/").join(rel);
+
+    common::btrfs_mounts().ok()?.into_iter().find_map(|mount| {
+        let candidate = mount
+            .mount_point
+            .join(target.strip_prefix(&mount.root).ok()?);
+        if fsid(&candidate).ok()? != expected {
+            return None;
+        }
+        Some(candidate)
+    })
+}
+
+fn fsid(path: &Path) -> Result<RawUuid> {
+    let file = ioctl::open(path)?;
+    Ok(RawUuid(ioctl::fs_info(file.as_raw_fd())?.fsid))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    use std::collections::BTreeSet;
+    use std::collections::HashSet;
     use std::fs::{create_dir_all, OpenOptions};
     use std::path::Path;
+    use std::thread;
 
     use nix::mount::{mount, MsFlags};
 
+    use crate::fs::Filesystem;
     use crate::testing::{btrfs_create_fs, test_with_spec};
-    use btrfsutil_sys::BTRFS_FS_TREE_OBJECTID;
 
     fn test_btrfs_subvol(paths: &[&Path]) {
         // Create btrfs filesystem on loopback device
@@ -428,11 +1254,55 @@ mod test {
         .unwrap();
 
         let root_subvol = Subvolume::try_from(mount_pt).unwrap();
-        assert_eq!(root_subvol.id(), BTRFS_FS_TREE_OBJECTID);
+        assert_eq!(root_subvol.id(), SubvolumeId::FS_TREE);
+        assert_eq!(
+            root_subvol.usage().unwrap_err().lib_error(),
+            Some(&LibError::QuotaDisabled)
+        );
+
+        // A fresh filesystem has no other subvolumes and nothing to clean up
+        let admin = root_subvol.clone().elevate().unwrap();
+        assert!(admin.deleted().unwrap().is_empty());
+        let mut iter = SubvolumeIterator::new(mount_pt, None).unwrap();
+        assert!(iter.next().is_none());
+        assert!(iter.next().is_none());
+        assert!(SubvolumeIterator::new(mount_pt, None)
+            .unwrap()
+            .collect_borrowed()
+            .unwrap()
+            .is_empty());
+        let fs = Filesystem::new(mount_pt);
+        assert!(fs.is_empty_of_subvolumes().unwrap());
 
         let mut new_sv_path = mount_pt.to_owned();
         new_sv_path.push("subvol1");
         let sv1 = Subvolume::create(&*new_sv_path, None).unwrap();
+        assert!(!fs.is_empty_of_subvolumes().unwrap());
+        assert!(sv1.confirm_path().is_ok());
+        assert_eq!(sv1.abs_path(), &*new_sv_path);
+        assert_eq!(sv1.rel_path().unwrap(), Path::new("subvol1"));
+        assert_eq!(root_subvol.rel_path().unwrap(), Path::new(""));
+        let mounts = sv1.mount_points().unwrap();
+        assert_eq!(mounts.len(), 1);
+        assert_eq!(mounts[0].mount_point, mount_pt);
+        assert_eq!(mounts[0].path, new_sv_path);
+        assert!(!mounts[0].is_direct());
+        assert!(root_subvol.mount_points().unwrap()[0].is_direct());
+        let misplaced = Subvolume::new(sv1.id(), sv1.fsid(), mount_pt.to_owned());
+        assert!(misplaced.confirm_path().is_err());
+        // the path plays no part in comparisons
+        assert_eq!(misplaced, sv1);
+
+        // Iterators can be moved to another thread
+        let iter = SubvolumeIterator::new(mount_pt, None).unwrap();
+        let ids = thread::spawn(move || {
+            iter.map(|subvol| subvol.map(|subvol| subvol.id()))
+                .collect::<Result<Vec<SubvolumeId>>>()
+        })
+        .join()
+        .unwrap()
+        .unwrap();
+        assert_eq!(ids, vec![sv1.id()]);
 
         // Test path()
         let sv1_abs_path = sv1.path().to_owned();
@@ -459,7 +1329,7 @@ mod test {
         root_subvol.set_default().unwrap();
 
         let info = root_subvol.info().unwrap();
-        assert_eq!(info.id, BTRFS_FS_TREE_OBJECTID);
+        assert_eq!(info.id, SubvolumeId::FS_TREE);
         assert_eq!(info.parent_id, None);
         assert_eq!(info.dir_id, None);
         assert_eq!(info.parent_uuid, None);
@@ -478,20 +1348,30 @@ mod test {
 
         // Can now create a file
         sv1.set_ro(false).unwrap();
+        {
+            let guard = sv1.ro_guard().unwrap();
+            assert!(!guard.was_ro());
+            assert!(sv1.is_ro().unwrap());
+        }
+        assert!(!sv1.is_ro().unwrap());
+        let ctransid = sv1.info().unwrap().ctransid;
+        assert!(!sv1.changed_since(ctransid).unwrap());
         assert!(OpenOptions::new()
             .write(true)
             .create_new(true)
             .open(&file_path)
             .is_ok());
+        crate::sync::sync(mount_pt).unwrap();
+        assert!(sv1.changed_since(ctransid).unwrap());
 
         // Test is_subvolume
         Subvolume::is_subvolume(mount_pt).expect("Valid subvolume failed is_subvolume test");
         Subvolume::is_subvolume(&*new_sv_path).expect("Valid subvolume failed is_subvolume test");
         // Existing non-btrfs path
-        Subvolume::is_subvolume(Path::new("/tmp"))
+        Subvolume::is_subvolume("/tmp")
             .expect_err("Existing, non-btrfs path incorrectly flagged as subvolume");
         // Nonexistent path
-        Subvolume::is_subvolume(Path::new("/foobar"))
+        Subvolume::is_subvolume("/foobar")
             .expect_err("Nonexistent path incorrectly flagged as subvolume");
 
         let mut dir_path = sv1_abs_path.clone();
@@ -500,6 +1380,10 @@ mod test {
         // A directory within a subvolume is not a subvolume
         Subvolume::is_subvolume(&*dir_path)
             .expect_err("Directory within a subvolume incorrectly flagged as subvolume");
+        // but it is contained by it, and not by its parent
+        assert!(sv1.contains(&*dir_path).unwrap());
+        assert!(!root_subvol.contains(&*dir_path).unwrap());
+        assert!(root_subvol.same_filesystem(&sv1).unwrap());
 
         // Test making a snapshot
         let mut snap_path = mount_pt.to_owned();
@@ -511,6 +1395,69 @@ mod test {
         // File from orig also in snap
         assert!(OpenOptions::new().read(true).open(&snap_file_path).is_ok());
 
+        // Test children, leaving out nested subvolumes
+        let nested = Subvolume::create(&*sv1_abs_path.join("dir1/nested"), None).unwrap();
+        let mut children: Vec<SubvolumeId> = root_subvol
+            .children()
+            .unwrap()
+            .iter()
+            .map(Subvolume::id)
+            .collect();
+        children.sort_unstable();
+        assert_eq!(children, vec![sv1.id(), snap_sv1.id()]);
+        assert_eq!(sv1.children().unwrap(), vec![nested.clone()]);
+        assert!(nested.children().unwrap().is_empty());
+        let ordered: BTreeSet<Subvolume> = root_subvol.children().unwrap().into_iter().collect();
+        assert_eq!(
+            ordered.into_iter().collect::<Vec<_>>(),
+            vec![sv1.clone(), snap_sv1.clone()]
+        );
+
+        // Test iterator filters
+        let ids = |iter: SubvolumeIterator| -> Vec<SubvolumeId> {
+            let mut ids: Vec<SubvolumeId> = iter
+                .collect_borrowed()
+                .unwrap()
+                .iter()
+                .map(|s| s.id)
+                .collect();
+            ids.sort_unstable();
+            ids
+        };
+        let iter = SubvolumeIterator::new(mount_pt, None).unwrap();
+        assert_eq!(ids(iter.max_depth(1)), vec![sv1.id(), snap_sv1.id()]);
+        let iter = SubvolumeIterator::new(mount_pt, None).unwrap();
+        assert_eq!(ids(iter.snapshots_only()), vec![snap_sv1.id()]);
+        let iter = SubvolumeIterator::new(mount_pt, None).unwrap();
+        assert!(ids(iter.read_only()).is_empty());
+        let mut iter = SubvolumeIterator::new(mount_pt, None)
+            .unwrap()
+            .snapshots_only();
+        let snap_info = iter.next_info().unwrap().unwrap();
+        assert_eq!(snap_info.path, Path::new("snap1"));
+        assert_eq!(snap_info.parent_uuid, Some(sv1.info().unwrap().uuid));
+        let entries = SubvolumeIterator::new(mount_pt, None)
+            .unwrap()
+            .snapshots_only()
+            .entries()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(entries, vec![(snap_sv1.id(), PathBuf::from("snap1"))]);
+
+        // Test parent
+        assert_eq!(nested.parent().unwrap(), Some(sv1.clone()));
+        assert_eq!(
+            sv1.parent().unwrap().map(|parent| parent.id()),
+            Some(SubvolumeId::FS_TREE)
+        );
+        assert_eq!(root_subvol.parent().unwrap(), None);
+
+        // Test snapshot queries
+        assert!(snap_sv1.is_snapshot().unwrap());
+        assert!(!sv1.is_snapshot().unwrap());
+        assert_eq!(sv1.snapshots(mount_pt).unwrap(), vec![snap_sv1.clone()]);
+        assert!(snap_sv1.snapshots(mount_pt).unwrap().is_empty());
+
         // Test subvol deletion
         let snap_id = snap_sv1.info().unwrap().id;
         snap_sv1.delete(None).unwrap();
@@ -518,6 +1465,51 @@ mod test {
         let deleted = Subvolume::deleted(mount_pt).unwrap();
         assert_eq!(1, deleted.len());
         assert_eq!(snap_id, deleted[0].id());
+
+        // Test creating missing parents
+        let deep =
+            Subvolume::create_with_parents(&*mount_pt.join("snapshots/2020/deep"), None).unwrap();
+        assert!(mount_pt.join("snapshots/2020").is_dir());
+        Subvolume::is_subvolume(&*mount_pt.join("snapshots"))
+            .expect_err("Parent directory incorrectly created as subvolume");
+        let deep_id = deep.id();
+        deep.delete(None).unwrap();
+
+        // Test deletion by id, with nested subvolumes
+        let sv2 = Subvolume::create(&*mount_pt.join("subvol2"), None).unwrap();
+        Subvolume::create(&*mount_pt.join("subvol2/inner"), None).unwrap();
+        Subvolume::get(mount_pt)
+            .unwrap()
+            .elevate()
+            .unwrap()
+            .delete_by_id(sv2.id(), DeleteFlags::RECURSIVE)
+            .unwrap();
+        assert!(!sv2.path().exists());
+
+        // Test batch deletion, parents given before their nested subvolumes
+        let outer = Subvolume::create(&*mount_pt.join("outer"), None).unwrap();
+        let inner = Subvolume::create(&*mount_pt.join("outer/inner"), None).unwrap();
+        let batch_ids = [outer.id(), inner.id()];
+        let deleted = AdminSubvolume::delete_many(
+            vec![outer.elevate().unwrap(), inner.elevate().unwrap()],
+            None,
+        )
+        .unwrap();
+        assert_eq!(deleted.len(), 2);
+        assert!(!mount_pt.join("outer").exists());
+        assert!(AdminSubvolume::delete_many(
+            vec![Subvolume::get(mount_pt).unwrap().elevate().unwrap()],
+            None
+        )
+        .is_err());
+
+        Subvolume::wait_for_cleanup(
+            mount_pt,
+            &[snap_id, deep_id, sv2.id(), batch_ids[0], batch_ids[1]],
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        assert!(Subvolume::deleted(mount_pt).unwrap().is_empty());
     }
 
     #[test]
@@ -525,4 +1517,31 @@ mod test {
     fn loop_test_btrfs_subvol() {
         test_with_spec(1, test_btrfs_subvol);
     }
+
+    #[test]
+    fn subvolume_identity() {
+        let fs_a = RawUuid::from_bytes([1; 16]);
+        let fs_b = RawUuid::from_bytes([2; 16]);
+        let subvol =
+            |id, fsid, path: &str| Subvolume::new(SubvolumeId::from_raw(id), fsid, path.into());
+
+        assert_eq!(subvol(256, fs_a, "/mnt/a"), subvol(256, fs_a, "/mnt/b"));
+        assert_ne!(subvol(256, fs_a, "/mnt/a"), subvol(256, fs_b, "/mnt/a"));
+        let unique: HashSet<Subvolume> =
+            vec![subvol(256, fs_a, "/mnt/a"), subvol(256, fs_a, "/mnt/b")]
+                .into_iter()
+                .collect();
+        assert_eq!(unique.len(), 1);
+
+        let mut ordered = [
+            subvol(257, fs_a, "/a"),
+            subvol(256, fs_b, "/b"),
+            subvol(256, fs_a, "/c"),
+        ];
+        ordered.sort();
+        assert_eq!(
+            ordered.iter().map(Subvolume::path).collect::<Vec<_>>(),
+            vec![Path::new("/c"), Path::new("/b"), Path::new("/a")]
+        );
+    }
 }
@@ -1,19 +1,26 @@
 use crate::common;
+use crate::error::GlueError;
+use crate::ioctl;
 use crate::subvolume::Subvolume;
+use crate::subvolume::SubvolumeId;
+use crate::sys;
+use crate::sys::RawSubvolumeInfo;
+use crate::sys::Timespec;
 use crate::BtrfsUtilError;
+use crate::RawUuid;
 use crate::Result;
 
 use std::convert::TryFrom;
+use std::fmt;
+use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
 
-use btrfsutil_sys::btrfs_util_subvolume_info;
-
+#[cfg(feature = "chrono")]
 use chrono::DateTime;
+#[cfg(feature = "chrono")]
 use chrono::Local;
-use chrono::TimeZone;
-use chrono::Timelike;
-
-use uuid::Uuid;
 
 /// Information about a Btrfs subvolume.
 ///
@@ -21,9 +28,10 @@ use uuid::Uuid;
 ///
 /// [btrfs_util_subvolume_info]: https://docs.rs/btrfsutil-sys/1.2.1/btrfsutil_sys/struct.btrfs_util_subvolume_info.html
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SubvolumeInfo {
     /// ID of this subvolume, unique across the filesystem.
-    pub id: u64,
+    pub id: SubvolumeId,
     /// The path of the subvolume.
     pub path: PathBuf,
     /// ID of the subvolume which contains this subvolume, or zero for the root subvolume
@@ -31,7 +39,7 @@ pub struct SubvolumeInfo {
     /// deleted but not yet cleaned up).
     ///
     /// [BTRFS_FS_TREE_OBJECTID]: https://github.com/kdave/btrfs-progs/blob/471b4cf7e3a46222531a895f90228ea164b1b857/libbtrfsutil/btrfs_tree.h#L34
-    pub parent_id: Option<u64>,
+    pub parent_id: Option<SubvolumeId>,
     /// Inode number of the directory containing this subvolume in the parent subvolume, or zero
     /// for the root subvolume ([BTRFS_FS_TREE_OBJECTID]) or orphaned subvolumes.
     ///
@@ -40,15 +48,15 @@ pub struct SubvolumeInfo {
     /// On-disk root item flags.
     pub flags: u64,
     /// UUID of this subvolume.
-    pub uuid: Uuid,
+    pub uuid: RawUuid,
     /// UUID of the subvolume this subvolume is a snapshot of, or all zeroes if this subvolume is
     /// not a snapshot.
-    pub parent_uuid: Option<Uuid>,
+    pub parent_uuid: Option<RawUuid>,
     /// UUID of the subvolume this subvolume was received from, or all zeroes if this subvolume was
     /// not received. Note that this field, [stransid](#structfield.stransid),
     /// [rtransid](#structfield.rtransid), [stime](#structfield.stime), and
     /// [rtime](#structfield.rtime) are set manually by userspace after a subvolume is received.
-    pub received_uuid: Option<Uuid>,
+    pub received_uuid: Option<RawUuid>,
     /// Transaction ID of the subvolume root.
     pub generation: u64,
     /// Transaction ID when an inode in this subvolume was last changed.
@@ -62,20 +70,108 @@ pub struct SubvolumeInfo {
     /// received. See the note on [received_uuid](#structfield.received_uuid).
     pub rtransid: Option<u64>,
     /// Time when an inode in this subvolume was last changed.
-    pub ctime: DateTime<Local>,
+    pub ctime: SystemTime,
     /// Time when this subvolume was created.
-    pub otime: DateTime<Local>,
+    pub otime: SystemTime,
     /// Not well-defined, usually zero unless it was set otherwise. See the note on
     /// [received_uuid](#structfield.received_uuid).
-    pub stime: Option<DateTime<Local>>,
+    pub stime: Option<SystemTime>,
     /// Time when this subvolume was received, or zero if this subvolume was not received. See the
     /// [received_uuid](#structfield.received_uuid).
-    pub rtime: Option<DateTime<Local>>,
+    pub rtime: Option<SystemTime>,
+}
+
+impl SubvolumeInfo {
+    /// Get the time elapsed since this subvolume was created.
+    ///
+    /// Zero if the creation time is in the future, e.g. because the clock was set back.
+    pub fn age(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(self.otime)
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(feature = "chrono")]
+#[cfg_attr(docsrs, doc(cfg(feature = "chrono")))]
+impl SubvolumeInfo {
+    /// Get the time when an inode in this subvolume was last changed, in the local time zone.
+    pub fn ctime_local(&self) -> DateTime<Local> {
+        self.ctime.into()
+    }
+
+    /// Get the time when this subvolume was created, in the local time zone.
+    pub fn otime_local(&self) -> DateTime<Local> {
+        self.otime.into()
+    }
+
+    /// Get the [stime](#structfield.stime), in the local time zone.
+    pub fn stime_local(&self) -> Option<DateTime<Local>> {
+        self.stime.map(DateTime::from)
+    }
+
+    /// Get the time when this subvolume was received, in the local time zone.
+    pub fn rtime_local(&self) -> Option<DateTime<Local>> {
+        self.rtime.map(DateTime::from)
+    }
+}
+
+/// Predicate matching subvolumes created more than `age` ago.
+///
+/// Meant for filtering iterators of [SubvolumeInfo].
+///
+/// [SubvolumeInfo]: struct.SubvolumeInfo.html
+pub fn older_than(age: Duration) -> impl Fn(&SubvolumeInfo) -> bool {
+    move |info| info.age() > age
+}
+
+/// Predicate matching subvolumes created before a point in time, such as a [SystemTime] or,
+/// with the `chrono` feature, a chrono `DateTime`.
+///
+/// Meant for filtering iterators of [SubvolumeInfo].
+///
+/// [SystemTime]: https://doc.rust-lang.org/stable/std/time/struct.SystemTime.html
+/// [SubvolumeInfo]: struct.SubvolumeInfo.html
+pub fn created_before<T>(time: T) -> impl Fn(&SubvolumeInfo) -> bool
+where
+    T: Into<SystemTime>,
+{
+    let time = time.into();
+    move |info| info.otime < time
 }
 
-impl From<&SubvolumeInfo> for Subvolume {
-    fn from(info: &SubvolumeInfo) -> Self {
-        Self::new(info.id, info.path.clone())
+impl fmt::Display for SubvolumeInfo {
+    /// A one-line summary, such as
+    /// `subvol 259 @home/.snapshots/1, gen 1234, created 2024-05-01 10:00:00, read-only`, in the
+    /// local time zone, followed by the UUID of the source for snapshots.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "subvol {} {}, gen {}, created {}",
+            self.id,
+            self.path.display(),
+            self.generation,
+            common::format_local_time(self.otime, c"%Y-%m-%d %H:%M:%S")
+        )?;
+        if self.flags & ioctl::BTRFS_ROOT_SUBVOL_RDONLY != 0 {
+            write!(f, ", read-only")?;
+        }
+        if let Some(parent_uuid) = &self.parent_uuid {
+            write!(f, ", snapshot of {}", parent_uuid)?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&SubvolumeInfo> for Subvolume {
+    type Error = BtrfsUtilError;
+
+    /// Attempts to get the subvolume described, looking up the fsid of its filesystem through
+    /// its path.
+    fn try_from(info: &SubvolumeInfo) -> Result<Self> {
+        let file = ioctl::open(&info.path)?;
+        let fsid = RawUuid(ioctl::fs_info(file.as_raw_fd())?.fsid);
+        Ok(Self::new(info.id, fsid, info.path.clone()))
     }
 }
 
@@ -83,84 +179,35 @@ impl TryFrom<&Subvolume> for SubvolumeInfo {
     type Error = BtrfsUtilError;
 
     fn try_from(src: &Subvolume) -> Result<Self> {
-        let path_cstr = common::path_to_cstr(src.path());
-        let btrfs_subvolume_info_ptr: *mut btrfs_util_subvolume_info =
-            Box::into_raw(Box::from(btrfs_util_subvolume_info {
-                id: 0,
-                parent_id: 0,
-                dir_id: 0,
-                flags: 0,
-                uuid: [0; 16],
-                parent_uuid: [0; 16],
-                received_uuid: [0; 16],
-                generation: 0,
-                ctransid: 0,
-                otransid: 0,
-                stransid: 0,
-                rtransid: 0,
-                ctime: btrfsutil_sys::timespec {
-                    tv_nsec: 0 as btrfsutil_sys::__time_t,
-                    tv_sec: 0 as btrfsutil_sys::__syscall_slong_t,
-                },
-                otime: btrfsutil_sys::timespec {
-                    tv_nsec: 0 as btrfsutil_sys::__time_t,
-                    tv_sec: 0 as btrfsutil_sys::__syscall_slong_t,
-                },
-                stime: btrfsutil_sys::timespec {
-                    tv_nsec: 0 as btrfsutil_sys::__time_t,
-                    tv_sec: 0 as btrfsutil_sys::__syscall_slong_t,
-                },
-                rtime: btrfsutil_sys::timespec {
-                    tv_nsec: 0 as btrfsutil_sys::__time_t,
-                    tv_sec: 0 as btrfsutil_sys::__syscall_slong_t,
-                },
-            }));
-
-        unsafe_wrapper!({
-            btrfs_util_subvolume_info(path_cstr.as_ptr(), src.id(), btrfs_subvolume_info_ptr)
-        })?;
-
-        let info: Box<btrfs_util_subvolume_info> =
-            unsafe { Box::from_raw(btrfs_subvolume_info_ptr) };
-
-        // process the retrieved info struct
-        let uuid: Uuid = Uuid::from_slice(&info.uuid).expect("Failed to get uuid from C");
-        let parent_uuid_val: Uuid =
-            Uuid::from_slice(&info.parent_uuid).expect("Failed to get parent uuid from C");
-        let received_uuid_val: Uuid =
-            Uuid::from_slice(&info.received_uuid).expect("Failed to get received uuid from C");
-        let ctime: DateTime<Local> = Local
-            .timestamp_opt(info.ctime.tv_sec, info.ctime.tv_nsec as u32)
-            .single()
-            .expect("Failed to generate timestamp from C");
-        let otime: DateTime<Local> = Local
-            .timestamp_opt(info.otime.tv_sec, info.otime.tv_nsec as u32)
-            .single()
-            .expect("Failed to generate timestamp from C");
-        let stime_val: DateTime<Local> = Local
-            .timestamp_opt(info.stime.tv_sec, info.stime.tv_nsec as u32)
-            .single()
-            .expect("Failed to generate timestamp from C");
-        let rtime_val: DateTime<Local> = Local
-            .timestamp_opt(info.rtime.tv_sec, info.rtime.tv_nsec as u32)
-            .single()
-            .expect("Failed to generate timestamp from C");
-        let parent_id: Option<u64> = if info.parent_id == 0 {
+        let info = sys::subvolume_info(src.path(), src.id().get())?;
+        Self::from_raw(&info, src.path().to_path_buf())
+    }
+}
+
+impl SubvolumeInfo {
+    /// Convert the information read by libbtrfsutil.
+    pub(crate) fn from_raw(info: &RawSubvolumeInfo, path: PathBuf) -> Result<Self> {
+        let uuid = RawUuid(info.uuid);
+        let parent_uuid_val = RawUuid(info.parent_uuid);
+        let received_uuid_val = RawUuid(info.received_uuid);
+        let ctime: SystemTime = system_time(&info.ctime)?;
+        let otime: SystemTime = system_time(&info.otime)?;
+        let parent_id: Option<SubvolumeId> = if info.parent_id == 0 {
             None
         } else {
-            Some(info.parent_id)
+            Some(SubvolumeId::from_raw(info.parent_id))
         };
         let dir_id: Option<u64> = if info.dir_id == 0 {
             None
         } else {
             Some(info.dir_id)
         };
-        let parent_uuid: Option<Uuid> = if parent_uuid_val.is_nil() {
+        let parent_uuid: Option<RawUuid> = if parent_uuid_val.is_nil() {
             None
         } else {
             Some(parent_uuid_val)
         };
-        let received_uuid: Option<Uuid> = if received_uuid_val.is_nil() {
+        let received_uuid: Option<RawUuid> = if received_uuid_val.is_nil() {
             None
         } else {
             Some(received_uuid_val)
@@ -175,22 +222,20 @@ impl TryFrom<&Subvolume> for SubvolumeInfo {
         } else {
             Some(info.rtransid)
         };
-        let stime: Option<DateTime<Local>> =
-            if stime_val.nanosecond() == 0 && stime_val.second() == 0 {
-                None
-            } else {
-                Some(stime_val)
-            };
-        let rtime: Option<DateTime<Local>> =
-            if rtime_val.nanosecond() == 0 && rtime_val.second() == 0 {
-                None
-            } else {
-                Some(rtime_val)
-            };
+        let stime: Option<SystemTime> = if info.stime.sec == 0 && info.stime.nsec == 0 {
+            None
+        } else {
+            Some(system_time(&info.stime)?)
+        };
+        let rtime: Option<SystemTime> = if info.rtime.sec == 0 && info.rtime.nsec == 0 {
+            None
+        } else {
+            Some(system_time(&info.rtime)?)
+        };
 
         Ok(Self {
-            id: info.id,
-            path: src.path().to_path_buf(),
+            id: SubvolumeId::from_raw(info.id),
+            path,
             parent_id,
             dir_id,
             flags: info.flags,
@@ -209,3 +254,61 @@ impl TryFrom<&Subvolume> for SubvolumeInfo {
         })
     }
 }
+
+/// Convert a timespec read by libbtrfsutil.
+fn system_time(ts: &Timespec) -> Result<SystemTime> {
+    common::timespec_to_system_time(ts.sec, ts.nsec)
+        .ok_or_else(|| GlueError::BadTimespec(format!("{:?}", ts)).into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn info_summary() {
+        let otime = common::timespec_to_system_time(1_714_557_600, 0).unwrap();
+        let created = common::format_local_time(otime, c"%Y-%m-%d %H:%M:%S");
+        let mut info = SubvolumeInfo {
+            id: SubvolumeId::from_raw(259),
+            path: PathBuf::from("@home/.snapshots/1"),
+            parent_id: Some(SubvolumeId::from_raw(256)),
+            dir_id: Some(256),
+            flags: 0,
+            uuid: RawUuid::from_bytes(259u128.to_be_bytes()),
+            parent_uuid: None,
+            received_uuid: None,
+            generation: 1234,
+            ctransid: 1234,
+            otransid: 1234,
+            stransid: None,
+            rtransid: None,
+            ctime: otime,
+            otime,
+            stime: None,
+            rtime: None,
+        };
+        assert_eq!(
+            info.to_string(),
+            format!(
+                "subvol 259 @home/.snapshots/1, gen 1234, created {}",
+                created
+            )
+        );
+
+        info.flags = ioctl::BTRFS_ROOT_SUBVOL_RDONLY;
+        info.parent_uuid = Some(RawUuid::from_bytes(257u128.to_be_bytes()));
+        assert_eq!(
+            info.to_string(),
+            format!(
+                "subvol 259 @home/.snapshots/1, gen 1234, created {}, read-only, snapshot of \
+                 00000000-0000-0000-0000-000000000101",
+                created
+            )
+        );
+        assert_eq!(
+            Subvolume::new(info.id, RawUuid::NIL, info.path.clone()).to_string(),
+            "subvol 259 @home/.snapshots/1"
+        );
+    }
+}
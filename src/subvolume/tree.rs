@@ -0,0 +1,215 @@
+use crate::error::ResultExt;
+use crate::subvolume::SubvolumeId;
+use crate::subvolume::SubvolumeInfo;
+use crate::subvolume::SubvolumeIterator;
+use crate::RawUuid;
+use crate::Result;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// A subvolume of a [SubvolumeTree].
+///
+/// [SubvolumeTree]: struct.SubvolumeTree.html
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SubvolumeNode {
+    /// Information about the subvolume, with its path relative to the subvolume the tree was
+    /// built for.
+    pub info: SubvolumeInfo,
+    /// Ids of the subvolumes directly below this one, in the order they were listed.
+    pub children: Vec<SubvolumeId>,
+}
+
+impl SubvolumeNode {
+    /// Get the id of the subvolume.
+    #[inline]
+    pub fn id(&self) -> SubvolumeId {
+        self.info.id
+    }
+
+    /// Get the path of the subvolume, relative to the subvolume the tree was built for.
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.info.path
+    }
+}
+
+/// The hierarchy of the subvolumes below a subvolume.
+///
+/// The subvolume the tree is built for is not part of it; the subvolumes directly below it are
+/// the [roots].
+///
+/// [roots]: #method.roots
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SubvolumeTree {
+    nodes: BTreeMap<SubvolumeId, SubvolumeNode>,
+    roots: Vec<SubvolumeId>,
+    by_path: HashMap<PathBuf, SubvolumeId>,
+    by_uuid: HashMap<RawUuid, SubvolumeId>,
+}
+
+impl SubvolumeTree {
+    /// Build the tree of the subvolumes below the subvolume a path belongs to, listing them
+    /// along with their information in a single pass.
+    ///
+    /// Listing subvolumes of other users may require **CAP_SYS_ADMIN**.
+    pub fn build<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        Self::build_impl(path).context("SubvolumeTree::build", &[path])
+    }
+
+    fn build_impl(path: &Path) -> Result<Self> {
+        let mut iter = SubvolumeIterator::new(path, None)?;
+        let mut infos = Vec::new();
+        while let Some(info) = iter.next_info() {
+            infos.push(info?);
+        }
+        Ok(Self::from_infos(infos))
+    }
+
+    /// Link subvolumes to their parents, which may be listed after them.
+    fn from_infos(infos: Vec<SubvolumeInfo>) -> Self {
+        let mut tree = Self::default();
+        let mut parents = Vec::with_capacity(infos.len());
+        for info in infos {
+            parents.push((info.id, info.parent_id));
+            tree.by_path.insert(info.path.clone(), info.id);
+            tree.by_uuid.insert(info.uuid, info.id);
+            tree.nodes.insert(
+                info.id,
+                SubvolumeNode {
+                    info,
+                    children: Vec::new(),
+                },
+            );
+        }
+
+        for (id, parent_id) in parents {
+            match parent_id.and_then(|parent_id| tree.nodes.get_mut(&parent_id)) {
+                Some(parent) => parent.children.push(id),
+                None => tree.roots.push(id),
+            }
+        }
+        tree
+    }
+
+    /// Get the number of subvolumes in the tree.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Check whether the tree has no subvolumes.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Get the subvolumes directly below the subvolume the tree was built for.
+    pub fn roots(&self) -> impl Iterator<Item = &SubvolumeNode> {
+        self.roots.iter().filter_map(move |id| self.nodes.get(id))
+    }
+
+    /// Get the subvolumes directly below a subvolume of the tree.
+    pub fn children(&self, id: SubvolumeId) -> impl Iterator<Item = &SubvolumeNode> {
+        self.nodes
+            .get(&id)
+            .into_iter()
+            .flat_map(|node| node.children.iter())
+            .filter_map(move |id| self.nodes.get(id))
+    }
+
+    /// Get the subvolume with an id.
+    #[inline]
+    pub fn get(&self, id: SubvolumeId) -> Option<&SubvolumeNode> {
+        self.nodes.get(&id)
+    }
+
+    /// Get the subvolume at a path relative to the subvolume the tree was built for.
+    pub fn by_path<P>(&self, path: P) -> Option<&SubvolumeNode>
+    where
+        P: AsRef<Path>,
+    {
+        self.by_path
+            .get(path.as_ref())
+            .and_then(|id| self.nodes.get(id))
+    }
+
+    /// Get the subvolume with a UUID.
+    pub fn by_uuid(&self, uuid: &RawUuid) -> Option<&SubvolumeNode> {
+        self.by_uuid.get(uuid).and_then(|id| self.nodes.get(id))
+    }
+
+    /// Iterate over all subvolumes of the tree, by id.
+    pub fn iter(&self) -> impl Iterator<Item = &SubvolumeNode> {
+        self.nodes.values()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::SystemTime;
+
+    fn info(id: u64, parent_id: u64, path: &str) -> SubvolumeInfo {
+        SubvolumeInfo {
+            id: SubvolumeId::from_raw(id),
+            path: PathBuf::from(path),
+            parent_id: Some(SubvolumeId::from_raw(parent_id)),
+            dir_id: Some(256),
+            flags: 0,
+            uuid: RawUuid::from_bytes(u128::from(id).to_be_bytes()),
+            parent_uuid: None,
+            received_uuid: None,
+            generation: 0,
+            ctransid: 0,
+            otransid: 0,
+            stransid: None,
+            rtransid: None,
+            ctime: SystemTime::now(),
+            otime: SystemTime::now(),
+            stime: None,
+            rtime: None,
+        }
+    }
+
+    #[test]
+    fn tree_links_parents() {
+        // post order lists children first
+        let tree = SubvolumeTree::from_infos(vec![
+            info(258, 257, "@/home"),
+            info(259, 257, "@/.snapshots"),
+            info(257, 5, "@"),
+            info(260, 5, "@var"),
+        ]);
+
+        assert_eq!(tree.len(), 4);
+        assert_eq!(
+            tree.roots().map(|node| node.id().get()).collect::<Vec<_>>(),
+            vec![257, 260]
+        );
+        assert_eq!(
+            tree.children(SubvolumeId::from_raw(257))
+                .map(|node| node.id().get())
+                .collect::<Vec<_>>(),
+            vec![258, 259]
+        );
+        assert_eq!(
+            tree.by_path("@/home").map(SubvolumeNode::id),
+            Some(SubvolumeId::from_raw(258))
+        );
+        assert_eq!(
+            tree.by_uuid(&RawUuid::from_bytes(260u128.to_be_bytes()))
+                .map(SubvolumeNode::path),
+            Some(Path::new("@var"))
+        );
+        assert!(tree.get(SubvolumeId::FS_TREE).is_none());
+    }
+}
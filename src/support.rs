@@ -0,0 +1,297 @@
+//! Support bundles
+//!
+//! Captures the state of a filesystem as seen by this library in a single JSON document, for
+//! bug reports and fleet diagnostics.
+//!
+//! Collection is best effort: a part that cannot be collected, for example because it requires
+//! **CAP_SYS_ADMIN**, is recorded as an `error` object and the rest of the bundle is still
+//! written.
+
+use crate::common;
+use crate::error::LibError;
+use crate::ioctl;
+use crate::properties;
+use crate::subvolume::Subvolume;
+use crate::subvolume::SubvolumeId;
+use crate::subvolume::SubvolumeIterator;
+use crate::BtrfsUtilError;
+use crate::RawUuid;
+pub use crate::Result;
+
+use std::ffi::CStr;
+use std::io;
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Names of the device error counters, in the order the kernel reports them.
+const DEV_STAT_NAMES: [&str; ioctl::BTRFS_DEV_STAT_VALUES_MAX] = [
+    "write_errs",
+    "read_errs",
+    "flush_errs",
+    "corruption_errs",
+    "generation_errs",
+];
+
+/// A JSON value, just enough to write bundles without a serialization dependency.
+enum Json {
+    Null,
+    Bool(bool),
+    Num(u64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(&'static str, Json)>),
+}
+
+impl Json {
+    fn str<S>(s: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Json::Str(s.into())
+    }
+
+    fn opt<T, F>(val: Option<T>, f: F) -> Self
+    where
+        F: FnOnce(T) -> Json,
+    {
+        val.map(f).unwrap_or(Json::Null)
+    }
+
+    fn error(err: &BtrfsUtilError) -> Self {
+        Json::Obj(vec![("error", Json::str(err.to_string()))])
+    }
+
+    fn write_to<W>(&self, writer: &mut W) -> io::Result<()>
+    where
+        W: Write,
+    {
+        match self {
+            Json::Null => write!(writer, "null"),
+            Json::Bool(val) => write!(writer, "{}", val),
+            Json::Num(val) => write!(writer, "{}", val),
+            Json::Str(val) => write_str(writer, val),
+            Json::Arr(vals) => {
+                write!(writer, "[")?;
+                for (i, val) in vals.iter().enumerate() {
+                    if i > 0 {
+                        write!(writer, ",")?;
+                    }
+                    val.write_to(writer)?;
+                }
+                write!(writer, "]")
+            }
+            Json::Obj(fields) => {
+                write!(writer, "{{")?;
+                for (i, (name, val)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(writer, ",")?;
+                    }
+                    write_str(writer, name)?;
+                    write!(writer, ":")?;
+                    val.write_to(writer)?;
+                }
+                write!(writer, "}}")
+            }
+        }
+    }
+}
+
+fn write_str<W>(writer: &mut W, s: &str) -> io::Result<()>
+where
+    W: Write,
+{
+    write!(writer, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(writer, "\\\"")?,
+            '\\' => write!(writer, "\\\\")?,
+            '\n' => write!(writer, "\\n")?,
+            c if (c as u32) < 0x20 => write!(writer, "\\u{:04x}", c as u32)?,
+            c => write!(writer, "{}", c)?,
+        }
+    }
+    write!(writer, "\"")
+}
+
+/// Write a support bundle for the filesystem a path belongs to.
+///
+/// The bundle holds the filesystem information and label, its devices with their error
+/// counters, the default subvolume, every subvolume with its information and the mount the path
+/// belongs to. Only failing to write to `writer` is an error.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn bundle<P, W>(fs_root: P, mut writer: W) -> Result<()>
+where
+    P: AsRef<Path>,
+    W: Write,
+{
+    let fs_root = fs_root.as_ref();
+
+    let bundle = Json::Obj(vec![
+        ("crate_version", Json::str(env!("CARGO_PKG_VERSION"))),
+        (
+            "collected_at",
+            Json::str(common::format_rfc3339(SystemTime::now())),
+        ),
+        ("path", Json::str(fs_root.display().to_string())),
+        ("filesystem", section(filesystem(fs_root))),
+        ("devices", section(devices(fs_root))),
+        (
+            "default_subvolume",
+            section(Subvolume::get_default(fs_root).map(|subvol| Json::Num(subvol.id().get()))),
+        ),
+        ("subvolumes", section(subvolumes(fs_root))),
+        ("mount", section(mount(fs_root))),
+    ]);
+
+    let mut write = || -> io::Result<()> {
+        bundle.write_to(&mut writer)?;
+        writeln!(writer)?;
+        writer.flush()
+    };
+    write().map_err(|err| BtrfsUtilError::from_io(LibError::BundleWriteFailed, &err))
+}
+
+fn section(result: Result<Json>) -> Json {
+    result.unwrap_or_else(|err| Json::error(&err))
+}
+
+fn filesystem(fs_root: &Path) -> Result<Json> {
+    let file = ioctl::open(fs_root)?;
+    let info = ioctl::fs_info(file.as_raw_fd())?;
+
+    Ok(Json::Obj(vec![
+        ("fsid", Json::str(RawUuid(info.fsid).to_string())),
+        (
+            "label",
+            section(properties::get_label(fs_root).map(Json::Str)),
+        ),
+        ("num_devices", Json::Num(info.num_devices)),
+        ("nodesize", Json::Num(info.nodesize as u64)),
+        ("sectorsize", Json::Num(info.sectorsize as u64)),
+        ("csum_type", Json::Num(info.csum_type as u64)),
+        ("generation", Json::Num(info.generation)),
+    ]))
+}
+
+fn devices(fs_root: &Path) -> Result<Json> {
+    let file = ioctl::open(fs_root)?;
+    let fd = file.as_raw_fd();
+
+    let mut devices = Vec::new();
+    for devid in ioctl::device_ids(fd)? {
+        let info = match ioctl::dev_info(fd, devid)? {
+            Some(info) => info,
+            // removed in the meantime
+            None => continue,
+        };
+        let path = CStr::from_bytes_until_nul(&info.path)
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let stats = ioctl::dev_stats(fd, devid).map(|stats| {
+            Json::Obj(
+                DEV_STAT_NAMES
+                    .iter()
+                    .zip(stats.values.iter())
+                    .map(|(name, val)| (*name, Json::Num(*val)))
+                    .collect(),
+            )
+        });
+
+        devices.push(Json::Obj(vec![
+            ("devid", Json::Num(devid)),
+            ("path", Json::Str(path)),
+            ("uuid", Json::str(RawUuid(info.uuid).to_string())),
+            ("total_bytes", Json::Num(info.total_bytes)),
+            ("bytes_used", Json::Num(info.bytes_used)),
+            ("stats", section(stats)),
+        ]));
+    }
+
+    Ok(Json::Arr(devices))
+}
+
+fn subvolumes(fs_root: &Path) -> Result<Json> {
+    let mut subvolumes = Vec::new();
+    for subvol in SubvolumeIterator::new(fs_root, None)? {
+        let entry = subvol.and_then(|subvol| subvol.info()).map(|info| {
+            Json::Obj(vec![
+                ("id", Json::Num(info.id.get())),
+                ("path", Json::str(info.path.display().to_string())),
+                (
+                    "parent_id",
+                    Json::opt(info.parent_id.map(SubvolumeId::get), Json::Num),
+                ),
+                ("uuid", Json::str(info.uuid.to_string())),
+                (
+                    "parent_uuid",
+                    Json::opt(info.parent_uuid, |uuid| Json::str(uuid.to_string())),
+                ),
+                (
+                    "received_uuid",
+                    Json::opt(info.received_uuid, |uuid| Json::str(uuid.to_string())),
+                ),
+                ("flags", Json::Num(info.flags)),
+                (
+                    "read_only",
+                    Json::Bool(info.flags & ioctl::BTRFS_ROOT_SUBVOL_RDONLY != 0),
+                ),
+                ("generation", Json::Num(info.generation)),
+                ("ctransid", Json::Num(info.ctransid)),
+                ("otransid", Json::Num(info.otransid)),
+                ("otime", Json::str(common::format_rfc3339(info.otime))),
+            ])
+        });
+        subvolumes.push(section(entry));
+    }
+
+    Ok(Json::Arr(subvolumes))
+}
+
+/// Find the btrfs mount a path belongs to in `/proc/self/mountinfo`.
+fn mount(fs_root: &Path) -> Result<Json> {
+    let path = fs_root
+        .canonicalize()
+        .map_err(|err| BtrfsUtilError::from_io(LibError::StatFailed, &err))?;
+
+    let best = common::btrfs_mounts()?
+        .into_iter()
+        .filter(|mount| path.starts_with(&mount.mount_point))
+        .max_by_key(|mount| mount.mount_point.as_os_str().len());
+
+    Ok(match best {
+        Some(mount) => Json::Obj(vec![
+            (
+                "mount_point",
+                Json::str(mount.mount_point.to_string_lossy()),
+            ),
+            ("root", Json::str(mount.root.to_string_lossy())),
+            ("source", Json::str(mount.source.to_string_lossy())),
+            ("options", Json::str(mount.options)),
+            ("super_options", Json::str(mount.super_options)),
+        ]),
+        None => Json::Null,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn json_escaping() {
+        let val = Json::Obj(vec![
+            ("name", Json::str("a \"quoted\"\\path\n")),
+            ("ok", Json::Bool(true)),
+            ("ids", Json::Arr(vec![Json::Num(5), Json::Null])),
+        ]);
+        let mut out: Vec<u8> = Vec::new();
+        val.write_to(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"{"name":"a \"quoted\"\\path\n","ok":true,"ids":[5,null]}"#
+        );
+    }
+}
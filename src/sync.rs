@@ -1,31 +1,195 @@
 //! Module related to syncing a btrfs filesystem.
 
-use crate::common;
-use crate::Result;
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::ioctl;
+use crate::ioctl::SearchKey;
+use crate::sys;
+use crate::tree_search;
+pub use crate::Result;
 
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
 use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
-use btrfsutil_sys::btrfs_util_start_sync;
-use btrfsutil_sys::btrfs_util_wait_sync;
+/// Time between checks of the last committed transaction when waiting with a timeout.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-/// Start syncing on a btrfs filesystem.
-pub fn sync<'a, P>(path: P) -> Result<()>
+/// A transaction of a btrfs filesystem, returned by operations that do not wait for it to be
+/// committed.
+///
+/// Transactions are committed in order, so waiting for the latest of several transactions waits
+/// for all of them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TransactionId {
+    path: PathBuf,
+    id: u64,
+}
+
+impl TransactionId {
+    pub(crate) fn new(path: &Path, id: u64) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            id,
+        }
+    }
+
+    /// Get the transaction id.
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Wait for the transaction to be committed.
+    pub fn wait(&self) -> Result<()> {
+        wait_impl(&self.path, self.id, None).context("TransactionId::wait", &[&self.path])
+    }
+
+    /// Wait for the transaction to be committed, for at most `timeout`, see [wait].
+    ///
+    /// [wait]: fn.wait.html
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<()> {
+        wait_impl(&self.path, self.id, Some(timeout))
+            .context("TransactionId::wait_timeout", &[&self.path])
+    }
+}
+
+/// Start syncing on a btrfs filesystem without waiting for it to finish, and get the transaction
+/// being committed.
+pub fn start<P>(path: P) -> Result<TransactionId>
 where
-    P: Into<&'a Path>,
+    P: AsRef<Path>,
 {
-    sync_impl(path.into())
+    let path = path.as_ref();
+    start_impl(path).context("sync::start", &[path])
 }
 
-fn sync_impl(path: &Path) -> Result<()> {
-    let path_cstr = common::path_to_cstr(path);
+fn start_impl(path: &Path) -> Result<TransactionId> {
+    let async_transid = sys::start_sync(path)?;
+
+    Ok(TransactionId::new(path, async_transid))
+}
 
-    let async_transid: u64 = {
-        let mut async_transid: u64 = 0;
-        unsafe_wrapper!({ btrfs_util_start_sync(path_cstr.as_ptr(), &mut async_transid) })?;
-        async_transid
+/// Wait for a transaction of a btrfs filesystem to be committed, or for the current one if
+/// `transid` is zero.
+///
+/// With a timeout, fails with [LibError::SyncTimedOut] if the transaction is still not committed
+/// after it, instead of hanging on a filesystem that stopped committing. The kernel offers no
+/// way to give up waiting, so the last committed transaction is checked on until the deadline
+/// instead, which requires **CAP_SYS_ADMIN**. A transaction id of zero then stands for the
+/// transaction running when the wait starts, whose commit is started.
+///
+/// [LibError::SyncTimedOut]: ../error/enum.LibError.html#variant.SyncTimedOut
+pub fn wait<P, T>(path: P, transid: u64, timeout: T) -> Result<()>
+where
+    P: AsRef<Path>,
+    T: Into<Option<Duration>>,
+{
+    let path = path.as_ref();
+    wait_impl(path, transid, timeout.into()).context("sync::wait", &[path])
+}
+
+fn wait_impl(path: &Path, transid: u64, timeout: Option<Duration>) -> Result<()> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return sys::wait_sync(path, transid),
     };
 
-    unsafe_wrapper!({ btrfs_util_wait_sync(path_cstr.as_ptr(), async_transid) })?;
+    let deadline = Instant::now() + timeout;
+    let file = ioctl::open(path)?;
+    let transid = match transid {
+        0 => sys::start_sync_fd(file.as_raw_fd())?,
+        transid => transid,
+    };
+
+    loop {
+        if committed_transid(file.as_raw_fd())? >= transid {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(LibError::SyncTimedOut.into());
+        }
+        thread::sleep(WAIT_POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+/// Get the last transaction committed on the filesystem `fd` belongs to.
+///
+/// The root items of the internal trees are written by every commit changing anything, with
+/// the id of the transaction, so the newest of them is the last committed transaction.
+fn committed_transid(fd: RawFd) -> Result<u64> {
+    let key = SearchKey::new(
+        tree_search::ROOT_TREE_OBJECTID,
+        (
+            tree_search::ROOT_TREE_OBJECTID,
+            ioctl::BTRFS_ROOT_ITEM_KEY,
+            0,
+        ),
+        (
+            ioctl::BTRFS_FIRST_FREE_OBJECTID - 1,
+            ioctl::BTRFS_ROOT_ITEM_KEY,
+            u64::MAX,
+        ),
+    );
+
+    let mut committed = 0;
+    ioctl::tree_search(fd, key, |header, item| {
+        // the generation follows the 160 bytes of the inode item
+        if header.item_type == ioctl::BTRFS_ROOT_ITEM_KEY && item.len() >= 168 {
+            committed = committed.max(ioctl::le_u64(item, 160));
+        }
+        true
+    })?;
 
-    Ok(())
+    Ok(committed)
+}
+
+/// Sync a btrfs filesystem, waiting for the commit to finish.
+pub fn sync<P>(path: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    sync_impl(path).context("sync::sync", &[path])
+}
+
+fn sync_impl(path: &Path) -> Result<()> {
+    start_impl(path)?.wait()
+}
+
+/// Sync a btrfs filesystem through an open file or directory on it, waiting for the commit to
+/// finish.
+///
+/// Useful to daemons keeping the root of a filesystem open, which may no longer be reachable by
+/// its path after being unmounted elsewhere or remounted.
+pub fn sync_fd<F>(fd: &F) -> Result<()>
+where
+    F: AsRawFd,
+{
+    sys::sync_fd(fd.as_raw_fd()).context("sync::sync_fd", &[])
+}
+
+/// Same as [start], through an open file or directory, returning the transaction id.
+///
+/// [start]: fn.start.html
+pub fn start_fd<F>(fd: &F) -> Result<u64>
+where
+    F: AsRawFd,
+{
+    sys::start_sync_fd(fd.as_raw_fd()).context("sync::start_fd", &[])
+}
+
+/// Same as [wait], through an open file or directory.
+///
+/// [wait]: fn.wait.html
+pub fn wait_fd<F>(fd: &F, transid: u64) -> Result<()>
+where
+    F: AsRawFd,
+{
+    sys::wait_sync_fd(fd.as_raw_fd(), transid).context("sync::wait_fd", &[])
 }
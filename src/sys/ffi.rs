@@ -0,0 +1,441 @@
+//! The libbtrfsutil operations performed by calling into libbtrfsutil.
+//!
+//! This is the only module dealing in C types: it passes paths as C strings, turns the error
+//! codes of libbtrfsutil into errors carrying the `errno` it left behind, and copies and frees
+//! what libbtrfsutil allocates.
+
+use crate::common;
+use crate::error::GlueError;
+use crate::error::LibError;
+use crate::sys::RawSubvolumeInfo;
+use crate::sys::Timespec;
+use crate::BtrfsUtilError;
+use crate::Result;
+
+use std::convert::TryFrom;
+use std::ffi::CStr;
+use std::ffi::OsStr;
+use std::os::raw::c_char;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::path::PathBuf;
+
+use btrfsutil_sys::btrfs_util_create_qgroup_inherit;
+use btrfsutil_sys::btrfs_util_create_snapshot;
+use btrfsutil_sys::btrfs_util_create_subvolume;
+use btrfsutil_sys::btrfs_util_create_subvolume_iterator;
+use btrfsutil_sys::btrfs_util_delete_subvolume;
+use btrfsutil_sys::btrfs_util_deleted_subvolumes;
+use btrfsutil_sys::btrfs_util_destroy_qgroup_inherit;
+use btrfsutil_sys::btrfs_util_destroy_subvolume_iterator;
+use btrfsutil_sys::btrfs_util_error;
+use btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_OK;
+use btrfsutil_sys::btrfs_util_get_default_subvolume;
+use btrfsutil_sys::btrfs_util_get_subvolume_read_only;
+use btrfsutil_sys::btrfs_util_is_subvolume;
+use btrfsutil_sys::btrfs_util_qgroup_inherit;
+use btrfsutil_sys::btrfs_util_qgroup_inherit_add_group;
+use btrfsutil_sys::btrfs_util_set_default_subvolume;
+use btrfsutil_sys::btrfs_util_set_subvolume_read_only;
+use btrfsutil_sys::btrfs_util_start_sync;
+use btrfsutil_sys::btrfs_util_start_sync_fd;
+use btrfsutil_sys::btrfs_util_strerror;
+use btrfsutil_sys::btrfs_util_subvolume_id;
+use btrfsutil_sys::btrfs_util_subvolume_info;
+use btrfsutil_sys::btrfs_util_subvolume_iterator;
+use btrfsutil_sys::btrfs_util_subvolume_iterator_next;
+use btrfsutil_sys::btrfs_util_subvolume_iterator_next_info;
+use btrfsutil_sys::btrfs_util_subvolume_path;
+use btrfsutil_sys::btrfs_util_sync_fd;
+use btrfsutil_sys::btrfs_util_wait_sync;
+use btrfsutil_sys::btrfs_util_wait_sync_fd;
+use btrfsutil_sys::timespec;
+use btrfsutil_sys::BTRFS_UTIL_CREATE_SNAPSHOT_READ_ONLY;
+use btrfsutil_sys::BTRFS_UTIL_CREATE_SNAPSHOT_RECURSIVE;
+use btrfsutil_sys::BTRFS_UTIL_DELETE_SUBVOLUME_RECURSIVE;
+use btrfsutil_sys::BTRFS_UTIL_SUBVOLUME_ITERATOR_POST_ORDER;
+use libc::c_int;
+use libc::c_void;
+use libc::free;
+
+/// Call a libbtrfsutil function and react to its error code, keeping the `errno` libbtrfsutil
+/// left behind.
+fn call<F>(f: F) -> Result<()>
+where
+    F: FnOnce() -> btrfs_util_error,
+{
+    // libbtrfsutil leaves errno alone for the errors not caused by the OS
+    unsafe { *libc::__errno_location() = 0 };
+    let code = f();
+    if code == btrfs_util_error_BTRFS_UTIL_OK {
+        return Ok(());
+    }
+    match LibError::try_from(code) {
+        Ok(err) => Err(BtrfsUtilError::last_os_error(err)),
+        // an error code newer than this library, as a glue error
+        Err(err) => Err(err),
+    }
+}
+
+/// Copy a string allocated by libbtrfsutil with `malloc` into a buffer, and free it.
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a NUL-terminated string allocated with `malloc`.
+unsafe fn take_string(ptr: *mut c_char, buf: &mut Vec<u8>) {
+    buf.clear();
+    if !ptr.is_null() {
+        buf.extend_from_slice(CStr::from_ptr(ptr).to_bytes());
+        free(ptr as *mut c_void);
+    }
+}
+
+/// Convert the information filled in by libbtrfsutil.
+fn raw_info(info: &btrfs_util_subvolume_info) -> RawSubvolumeInfo {
+    // time_t and long are only 32 bits wide on some targets
+    #[allow(clippy::unnecessary_cast)]
+    let time = |ts: &timespec| Timespec {
+        sec: ts.tv_sec as i64,
+        nsec: ts.tv_nsec as i64,
+    };
+    RawSubvolumeInfo {
+        id: info.id,
+        parent_id: info.parent_id,
+        dir_id: info.dir_id,
+        flags: info.flags,
+        uuid: info.uuid,
+        parent_uuid: info.parent_uuid,
+        received_uuid: info.received_uuid,
+        generation: info.generation,
+        ctransid: info.ctransid,
+        otransid: info.otransid,
+        stransid: info.stransid,
+        rtransid: info.rtransid,
+        ctime: time(&info.ctime),
+        otime: time(&info.otime),
+        stime: time(&info.stime),
+        rtime: time(&info.rtime),
+    }
+}
+
+/// Get the message of a libbtrfsutil error code, using [btrfs_util_strerror()].
+///
+/// [btrfs_util_strerror()]: https://docs.rs/btrfsutil-sys/1.2.1/btrfsutil_sys/fn.btrfs_util_strerror.html
+pub(crate) fn strerror(code: btrfs_util_error) -> Result<&'static str> {
+    let ptr = unsafe { btrfs_util_strerror(code) };
+    glue_error!(ptr.is_null(), GlueError::NullPointerReceived);
+
+    match unsafe { CStr::from_ptr(ptr) }.to_str() {
+        Ok(val) => Ok(val),
+        Err(e) => glue_error!(GlueError::Utf8Error(e)),
+    }
+}
+
+/// Check whether a path is the root of a subvolume.
+pub(crate) fn is_subvolume(path: &Path) -> Result<()> {
+    let path_cstr = common::path_to_cstr(path);
+    call(|| unsafe { btrfs_util_is_subvolume(path_cstr.as_ptr()) })
+}
+
+/// Get the id of the subvolume a path belongs to.
+pub(crate) fn subvolume_id(path: &Path) -> Result<u64> {
+    let path_cstr = common::path_to_cstr(path);
+    let mut id: u64 = 0;
+    call(|| unsafe { btrfs_util_subvolume_id(path_cstr.as_ptr(), &mut id) })?;
+    Ok(id)
+}
+
+/// Get the path of the subvolume `id`, or of the one `path` belongs to if zero, relative to the
+/// top-level subvolume.
+pub(crate) fn subvolume_path(path: &Path, id: u64) -> Result<PathBuf> {
+    let path_cstr = common::path_to_cstr(path);
+    let mut path_ptr: *mut c_char = std::ptr::null_mut();
+    call(|| unsafe { btrfs_util_subvolume_path(path_cstr.as_ptr(), id, &mut path_ptr) })?;
+
+    let mut path_ret = Vec::new();
+    unsafe { take_string(path_ptr, &mut path_ret) };
+    Ok(PathBuf::from(OsStr::from_bytes(&path_ret)))
+}
+
+/// Get information about the subvolume `id`, or about the one whose root is at `path` if zero.
+pub(crate) fn subvolume_info(path: &Path, id: u64) -> Result<RawSubvolumeInfo> {
+    let path_cstr = common::path_to_cstr(path);
+    // plain integers and arrays, all zeroes is a valid value
+    let mut info: btrfs_util_subvolume_info = unsafe { std::mem::zeroed() };
+    call(|| unsafe { btrfs_util_subvolume_info(path_cstr.as_ptr(), id, &mut info) })?;
+    Ok(raw_info(&info))
+}
+
+/// Check whether the subvolume a path belongs to is read-only.
+pub(crate) fn subvolume_read_only(path: &Path) -> Result<bool> {
+    let path_cstr = common::path_to_cstr(path);
+    let mut read_only = false;
+    call(|| unsafe { btrfs_util_get_subvolume_read_only(path_cstr.as_ptr(), &mut read_only) })?;
+    Ok(read_only)
+}
+
+/// Set whether the subvolume a path belongs to is read-only.
+pub(crate) fn set_subvolume_read_only(path: &Path, read_only: bool) -> Result<()> {
+    let path_cstr = common::path_to_cstr(path);
+    call(|| unsafe { btrfs_util_set_subvolume_read_only(path_cstr.as_ptr(), read_only) })
+}
+
+/// Get the id of the default subvolume of the filesystem a path belongs to.
+pub(crate) fn default_subvolume(path: &Path) -> Result<u64> {
+    let path_cstr = common::path_to_cstr(path);
+    let mut id: u64 = 0;
+    call(|| unsafe { btrfs_util_get_default_subvolume(path_cstr.as_ptr(), &mut id) })?;
+    Ok(id)
+}
+
+/// Make the subvolume `id`, or the one `path` belongs to if zero, the default subvolume.
+pub(crate) fn set_default_subvolume(path: &Path, id: u64) -> Result<()> {
+    let path_cstr = common::path_to_cstr(path);
+    call(|| unsafe { btrfs_util_set_default_subvolume(path_cstr.as_ptr(), id) })
+}
+
+/// A qgroup inheritance specifier built by libbtrfsutil, destroyed when dropped.
+struct QgroupInherit(*mut btrfs_util_qgroup_inherit);
+
+impl QgroupInherit {
+    fn new(qgroups: &[u64]) -> Result<Self> {
+        let mut inherit = Self(std::ptr::null_mut());
+        call(|| unsafe { btrfs_util_create_qgroup_inherit(0, &mut inherit.0) })?;
+        for &qgroup in qgroups {
+            // libbtrfsutil reallocates the specifier to make room for the qgroup
+            call(|| unsafe { btrfs_util_qgroup_inherit_add_group(&mut inherit.0, qgroup) })?;
+        }
+        Ok(inherit)
+    }
+
+    /// Build a specifier if there are qgroups to inherit from, returning it along with the
+    /// pointer to pass to libbtrfsutil.
+    fn for_qgroups(
+        qgroups: Option<&[u64]>,
+    ) -> Result<(Option<Self>, *mut btrfs_util_qgroup_inherit)> {
+        let inherit = qgroups.map(Self::new).transpose()?;
+        let ptr = inherit
+            .as_ref()
+            .map_or(std::ptr::null_mut(), |inherit| inherit.0);
+        Ok((inherit, ptr))
+    }
+}
+
+impl Drop for QgroupInherit {
+    fn drop(&mut self) {
+        unsafe { btrfs_util_destroy_qgroup_inherit(self.0) };
+    }
+}
+
+/// Create a subvolume, adding it to the given qgroups, and return the transaction creating it.
+pub(crate) fn create_subvolume(path: &Path, qgroups: Option<&[u64]>) -> Result<u64> {
+    let path_cstr = common::path_to_cstr(path);
+    let (_inherit, inherit_ptr) = QgroupInherit::for_qgroups(qgroups)?;
+    let mut transid: u64 = 0;
+    call(|| unsafe {
+        btrfs_util_create_subvolume(path_cstr.as_ptr(), 0, &mut transid, inherit_ptr)
+    })?;
+    Ok(transid)
+}
+
+/// Snapshot a subvolume, adding the snapshot to the given qgroups, and return the transaction
+/// creating it.
+pub(crate) fn create_snapshot(
+    source: &Path,
+    path: &Path,
+    recursive: bool,
+    read_only: bool,
+    qgroups: Option<&[u64]>,
+) -> Result<u64> {
+    let source_cstr = common::path_to_cstr(source);
+    let path_cstr = common::path_to_cstr(path);
+    let mut flags = 0;
+    if recursive {
+        flags |= BTRFS_UTIL_CREATE_SNAPSHOT_RECURSIVE;
+    }
+    if read_only {
+        flags |= BTRFS_UTIL_CREATE_SNAPSHOT_READ_ONLY;
+    }
+    let (_inherit, inherit_ptr) = QgroupInherit::for_qgroups(qgroups)?;
+    let mut transid: u64 = 0;
+    call(|| unsafe {
+        btrfs_util_create_snapshot(
+            source_cstr.as_ptr(),
+            path_cstr.as_ptr(),
+            flags as c_int,
+            &mut transid,
+            inherit_ptr,
+        )
+    })?;
+    Ok(transid)
+}
+
+/// Delete a subvolume, and the subvolumes below it first if recursive.
+pub(crate) fn delete_subvolume(path: &Path, recursive: bool) -> Result<()> {
+    let path_cstr = common::path_to_cstr(path);
+    let flags = if recursive {
+        BTRFS_UTIL_DELETE_SUBVOLUME_RECURSIVE
+    } else {
+        0
+    };
+    call(|| unsafe { btrfs_util_delete_subvolume(path_cstr.as_ptr(), flags as c_int) })
+}
+
+/// Get the ids of the subvolumes deleted but not yet cleaned up on the filesystem a path belongs
+/// to.
+pub(crate) fn deleted_subvolumes(path: &Path) -> Result<Vec<u64>> {
+    let path_cstr = common::path_to_cstr(path);
+    let mut ids_ptr: *mut u64 = std::ptr::null_mut();
+    let mut ids_count: usize = 0;
+    call(|| unsafe {
+        btrfs_util_deleted_subvolumes(path_cstr.as_ptr(), &mut ids_ptr, &mut ids_count)
+    })?;
+
+    let ids = if ids_count == 0 {
+        Vec::new()
+    } else {
+        unsafe { std::slice::from_raw_parts(ids_ptr, ids_count) }.to_vec()
+    };
+    // allocated by libbtrfsutil with malloc, possibly even when empty
+    unsafe { free(ids_ptr as *mut c_void) };
+    Ok(ids)
+}
+
+/// An iterator over the subvolumes below a subvolume, created by libbtrfsutil.
+pub(crate) struct SubvolumeIter {
+    raw: *mut btrfs_util_subvolume_iterator,
+}
+
+// The libbtrfsutil iterator owns its file descriptor and its buffers and keeps no thread-local
+// state, so it can be advanced from any thread. errno is thread-local but read by the thread the
+// iterator failed on. Advancing it requires &mut self, so it is never used by two threads at
+// once.
+unsafe impl Send for SubvolumeIter {}
+
+impl SubvolumeIter {
+    /// List the subvolumes below the subvolume `top`, or below the one `path` belongs to if zero.
+    pub(crate) fn new(path: &Path, top: u64, post_order: bool) -> Result<Self> {
+        let path_cstr = common::path_to_cstr(path);
+        let flags = if post_order {
+            BTRFS_UTIL_SUBVOLUME_ITERATOR_POST_ORDER
+        } else {
+            0
+        };
+        let mut iter = Self {
+            raw: std::ptr::null_mut(),
+        };
+        call(|| unsafe {
+            btrfs_util_create_subvolume_iterator(
+                path_cstr.as_ptr(),
+                top,
+                flags as c_int,
+                &mut iter.raw,
+            )
+        })?;
+        Ok(iter)
+    }
+
+    /// Get the id of the next subvolume, leaving its relative path in `path`.
+    pub(crate) fn next(&mut self, path: &mut Vec<u8>) -> Option<Result<u64>> {
+        let mut path_ptr: *mut c_char = std::ptr::null_mut();
+        let mut id: u64 = 0;
+        let result = call(|| unsafe {
+            btrfs_util_subvolume_iterator_next(self.raw, &mut path_ptr, &mut id)
+        });
+        Some(Self::advanced(result, path_ptr, path)?.map(|()| id))
+    }
+
+    /// Get information about the next subvolume, leaving its relative path in `path`.
+    pub(crate) fn next_info(&mut self, path: &mut Vec<u8>) -> Option<Result<RawSubvolumeInfo>> {
+        let mut path_ptr: *mut c_char = std::ptr::null_mut();
+        // plain integers and arrays, all zeroes is a valid value
+        let mut info: btrfs_util_subvolume_info = unsafe { std::mem::zeroed() };
+        let result = call(|| unsafe {
+            btrfs_util_subvolume_iterator_next_info(self.raw, &mut path_ptr, &mut info)
+        });
+        Some(Self::advanced(result, path_ptr, path)?.map(|()| raw_info(&info)))
+    }
+
+    /// Turn the outcome of advancing into the end of the iteration or a result, taking the path
+    /// libbtrfsutil allocated.
+    fn advanced(
+        result: Result<()>,
+        path_ptr: *mut c_char,
+        path: &mut Vec<u8>,
+    ) -> Option<Result<()>> {
+        match result {
+            Err(e) if e == LibError::StopIteration => None,
+            Err(e) => Some(Err(e)),
+            Ok(()) => {
+                unsafe { take_string(path_ptr, path) };
+                Some(Ok(()))
+            }
+        }
+    }
+}
+
+impl Drop for SubvolumeIter {
+    fn drop(&mut self) {
+        unsafe { btrfs_util_destroy_subvolume_iterator(self.raw) };
+    }
+}
+
+/// Sync the filesystem `fd` belongs to.
+pub(crate) fn sync_fd(fd: RawFd) -> Result<()> {
+    call(|| unsafe { btrfs_util_sync_fd(fd) })
+}
+
+/// Start a sync of the filesystem `fd` belongs to, returning the transaction it commits.
+pub(crate) fn start_sync_fd(fd: RawFd) -> Result<u64> {
+    let mut transid: u64 = 0;
+    call(|| unsafe { btrfs_util_start_sync_fd(fd, &mut transid) })?;
+    Ok(transid)
+}
+
+/// Wait for a transaction of the filesystem `fd` belongs to to be committed, or for the current
+/// one if zero.
+pub(crate) fn wait_sync_fd(fd: RawFd, transid: u64) -> Result<()> {
+    call(|| unsafe { btrfs_util_wait_sync_fd(fd, transid) })
+}
+
+/// Same as [start_sync_fd], through a path.
+///
+/// [start_sync_fd]: fn.start_sync_fd.html
+pub(crate) fn start_sync(path: &Path) -> Result<u64> {
+    let path_cstr = common::path_to_cstr(path);
+    let mut transid: u64 = 0;
+    call(|| unsafe { btrfs_util_start_sync(path_cstr.as_ptr(), &mut transid) })?;
+    Ok(transid)
+}
+
+/// Same as [wait_sync_fd], through a path.
+///
+/// [wait_sync_fd]: fn.wait_sync_fd.html
+pub(crate) fn wait_sync(path: &Path, transid: u64) -> Result<()> {
+    let path_cstr = common::path_to_cstr(path);
+    call(|| unsafe { btrfs_util_wait_sync(path_cstr.as_ptr(), transid) })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::error::LibErrorCode;
+
+    use btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_FS_INFO_FAILED;
+    use btrfsutil_sys::btrfs_util_error_BTRFS_UTIL_ERROR_STOP_ITERATION;
+
+    #[test]
+    fn error_codes_match() {
+        assert_eq!(LibError::Ok as LibErrorCode, btrfs_util_error_BTRFS_UTIL_OK);
+        assert_eq!(
+            LibError::StopIteration as LibErrorCode,
+            btrfs_util_error_BTRFS_UTIL_ERROR_STOP_ITERATION
+        );
+        assert_eq!(
+            LibError::FsInfoFailed as LibErrorCode,
+            btrfs_util_error_BTRFS_UTIL_ERROR_FS_INFO_FAILED
+        );
+    }
+}
@@ -0,0 +1,51 @@
+//! The libbtrfsutil operations the rest of the library is written against.
+//!
+//! They take paths and file descriptors and return Rust values, so the rest of the library never
+//! deals in C types. With the `libbtrfsutil` feature, `ffi` performs them by calling into
+//! libbtrfsutil and is the only module doing so. Without it, `pure` performs them with the btrfs
+//! ioctls, so that building the library does not need libbtrfsutil at all. The `pure-rust`
+//! feature always builds `pure`, which the [IoctlBackend] is written on.
+//!
+//! There is no bundled build of libbtrfsutil: btrfsutil-sys only links the one of the system, and
+//! this crate does not carry the libbtrfsutil sources. Builds that cannot link it disable the
+//! default features and enable `pure-rust` instead.
+//!
+//! [IoctlBackend]: ../backend/struct.IoctlBackend.html
+
+#[cfg(feature = "libbtrfsutil")]
+mod ffi;
+#[cfg(feature = "pure-rust")]
+pub(crate) mod pure;
+
+#[cfg(feature = "libbtrfsutil")]
+pub(crate) use ffi::*;
+#[cfg(not(feature = "libbtrfsutil"))]
+pub(crate) use pure::*;
+
+/// Seconds and nanoseconds since the epoch.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct Timespec {
+    pub(crate) sec: i64,
+    pub(crate) nsec: i64,
+}
+
+/// Information about a subvolume, as held by `struct btrfs_util_subvolume_info`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct RawSubvolumeInfo {
+    pub(crate) id: u64,
+    pub(crate) parent_id: u64,
+    pub(crate) dir_id: u64,
+    pub(crate) flags: u64,
+    pub(crate) uuid: [u8; 16],
+    pub(crate) parent_uuid: [u8; 16],
+    pub(crate) received_uuid: [u8; 16],
+    pub(crate) generation: u64,
+    pub(crate) ctransid: u64,
+    pub(crate) otransid: u64,
+    pub(crate) stransid: u64,
+    pub(crate) rtransid: u64,
+    pub(crate) ctime: Timespec,
+    pub(crate) otime: Timespec,
+    pub(crate) stime: Timespec,
+    pub(crate) rtime: Timespec,
+}
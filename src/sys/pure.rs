@@ -0,0 +1,709 @@
+//! libbtrfsutil reimplemented with the btrfs ioctls.
+//!
+//! Every operation does what its libbtrfsutil counterpart does and fails with the same
+//! [LibError]s, carrying the OS error of the failure, so callers cannot tell the two apart.
+//!
+//! [LibError]: ../../error/enum.LibError.html
+
+// with libbtrfsutil, the operations are performed through it and only the IoctlBackend is built
+// on these
+#![cfg_attr(feature = "libbtrfsutil", allow(dead_code))]
+
+use crate::common;
+use crate::error::GlueError;
+use crate::error::LibError;
+use crate::error::LibErrorCode;
+use crate::ioctl;
+use crate::ioctl::InoLookupArgs;
+use crate::ioctl::SearchKey;
+use crate::ioctl::VolArgs;
+use crate::ioctl::VolArgsV2;
+use crate::sys::RawSubvolumeInfo;
+use crate::sys::Timespec;
+use crate::BtrfsUtilError;
+use crate::Result;
+
+use std::collections::VecDeque;
+use std::ffi::OsStr;
+use std::fs;
+use std::fs::File;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// The messages of `btrfs_util_strerror`, by error code.
+const MESSAGES: [&str; 27] = [
+    "Success",
+    "Stop iteration",
+    "Cannot allocate memory",
+    "Invalid argument",
+    "Not a Btrfs filesystem",
+    "Not a Btrfs subvolume",
+    "Subvolume not found",
+    "Could not open",
+    "Could not rmdir",
+    "Could not unlink",
+    "Could not stat",
+    "Could not statfs",
+    "Could not search B-tree",
+    "Could not lookup inode",
+    "Could not get subvolume flags",
+    "Could not set subvolume flags",
+    "Could not create subvolume",
+    "Could not create snapshot",
+    "Could not destroy subvolume",
+    "Could not set default subvolume",
+    "Could not sync filesystem",
+    "Could not start filesystem sync",
+    "Could not wait for filesystem sync",
+    "Could not get subvolume information with BTRFS_IOC_GET_SUBVOL_INFO",
+    "Could not get rootref information with BTRFS_IOC_GET_SUBVOL_ROOTREF",
+    "Could not resolve subvolume path with BTRFS_IOC_INO_LOOKUP_USER",
+    "Could not get filesystem information",
+];
+
+/// Number of words of `struct btrfs_qgroup_inherit` before the qgroup ids: flags, the number of
+/// qgroups and of copies, and the five words of `struct btrfs_qgroup_limit`.
+const QGROUP_INHERIT_HEADER: usize = 9;
+
+/// Get the message of a libbtrfsutil error code.
+pub(crate) fn strerror(code: LibErrorCode) -> Result<&'static str> {
+    MESSAGES
+        .get(code as usize)
+        .copied()
+        .ok_or_else(|| GlueError::UnknownErrno(code).into())
+}
+
+/// Open the parent directory of a path, along with the name of the path in it.
+fn parent_and_name(path: &Path) -> Result<(File, &OsStr)> {
+    let name = path.file_name().ok_or(LibError::InvalidArgument)?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Ok((ioctl::open(parent)?, name))
+}
+
+/// Check whether a path is the root of a subvolume.
+pub(crate) fn is_subvolume(path: &Path) -> Result<()> {
+    let path_cstr = common::path_to_cstr(path);
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    if unsafe { libc::statfs(path_cstr.as_ptr(), stat.as_mut_ptr()) } < 0 {
+        return Err(BtrfsUtilError::last_os_error(LibError::StatfsFailed));
+    }
+    if unsafe { stat.assume_init() }.f_type as u32 != libc::BTRFS_SUPER_MAGIC as u32 {
+        return Err(LibError::NotBtrfs.into());
+    }
+
+    // the root directory of every subvolume has the first free inode number
+    let metadata =
+        fs::metadata(path).map_err(|err| BtrfsUtilError::from_io(LibError::StatFailed, &err))?;
+    if !metadata.is_dir() || metadata.ino() != ioctl::BTRFS_FIRST_FREE_OBJECTID {
+        return Err(LibError::NotSubvolume.into());
+    }
+    Ok(())
+}
+
+/// Get the id of the subvolume a path belongs to.
+pub(crate) fn subvolume_id(path: &Path) -> Result<u64> {
+    let file = ioctl::open(path)?;
+    ioctl::subvolume_id(file.as_raw_fd())
+}
+
+/// Get the path of the directory `dirid` in the subvolume `treeid`, relative to the root of the
+/// subvolume, with `BTRFS_IOC_INO_LOOKUP`.
+fn ino_lookup(fd: RawFd, treeid: u64, dirid: u64) -> Result<Vec<u8>> {
+    let mut args = InoLookupArgs::new(treeid, dirid);
+    unsafe {
+        ioctl::ioctl(
+            fd,
+            ioctl::BTRFS_IOC_INO_LOOKUP,
+            &mut args,
+            LibError::InoLookupFailed,
+        )?
+    };
+    Ok(args.name().to_vec())
+}
+
+/// Parse a `struct btrfs_root_ref`, the item behind root refs and backrefs, into the directory
+/// and name the subvolume is found at.
+fn root_ref(item: &[u8]) -> Option<(u64, &[u8])> {
+    // dirid, sequence, name_len, name
+    let name_len = usize::from(u16::from_le_bytes(item.get(16..18)?.try_into().ok()?));
+    Some((ioctl::le_u64(item, 0), item.get(18..18 + name_len)?))
+}
+
+/// Find the subvolume a subvolume is in, along with the directory and name it is found at.
+fn backref(fd: RawFd, id: u64) -> Result<Option<(u64, u64, Vec<u8>)>> {
+    let key = SearchKey::new(
+        ioctl::BTRFS_ROOT_TREE_OBJECTID,
+        (id, ioctl::BTRFS_ROOT_BACKREF_KEY, 0),
+        (id, ioctl::BTRFS_ROOT_BACKREF_KEY, u64::MAX),
+    );
+    let mut backref = None;
+    ioctl::tree_search(fd, key, |header, item| {
+        if header.item_type != ioctl::BTRFS_ROOT_BACKREF_KEY {
+            return true;
+        }
+        backref = root_ref(item).map(|(dirid, name)| (header.offset, dirid, name.to_vec()));
+        false
+    })?;
+    Ok(backref)
+}
+
+/// Get the path of the subvolume `id`, or of the one `path` belongs to if zero, relative to the
+/// top-level subvolume.
+pub(crate) fn subvolume_path(path: &Path, id: u64) -> Result<PathBuf> {
+    let file = ioctl::open(path)?;
+    let fd = file.as_raw_fd();
+    let mut id = if id == 0 {
+        ioctl::subvolume_id(fd)?
+    } else {
+        id
+    };
+
+    // walk up to the top-level subvolume, collecting the path of each subvolume in its parent
+    let mut components: Vec<Vec<u8>> = Vec::new();
+    while id != ioctl::BTRFS_FS_TREE_OBJECTID {
+        let (parent, dirid, name) = backref(fd, id)?
+            .ok_or_else(|| BtrfsUtilError::os(LibError::SubvolumeNotFound, libc::ENOENT))?;
+        let mut component = ino_lookup(fd, parent, dirid)?;
+        component.extend_from_slice(&name);
+        components.push(component);
+        id = parent;
+    }
+
+    components.reverse();
+    Ok(PathBuf::from(OsStr::from_bytes(&components.join(&b'/'))))
+}
+
+/// Fill in the information held by a `struct btrfs_root_item`.
+fn root_item_info(item: &[u8], info: &mut RawSubvolumeInfo) {
+    // the inode item is followed by generation, root_dirid, bytenr, byte_limit, bytes_used,
+    // last_snapshot and flags
+    if item.len() < 216 {
+        return;
+    }
+    info.generation = ioctl::le_u64(item, 160);
+    info.flags = ioctl::le_u64(item, 208);
+
+    // since Linux 3.5, refs, drop_progress, drop_level, level and generation_v2 are followed by
+    // the uuids, the transids and the times
+    if item.len() < 375 {
+        return;
+    }
+    let uuid = |offset: usize| -> [u8; 16] { item[offset..offset + 16].try_into().unwrap() };
+    let time = |offset: usize| Timespec {
+        sec: ioctl::le_u64(item, offset) as i64,
+        nsec: i64::from(u32::from_le_bytes(
+            item[offset + 8..offset + 12].try_into().unwrap(),
+        )),
+    };
+    info.uuid = uuid(247);
+    info.parent_uuid = uuid(263);
+    info.received_uuid = uuid(279);
+    info.ctransid = ioctl::le_u64(item, 295);
+    info.otransid = ioctl::le_u64(item, 303);
+    info.stransid = ioctl::le_u64(item, 311);
+    info.rtransid = ioctl::le_u64(item, 319);
+    info.ctime = time(327);
+    info.otime = time(339);
+    info.stime = time(351);
+    info.rtime = time(363);
+}
+
+/// Get information about any subvolume by searching the root tree, which requires
+/// **CAP_SYS_ADMIN**.
+fn search_subvolume_info(fd: RawFd, id: u64) -> Result<RawSubvolumeInfo> {
+    let mut info = RawSubvolumeInfo {
+        id,
+        ..RawSubvolumeInfo::default()
+    };
+
+    let key = SearchKey::new(
+        ioctl::BTRFS_ROOT_TREE_OBJECTID,
+        (id, ioctl::BTRFS_ROOT_ITEM_KEY, 0),
+        (id, ioctl::BTRFS_ROOT_ITEM_KEY, u64::MAX),
+    );
+    let mut found = false;
+    ioctl::tree_search(fd, key, |header, item| {
+        if header.item_type != ioctl::BTRFS_ROOT_ITEM_KEY {
+            return true;
+        }
+        root_item_info(item, &mut info);
+        found = true;
+        false
+    })?;
+    if !found {
+        return Err(BtrfsUtilError::os(
+            LibError::SubvolumeNotFound,
+            libc::ENOENT,
+        ));
+    }
+
+    // the top-level subvolume is in no other
+    if id != ioctl::BTRFS_FS_TREE_OBJECTID {
+        let (parent, dirid, _) = backref(fd, id)?
+            .ok_or_else(|| BtrfsUtilError::os(LibError::SubvolumeNotFound, libc::ENOENT))?;
+        info.parent_id = parent;
+        info.dir_id = dirid;
+    }
+    Ok(info)
+}
+
+/// Get information about the subvolume `id`, or about the one whose root is at `path` if zero.
+pub(crate) fn subvolume_info(path: &Path, id: u64) -> Result<RawSubvolumeInfo> {
+    let file = ioctl::open(path)?;
+    if id != 0 {
+        return search_subvolume_info(file.as_raw_fd(), id);
+    }
+
+    is_subvolume(path)?;
+    match ioctl::subvolume_info(file.as_raw_fd()) {
+        // kernels before 4.18 lack the unprivileged ioctl
+        Err(err) if err.errno() == Some(libc::ENOTTY) => {
+            search_subvolume_info(file.as_raw_fd(), ioctl::subvolume_id(file.as_raw_fd())?)
+        }
+        result => result,
+    }
+}
+
+/// List the subvolumes below the subvolume `parent` by searching the root tree, which requires
+/// **CAP_SYS_ADMIN**, with their paths relative to the subvolume the listing started from.
+fn search_subvolumes(
+    fd: RawFd,
+    parent: u64,
+    prefix: &[u8],
+    post_order: bool,
+    entries: &mut VecDeque<(Vec<u8>, u64)>,
+) -> Result<()> {
+    let key = SearchKey::new(
+        ioctl::BTRFS_ROOT_TREE_OBJECTID,
+        (parent, ioctl::BTRFS_ROOT_REF_KEY, 0),
+        (parent, ioctl::BTRFS_ROOT_REF_KEY, u64::MAX),
+    );
+    let mut children: Vec<(u64, u64, Vec<u8>)> = Vec::new();
+    ioctl::tree_search(fd, key, |header, item| {
+        if header.item_type == ioctl::BTRFS_ROOT_REF_KEY {
+            if let Some((dirid, name)) = root_ref(item) {
+                children.push((header.offset, dirid, name.to_vec()));
+            }
+        }
+        true
+    })?;
+
+    for (id, dirid, name) in children {
+        let mut path = prefix.to_vec();
+        if !path.is_empty() {
+            path.push(b'/');
+        }
+        path.extend(ino_lookup(fd, parent, dirid)?);
+        path.extend(name);
+
+        if !post_order {
+            entries.push_back((path.clone(), id));
+        }
+        search_subvolumes(fd, id, &path, post_order, entries)?;
+        if post_order {
+            entries.push_back((path, id));
+        }
+    }
+    Ok(())
+}
+
+/// List the subvolumes below the one whose root is at `path` with the unprivileged ioctls, with
+/// their paths relative to it.
+///
+/// Subvolumes in directories that cannot be searched are left out.
+fn list_subvolumes(
+    path: &Path,
+    prefix: &Path,
+    post_order: bool,
+    entries: &mut VecDeque<(Vec<u8>, u64)>,
+) -> Result<()> {
+    let file = ioctl::open(&path.join(prefix))?;
+    for rootref in ioctl::subvol_rootrefs(file.as_raw_fd())? {
+        let rel = match ioctl::ino_lookup_user(file.as_raw_fd(), rootref.treeid, rootref.dirid) {
+            Ok(rel) => prefix.join(rel),
+            Err(_) => continue,
+        };
+
+        if !post_order {
+            entries.push_back((ioctl::path_bytes(&rel).to_vec(), rootref.treeid));
+        }
+        list_subvolumes(path, &rel, post_order, entries)?;
+        if post_order {
+            entries.push_back((ioctl::path_bytes(&rel).to_vec(), rootref.treeid));
+        }
+    }
+    Ok(())
+}
+
+/// An iterator over the subvolumes below a subvolume, all listed when it is created.
+pub(crate) struct SubvolumeIter {
+    /// Path the iterator was created with.
+    path: PathBuf,
+    /// Whether the subvolumes were listed with the unprivileged ioctls.
+    unprivileged: bool,
+    /// Paths relative to the subvolume iterated over and ids of the subvolumes left.
+    entries: VecDeque<(Vec<u8>, u64)>,
+}
+
+impl SubvolumeIter {
+    /// List the subvolumes below the subvolume `top`, or below the one `path` belongs to if zero.
+    pub(crate) fn new(path: &Path, top: u64, post_order: bool) -> Result<Self> {
+        // as libbtrfsutil, only unprivileged users list the subvolumes below their path with the
+        // unprivileged ioctls
+        let unprivileged = top == 0 && unsafe { libc::geteuid() } != 0;
+        let mut entries = VecDeque::new();
+        if unprivileged {
+            is_subvolume(path)?;
+            list_subvolumes(path, Path::new(""), post_order, &mut entries)?;
+        } else {
+            let file = ioctl::open(path)?;
+            let top = if top == 0 {
+                ioctl::subvolume_id(file.as_raw_fd())?
+            } else {
+                top
+            };
+            search_subvolumes(file.as_raw_fd(), top, &[], post_order, &mut entries)?;
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            unprivileged,
+            entries,
+        })
+    }
+
+    /// Get the id of the next subvolume, leaving its relative path in `path`.
+    pub(crate) fn next(&mut self, path: &mut Vec<u8>) -> Option<Result<u64>> {
+        let (rel, id) = self.entries.pop_front()?;
+        *path = rel;
+        Some(Ok(id))
+    }
+
+    /// Get information about the next subvolume, leaving its relative path in `path`.
+    pub(crate) fn next_info(&mut self, path: &mut Vec<u8>) -> Option<Result<RawSubvolumeInfo>> {
+        let (rel, id) = self.entries.pop_front()?;
+        let info = if self.unprivileged {
+            subvolume_info(&self.path.join(OsStr::from_bytes(&rel)), 0)
+        } else {
+            subvolume_info(&self.path, id)
+        };
+        *path = rel;
+        Some(info)
+    }
+}
+
+/// Sync the filesystem `fd` belongs to.
+pub(crate) fn sync_fd(fd: RawFd) -> Result<()> {
+    unsafe {
+        ioctl::ioctl(
+            fd,
+            ioctl::BTRFS_IOC_SYNC,
+            std::ptr::null_mut::<u8>(),
+            LibError::SyncFailed,
+        )
+    }
+}
+
+/// Start a sync of the filesystem `fd` belongs to, returning the transaction it commits.
+pub(crate) fn start_sync_fd(fd: RawFd) -> Result<u64> {
+    let mut transid: u64 = 0;
+    unsafe {
+        ioctl::ioctl(
+            fd,
+            ioctl::BTRFS_IOC_START_SYNC,
+            &mut transid,
+            LibError::StartSyncFailed,
+        )?
+    };
+    Ok(transid)
+}
+
+/// Wait for a transaction of the filesystem `fd` belongs to to be committed, or for the current
+/// one if zero.
+pub(crate) fn wait_sync_fd(fd: RawFd, mut transid: u64) -> Result<()> {
+    unsafe {
+        ioctl::ioctl(
+            fd,
+            ioctl::BTRFS_IOC_WAIT_SYNC,
+            &mut transid,
+            LibError::WaitSyncFailed,
+        )
+    }
+}
+
+/// Same as [start_sync_fd], through a path.
+///
+/// [start_sync_fd]: fn.start_sync_fd.html
+pub(crate) fn start_sync(path: &Path) -> Result<u64> {
+    let file = ioctl::open(path)?;
+    start_sync_fd(file.as_raw_fd())
+}
+
+/// Same as [wait_sync_fd], through a path.
+///
+/// [wait_sync_fd]: fn.wait_sync_fd.html
+pub(crate) fn wait_sync(path: &Path, transid: u64) -> Result<()> {
+    let file = ioctl::open(path)?;
+    wait_sync_fd(file.as_raw_fd(), transid)
+}
+
+/// Lay out qgroup ids as a `struct btrfs_qgroup_inherit`, which is passed to the kernel as is.
+fn qgroup_inherit(qgroups: &[u64]) -> Vec<u64> {
+    let mut words = vec![0; QGROUP_INHERIT_HEADER];
+    // num_qgroups
+    words[1] = qgroups.len() as u64;
+    words.extend_from_slice(qgroups);
+    words
+}
+
+/// Point the qgroup inheritance arguments of `args` at a `struct btrfs_qgroup_inherit`, which
+/// has to outlive the ioctl.
+fn set_qgroup_inherit(args: &mut VolArgsV2, inherit: &[u64]) {
+    args.flags |= ioctl::BTRFS_SUBVOL_QGROUP_INHERIT;
+    args.unused[0] = std::mem::size_of_val(inherit) as u64;
+    args.unused[1] = inherit.as_ptr() as u64;
+}
+
+/// Create a subvolume, adding it to the given qgroups, and return the transaction creating it.
+pub(crate) fn create_subvolume(path: &Path, qgroups: Option<&[u64]>) -> Result<u64> {
+    let (parent, name) = parent_and_name(path)?;
+    let mut args = VolArgsV2::with_name(ioctl::path_bytes(Path::new(name)))?;
+    let inherit = qgroups.map(qgroup_inherit);
+    if let Some(inherit) = &inherit {
+        set_qgroup_inherit(&mut args, inherit);
+    }
+    unsafe {
+        ioctl::ioctl(
+            parent.as_raw_fd(),
+            ioctl::BTRFS_IOC_SUBVOL_CREATE_V2,
+            &mut args,
+            LibError::SubvolCreateFailed,
+        )?
+    };
+
+    // the kernel dropped asynchronous creation, the transaction is the one to wait on
+    start_sync_fd(parent.as_raw_fd())
+}
+
+/// Snapshot a subvolume, adding the snapshot to the given qgroups, and return the transaction
+/// creating it.
+pub(crate) fn create_snapshot(
+    source: &Path,
+    path: &Path,
+    recursive: bool,
+    read_only: bool,
+    qgroups: Option<&[u64]>,
+) -> Result<u64> {
+    let source_file = ioctl::open(source)?;
+    let (parent, name) = parent_and_name(path)?;
+    let mut args = VolArgsV2::with_name(ioctl::path_bytes(Path::new(name)))?;
+    args.fd = i64::from(source_file.as_raw_fd());
+    // a recursive snapshot is only made read-only once the nested ones are in it
+    if read_only && !recursive {
+        args.flags |= ioctl::BTRFS_SUBVOL_RDONLY;
+    }
+    let inherit = qgroups.map(qgroup_inherit);
+    if let Some(inherit) = &inherit {
+        set_qgroup_inherit(&mut args, inherit);
+    }
+    unsafe {
+        ioctl::ioctl(
+            parent.as_raw_fd(),
+            ioctl::BTRFS_IOC_SNAP_CREATE_V2,
+            &mut args,
+            LibError::SnapCreateFailed,
+        )?
+    };
+
+    if recursive {
+        // nested subvolumes are left as empty directories in the snapshot, replaced by snapshots
+        // of them, parents first
+        let mut iter = SubvolumeIter::new(source, 0, false)?;
+        let mut rel = Vec::new();
+        while let Some(id) = iter.next(&mut rel) {
+            id?;
+            let rel = Path::new(OsStr::from_bytes(&rel));
+            let nested = path.join(rel);
+            fs::remove_dir(&nested)
+                .map_err(|err| BtrfsUtilError::from_io(LibError::RmdirFailed, &err))?;
+            create_snapshot(&source.join(rel), &nested, false, false, None)?;
+        }
+        if read_only {
+            set_subvolume_read_only(path, true)?;
+        }
+    }
+
+    // the kernel dropped asynchronous creation, the transaction is the one to wait on
+    start_sync_fd(parent.as_raw_fd())
+}
+
+/// Delete a subvolume, and the subvolumes below it first if recursive.
+pub(crate) fn delete_subvolume(path: &Path, recursive: bool) -> Result<()> {
+    if recursive {
+        is_subvolume(path)?;
+        // deepest first
+        let mut iter = SubvolumeIter::new(path, 0, true)?;
+        let mut rel = Vec::new();
+        while let Some(id) = iter.next(&mut rel) {
+            id?;
+            delete_subvolume(&path.join(OsStr::from_bytes(&rel)), false)?;
+        }
+    }
+
+    let (parent, name) = parent_and_name(path)?;
+    let mut args = VolArgs::with_name(ioctl::path_bytes(Path::new(name)))?;
+    unsafe {
+        ioctl::ioctl(
+            parent.as_raw_fd(),
+            ioctl::BTRFS_IOC_SNAP_DESTROY,
+            &mut args,
+            LibError::SnapDestroyFailed,
+        )
+    }
+}
+
+/// Get the ids of the subvolumes deleted but not yet cleaned up on the filesystem a path belongs
+/// to.
+pub(crate) fn deleted_subvolumes(path: &Path) -> Result<Vec<u64>> {
+    let file = ioctl::open(path)?;
+    // subvolumes being cleaned up have an orphan item in the root tree
+    let key = SearchKey::new(
+        ioctl::BTRFS_ROOT_TREE_OBJECTID,
+        (
+            ioctl::BTRFS_ORPHAN_OBJECTID,
+            ioctl::BTRFS_ORPHAN_ITEM_KEY,
+            0,
+        ),
+        (
+            ioctl::BTRFS_ORPHAN_OBJECTID,
+            ioctl::BTRFS_ORPHAN_ITEM_KEY,
+            u64::MAX,
+        ),
+    );
+    let mut ids = Vec::new();
+    ioctl::tree_search(file.as_raw_fd(), key, |header, _| {
+        if header.item_type == ioctl::BTRFS_ORPHAN_ITEM_KEY {
+            ids.push(header.offset);
+        }
+        true
+    })?;
+    Ok(ids)
+}
+
+/// Get the id of the default subvolume of the filesystem a path belongs to.
+pub(crate) fn default_subvolume(path: &Path) -> Result<u64> {
+    let file = ioctl::open(path)?;
+    // the "default" entry of the root tree directory points at the default subvolume
+    let key = SearchKey::new(
+        ioctl::BTRFS_ROOT_TREE_OBJECTID,
+        (
+            ioctl::BTRFS_ROOT_TREE_DIR_OBJECTID,
+            ioctl::BTRFS_DIR_ITEM_KEY,
+            0,
+        ),
+        (
+            ioctl::BTRFS_ROOT_TREE_DIR_OBJECTID,
+            ioctl::BTRFS_DIR_ITEM_KEY,
+            u64::MAX,
+        ),
+    );
+    let mut id = None;
+    ioctl::tree_search(file.as_raw_fd(), key, |header, item| {
+        // struct btrfs_dir_item: location key, transid, data_len, name_len, type, name
+        if header.item_type != ioctl::BTRFS_DIR_ITEM_KEY || item.len() < 30 {
+            return true;
+        }
+        let name_len = usize::from(u16::from_le_bytes([item[27], item[28]]));
+        if item.get(30..30 + name_len) == Some(b"default".as_ref()) {
+            id = Some(ioctl::le_u64(item, 0));
+            return false;
+        }
+        true
+    })?;
+    id.ok_or_else(|| BtrfsUtilError::os(LibError::DefaultSubvolFailed, libc::ENOENT))
+}
+
+/// Make the subvolume `id`, or the one `path` belongs to if zero, the default subvolume.
+pub(crate) fn set_default_subvolume(path: &Path, id: u64) -> Result<()> {
+    let file = ioctl::open(path)?;
+    let mut id = if id == 0 {
+        ioctl::subvolume_id(file.as_raw_fd())?
+    } else {
+        id
+    };
+    unsafe {
+        ioctl::ioctl(
+            file.as_raw_fd(),
+            ioctl::BTRFS_IOC_DEFAULT_SUBVOL,
+            &mut id,
+            LibError::DefaultSubvolFailed,
+        )
+    }
+}
+
+/// Check whether the subvolume a path belongs to is read-only.
+pub(crate) fn subvolume_read_only(path: &Path) -> Result<bool> {
+    let file = ioctl::open(path)?;
+    Ok(ioctl::subvolume_flags(file.as_raw_fd())? & ioctl::BTRFS_SUBVOL_RDONLY != 0)
+}
+
+/// Set whether the subvolume a path belongs to is read-only.
+pub(crate) fn set_subvolume_read_only(path: &Path, read_only: bool) -> Result<()> {
+    let file = ioctl::open(path)?;
+    let mut flags = ioctl::subvolume_flags(file.as_raw_fd())?;
+    if read_only {
+        flags |= ioctl::BTRFS_SUBVOL_RDONLY;
+    } else {
+        flags &= !ioctl::BTRFS_SUBVOL_RDONLY;
+    }
+    unsafe {
+        ioctl::ioctl(
+            file.as_raw_fd(),
+            ioctl::BTRFS_IOC_SUBVOL_SETFLAGS,
+            &mut flags,
+            LibError::SubvolSetflagsFailed,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn root_ref_parse() {
+        let mut item = Vec::new();
+        item.extend_from_slice(&300u64.to_le_bytes());
+        item.extend_from_slice(&7u64.to_le_bytes());
+        item.extend_from_slice(&4u16.to_le_bytes());
+        item.extend_from_slice(b"snap");
+        assert_eq!(root_ref(&item), Some((300, b"snap".as_ref())));
+        assert_eq!(root_ref(&item[..20]), None);
+    }
+
+    #[test]
+    fn strerror_matches_codes() {
+        assert_eq!(strerror(LibError::Ok as LibErrorCode), Ok("Success"));
+        assert_eq!(
+            strerror(LibError::FsInfoFailed as LibErrorCode),
+            Ok("Could not get filesystem information")
+        );
+        assert!(strerror(27).is_err());
+    }
+
+    #[test]
+    fn qgroup_inherit_layout() {
+        let words = qgroup_inherit(&[5, 1 << 48 | 100]);
+        assert_eq!(words.len(), QGROUP_INHERIT_HEADER + 2);
+        assert_eq!(words[1], 2);
+        assert_eq!(&words[QGROUP_INHERIT_HEADER..], &[5, 1 << 48 | 100]);
+    }
+}
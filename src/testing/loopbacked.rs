@@ -10,8 +10,11 @@ use std::{
 };
 
 use loopdev::{LoopControl, LoopDevice};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
 use tempfile::{self, TempDir};
 
+use crate::testing::test_lib::btrfs_create_fs;
+#[cfg(test)]
 use crate::testing::test_lib::clean_up;
 
 pub(crate) struct LoopTestDev {
@@ -48,9 +51,9 @@ impl Drop for LoopTestDev {
 }
 
 /// Setup count loop backed devices in dir.
-/// Make sure each loop device is backed by a sparse 1 GiB file. The entire file will read back
-/// as initialized with zero.
-fn get_devices(count: u8, dir: &TempDir) -> Vec<LoopTestDev> {
+/// Make sure each loop device is backed by a sparse file of size bytes. The entire file will read
+/// back as initialized with zero.
+fn get_devices(count: u8, size: u64, dir: &TempDir) -> Vec<LoopTestDev> {
     let lc = LoopControl::open().unwrap();
     let mut loop_devices = Vec::new();
 
@@ -60,10 +63,11 @@ fn get_devices(count: u8, dir: &TempDir) -> Vec<LoopTestDev> {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(true)
             .open(&path)
             .unwrap();
 
-        nix::unistd::ftruncate(f.as_raw_fd(), 1_000_000_000).unwrap();
+        nix::unistd::ftruncate(f.as_raw_fd(), size as i64).unwrap();
         f.sync_all().unwrap();
 
         let ltd = LoopTestDev::new(&lc, &path);
@@ -73,24 +77,78 @@ fn get_devices(count: u8, dir: &TempDir) -> Vec<LoopTestDev> {
     loop_devices
 }
 
-/// Set up count loopbacked devices.
-/// Then, run the designated test.
-/// Then, take down the loop devices.
-pub(crate) fn test_with_spec<F>(count: u8, test: F)
+/// Set up `count` loop devices, each backed by a sparse file of `size` bytes which reads back as
+/// zeroes, run `test` with their paths, then detach them and remove the files.
+///
+/// Panics if the devices cannot be set up, and resumes the panic of `test` once they are torn
+/// down.
+pub fn with_loopback_devices<F>(count: u8, size: u64, test: F)
 where
-    F: Fn(&[&Path]) + panic::RefUnwindSafe,
+    F: FnOnce(&[&Path]) + panic::UnwindSafe,
 {
-    clean_up().unwrap();
-
     let tmpdir = tempfile::Builder::new()
         .prefix("btrfsutil-")
         .tempdir()
         .unwrap();
-    let loop_devices: Vec<LoopTestDev> = get_devices(count, &tmpdir);
+    let loop_devices: Vec<LoopTestDev> = get_devices(count, size, &tmpdir);
     let device_paths: Vec<PathBuf> = loop_devices.iter().map(|x| x.path()).collect();
     let device_paths: Vec<&Path> = device_paths.iter().map(|x| x.as_path()).collect();
 
     let result = panic::catch_unwind(|| test(&device_paths));
+    drop(loop_devices);
+
+    if let Err(panic) = result {
+        panic::resume_unwind(panic);
+    }
+}
+
+/// Create a btrfs filesystem of `size` bytes on a loop device, mount it on a temporary
+/// directory, run `test` with the mount point, then unmount it and tear the device down.
+///
+/// `mkfs.btrfs` refuses devices smaller than about 114 MiB with the default profiles.
+///
+/// Panics if the filesystem cannot be set up, and resumes the panic of `test` once it is torn
+/// down.
+pub fn with_loopback_btrfs<F>(size: u64, test: F)
+where
+    F: FnOnce(&Path) + panic::UnwindSafe,
+{
+    with_loopback_devices(1, size, |devices| {
+        btrfs_create_fs(devices[0]).unwrap();
+
+        let mount_point = tempfile::Builder::new()
+            .prefix("btrfsutil-mnt-")
+            .tempdir()
+            .unwrap();
+        mount(
+            Some(devices[0]),
+            mount_point.path(),
+            Some("btrfs"),
+            MsFlags::empty(),
+            None as Option<&str>,
+        )
+        .unwrap();
+
+        let result = panic::catch_unwind(|| test(mount_point.path()));
+        umount2(mount_point.path(), MntFlags::MNT_DETACH).unwrap();
+
+        if let Err(panic) = result {
+            panic::resume_unwind(panic);
+        }
+    })
+}
+
+/// Set up count loopbacked devices.
+/// Then, run the designated test.
+/// Then, take down the loop devices.
+#[cfg(test)]
+pub(crate) fn test_with_spec<F>(count: u8, test: F)
+where
+    F: Fn(&[&Path]) + panic::RefUnwindSafe,
+{
+    clean_up().unwrap();
+
+    let result = panic::catch_unwind(|| with_loopback_devices(count, 1_000_000_000, &test));
     let tear_down = clean_up();
 
     result.unwrap();
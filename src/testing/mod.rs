@@ -2,10 +2,21 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-//! Modules that support testing.
+//! Test harness
+//!
+//! Sets up loop devices backed by sparse files and btrfs filesystems on them, for tests of code
+//! that needs a real filesystem. Everything is torn down once the test returns or panics, and
+//! the panic is then resumed so the test still fails.
+//!
+//! The helpers require root, a `loop` kernel module and `mkfs.btrfs` in the `PATH`, and panic
+//! when these are missing.
 
 mod loopbacked;
 mod test_lib;
 
+pub use self::loopbacked::with_loopback_btrfs;
+pub use self::loopbacked::with_loopback_devices;
+pub use self::test_lib::btrfs_create_fs;
+
+#[cfg(test)]
 pub(crate) use self::loopbacked::test_with_spec;
-pub(crate) use self::test_lib::btrfs_create_fs;
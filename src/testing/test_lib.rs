@@ -2,14 +2,11 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::{
-    fs::File,
-    io,
-    io::Read,
-    path::{Path, PathBuf},
-    process::Command,
-};
+#[cfg(test)]
+use std::{fs::File, io::Read, path::PathBuf};
+use std::{io, path::Path, process::Command};
 
+#[cfg(test)]
 use nix::mount::{umount2, MntFlags};
 
 /// Execute command while collecting stdout & stderr.
@@ -30,19 +27,25 @@ fn execute_cmd(cmd: &mut Command) -> io::Result<()> {
                     "cmd: {:?} stdout: {} stderr: {}",
                     cmd, std_out_txt, std_err_txt
                 );
-                Ok(())
+                Err(io::Error::other(format!(
+                    "{:?} failed with {}",
+                    cmd, result.status
+                )))
             }
         }
     }
 }
 
-/// Generate a btrfs FS, does not specify UUID as that's not supported on version in Travis
-pub(crate) fn btrfs_create_fs(devnode: &Path) -> io::Result<()> {
+/// Create a btrfs filesystem on a device with `mkfs.btrfs`, overwriting anything on it.
+///
+/// Fails if `mkfs.btrfs` cannot be run or exits with an error.
+pub fn btrfs_create_fs(devnode: &Path) -> io::Result<()> {
     execute_cmd(Command::new("mkfs.btrfs").arg("-f").arg("-q").arg(devnode))
 }
 
 /// Unmount any filesystems that contain TEST_ID in the mount point.
 /// Return immediately on the first unmount failure.
+#[cfg(test)]
 fn test_fs_unmount() -> io::Result<()> {
     || -> io::Result<()> {
         let mut mount_data = String::new();
@@ -56,13 +59,14 @@ fn test_fs_unmount() -> io::Result<()> {
         {
             umount2(&PathBuf::from(mount_point), MntFlags::MNT_DETACH).map_err(|e| {
                 eprintln!("Could not umount2: {}", e);
-                io::Error::new(io::ErrorKind::Other, e)
+                io::Error::other(e)
             })?;
         }
         Ok(())
     }()
 }
 
+#[cfg(test)]
 pub(crate) fn clean_up() -> io::Result<()> {
     test_fs_unmount()
 }
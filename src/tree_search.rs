@@ -0,0 +1,237 @@
+//! Btrfs tree search
+//!
+//! A safe wrapper over `BTRFS_IOC_TREE_SEARCH_V2`, walking the items of a btrfs tree in key
+//! order. Items are handed out as raw on-disk bytes with bounds-checked accessors; interpreting
+//! them is up to the caller, see [linux/btrfs_tree.h] for their layouts.
+//!
+//! [linux/btrfs_tree.h]: https://github.com/torvalds/linux/blob/master/include/uapi/linux/btrfs_tree.h
+
+use crate::error::ResultExt;
+use crate::ioctl;
+use crate::ioctl::SearchKey;
+pub use crate::Result;
+
+use std::convert::TryInto;
+use std::ops::RangeInclusive;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// The tree of the subvolume the searched path belongs to.
+pub const SUBVOLUME_TREE: u64 = 0;
+/// The tree of tree roots.
+pub const ROOT_TREE_OBJECTID: u64 = 1;
+/// The extent tree.
+pub const EXTENT_TREE_OBJECTID: u64 = 2;
+/// The chunk tree.
+pub const CHUNK_TREE_OBJECTID: u64 = 3;
+/// The device tree.
+pub const DEV_TREE_OBJECTID: u64 = 4;
+/// The tree of the top-level subvolume.
+pub const FS_TREE_OBJECTID: u64 = 5;
+/// The checksum tree.
+pub const CSUM_TREE_OBJECTID: u64 = 7;
+/// The quota tree.
+pub const QUOTA_TREE_OBJECTID: u64 = 8;
+/// The tree mapping subvolume UUIDs to subvolume ids.
+pub const UUID_TREE_OBJECTID: u64 = 9;
+
+/// Key of a btrfs item.
+///
+/// Keys are ordered by objectid, then type, then offset, like the items of a tree.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Key {
+    /// Object id.
+    pub objectid: u64,
+    /// Item type.
+    pub item_type: u8,
+    /// Offset, whose meaning depends on the item type.
+    pub offset: u64,
+}
+
+impl Key {
+    /// The smallest key.
+    pub const MIN: Key = Key::new(0, 0, 0);
+    /// The largest key.
+    pub const MAX: Key = Key::new(u64::MAX, u8::MAX, u64::MAX);
+
+    /// Create a key.
+    pub const fn new(objectid: u64, item_type: u8, offset: u64) -> Self {
+        Self {
+            objectid,
+            item_type,
+            offset,
+        }
+    }
+}
+
+/// The items of a tree to search.
+///
+/// The key range is compound: every item whose key sorts between the two bounds is returned,
+/// whatever its individual fields are.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SearchRange {
+    tree_id: u64,
+    min: Key,
+    max: Key,
+    transids: RangeInclusive<u64>,
+}
+
+impl SearchRange {
+    /// Search the items of a tree with keys in `min..=max`.
+    pub fn new(tree_id: u64, min: Key, max: Key) -> Self {
+        Self {
+            tree_id,
+            min,
+            max,
+            transids: 0..=u64::MAX,
+        }
+    }
+
+    /// Search all items of a tree.
+    pub fn all(tree_id: u64) -> Self {
+        Self::new(tree_id, Key::MIN, Key::MAX)
+    }
+
+    /// Search the items of one type belonging to one object.
+    pub fn object(tree_id: u64, objectid: u64, item_type: u8) -> Self {
+        Self::new(
+            tree_id,
+            Key::new(objectid, item_type, 0),
+            Key::new(objectid, item_type, u64::MAX),
+        )
+    }
+
+    /// Only search the parts of the tree written in a range of transactions.
+    ///
+    /// The kernel filters whole tree blocks, so older items sharing a block with newer ones are
+    /// still returned; use [Item::transid] to tell them apart.
+    ///
+    /// [Item::transid]: struct.Item.html#method.transid
+    pub fn transids(mut self, transids: RangeInclusive<u64>) -> Self {
+        self.transids = transids;
+        self
+    }
+
+    fn to_key(&self) -> SearchKey {
+        let mut key = SearchKey::new(
+            self.tree_id,
+            (
+                self.min.objectid,
+                self.min.item_type as u32,
+                self.min.offset,
+            ),
+            (
+                self.max.objectid,
+                self.max.item_type as u32,
+                self.max.offset,
+            ),
+        );
+        key.min_transid = *self.transids.start();
+        key.max_transid = *self.transids.end();
+        key
+    }
+}
+
+/// An item found by a search.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Item<'a> {
+    key: Key,
+    transid: u64,
+    data: &'a [u8],
+}
+
+impl<'a> Item<'a> {
+    /// Get the key of the item.
+    #[inline]
+    pub fn key(&self) -> Key {
+        self.key
+    }
+
+    /// Get the transaction id of the tree block holding the item.
+    #[inline]
+    pub fn transid(&self) -> u64 {
+        self.transid
+    }
+
+    /// Get the raw contents of the item.
+    #[inline]
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Get `len` bytes of the item starting at `offset`, or None if they are out of bounds.
+    pub fn bytes(&self, offset: usize, len: usize) -> Option<&'a [u8]> {
+        self.data.get(offset..offset.checked_add(len)?)
+    }
+
+    /// Read a byte, or None if it is out of bounds.
+    pub fn u8(&self, offset: usize) -> Option<u8> {
+        self.data.get(offset).copied()
+    }
+
+    /// Read a little-endian u16, or None if it is out of bounds.
+    pub fn le_u16(&self, offset: usize) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(offset, 2)?.try_into().ok()?))
+    }
+
+    /// Read a little-endian u32, or None if it is out of bounds.
+    pub fn le_u32(&self, offset: usize) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(offset, 4)?.try_into().ok()?))
+    }
+
+    /// Read a little-endian u64, or None if it is out of bounds.
+    pub fn le_u64(&self, offset: usize) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(offset, 8)?.try_into().ok()?))
+    }
+}
+
+/// Call `f` on every item in range of the tree, in key order, on the filesystem a path belongs
+/// to.
+///
+/// The callback returns false to stop the search early.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+pub fn for_each<P, F>(path: P, range: &SearchRange, f: F) -> Result<()>
+where
+    P: AsRef<Path>,
+    F: FnMut(&Item) -> bool,
+{
+    let path = path.as_ref();
+    for_each_impl(path, range, f).context("tree_search::for_each", &[path])
+}
+
+fn for_each_impl<F>(path: &Path, range: &SearchRange, mut f: F) -> Result<()>
+where
+    F: FnMut(&Item) -> bool,
+{
+    let file = ioctl::open(path)?;
+
+    ioctl::tree_search(file.as_raw_fd(), range.to_key(), |header, data| {
+        f(&Item {
+            key: Key::new(header.objectid, header.item_type as u8, header.offset),
+            transid: header.transid,
+            data,
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tree_search_item_bounds() {
+        let data = [1, 0, 2, 0, 0, 0, 0, 0, 0, 0];
+        let item = Item {
+            key: Key::new(256, 1, 0),
+            transid: 0,
+            data: &data,
+        };
+
+        assert_eq!(item.le_u16(0), Some(1));
+        assert_eq!(item.le_u64(2), Some(2));
+        assert_eq!(item.le_u64(3), None);
+        assert_eq!(item.bytes(usize::MAX, 2), None);
+        assert!(Key::new(1, 2, 0) < Key::new(1, 3, 0));
+    }
+}
@@ -0,0 +1,470 @@
+//! Read-integrity audits against the data checksums btrfs keeps in its checksum tree.
+//!
+//! This recomputes the checksum of every data sector of a file range and compares it with the
+//! one recorded in the checksum tree, giving a lightweight audit of a file or a sample of a
+//! subvolume without running a full scrub.
+//!
+//! Inline, preallocated and compressed extents, as well as files without data checksums
+//! (`nodatasum`), cannot be verified this way and are counted as skipped.
+//!
+//! ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+
+use crate::error::LibError;
+use crate::error::ResultExt;
+use crate::ioctl;
+use crate::ioctl::SearchKey;
+use crate::subvolume::Subvolume;
+use crate::BtrfsUtilError;
+pub use crate::Result;
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// A checksum algorithm used to recompute data checksums.
+///
+/// [Crc32c] is provided; other algorithms supported by btrfs (xxhash64, sha256, blake2b) can be
+/// plugged in by implementing this trait.
+///
+/// [Crc32c]: struct.Crc32c.html
+pub trait Checksummer {
+    /// The btrfs checksum type (`BTRFS_CSUM_TYPE_*`) this implementation computes.
+    fn csum_type(&self) -> u16;
+
+    /// Compute the checksum of one sector, in the byte layout btrfs stores on disk.
+    fn checksum(&self, data: &[u8]) -> Vec<u8>;
+}
+
+/// The crc32c checksum, the btrfs default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Crc32c;
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+impl Crc32c {
+    /// Compute the crc32c of a buffer.
+    pub fn crc(data: &[u8]) -> u32 {
+        !data.iter().fold(!0u32, |crc, byte| {
+            CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+        })
+    }
+}
+
+impl Checksummer for Crc32c {
+    fn csum_type(&self) -> u16 {
+        ioctl::BTRFS_CSUM_TYPE_CRC32
+    }
+
+    fn checksum(&self, data: &[u8]) -> Vec<u8> {
+        Self::crc(data).to_le_bytes().to_vec()
+    }
+}
+
+/// A byte range of a file to verify.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PathRange<'a> {
+    path: &'a Path,
+    offset: u64,
+    len: Option<u64>,
+}
+
+impl<'a> PathRange<'a> {
+    /// Create a range of `len` bytes starting at `offset`.
+    ///
+    /// The range is widened to whole sectors.
+    pub fn new(path: &'a Path, offset: u64, len: u64) -> Self {
+        Self {
+            path,
+            offset,
+            len: Some(len),
+        }
+    }
+
+    /// Get the path of the file.
+    #[inline]
+    pub fn path(&self) -> &Path {
+        self.path
+    }
+}
+
+impl<'a> From<&'a Path> for PathRange<'a> {
+    /// The whole file.
+    fn from(path: &'a Path) -> Self {
+        Self {
+            path,
+            offset: 0,
+            len: None,
+        }
+    }
+}
+
+/// A sector whose data does not match its stored checksum.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsumMismatch {
+    /// Offset of the sector within the file.
+    pub file_offset: u64,
+    /// Logical address of the sector.
+    pub logical: u64,
+    /// Checksum stored in the checksum tree.
+    pub expected: Vec<u8>,
+    /// Checksum of the data read back, or None if the sector could not be read (the kernel
+    /// refuses to return data that fails its own verification).
+    pub actual: Option<Vec<u8>>,
+}
+
+/// Outcome of verifying a file range.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsumReport {
+    /// The path of the file.
+    pub path: PathBuf,
+    /// Number of sectors whose checksum matched.
+    pub verified: u64,
+    /// Number of sectors that could not be verified.
+    pub skipped: u64,
+    /// Sectors whose checksum did not match.
+    pub mismatches: Vec<CsumMismatch>,
+}
+
+impl CsumReport {
+    /// Check whether no mismatches were found.
+    #[inline]
+    pub fn is_ok(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// A file extent item, reduced to what verification needs.
+struct FileExtent {
+    file_offset: u64,
+    extent_type: u8,
+    compression: u8,
+    disk_bytenr: u64,
+    offset: u64,
+    num_bytes: u64,
+}
+
+/// Verify the data checksums of a file range using [Crc32c].
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+///
+/// [Crc32c]: struct.Crc32c.html
+pub fn csums<'a, R>(range: R) -> Result<CsumReport>
+where
+    R: Into<PathRange<'a>>,
+{
+    let range = range.into();
+    csums_impl(range, &Crc32c).context("verify::csums", &[range.path])
+}
+
+/// Verify the data checksums of a file range using a custom [Checksummer].
+///
+/// Fails with [LibError::InvalidArgument] if the filesystem uses a different checksum type.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+///
+/// [Checksummer]: trait.Checksummer.html
+/// [LibError::InvalidArgument]: ../error/enum.LibError.html#variant.InvalidArgument
+pub fn csums_with<'a, R, C>(range: R, checksummer: &C) -> Result<CsumReport>
+where
+    R: Into<PathRange<'a>>,
+    C: Checksummer,
+{
+    let range = range.into();
+    csums_impl(range, checksummer).context("verify::csums_with", &[range.path])
+}
+
+/// Verify the data checksums of up to `count` regular files of a subvolume using [Crc32c].
+///
+/// Files are picked breadth-first from the root of the subvolume, without descending into
+/// nested subvolumes.
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+///
+/// [Crc32c]: struct.Crc32c.html
+pub fn subvolume_sample(subvol: &Subvolume, count: usize) -> Result<Vec<CsumReport>> {
+    subvolume_sample_with(subvol, count, &Crc32c)
+}
+
+/// Verify the data checksums of up to `count` regular files of a subvolume using a custom
+/// [Checksummer].
+///
+/// ![Requires **CAP_SYS_ADMIN**](https://img.shields.io/static/v1?label=Requires&message=CAP_SYS_ADMIN&color=informational)
+///
+/// [Checksummer]: trait.Checksummer.html
+pub fn subvolume_sample_with<C>(
+    subvol: &Subvolume,
+    count: usize,
+    checksummer: &C,
+) -> Result<Vec<CsumReport>>
+where
+    C: Checksummer,
+{
+    subvolume_sample_with_impl(subvol, count, checksummer)
+        .context("verify::subvolume_sample_with", &[subvol.path()])
+}
+
+fn subvolume_sample_with_impl<C>(
+    subvol: &Subvolume,
+    count: usize,
+    checksummer: &C,
+) -> Result<Vec<CsumReport>>
+where
+    C: Checksummer,
+{
+    let root_dev = std::fs::symlink_metadata(subvol.path())
+        .map_err(|err| BtrfsUtilError::from_io(LibError::StatFailed, &err))?
+        .dev();
+
+    let mut reports: Vec<CsumReport> = Vec::new();
+    let mut dirs: VecDeque<PathBuf> = VecDeque::new();
+    dirs.push_back(subvol.path().to_path_buf());
+
+    while let Some(dir) = dirs.pop_front() {
+        let entries = std::fs::read_dir(&dir)
+            .map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?;
+        for entry in entries {
+            if reports.len() >= count {
+                return Ok(reports);
+            }
+
+            let path = entry
+                .map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?
+                .path();
+            let metadata = std::fs::symlink_metadata(&path)
+                .map_err(|err| BtrfsUtilError::from_io(LibError::StatFailed, &err))?;
+            // nested subvolumes have their own anonymous device
+            if metadata.dev() != root_dev {
+                continue;
+            }
+
+            if metadata.is_dir() {
+                dirs.push_back(path);
+            } else if metadata.is_file() {
+                reports.push(csums_impl(PathRange::from(path.as_path()), checksummer)?);
+            }
+        }
+    }
+
+    Ok(reports)
+}
+
+pub(crate) fn csums_impl(range: PathRange, checksummer: &dyn Checksummer) -> Result<CsumReport> {
+    let file = File::open(range.path)
+        .map_err(|err| BtrfsUtilError::from_io(LibError::OpenFailed, &err))?;
+    let metadata = file
+        .metadata()
+        .map_err(|err| BtrfsUtilError::from_io(LibError::StatFailed, &err))?;
+    if !metadata.is_file() {
+        return Err(LibError::InvalidArgument.into());
+    }
+
+    let fd = file.as_raw_fd();
+    let fs_info = ioctl::fs_info(fd)?;
+    if fs_info.csum_type != checksummer.csum_type() {
+        return Err(LibError::InvalidArgument.into());
+    }
+
+    let sectorsize = fs_info.sectorsize as u64;
+    let start = range.offset - range.offset % sectorsize;
+    let end = match range.len {
+        Some(len) => metadata.size().min(range.offset.saturating_add(len)),
+        None => metadata.size(),
+    };
+
+    let mut report = CsumReport {
+        path: range.path.to_path_buf(),
+        verified: 0,
+        skipped: 0,
+        mismatches: Vec::new(),
+    };
+
+    let ino = metadata.ino();
+    let mut extents: Vec<FileExtent> = Vec::new();
+    ioctl::tree_search(
+        fd,
+        SearchKey::new(
+            0,
+            (ino, ioctl::BTRFS_EXTENT_DATA_KEY, 0),
+            (ino, ioctl::BTRFS_EXTENT_DATA_KEY, end),
+        ),
+        |header, item| {
+            if header.item_type != ioctl::BTRFS_EXTENT_DATA_KEY || item.len() < 21 {
+                return true;
+            }
+            let extent_type = item[20];
+            let inline = extent_type == ioctl::BTRFS_FILE_EXTENT_INLINE;
+            // regular and preallocated extents end with disk_bytenr, disk_num_bytes, offset and
+            // num_bytes
+            if !inline && item.len() < 53 {
+                return true;
+            }
+            extents.push(FileExtent {
+                file_offset: header.offset,
+                extent_type,
+                compression: item[16],
+                disk_bytenr: if inline { 0 } else { ioctl::le_u64(item, 21) },
+                offset: if inline { 0 } else { ioctl::le_u64(item, 37) },
+                // inline extents keep their length in ram_bytes
+                num_bytes: if inline {
+                    ioctl::le_u64(item, 8)
+                } else {
+                    ioctl::le_u64(item, 45)
+                },
+            });
+            true
+        },
+    )?;
+
+    let mut sector: Vec<u8> = vec![0; sectorsize as usize];
+    for extent in extents {
+        let extent_start = start.max(extent.file_offset);
+        let extent_end = end.min(extent.file_offset.saturating_add(extent.num_bytes));
+        if extent_start >= extent_end {
+            continue;
+        }
+        let sectors = (extent_end - extent_start).div_ceil(sectorsize);
+
+        let verifiable = extent.extent_type == ioctl::BTRFS_FILE_EXTENT_REG
+            && extent.compression == 0
+            && extent.disk_bytenr != 0;
+        if !verifiable {
+            // holes have nothing to verify
+            if extent.extent_type == ioctl::BTRFS_FILE_EXTENT_INLINE
+                || extent.extent_type == ioctl::BTRFS_FILE_EXTENT_PREALLOC
+                || extent.disk_bytenr != 0
+            {
+                report.skipped += sectors;
+            }
+            continue;
+        }
+
+        let logical_start =
+            extent.disk_bytenr + extent.offset + (extent_start - extent.file_offset);
+        let expected = lookup_csums(fd, &fs_info, logical_start, sectors)?;
+
+        for (index, expected) in expected.into_iter().enumerate() {
+            let file_offset = extent_start + index as u64 * sectorsize;
+            let expected = match expected {
+                Some(val) => val,
+                None => {
+                    report.skipped += 1;
+                    continue;
+                }
+            };
+
+            let actual =
+                read_sector(&file, file_offset, &mut sector).map(|_| checksummer.checksum(&sector));
+            if actual.as_ref() == Some(&expected) {
+                report.verified += 1;
+            } else {
+                report.mismatches.push(CsumMismatch {
+                    file_offset,
+                    logical: logical_start + index as u64 * sectorsize,
+                    expected,
+                    actual,
+                });
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Look up the stored checksums of `count` sectors starting at a logical address.
+fn lookup_csums(
+    fd: RawFd,
+    fs_info: &ioctl::FsInfoArgs,
+    logical: u64,
+    count: u64,
+) -> Result<Vec<Option<Vec<u8>>>> {
+    let sectorsize = fs_info.sectorsize as u64;
+    let csum_size = fs_info.csum_size as usize;
+    let end = logical + count * sectorsize;
+    // a checksum item starting before the range can still cover its first sectors, but no item
+    // spans more than a full leaf worth of checksums
+    let max_span = (fs_info.nodesize as u64 / csum_size as u64) * sectorsize;
+
+    let mut csums: Vec<Option<Vec<u8>>> = vec![None; count as usize];
+    ioctl::tree_search(
+        fd,
+        SearchKey::new(
+            ioctl::BTRFS_CSUM_TREE_OBJECTID,
+            (
+                ioctl::BTRFS_EXTENT_CSUM_OBJECTID,
+                ioctl::BTRFS_EXTENT_CSUM_KEY,
+                logical.saturating_sub(max_span),
+            ),
+            (
+                ioctl::BTRFS_EXTENT_CSUM_OBJECTID,
+                ioctl::BTRFS_EXTENT_CSUM_KEY,
+                end - 1,
+            ),
+        ),
+        |header, item| {
+            if header.item_type != ioctl::BTRFS_EXTENT_CSUM_KEY {
+                return true;
+            }
+            for (index, csum) in item.chunks_exact(csum_size).enumerate() {
+                let sector_logical = header.offset + index as u64 * sectorsize;
+                if sector_logical >= end {
+                    break;
+                }
+                if sector_logical >= logical {
+                    csums[((sector_logical - logical) / sectorsize) as usize] = Some(csum.to_vec());
+                }
+            }
+            true
+        },
+    )?;
+
+    Ok(csums)
+}
+
+/// Read one sector of a file, zero-padding past the end of the file like btrfs does on disk.
+fn read_sector(file: &File, offset: u64, sector: &mut [u8]) -> Option<()> {
+    let mut filled = 0;
+    while filled < sector.len() {
+        match file.read_at(&mut sector[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) => return None,
+        }
+    }
+    sector[filled..].iter_mut().for_each(|byte| *byte = 0);
+    Some(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(Crc32c::crc(b"123456789"), 0xe306_9283);
+        assert_eq!(Crc32c::crc(&[]), 0);
+    }
+}
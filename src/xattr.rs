@@ -0,0 +1,106 @@
+//! Extended attribute helpers for the metadata this library keeps on subvolumes.
+
+use crate::common;
+use crate::error::LibError;
+use crate::BtrfsUtilError;
+use crate::Result;
+
+use std::ffi::CString;
+use std::path::Path;
+
+use libc::c_void;
+
+/// Get the value of an extended attribute, or None if it is not set.
+pub(crate) fn get(path: &Path, name: &str) -> Result<Option<Vec<u8>>> {
+    let path_cstr = common::path_to_cstr(path);
+    let name_cstr = name_to_cstr(name)?;
+
+    loop {
+        let size = unsafe {
+            libc::getxattr(
+                path_cstr.as_ptr(),
+                name_cstr.as_ptr(),
+                std::ptr::null_mut(),
+                0,
+            )
+        };
+        if size < 0 {
+            return match std::io::Error::last_os_error().raw_os_error() {
+                Some(libc::ENODATA) => Ok(None),
+                _ => Err(LibError::GetXattrFailed.into()),
+            };
+        }
+
+        let mut value: Vec<u8> = vec![0; size as usize];
+        let read = unsafe {
+            libc::getxattr(
+                path_cstr.as_ptr(),
+                name_cstr.as_ptr(),
+                value.as_mut_ptr() as *mut c_void,
+                value.len(),
+            )
+        };
+        if read < 0 {
+            match std::io::Error::last_os_error().raw_os_error() {
+                // the value grew in between the two calls
+                Some(libc::ERANGE) => continue,
+                Some(libc::ENODATA) => return Ok(None),
+                _ => return Err(LibError::GetXattrFailed.into()),
+            }
+        }
+
+        value.truncate(read as usize);
+        return Ok(Some(value));
+    }
+}
+
+/// Get the value of an extended attribute as a string, or None if it is not set.
+pub(crate) fn get_string(path: &Path, name: &str) -> Result<Option<String>> {
+    match get(path, name)? {
+        Some(value) => String::from_utf8(value)
+            .map(Some)
+            .map_err(|_| LibError::GetXattrFailed.into()),
+        None => Ok(None),
+    }
+}
+
+/// Set an extended attribute, replacing any previous value.
+pub(crate) fn set(path: &Path, name: &str, value: &[u8]) -> Result<()> {
+    let path_cstr = common::path_to_cstr(path);
+    let name_cstr = name_to_cstr(name)?;
+
+    let ret = unsafe {
+        libc::setxattr(
+            path_cstr.as_ptr(),
+            name_cstr.as_ptr(),
+            value.as_ptr() as *const c_void,
+            value.len(),
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(BtrfsUtilError::last_os_error(LibError::SetXattrFailed));
+    }
+
+    Ok(())
+}
+
+/// Remove an extended attribute. Removing one that is not set is not an error.
+pub(crate) fn remove(path: &Path, name: &str) -> Result<()> {
+    let path_cstr = common::path_to_cstr(path);
+    let name_cstr = name_to_cstr(name)?;
+
+    if unsafe { libc::removexattr(path_cstr.as_ptr(), name_cstr.as_ptr()) } < 0 {
+        return match std::io::Error::last_os_error().raw_os_error() {
+            Some(libc::ENODATA) => Ok(()),
+            _ => Err(LibError::SetXattrFailed.into()),
+        };
+    }
+
+    Ok(())
+}
+
+#[inline]
+fn name_to_cstr(name: &str) -> Result<CString> {
+    CString::new(name).map_err(|_| LibError::InvalidArgument.into())
+}